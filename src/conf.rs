@@ -2,47 +2,100 @@
 //!
 //! The Conf module provides the beamium configuration.
 //! It set defaults and then load config from '/etc', local dir and provided path.
-use std::collections::HashMap;
+use std::cmp::max;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
-use std::net::SocketAddr;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::{Deref, RangeInclusive};
 use std::path::PathBuf;
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::time::Duration;
 
-use config::{Config, File};
+use config::{Config, File, FileSourceFile, Source};
 use failure::{format_err, Error, ResultExt};
 use humanize_rs::bytes::{Bytes, Unit};
 use humanize_rs::duration::parse;
 use hyper::Uri;
 use notify::{watcher, DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
-use regex::{Regex, RegexSet};
+use regex::{escape, Regex, RegexSet};
 use serde_derive::{Deserialize, Serialize};
 use std::env;
 
 use glob::glob;
 
+use crate::constants::{CONFIG_ORIGIN_LABEL, MIN_BATCH_SIZE, MIN_PERIOD, TENANT_PLACEHOLDER};
+use crate::lib::secret;
+
 /// `Scraper` config.
-#[derive(Deserialize, Serialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
 pub(crate) struct RawScraper {
+    #[serde(default)]
     pub url: String,
     pub period: String,
     pub format: Option<String>,
     pub metrics: Option<Vec<String>>,
-    pub headers: Option<HashMap<String, String>>,
+    #[serde(rename = "metrics-match")]
+    pub metrics_match: Option<String>,
+    pub headers: Option<Headers>,
     pub labels: Option<HashMap<String, String>>,
+    pub attributes: Option<HashMap<String, String>>,
     #[serde(rename = "filtered-labels")]
     pub filtered_labels: Option<Vec<String>>,
     pub pool: Option<usize>,
+    #[serde(rename = "emit-failure-series")]
+    pub emit_failure_series: Option<bool>,
+    #[serde(rename = "failure-series-class")]
+    pub failure_series_class: Option<String>,
+    #[serde(rename = "emit-status-series")]
+    pub emit_status_series: Option<bool>,
+    #[serde(rename = "status-series-class")]
+    pub status_series_class: Option<String>,
+    #[serde(rename = "stop-at-eof")]
+    pub stop_at_eof: Option<bool>,
+    #[serde(rename = "normalize-label-keys")]
+    pub normalize_label_keys: Option<bool>,
+    #[serde(rename = "normalize-label-values")]
+    pub normalize_label_values: Option<bool>,
+    #[serde(rename = "write-every")]
+    pub write_every: Option<String>,
+    pub clock: Option<String>,
+    #[serde(rename = "counter-reset-detection")]
+    pub counter_reset_detection: Option<String>,
+    #[serde(rename = "continuation-lines")]
+    pub continuation_lines: Option<bool>,
+    #[serde(rename = "bind-address")]
+    pub bind_address: Option<String>,
+    pub timeout: Option<String>,
+    pub geo: Option<RawGeo>,
+    #[serde(rename = "stream-body")]
+    pub stream_body: Option<bool>,
+    #[serde(rename = "parallel-parsing")]
+    pub parallel_parsing: Option<bool>,
+    #[serde(rename = "cardinality-guard")]
+    pub cardinality_guard: Option<RawCardinalityGuard>,
+    #[serde(rename = "label-cardinality")]
+    pub label_cardinality: Option<bool>,
+    #[serde(rename = "targets-file")]
+    pub targets_file: Option<String>,
+    #[serde(rename = "require-content-type")]
+    pub require_content_type: Option<String>,
+    #[serde(rename = "summary-handling")]
+    pub summary_handling: Option<String>,
+    #[serde(rename = "label-escapes")]
+    pub label_escapes: Option<String>,
 }
 
 /// `RawSink` config.
-#[derive(Deserialize, Serialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
 pub(crate) struct RawSink {
     pub url: String,
     pub token: String,
     #[serde(rename = "token-header")]
     pub token_header: Option<String>,
     pub selector: Option<String>,
+    #[serde(rename = "priority-selector")]
+    pub priority_selector: Option<String>,
     pub ttl: Option<String>,
     pub size: Option<String>,
     pub parallel: Option<usize>,
@@ -50,6 +103,45 @@ pub(crate) struct RawSink {
     pub keep_alive: Option<bool>,
     #[serde(rename = "keep-alive-timeout")]
     pub keep_alive_timeout: Option<String>,
+    #[serde(rename = "max-connection-age")]
+    pub max_connection_age: Option<String>,
+    pub prefetch: Option<bool>,
+    #[serde(rename = "circuit-breaker-threshold")]
+    pub circuit_breaker_threshold: Option<u64>,
+    #[serde(rename = "circuit-breaker-probe")]
+    pub circuit_breaker_probe: Option<String>,
+    #[serde(rename = "ramp-up")]
+    pub ramp_up: Option<String>,
+    #[serde(rename = "retryable-statuses")]
+    pub retryable_statuses: Option<Vec<u16>>,
+    #[serde(rename = "droppable-statuses")]
+    pub droppable_statuses: Option<Vec<u16>>,
+    #[serde(rename = "skip-partial-lines")]
+    pub skip_partial_lines: Option<bool>,
+    #[serde(rename = "skip-empty-batches")]
+    pub skip_empty_batches: Option<bool>,
+    #[serde(rename = "tenant-label")]
+    pub tenant_label: Option<String>,
+    #[serde(rename = "tenant-fallback")]
+    pub tenant_fallback: Option<String>,
+    #[serde(rename = "token-by-label")]
+    pub token_by_label: Option<RawTokenByLabel>,
+    pub format: Option<String>,
+    #[serde(rename = "shard-range")]
+    pub shard_range: Option<String>,
+    #[serde(rename = "bind-address")]
+    pub bind_address: Option<String>,
+    pub timeout: Option<String>,
+    pub backoff: Option<RawSinkBackoff>,
+    pub transport: Option<String>,
+    #[serde(rename = "overflow-policy")]
+    pub overflow_policy: Option<String>,
+    #[serde(rename = "archive-dir")]
+    pub archive_dir: Option<String>,
+    #[serde(rename = "archive-size")]
+    pub archive_size: Option<String>,
+    #[serde(rename = "max-files")]
+    pub max_files: Option<usize>,
 }
 
 /// `RawBackoff` config.
@@ -59,6 +151,59 @@ pub(crate) struct RawBackoff {
     pub max: String,
     pub multiplier: f64,
     pub randomization: f64,
+    pub strategy: String,
+}
+
+/// `RawSinkBackoff` config, unset values fall back on the backoff parameters.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub(crate) struct RawSinkBackoff {
+    pub initial: Option<String>,
+    pub max: Option<String>,
+    pub multiplier: Option<f64>,
+    pub randomization: Option<f64>,
+    pub strategy: Option<String>,
+}
+
+/// `RawHeartbeat` config.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub(crate) struct RawHeartbeat {
+    pub interval: String,
+    pub class: Option<String>,
+    pub labels: Option<HashMap<String, String>>,
+}
+
+/// `RawCheckpoint` config.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub(crate) struct RawCheckpoint {
+    pub dir: String,
+    pub interval: Option<String>,
+    #[serde(rename = "max-age")]
+    pub max_age: Option<String>,
+    #[serde(rename = "max-series")]
+    pub max_series: Option<usize>,
+}
+
+/// `RawGeo` config.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub(crate) struct RawGeo {
+    pub latitude: String,
+    pub longitude: String,
+    pub elevation: Option<String>,
+}
+
+/// `RawCardinalityGuard` config.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub(crate) struct RawCardinalityGuard {
+    pub mode: Option<String>,
+    pub factor: Option<f64>,
+    pub window: Option<usize>,
+}
+
+/// `RawTokenByLabel` config.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub(crate) struct RawTokenByLabel {
+    pub label: String,
+    pub tokens: HashMap<String, String>,
 }
 
 /// `RawParameters` config.
@@ -79,13 +224,84 @@ pub(crate) struct RawParameters {
     #[serde(rename = "log-level")]
     pub log_level: usize,
     pub syslog: bool,
-    pub timeout: String,
+    pub timeout: Option<String>,
+    #[serde(rename = "scrape-timeout")]
+    pub scrape_timeout: Option<String>,
+    #[serde(rename = "push-timeout")]
+    pub push_timeout: Option<String>,
     #[serde(rename = "router-parallel")]
     pub router_parallel: usize,
+    #[serde(rename = "router-max-inflight")]
+    pub router_max_inflight: usize,
     pub backoff: RawBackoff,
     pub metrics: Option<String>,
+    #[serde(rename = "metrics-required")]
+    pub metrics_required: bool,
+    #[serde(rename = "status-endpoint")]
+    pub status_endpoint: bool,
+    #[serde(rename = "emit-config-origin-label")]
+    pub emit_config_origin_label: bool,
+    #[serde(rename = "directory-retries")]
+    pub directory_retries: usize,
+    #[serde(rename = "run-id-label")]
+    pub run_id_label: Option<String>,
+    #[serde(rename = "directory-retry-delay")]
+    pub directory_retry_delay: String,
     #[serde(rename = "filesystem-threads")]
     pub filesystem_threads: usize,
+    #[serde(rename = "max-sink-fanout")]
+    pub max_sink_fanout: Option<usize>,
+    #[serde(rename = "sink-fanout-policy")]
+    pub sink_fanout_policy: String,
+    #[serde(rename = "tls-session-resumption")]
+    pub tls_session_resumption: bool,
+    #[serde(rename = "nested-sink-dir")]
+    pub nested_sink_dir: bool,
+    #[serde(rename = "log-rate-limit")]
+    pub log_rate_limit: Option<u32>,
+    #[serde(rename = "max-dns-lookups")]
+    pub max_dns_lookups: usize,
+    #[serde(rename = "spool-symlinks")]
+    pub spool_symlinks: String,
+    #[serde(rename = "max-scrapes-per-second")]
+    pub max_scrapes_per_second: Option<u32>,
+    #[serde(rename = "max-scrapes-per-host")]
+    pub max_scrapes_per_host: Option<usize>,
+    #[serde(rename = "shard-by")]
+    pub shard_by: Option<String>,
+    #[serde(rename = "shard-count")]
+    pub shard_count: u64,
+    pub heartbeat: Option<RawHeartbeat>,
+    pub checkpoint: Option<RawCheckpoint>,
+    #[serde(rename = "sort-timestamps")]
+    pub sort_timestamps: bool,
+    #[serde(rename = "max-continuations")]
+    pub max_continuations: Option<usize>,
+    #[serde(rename = "bind-address")]
+    pub bind_address: Option<String>,
+    #[serde(rename = "scraper-start-wave")]
+    pub scraper_start_wave: Option<usize>,
+    #[serde(rename = "scraper-start-delay")]
+    pub scraper_start_delay: String,
+    #[serde(rename = "router-delta")]
+    pub router_delta: bool,
+    #[serde(rename = "router-delta-heartbeat")]
+    pub router_delta_heartbeat: String,
+    #[serde(rename = "duplicate-names")]
+    pub duplicate_names: String,
+    #[serde(rename = "status-interval")]
+    pub status_interval: Option<String>,
+    #[serde(rename = "min-tls-version")]
+    pub min_tls_version: String,
+    #[serde(rename = "disk-full-backoff")]
+    pub disk_full_backoff: String,
+    pub durability: String,
+    #[serde(rename = "max-lifetime")]
+    pub max_lifetime: Option<String>,
+    #[serde(rename = "platform-time-unit")]
+    pub platform_time_unit: String,
+    #[serde(rename = "fetch-success-log-every")]
+    pub fetch_success_log_every: Option<u64>,
 }
 
 /// `RawConfig` root.
@@ -96,6 +312,39 @@ pub(crate) struct RawConf {
     pub sinks: Option<HashMap<String, RawSink>>,
     pub labels: Option<HashMap<String, String>>,
     pub parameters: RawParameters,
+    /// File defining each scraper
+    #[serde(skip)]
+    pub origins: HashMap<String, String>,
+    /// Scrapers and sinks defined in several files
+    #[serde(skip)]
+    pub duplicates: Vec<Duplicate>,
+}
+
+/// `Duplicate` scraper or sink defined in several configuration files.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Duplicate {
+    pub kind: &'static str,
+    pub name: String,
+    /// Files defining it, in merge order
+    pub origins: Vec<String>,
+}
+
+impl fmt::Display for Duplicate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let origins: Vec<String> = self
+            .origins
+            .iter()
+            .map(|origin| format!("'{}'", origin))
+            .collect();
+
+        write!(
+            f,
+            "{} '{}' is defined in several files, {}",
+            self.kind,
+            self.name,
+            origins.join(", ")
+        )
+    }
 }
 
 impl TryFrom<&PathBuf> for RawConf {
@@ -106,16 +355,21 @@ impl TryFrom<&PathBuf> for RawConf {
         let mut config = Self::initialize()
             .with_context(|err| format!("could not initialize the configuration, {}", err))?;
 
-        config
-            .merge(File::from(path.to_owned()).required(true))
-            .with_context(|err| format!("could not merge configuration with file, {}", err))?;
+        let file = File::from(path.to_owned()).required(true);
+        let (origins, duplicates) =
+            Self::merge_files(&mut config, vec![(path.display().to_string(), file)])
+                .with_context(|err| format!("could not merge configuration with file, {}", err))?;
 
-        Ok(config.try_into::<Self>()?)
+        let mut raw_config = config.try_into::<Self>()?;
+        raw_config.origins = origins;
+        raw_config.duplicates = duplicates;
+
+        Ok(raw_config)
     }
 }
 
 impl RawConf {
-    fn initialize() -> Result<Config, Error> {
+    pub(crate) fn initialize() -> Result<Config, Error> {
         let mut config = Config::default();
 
         // parameters
@@ -127,15 +381,36 @@ impl RawConf {
         config.set_default("parameters.log-file", "beamium.log")?;
         config.set_default("parameters.log-level", 4)?;
         config.set_default("parameters.syslog", false)?;
-        config.set_default("parameters.timeout", "500s")?;
         config.set_default("parameters.router-parallel", 1)?;
+        config.set_default("parameters.router-max-inflight", 100)?;
         config.set_default("parameters.filesystem-threads", 100)?;
+        config.set_default("parameters.sink-fanout-policy", "skip")?;
+        config.set_default("parameters.tls-session-resumption", true)?;
+        config.set_default("parameters.nested-sink-dir", false)?;
+        config.set_default("parameters.max-dns-lookups", 32)?;
+        config.set_default("parameters.spool-symlinks", "follow")?;
+        config.set_default("parameters.shard-count", 16)?;
+        config.set_default("parameters.sort-timestamps", false)?;
+        config.set_default("parameters.metrics-required", false)?;
+        config.set_default("parameters.status-endpoint", false)?;
+        config.set_default("parameters.emit-config-origin-label", false)?;
+        config.set_default("parameters.directory-retries", 3)?;
+        config.set_default("parameters.directory-retry-delay", "1s")?;
+        config.set_default("parameters.scraper-start-delay", "100ms")?;
+        config.set_default("parameters.router-delta", false)?;
+        config.set_default("parameters.router-delta-heartbeat", "10m")?;
+        config.set_default("parameters.duplicate-names", "warn")?;
+        config.set_default("parameters.min-tls-version", "1.2")?;
+        config.set_default("parameters.disk-full-backoff", "10s")?;
+        config.set_default("parameters.durability", "flush")?;
+        config.set_default("parameters.platform-time-unit", "us")?;
 
         // backoff parameters
         config.set_default("parameters.backoff.initial", "500ms")?;
         config.set_default("parameters.backoff.max", "1m")?;
         config.set_default("parameters.backoff.multiplier", 1.5)?;
         config.set_default("parameters.backoff.randomization", 0.3)?;
+        config.set_default("parameters.backoff.strategy", "exponential")?;
 
         Ok(config)
     }
@@ -151,11 +426,14 @@ impl RawConf {
 
                 let path = result.expect("error is filter above");
 
-                Some(File::from(path).required(false))
+                Some((path.display().to_string(), File::from(path).required(false)))
             })
             .collect::<Vec<_>>();
 
-        paths.push(File::with_name("/etc/beamium/config").required(false));
+        paths.push((
+            String::from("/etc/beamium/config"),
+            File::with_name("/etc/beamium/config").required(false),
+        ));
 
         paths.append(
             &mut glob(format!("{}/.beamium.d/**/*", env!("HOME")).as_str())?
@@ -166,28 +444,91 @@ impl RawConf {
 
                     let path = result.expect("error is filter above");
 
-                    Some(File::from(path).required(false))
+                    Some((path.display().to_string(), File::from(path).required(false)))
                 })
                 .collect::<Vec<_>>(),
         );
 
-        paths.push(
-            File::with_name(format!("{}/.beamium/config", env!("HOME")).as_str()).required(false),
-        );
+        let path = format!("{}/.beamium/config", env!("HOME"));
+        paths.push((
+            path.to_owned(),
+            File::with_name(path.as_str()).required(false),
+        ));
 
-        config.merge(paths).with_context(|err| {
+        let (origins, duplicates) = Self::merge_files(&mut config, paths).with_context(|err| {
             format!("could not merge configuration using default paths, {}", err)
         })?;
 
-        Ok(config.try_into::<Self>()?)
+        let mut raw_config = config.try_into::<Self>()?;
+        raw_config.origins = origins;
+        raw_config.duplicates = duplicates;
+
+        Ok(raw_config)
+    }
+
+    /// Merge the files into the configuration and retrieve the file defining each scraper, the
+    /// last one if several files define it, and the scrapers and sinks defined in several files
+    fn merge_files(
+        config: &mut Config,
+        files: Vec<(String, File<FileSourceFile>)>,
+    ) -> Result<(HashMap<String, String>, Vec<Duplicate>), Error> {
+        let mut origins = HashMap::new();
+        let mut definitions: Vec<Duplicate> = vec![];
+        for (origin, file) in &files {
+            // Malformed files are reported by the merge
+            let table = match file.collect() {
+                Ok(table) => table,
+                Err(_) => continue,
+            };
+
+            for (key, kind) in &[
+                ("sources", "scraper"),
+                ("scrapers", "scraper"),
+                ("sinks", "sink"),
+            ] {
+                let entries = match table.get(*key).map(|value| value.to_owned().into_table()) {
+                    Some(Ok(entries)) => entries,
+                    _ => continue,
+                };
+
+                for name in entries.keys() {
+                    if *kind == "scraper" {
+                        origins.insert(name.to_owned(), origin.to_owned());
+                    }
+
+                    match definitions
+                        .iter_mut()
+                        .find(|definition| definition.kind == *kind && definition.name == *name)
+                    {
+                        Some(definition) => definition.origins.push(origin.to_owned()),
+                        None => definitions.push(Duplicate {
+                            kind,
+                            name: name.to_owned(),
+                            origins: vec![origin.to_owned()],
+                        }),
+                    }
+                }
+            }
+        }
+
+        config.merge(files.into_iter().map(|(_, file)| file).collect::<Vec<_>>())?;
+
+        let duplicates = definitions
+            .into_iter()
+            .filter(|definition| definition.origins.len() > 1)
+            .collect();
+
+        Ok((origins, duplicates))
     }
 }
 
 /// `Scraper` format.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ScraperFormat {
     Prometheus,
     Sensision,
+    /// Detect the format using the first time series of the body
+    Auto,
 }
 
 impl TryFrom<&str> for ScraperFormat {
@@ -197,13 +538,193 @@ impl TryFrom<&str> for ScraperFormat {
         match v {
             "sensision" => Ok(ScraperFormat::Sensision),
             "prometheus" => Ok(ScraperFormat::Prometheus),
+            "auto" => Ok(ScraperFormat::Auto),
+            _ => Err(format_err!(
+                "the scraper's format field should be one of 'sensision', 'prometheus' or 'auto'"
+            )),
+        }
+    }
+}
+
+/// `Scraper` metrics match target.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetricsMatch {
+    /// Patterns are anchored on the class name of the time series
+    Class,
+    /// Patterns are matched anywhere in the formatted line
+    Line,
+}
+
+impl TryFrom<&str> for MetricsMatch {
+    type Error = Error;
+
+    fn try_from(v: &str) -> Result<Self, Self::Error> {
+        match v {
+            "class" => Ok(MetricsMatch::Class),
+            "line" => Ok(MetricsMatch::Line),
+            _ => Err(format_err!(
+                "the scraper's metrics-match field should be one of 'class' or 'line'"
+            )),
+        }
+    }
+}
+
+/// `Metrics` selecting the fetched time series of a scraper.
+#[derive(Debug, Clone)]
+pub enum Metrics {
+    /// Patterns matched using a regex set
+    Patterns(RegexSet),
+    /// Class names looked up in a set, used when the patterns anchored on the class are literals
+    Classes(HashSet<String>),
+}
+
+impl Metrics {
+    pub fn new(patterns: &[String], metrics_match: &MetricsMatch) -> Result<Self, Error> {
+        let patterns: Vec<String> = match metrics_match {
+            MetricsMatch::Line => patterns.to_owned(),
+            // Thousands of class names are looked up faster than matched by a regex set
+            MetricsMatch::Class if patterns.iter().all(|pattern| escape(pattern) == *pattern) => {
+                return Ok(Metrics::Classes(patterns.iter().cloned().collect()));
+            }
+            MetricsMatch::Class => patterns
+                .iter()
+                .map(|pattern| format!("^(?:{})$", pattern))
+                .collect(),
+        };
+
+        Ok(Metrics::Patterns(RegexSet::new(patterns).with_context(
+            |err| format!("Could not create regex set from 'metrics' field, {}", err),
+        )?))
+    }
+
+    /// Whether the line, or the class depending on the metrics match, is selected
+    pub fn is_match(&self, text: &str) -> bool {
+        match self {
+            Metrics::Patterns(patterns) => patterns.is_match(text),
+            Metrics::Classes(classes) => classes.contains(text),
+        }
+    }
+}
+
+/// `Scraper` handling of the counters resets.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CounterReset {
+    /// Add the value reached before each reset, so the counter keeps increasing
+    Adjust,
+    /// Add a `reset=true` label to the first sample following a reset
+    Marker,
+}
+
+impl TryFrom<&str> for CounterReset {
+    type Error = Error;
+
+    fn try_from(v: &str) -> Result<Self, Self::Error> {
+        match v {
+            "adjust" => Ok(CounterReset::Adjust),
+            "marker" => Ok(CounterReset::Marker),
+            _ => Err(format_err!(
+                "the scraper's counter-reset-detection field should be one of 'adjust' or 'marker'"
+            )),
+        }
+    }
+}
+
+/// `Scraper` handling of the quantile series of the Prometheus summaries.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SummaryHandling {
+    /// Keep the `quantile` label as is
+    Passthrough,
+    /// Move the quantile in the class, e.g. `f{quantile="0.99"}` is written as `f_p99{}`
+    Split,
+}
+
+impl TryFrom<&str> for SummaryHandling {
+    type Error = Error;
+
+    fn try_from(v: &str) -> Result<Self, Self::Error> {
+        match v {
+            "passthrough" => Ok(SummaryHandling::Passthrough),
+            "split" => Ok(SummaryHandling::Split),
+            _ => Err(format_err!(
+                "the scraper's summary-handling field should be one of 'passthrough' or 'split'"
+            )),
+        }
+    }
+}
+
+/// `Scraper` handling of the escape sequences of the Prometheus label values.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LabelEscapes {
+    /// Encode the escape sequences as is, e.g. `a\\b` is written as `a%5C%5Cb`
+    Keep,
+    /// Decode the escape sequences before encoding, e.g. `a\\b` is written as `a%5Cb`
+    Decode,
+}
+
+impl TryFrom<&str> for LabelEscapes {
+    type Error = Error;
+
+    fn try_from(v: &str) -> Result<Self, Self::Error> {
+        match v {
+            "keep" => Ok(LabelEscapes::Keep),
+            "decode" => Ok(LabelEscapes::Decode),
+            _ => Err(format_err!(
+                "the scraper's label-escapes field should be one of 'keep' or 'decode'"
+            )),
+        }
+    }
+}
+
+/// `Scraper` clock used for samples lacking a timestamp.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Clock {
+    /// Wall-clock time at each scrape
+    Wall,
+    /// Wall-clock time anchored once and advanced using monotonic time
+    Monotonic,
+}
+
+impl TryFrom<&str> for Clock {
+    type Error = Error;
+
+    fn try_from(v: &str) -> Result<Self, Self::Error> {
+        match v {
+            "wall" => Ok(Clock::Wall),
+            "monotonic" => Ok(Clock::Monotonic),
             _ => Err(format_err!(
-                "the scraper's format field should be one of 'sensision' or 'prometheus'"
+                "the scraper's clock field should be one of 'wall' or 'monotonic'"
             )),
         }
     }
 }
 
+/// Http headers of the scrapes, the values of the sensitive ones are masked when debugged
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq)]
+#[serde(transparent)]
+pub struct Headers(pub HashMap<String, String>);
+
+impl Deref for Headers {
+    type Target = HashMap<String, String>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Headers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map()
+            .entries(self.0.iter().map(|(name, value)| {
+                if secret::is_sensitive(name) {
+                    (name, "***")
+                } else {
+                    (name, value.as_str())
+                }
+            }))
+            .finish()
+    }
+}
+
 /// `Scraper` config.
 #[derive(Clone, Debug)]
 pub struct Scraper {
@@ -211,11 +732,75 @@ pub struct Scraper {
     pub url: Uri,
     pub period: Duration,
     pub format: ScraperFormat,
-    pub metrics: Option<RegexSet>,
-    pub headers: HashMap<String, String>,
+    pub metrics: Option<Metrics>,
+    pub metrics_match: MetricsMatch,
+    pub headers: Headers,
     pub labels: HashMap<String, String>,
+    /// Key/values written in the Warp10 attributes block of the fetched time series
+    pub attributes: HashMap<String, String>,
     pub filtered_labels: Vec<String>,
     pub pool: usize,
+    /// Class of the series written on scrape failure, `None` if disabled
+    pub failure_series: Option<String>,
+    /// Class of the series holding the http status of each scrape, `None` if disabled
+    pub status_series: Option<String>,
+    /// Ignore the content after the OpenMetrics '# EOF' marker
+    pub stop_at_eof: bool,
+    /// Lowercase and sanitize the label keys of the fetched time series
+    pub normalize_label_keys: bool,
+    /// Lowercase the label values of the fetched time series
+    pub normalize_label_values: bool,
+    /// Accumulate scrapes in memory and write them at this interval, `None` to write each scrape
+    pub write_every: Option<Duration>,
+    /// Clock used for samples lacking a timestamp
+    pub clock: Clock,
+    /// Handling of the resets of the declared counters, `None` if disabled
+    pub counter_reset_detection: Option<CounterReset>,
+    /// Lines starting with '=' are Warp10 continuations and skip the label treatment
+    pub continuation_lines: bool,
+    /// Local address of the connections, `None` to use the 'bind-address' parameter
+    pub bind_address: Option<IpAddr>,
+    /// Timeout of the scrapes, `None` to use the 'scrape-timeout' parameter
+    pub timeout: Option<Duration>,
+    /// Labels rendered as the Warp10 location of the time series, `None` to keep them as labels
+    pub geo: Option<Geo>,
+    /// Process and write the body while it is received instead of buffering it
+    pub stream_body: bool,
+    /// Parse large bodies in chunks on the workers of the pool
+    pub parallel_parsing: bool,
+    /// Compare the number of time series of each scrape with the previous ones, `None` if disabled
+    pub cardinality_guard: Option<CardinalityGuard>,
+    /// Expose the number of distinct values of each label key of the scrapes
+    pub label_cardinality: bool,
+    /// File listing the targets scraped using these settings, `None` to scrape `url`
+    pub targets_file: Option<PathBuf>,
+    /// Pattern the content type of the responses should match, `None` to accept any response
+    pub require_content_type: Option<Regex>,
+    /// Handling of the quantile series of the Prometheus summaries
+    pub summary_handling: SummaryHandling,
+    /// Handling of the escape sequences of the Prometheus label values
+    pub label_escapes: LabelEscapes,
+}
+
+impl Scraper {
+    /// Timeout of the scrapes, falling back on the parameters
+    pub fn timeout_of(&self, params: &Parameters) -> Duration {
+        self.timeout.unwrap_or(params.scrape_timeout)
+    }
+
+    /// Parse the url of the scrapes, which should use the 'http' or 'https' protocol
+    pub fn parse_url(url: &str) -> Result<Uri, Error> {
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            Err(format_err!(
+                "protocol is missing or incorrect, it should be one of 'http' or 'https'"
+            ))
+            .with_context(|err| format!("could not parse 'url' setting, {}", err))?;
+        }
+
+        Ok(url
+            .parse::<Uri>()
+            .with_context(|err| format!("could not parse 'url' setting, {}", err))?)
+    }
 }
 
 impl TryFrom<(String, RawScraper)> for Scraper {
@@ -225,10 +810,14 @@ impl TryFrom<(String, RawScraper)> for Scraper {
         let name = value.0;
         let raw_scraper = value.1;
 
+        let metrics_match = match raw_scraper.metrics_match {
+            None => MetricsMatch::Line,
+            Some(ref metrics_match) => MetricsMatch::try_from(metrics_match.as_str())
+                .with_context(|err| format!("could not parse 'metrics-match' setting, {}", err))?,
+        };
+
         let metrics = match raw_scraper.metrics {
-            Some(ref patterns) => Some(RegexSet::new(patterns).with_context(|err| {
-                format!("Could not create regex set from 'metrics' field, {}", err)
-            })?),
+            Some(ref patterns) => Some(Metrics::new(patterns, &metrics_match)?),
             None => None,
         };
 
@@ -238,11 +827,79 @@ impl TryFrom<(String, RawScraper)> for Scraper {
                 .with_context(|err| format!("could not parse 'period' setting, {}", err))?,
         };
 
-        let headers = match raw_scraper.headers {
-            None => HashMap::new(),
-            Some(headers) => headers,
+        if period < MIN_PERIOD {
+            return Err(format_err!(
+                "could not parse 'period' setting, it should be at least {}ms, got {}ms",
+                MIN_PERIOD.as_millis(),
+                period.as_millis()
+            ));
+        }
+
+        let write_every =
+            match raw_scraper.write_every {
+                None => None,
+                Some(write_every) => Some(parse(write_every.as_str()).with_context(|err| {
+                    format!("could not parse 'write-every' setting, {}", err)
+                })?),
+            };
+
+        let stream_body = raw_scraper.stream_body.unwrap_or(false);
+        if stream_body && write_every.is_some() {
+            return Err(format_err!(
+                "could not parse 'stream-body' setting, it could not be used with 'write-every'"
+            ));
+        }
+
+        let parallel_parsing = raw_scraper.parallel_parsing.unwrap_or(false);
+        if parallel_parsing && stream_body {
+            return Err(format_err!(
+                "could not parse 'parallel-parsing' setting, it could not be used with 'stream-body'"
+            ));
+        }
+
+        let cardinality_guard = match raw_scraper.cardinality_guard {
+            None => None,
+            Some(ref guard) => Some(CardinalityGuard::try_from(guard)?),
+        };
+
+        if cardinality_guard.is_some() && stream_body {
+            return Err(format_err!(
+                "could not parse 'cardinality-guard' setting, it could not be used with 'stream-body'"
+            ));
+        }
+
+        let clock = match raw_scraper.clock {
+            None => Clock::Wall,
+            Some(ref clock) => Clock::try_from(clock.as_str())
+                .with_context(|err| format!("could not parse 'clock' setting, {}", err))?,
+        };
+
+        let summary_handling = match raw_scraper.summary_handling {
+            None => SummaryHandling::Passthrough,
+            Some(ref handling) => {
+                SummaryHandling::try_from(handling.as_str()).with_context(|err| {
+                    format!("could not parse 'summary-handling' setting, {}", err)
+                })?
+            }
+        };
+
+        let label_escapes = match raw_scraper.label_escapes {
+            None => LabelEscapes::Keep,
+            Some(ref escapes) => LabelEscapes::try_from(escapes.as_str())
+                .with_context(|err| format!("could not parse 'label-escapes' setting, {}", err))?,
+        };
+
+        let counter_reset_detection = match raw_scraper.counter_reset_detection {
+            None => None,
+            Some(ref policy) => {
+                Some(CounterReset::try_from(policy.as_str()).with_context(|err| {
+                    format!("could not parse 'counter-reset-detection' setting, {}", err)
+                })?)
+            }
         };
 
+        let headers = raw_scraper.headers.unwrap_or_default();
+
         let mut labels = match raw_scraper.labels {
             None => HashMap::new(),
             Some(mut labels) => {
@@ -269,37 +926,86 @@ impl TryFrom<(String, RawScraper)> for Scraper {
             None => 1,
         };
 
+        let attributes = raw_scraper.attributes.unwrap_or_default();
+
         let filtered_labels = match raw_scraper.filtered_labels {
             Some(filtered_labels) => filtered_labels,
             None => vec![],
         };
 
+        let failure_series = match raw_scraper.emit_failure_series {
+            Some(true) => Some(match raw_scraper.failure_series_class {
+                None => String::from("scrape_error"),
+                Some(class) => class,
+            }),
+            _ => None,
+        };
+
+        let status_series = match raw_scraper.emit_status_series {
+            Some(true) => Some(match raw_scraper.status_series_class {
+                None => String::from("scrape_http_status"),
+                Some(class) => class,
+            }),
+            _ => None,
+        };
+
         let format = match raw_scraper.format {
             None => String::from("prometheus"),
             Some(format) => format,
         };
 
-        if !raw_scraper.url.starts_with("http://") && !raw_scraper.url.starts_with("https://") {
-            Err(format_err!(
-                "protocol is missing or incorrect, it should be one of 'http' or 'https'"
-            ))
-            .with_context(|err| format!("could not parse 'url' setting, {}", err))?;
-        }
+        // The url of each target is read from the targets file
+        let url = match raw_scraper.targets_file {
+            Some(_) if !raw_scraper.url.is_empty() => {
+                return Err(format_err!(
+                    "could not parse 'targets-file' setting, it could not be used with 'url'"
+                ));
+            }
+            Some(_) => Uri::default(),
+            None => Self::parse_url(raw_scraper.url.as_str())?,
+        };
 
         Ok(Self {
             name,
-            url: raw_scraper
-                .url
-                .parse::<Uri>()
-                .with_context(|err| format!("could not parse 'url' setting, {}", err))?,
+            url,
             period,
             format: ScraperFormat::try_from(format.as_str())
                 .with_context(|err| format!("could not parse 'format' setting, {}", err))?,
             metrics,
+            metrics_match,
             headers,
             labels,
+            attributes,
             filtered_labels,
             pool,
+            failure_series,
+            status_series,
+            stop_at_eof: raw_scraper.stop_at_eof.unwrap_or(false),
+            normalize_label_keys: raw_scraper.normalize_label_keys.unwrap_or(false),
+            normalize_label_values: raw_scraper.normalize_label_values.unwrap_or(false),
+            write_every,
+            clock,
+            counter_reset_detection,
+            continuation_lines: raw_scraper.continuation_lines.unwrap_or(true),
+            bind_address: Conf::parse_bind_address(raw_scraper.bind_address)?,
+            timeout: Conf::parse_timeout("timeout", raw_scraper.timeout)?,
+            geo: match raw_scraper.geo {
+                None => None,
+                Some(ref geo) => Some(Geo::try_from(geo)?),
+            },
+            stream_body,
+            parallel_parsing,
+            cardinality_guard,
+            label_cardinality: raw_scraper.label_cardinality.unwrap_or(false),
+            targets_file: raw_scraper.targets_file.map(PathBuf::from),
+            require_content_type: match raw_scraper.require_content_type {
+                None => None,
+                Some(ref pattern) => Some(Regex::new(pattern).with_context(|err| {
+                    format!("could not parse 'require-content-type' setting, {}", err)
+                })?),
+            },
+            summary_handling,
+            label_escapes,
         })
     }
 }
@@ -312,11 +1018,83 @@ pub struct Sink {
     pub token: String,
     pub token_header: String,
     pub selector: Option<Regex>,
+    pub priority_selector: Option<Regex>,
     pub ttl: Duration,
     pub size: u64,
     pub parallel: usize,
     pub keep_alive: bool,
     pub keep_alive_timeout: Duration,
+    pub max_connection_age: Option<Duration>,
+    pub prefetch: bool,
+    pub circuit_breaker_threshold: Option<u64>,
+    pub circuit_breaker_probe: Duration,
+    /// Period to raise the number of senders from one to `parallel`, `None` to start them all
+    pub ramp_up: Option<Duration>,
+    /// Failed http statuses whose batch is sent again, `None` to send it again on any failure
+    pub retryable_statuses: Option<Vec<u16>>,
+    /// Failed http statuses whose batch is dropped
+    pub droppable_statuses: Vec<u16>,
+    /// Skip the last line of a file if it is not terminated by a newline, e.g. truncated by a crash
+    pub skip_partial_lines: bool,
+    /// Remove the files of a batch without time series instead of posting it
+    pub skip_empty_batches: bool,
+    /// Label whose value replaces the '{tenant}' placeholder of the url, `None` if not templated
+    pub tenant_label: Option<String>,
+    /// Tenant of the time series without the tenant label, `None` to keep their batch
+    pub tenant_fallback: Option<String>,
+    /// Tokens used for the time series depending on the value of a label, `None` to use `token`
+    pub token_by_label: Option<TokenByLabel>,
+    /// Url holding the '{tenant}' placeholder
+    pub url_template: Option<String>,
+    /// Format of the posted time series
+    pub format: SinkFormat,
+    /// Buckets of the series routed to the sink when sharding, `None` to receive every series
+    pub shard_range: Option<RangeInclusive<u64>>,
+    /// Local address of the connections, `None` to use the 'bind-address' parameter
+    pub bind_address: Option<IpAddr>,
+    /// Timeout of the requests, `None` to use the 'push-timeout' parameter
+    pub timeout: Option<Duration>,
+    /// Backoff of the requests, `None` to use the 'backoff' parameter
+    pub backoff: Option<SinkBackoff>,
+    /// Transport of the time series to the url
+    pub transport: Transport,
+    /// Handling of the oldest files once the sink is too large
+    pub overflow_policy: OverflowPolicy,
+    /// Directory receiving the compressed files overflowing the sink, `None` if they are dropped
+    pub archive_dir: Option<PathBuf>,
+    /// Maximum size of the archive directory, beyond which the oldest archived files are removed
+    pub archive_size: u64,
+    /// Maximum number of files queued by the sink, beyond which the router skips it, `None` if
+    /// unbounded
+    pub max_files: Option<usize>,
+}
+
+impl Sink {
+    /// Timeout of the requests, falling back on the parameters
+    pub fn timeout_of(&self, params: &Parameters) -> Duration {
+        self.timeout.unwrap_or(params.push_timeout)
+    }
+
+    /// Backoff of the requests, falling back on the parameters
+    pub fn backoff_of(&self, params: &Parameters) -> Backoff {
+        match self.backoff {
+            None => params.backoff.to_owned(),
+            Some(ref backoff) => backoff.over(&params.backoff),
+        }
+    }
+
+    /// Url of the sink for the given tenant
+    pub fn url_of(&self, tenant: &str) -> Result<Uri, Error> {
+        match &self.url_template {
+            None => Ok(self.url.to_owned()),
+            Some(template) => Ok(template
+                .replace(TENANT_PLACEHOLDER, tenant)
+                .parse::<Uri>()
+                .with_context(|err| {
+                    format!("could not expand url for tenant '{}', {}", tenant, err)
+                })?),
+        }
+    }
 }
 
 impl TryFrom<(String, RawSink)> for Sink {
@@ -335,6 +1113,18 @@ impl TryFrom<(String, RawSink)> for Sink {
             }
         };
 
+        let priority_selector = match raw_sink.priority_selector {
+            None => None,
+            Some(ref pattern) => {
+                Some(Regex::new(&format!("^{}", pattern)).with_context(|err| {
+                    format!(
+                        "could not create regex from 'priority-selector' field, {}",
+                        err
+                    )
+                })?)
+            }
+        };
+
         let keep_alive_timeout = match raw_sink.keep_alive_timeout {
             None => Duration::from_secs(3600),
             Some(timeout) => parse(timeout.as_str()).with_context(|err| {
@@ -342,6 +1132,187 @@ impl TryFrom<(String, RawSink)> for Sink {
             })?,
         };
 
+        let max_connection_age = match raw_sink.max_connection_age {
+            None => None,
+            Some(age) => Some(parse(age.as_str()).with_context(|err| {
+                format!("could not parse 'max-connection-age' setting, {}", err)
+            })?),
+        };
+
+        let circuit_breaker_threshold = match raw_sink.circuit_breaker_threshold {
+            Some(0) => {
+                return Err(format_err!(
+                    "could not parse 'circuit-breaker-threshold' setting, value must be greater than 0"
+                ));
+            }
+            threshold => threshold,
+        };
+
+        let circuit_breaker_probe = match raw_sink.circuit_breaker_probe {
+            None => Duration::from_secs(30),
+            Some(probe) => parse(probe.as_str()).with_context(|err| {
+                format!("could not parse 'circuit-breaker-probe' setting, {}", err)
+            })?,
+        };
+
+        let ramp_up = match raw_sink.ramp_up {
+            None => None,
+            Some(ramp_up) => Some(
+                parse(ramp_up.as_str())
+                    .with_context(|err| format!("could not parse 'ramp-up' setting, {}", err))?,
+            ),
+        };
+
+        let url_template = if raw_sink.url.contains(TENANT_PLACEHOLDER) {
+            Some(raw_sink.url.to_owned())
+        } else {
+            None
+        };
+
+        match (&url_template, &raw_sink.tenant_label) {
+            (Some(_), None) => {
+                return Err(format_err!(
+                    "could not parse 'url' setting, the '{}' placeholder requires the 'tenant-label' setting",
+                    TENANT_PLACEHOLDER
+                ));
+            }
+            (None, Some(_)) => {
+                return Err(format_err!(
+                    "could not parse 'tenant-label' setting, the url should hold the '{}' placeholder",
+                    TENANT_PLACEHOLDER
+                ));
+            }
+            _ => {}
+        }
+
+        if let Some(ref fallback) = raw_sink.tenant_fallback {
+            if raw_sink.tenant_label.is_none() {
+                return Err(format_err!(
+                    "could not parse 'tenant-fallback' setting, it requires the 'tenant-label' setting"
+                ));
+            }
+
+            raw_sink
+                .url
+                .replace(TENANT_PLACEHOLDER, fallback)
+                .parse::<Uri>()
+                .with_context(|err| {
+                    format!("could not parse 'tenant-fallback' setting, {}", err)
+                })?;
+        }
+
+        let token_by_label = match raw_sink.token_by_label {
+            None => None,
+            Some(ref token_by_label) => Some(TokenByLabel::try_from(token_by_label)?),
+        };
+
+        if token_by_label.is_some() && raw_sink.tenant_label.is_some() {
+            return Err(format_err!(
+                "could not parse 'token-by-label' setting, it could not be used with the 'tenant-label' setting"
+            ));
+        }
+
+        if let Some(ref statuses) = raw_sink.retryable_statuses {
+            Self::check_statuses(statuses).with_context(|err| {
+                format!("could not parse 'retryable-statuses' setting, {}", err)
+            })?;
+        }
+
+        let droppable_statuses = raw_sink.droppable_statuses.unwrap_or_default();
+        Self::check_statuses(&droppable_statuses)
+            .with_context(|err| format!("could not parse 'droppable-statuses' setting, {}", err))?;
+
+        if let Some(ref statuses) = raw_sink.retryable_statuses {
+            if let Some(status) = statuses.iter().find(|s| droppable_statuses.contains(s)) {
+                return Err(format_err!(
+                    "could not parse 'droppable-statuses' setting, status {} is also retryable",
+                    status
+                ));
+            }
+        }
+
+        let shard_range =
+            match raw_sink.shard_range {
+                None => None,
+                Some(ref range) => Some(Self::parse_shard_range(range).with_context(|err| {
+                    format!("could not parse 'shard-range' setting, {}", err)
+                })?),
+            };
+
+        let format = match raw_sink.format {
+            None => SinkFormat::Warp10,
+            Some(ref format) => SinkFormat::try_from(format.as_str())
+                .with_context(|err| format!("could not parse 'format' setting, {}", err))?,
+        };
+
+        let transport = match raw_sink.transport {
+            None => Transport::Http,
+            Some(ref transport) => Transport::try_from(transport.as_str())
+                .with_context(|err| format!("could not parse 'transport' setting, {}", err))?,
+        };
+
+        if transport == Transport::Tcp {
+            let is_grouped = raw_sink.tenant_label.is_some() || token_by_label.is_some();
+            Self::check_relay(&raw_sink.url, &format, is_grouped)
+                .with_context(|err| format!("could not parse 'transport' setting, {}", err))?;
+        }
+
+        let overflow_policy = match raw_sink.overflow_policy {
+            None => OverflowPolicy::Drop,
+            Some(ref policy) => OverflowPolicy::try_from(policy.as_str()).with_context(|err| {
+                format!("could not parse 'overflow-policy' setting, {}", err)
+            })?,
+        };
+
+        let archive_dir = match (&overflow_policy, raw_sink.archive_dir) {
+            (OverflowPolicy::Archive, Some(dir)) => Some(PathBuf::from(dir)),
+            (OverflowPolicy::Archive, None) => {
+                return Err(format_err!(
+                    "could not parse 'archive-dir' setting, it should be set to archive the overflowing files"
+                ));
+            }
+            (OverflowPolicy::Drop, Some(_)) => {
+                return Err(format_err!(
+                    "could not parse 'archive-dir' setting, it requires 'overflow-policy: archive'"
+                ));
+            }
+            (OverflowPolicy::Drop, None) => None,
+        };
+
+        let archive_size = match (&overflow_policy, raw_sink.archive_size) {
+            (OverflowPolicy::Drop, Some(_)) => {
+                return Err(format_err!(
+                    "could not parse 'archive-size' setting, it requires 'overflow-policy: archive'"
+                ));
+            }
+            (_, None) => String::from("10Gb"),
+            (_, Some(size)) => size,
+        };
+
+        let archive_size = match archive_size.parse::<u64>() {
+            Ok(size) => Bytes::new(size, Unit::Byte)?.size(),
+            Err(_) => archive_size
+                .parse::<Bytes>()
+                .with_context(|err| format!("could not parse 'archive-size' setting, {}", err))?
+                .size() as u64,
+        };
+
+        if raw_sink.max_files == Some(0) {
+            return Err(format_err!(
+                "could not parse 'max-files' setting, it should be greater than 0"
+            ));
+        }
+
+        // OpenTSDB does not authenticate the writes, nor do the relays reached over tcp
+        if format == SinkFormat::Warp10
+            && transport == Transport::Http
+            && raw_sink.token.trim().is_empty()
+        {
+            return Err(format_err!(
+                "could not parse 'token' setting, it should not be empty"
+            ));
+        }
+
         let token_header = match raw_sink.token_header {
             None => String::from("X-Warp10-Token"),
             Some(token_header) => token_header,
@@ -383,8 +1354,10 @@ impl TryFrom<(String, RawSink)> for Sink {
 
         Ok(Self {
             name,
+            // A templated url is checked using the placeholder name as tenant
             url: raw_sink
                 .url
+                .replace(TENANT_PLACEHOLDER, "tenant")
                 .parse::<Uri>()
                 .with_context(|err| format!("could not parse 'url' setting, {}", err))?,
             token: raw_sink.token,
@@ -392,20 +1365,74 @@ impl TryFrom<(String, RawSink)> for Sink {
             ttl,
             size,
             selector,
+            priority_selector,
             parallel,
             keep_alive,
             keep_alive_timeout,
+            max_connection_age,
+            prefetch: raw_sink.prefetch.unwrap_or(false),
+            circuit_breaker_threshold,
+            circuit_breaker_probe,
+            ramp_up,
+            retryable_statuses: raw_sink.retryable_statuses,
+            droppable_statuses,
+            skip_partial_lines: raw_sink.skip_partial_lines.unwrap_or(false),
+            skip_empty_batches: raw_sink.skip_empty_batches.unwrap_or(false),
+            tenant_label: raw_sink.tenant_label,
+            tenant_fallback: raw_sink.tenant_fallback,
+            token_by_label,
+            url_template,
+            format,
+            shard_range,
+            bind_address: Conf::parse_bind_address(raw_sink.bind_address)?,
+            timeout: Conf::parse_timeout("timeout", raw_sink.timeout)?,
+            backoff: match raw_sink.backoff {
+                None => None,
+                Some(ref backoff) => Some(SinkBackoff::try_from(backoff)?),
+            },
+            transport,
+            overflow_policy,
+            archive_dir,
+            archive_size,
+            max_files: raw_sink.max_files,
         })
     }
 }
 
-/// `Backoff` config.
-#[derive(Clone, Debug)]
-pub struct Backoff {
-    pub initial: Duration,
+/// `BackoffStrategy` applied on the exponential delay between two failed requests.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BackoffStrategy {
+    /// Randomize the delay using the randomization factor
+    Exponential,
+    /// Pick the delay between zero and the exponential delay
+    FullJitter,
+    /// Pick the delay between the half of the exponential delay and the exponential delay
+    EqualJitter,
+}
+
+impl TryFrom<&str> for BackoffStrategy {
+    type Error = Error;
+
+    fn try_from(v: &str) -> Result<Self, Self::Error> {
+        match v {
+            "exponential" => Ok(BackoffStrategy::Exponential),
+            "full-jitter" => Ok(BackoffStrategy::FullJitter),
+            "equal-jitter" => Ok(BackoffStrategy::EqualJitter),
+            _ => Err(format_err!(
+                "the backoff strategy should be one of 'exponential', 'full-jitter' or 'equal-jitter'"
+            )),
+        }
+    }
+}
+
+/// `Backoff` config.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    pub initial: Duration,
     pub max: Duration,
     pub multiplier: f64,
     pub randomization: f64,
+    pub strategy: BackoffStrategy,
 }
 
 impl TryFrom<&RawBackoff> for Backoff {
@@ -420,10 +1447,625 @@ impl TryFrom<&RawBackoff> for Backoff {
                 .with_context(|err| format!("could not parse 'backoff.max' setting, {}", err))?,
             multiplier: raw_backoff.multiplier,
             randomization: raw_backoff.randomization,
+            strategy: BackoffStrategy::try_from(raw_backoff.strategy.as_str()).with_context(
+                |err| format!("could not parse 'backoff.strategy' setting, {}", err),
+            )?,
+        })
+    }
+}
+
+/// `SinkBackoff` config, unset values fall back on the backoff parameters.
+#[derive(Clone, Debug, Default)]
+pub struct SinkBackoff {
+    pub initial: Option<Duration>,
+    pub max: Option<Duration>,
+    pub multiplier: Option<f64>,
+    pub randomization: Option<f64>,
+    pub strategy: Option<BackoffStrategy>,
+}
+
+impl SinkBackoff {
+    /// Backoff using the values of the given one when unset
+    pub fn over(&self, backoff: &Backoff) -> Backoff {
+        Backoff {
+            initial: self.initial.unwrap_or(backoff.initial),
+            max: self.max.unwrap_or(backoff.max),
+            multiplier: self.multiplier.unwrap_or(backoff.multiplier),
+            randomization: self.randomization.unwrap_or(backoff.randomization),
+            strategy: self
+                .strategy
+                .to_owned()
+                .unwrap_or_else(|| backoff.strategy.to_owned()),
+        }
+    }
+}
+
+impl TryFrom<&RawSinkBackoff> for SinkBackoff {
+    type Error = Error;
+
+    fn try_from(raw_backoff: &RawSinkBackoff) -> Result<Self, Self::Error> {
+        Ok(Self {
+            initial: match raw_backoff.initial {
+                None => None,
+                Some(ref initial) => Some(parse(initial.as_str()).with_context(|err| {
+                    format!("could not parse 'backoff.initial' setting, {}", err)
+                })?),
+            },
+            max: match raw_backoff.max {
+                None => None,
+                Some(ref max) => Some(parse(max.as_str()).with_context(|err| {
+                    format!("could not parse 'backoff.max' setting, {}", err)
+                })?),
+            },
+            multiplier: raw_backoff.multiplier,
+            randomization: raw_backoff.randomization,
+            strategy: match raw_backoff.strategy {
+                None => None,
+                Some(ref strategy) => Some(
+                    BackoffStrategy::try_from(strategy.as_str()).with_context(|err| {
+                        format!("could not parse 'backoff.strategy' setting, {}", err)
+                    })?,
+                ),
+            },
+        })
+    }
+}
+
+/// `Heartbeat` config.
+#[derive(Clone, Debug)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub class: String,
+    pub labels: HashMap<String, String>,
+}
+
+impl TryFrom<&RawHeartbeat> for Heartbeat {
+    type Error = Error;
+
+    fn try_from(raw_heartbeat: &RawHeartbeat) -> Result<Self, Self::Error> {
+        let interval = parse(raw_heartbeat.interval.as_str())
+            .with_context(|err| format!("could not parse 'heartbeat.interval' setting, {}", err))?;
+
+        if interval < MIN_PERIOD {
+            return Err(format_err!(
+                "could not parse 'heartbeat.interval' setting, it should be at least {}ms, got {}ms",
+                MIN_PERIOD.as_millis(),
+                interval.as_millis()
+            ));
+        }
+
+        let mut labels = HashMap::new();
+        for (k, v) in raw_heartbeat.labels.to_owned().unwrap_or_default() {
+            if let Some(v) = Conf::replace_env(v) {
+                labels.insert(k, v);
+            }
+        }
+
+        Ok(Self {
+            interval,
+            class: match raw_heartbeat.class {
+                None => String::from("beamium_heartbeat"),
+                Some(ref class) => class.to_owned(),
+            },
+            labels,
+        })
+    }
+}
+
+/// `Checkpoint` config.
+#[derive(Clone, Debug)]
+pub struct Checkpoint {
+    /// Directory holding the state of the scrapers and the router
+    pub dir: PathBuf,
+    /// Interval between two saves of the state
+    pub interval: Duration,
+    /// Age after which the saved state of a time series is not loaded anymore
+    pub max_age: Duration,
+    /// Maximum number of time series saved by each scraper and by the router
+    pub max_series: usize,
+}
+
+impl TryFrom<&RawCheckpoint> for Checkpoint {
+    type Error = Error;
+
+    fn try_from(raw_checkpoint: &RawCheckpoint) -> Result<Self, Self::Error> {
+        if raw_checkpoint.dir.trim().is_empty() {
+            return Err(format_err!(
+                "could not parse 'checkpoint.dir' setting, it should not be empty"
+            ));
+        }
+
+        let interval = match raw_checkpoint.interval {
+            None => Duration::from_secs(60),
+            Some(ref interval) => parse(interval.as_str()).with_context(|err| {
+                format!("could not parse 'checkpoint.interval' setting, {}", err)
+            })?,
+        };
+
+        if interval < MIN_PERIOD {
+            return Err(format_err!(
+                "could not parse 'checkpoint.interval' setting, it should be at least {}ms, got {}ms",
+                MIN_PERIOD.as_millis(),
+                interval.as_millis()
+            ));
+        }
+
+        let max_age = match raw_checkpoint.max_age {
+            None => Duration::from_secs(3600),
+            Some(ref max_age) => parse(max_age.as_str()).with_context(|err| {
+                format!("could not parse 'checkpoint.max-age' setting, {}", err)
+            })?,
+        };
+
+        let max_series = raw_checkpoint.max_series.unwrap_or(100_000);
+        if max_series == 0 {
+            return Err(format_err!(
+                "could not parse 'checkpoint.max-series' setting, it should be greater than 0"
+            ));
+        }
+
+        Ok(Self {
+            dir: PathBuf::from(raw_checkpoint.dir.to_owned()),
+            interval,
+            max_age,
+            max_series,
+        })
+    }
+}
+
+/// `Geo` config.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Geo {
+    /// Label holding the latitude, in degrees
+    pub latitude: String,
+    /// Label holding the longitude, in degrees
+    pub longitude: String,
+    /// Label holding the elevation, in millimeters
+    pub elevation: Option<String>,
+}
+
+impl TryFrom<&RawGeo> for Geo {
+    type Error = Error;
+
+    fn try_from(raw_geo: &RawGeo) -> Result<Self, Self::Error> {
+        let labels = vec![
+            ("latitude", Some(&raw_geo.latitude)),
+            ("longitude", Some(&raw_geo.longitude)),
+            ("elevation", raw_geo.elevation.as_ref()),
+        ];
+
+        for (setting, label) in labels {
+            if let Some(label) = label {
+                if label.trim().is_empty() {
+                    return Err(format_err!(
+                        "could not parse 'geo.{}' setting, it should not be empty",
+                        setting
+                    ));
+                }
+            }
+        }
+
+        if raw_geo.latitude == raw_geo.longitude
+            || raw_geo.elevation.as_ref() == Some(&raw_geo.latitude)
+            || raw_geo.elevation.as_ref() == Some(&raw_geo.longitude)
+        {
+            return Err(format_err!(
+                "could not parse 'geo' setting, labels should be distinct"
+            ));
+        }
+
+        Ok(Self {
+            latitude: raw_geo.latitude.to_owned(),
+            longitude: raw_geo.longitude.to_owned(),
+            elevation: raw_geo.elevation.to_owned(),
         })
     }
 }
 
+/// `CardinalityGuard` config.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CardinalityGuard {
+    pub mode: GuardMode,
+    /// Factor of the baseline beyond which the number of time series of a scrape is a jump
+    pub factor: f64,
+    /// Number of previous scrapes averaged as the baseline
+    pub window: usize,
+}
+
+impl TryFrom<&RawCardinalityGuard> for CardinalityGuard {
+    type Error = Error;
+
+    fn try_from(raw_guard: &RawCardinalityGuard) -> Result<Self, Self::Error> {
+        let mode = match raw_guard.mode {
+            None => GuardMode::Warn,
+            Some(ref mode) => GuardMode::try_from(mode.as_str()).with_context(|err| {
+                format!("could not parse 'cardinality-guard.mode' setting, {}", err)
+            })?,
+        };
+
+        let factor = raw_guard.factor.unwrap_or(2.0);
+        if factor.is_nan() || factor <= 1.0 {
+            return Err(format_err!(
+                "could not parse 'cardinality-guard.factor' setting, it should be greater than 1"
+            ));
+        }
+
+        let window = raw_guard.window.unwrap_or(10);
+        if window == 0 {
+            return Err(format_err!(
+                "could not parse 'cardinality-guard.window' setting, it should be greater than 0"
+            ));
+        }
+
+        Ok(Self {
+            mode,
+            factor,
+            window,
+        })
+    }
+}
+
+/// `GuardMode` of a scrape whose number of time series jumped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GuardMode {
+    /// Log a warning and forward the scrape
+    Warn,
+    /// Log a warning and drop the scrape
+    Block,
+}
+
+impl TryFrom<&str> for GuardMode {
+    type Error = Error;
+
+    fn try_from(v: &str) -> Result<Self, Self::Error> {
+        match v {
+            "warn" => Ok(GuardMode::Warn),
+            "block" => Ok(GuardMode::Block),
+            _ => Err(format_err!(
+                "the cardinality guard mode should be one of 'warn' or 'block'"
+            )),
+        }
+    }
+}
+
+/// `TokenByLabel` config.
+#[derive(Clone, Debug)]
+pub struct TokenByLabel {
+    pub label: String,
+    /// Token of each value of the label
+    pub tokens: HashMap<String, String>,
+}
+
+impl TryFrom<&RawTokenByLabel> for TokenByLabel {
+    type Error = Error;
+
+    fn try_from(raw_token_by_label: &RawTokenByLabel) -> Result<Self, Self::Error> {
+        if raw_token_by_label.label.trim().is_empty() {
+            return Err(format_err!(
+                "could not parse 'token-by-label.label' setting, it should not be empty"
+            ));
+        }
+
+        if let Some((value, _)) = raw_token_by_label
+            .tokens
+            .iter()
+            .find(|(_, token)| token.trim().is_empty())
+        {
+            return Err(format_err!(
+                "could not parse 'token-by-label.tokens' setting, the token of '{}' should not be empty",
+                value
+            ));
+        }
+
+        Ok(Self {
+            label: raw_token_by_label.label.to_owned(),
+            tokens: raw_token_by_label.tokens.to_owned(),
+        })
+    }
+}
+
+impl Sink {
+    /// Parse an inclusive range of buckets, e.g. '0-7' or '3'
+    fn parse_shard_range(range: &str) -> Result<RangeInclusive<u64>, Error> {
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (start, end),
+            None => (range, range),
+        };
+
+        let start = start.trim().parse::<u64>()?;
+        let end = end.trim().parse::<u64>()?;
+        if start > end {
+            return Err(format_err!(
+                "the start of the range should not be greater than its end"
+            ));
+        }
+
+        Ok(start..=end)
+    }
+
+    /// Check that the statuses are failed http statuses
+    fn check_statuses(statuses: &[u16]) -> Result<(), Error> {
+        match statuses.iter().find(|status| !(300..600).contains(*status)) {
+            Some(status) => Err(format_err!(
+                "status {} is not a failed http status, it should be between 300 and 599",
+                status
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Check that a sink using the tcp transport streams Warp10 lines to a 'tcp://' or 'tls://'
+    /// url holding a port
+    fn check_relay(url: &str, format: &SinkFormat, is_grouped: bool) -> Result<(), Error> {
+        let url = url.parse::<Uri>()?;
+        if !matches!(url.scheme_str(), Some("tcp") | Some("tls")) || url.port_u16().is_none() {
+            return Err(format_err!(
+                "the url should be a 'tcp://host:port' or 'tls://host:port' one"
+            ));
+        }
+
+        if *format != SinkFormat::Warp10 {
+            return Err(format_err!("the sink's format should be 'warp10'"));
+        }
+
+        if is_grouped {
+            return Err(format_err!(
+                "it could not be used with the 'tenant-label' and 'token-by-label' settings"
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// `ShardBy` key hashed to route the time series to sharded sinks.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ShardBy {
+    /// Class and labels of the time series
+    Series,
+}
+
+impl TryFrom<&str> for ShardBy {
+    type Error = Error;
+
+    fn try_from(v: &str) -> Result<Self, Self::Error> {
+        match v {
+            "series" => Ok(ShardBy::Series),
+            _ => Err(format_err!("the shard key should be 'series'")),
+        }
+    }
+}
+
+/// `Sink` format of the posted time series.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SinkFormat {
+    /// Warp10 lines, as spooled
+    Warp10,
+    /// Json body of the OpenTSDB `/api/put` endpoint
+    OpenTsdb,
+}
+
+impl TryFrom<&str> for SinkFormat {
+    type Error = Error;
+
+    fn try_from(v: &str) -> Result<Self, Self::Error> {
+        match v {
+            "warp10" => Ok(SinkFormat::Warp10),
+            "opentsdb" => Ok(SinkFormat::OpenTsdb),
+            _ => Err(format_err!(
+                "the sink's format field should be one of 'warp10' or 'opentsdb'"
+            )),
+        }
+    }
+}
+
+/// `Transport` of the time series pushed by a sink.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transport {
+    /// Http requests posting each batch
+    Http,
+    /// Warp10 lines streamed over a persistent connection, secured using TLS for 'tls://' urls
+    Tcp,
+}
+
+impl TryFrom<&str> for Transport {
+    type Error = Error;
+
+    fn try_from(v: &str) -> Result<Self, Self::Error> {
+        match v {
+            "http" => Ok(Transport::Http),
+            "tcp" => Ok(Transport::Tcp),
+            _ => Err(format_err!(
+                "the sink's transport field should be one of 'http' or 'tcp'"
+            )),
+        }
+    }
+}
+
+/// `OverflowPolicy` applied to the oldest files of a sink which is too large.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OverflowPolicy {
+    /// Remove the files
+    Drop,
+    /// Compress the files into the archive directory, so they could be replayed later
+    Archive,
+}
+
+impl TryFrom<&str> for OverflowPolicy {
+    type Error = Error;
+
+    fn try_from(v: &str) -> Result<Self, Self::Error> {
+        match v {
+            "drop" => Ok(OverflowPolicy::Drop),
+            "archive" => Ok(OverflowPolicy::Archive),
+            _ => Err(format_err!(
+                "the sink's overflow-policy field should be one of 'drop' or 'archive'"
+            )),
+        }
+    }
+}
+
+/// `DuplicatePolicy` applied when a scraper or a sink is defined in several files.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DuplicatePolicy {
+    /// Log a warning, the files are merged and the settings of the last one win
+    Warn,
+    /// Reject the configuration
+    Reject,
+}
+
+impl TryFrom<&str> for DuplicatePolicy {
+    type Error = Error;
+
+    fn try_from(v: &str) -> Result<Self, Self::Error> {
+        match v {
+            "warn" => Ok(DuplicatePolicy::Warn),
+            "error" => Ok(DuplicatePolicy::Reject),
+            _ => Err(format_err!(
+                "the duplicate names policy should be one of 'warn' or 'error'"
+            )),
+        }
+    }
+}
+
+/// `FanoutPolicy` applied when a source file fans into more sinks than allowed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FanoutPolicy {
+    /// Write the file into the first sinks and skip the extra ones
+    Skip,
+    /// Discard the file without writing it into any sink
+    Abort,
+}
+
+impl TryFrom<&str> for FanoutPolicy {
+    type Error = Error;
+
+    fn try_from(v: &str) -> Result<Self, Self::Error> {
+        match v {
+            "skip" => Ok(FanoutPolicy::Skip),
+            "abort" => Ok(FanoutPolicy::Abort),
+            _ => Err(format_err!(
+                "the sink fanout policy should be one of 'skip' or 'abort'"
+            )),
+        }
+    }
+}
+
+/// `Symlinks` handling of the symbolic links found in the source and sink directories.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Symlinks {
+    /// Process the file targeted by the link
+    Follow,
+    /// Skip the link
+    Ignore,
+}
+
+impl TryFrom<&str> for Symlinks {
+    type Error = Error;
+
+    fn try_from(v: &str) -> Result<Self, Self::Error> {
+        match v {
+            "follow" => Ok(Symlinks::Follow),
+            "ignore" => Ok(Symlinks::Ignore),
+            _ => Err(format_err!(
+                "the spool symlinks handling should be one of 'follow' or 'ignore'"
+            )),
+        }
+    }
+}
+
+/// `Durability` of the files written in the source and sink directories.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Durability {
+    /// Leave the data in the buffers of the process
+    None,
+    /// Flush the data to the operating system
+    Flush,
+    /// Flush the data and sync it on disk, so the file survives a power loss once renamed
+    Fsync,
+}
+
+impl TryFrom<&str> for Durability {
+    type Error = Error;
+
+    fn try_from(v: &str) -> Result<Self, Self::Error> {
+        match v {
+            "none" => Ok(Durability::None),
+            "flush" => Ok(Durability::Flush),
+            "fsync" => Ok(Durability::Fsync),
+            _ => Err(format_err!(
+                "the durability should be one of 'none', 'flush' or 'fsync'"
+            )),
+        }
+    }
+}
+
+/// `TlsVersion` lowest TLS protocol version negotiated by the scrapers and sinks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TlsVersion {
+    Tls12,
+    Tls13,
+}
+
+impl TryFrom<&str> for TlsVersion {
+    type Error = Error;
+
+    fn try_from(v: &str) -> Result<Self, Self::Error> {
+        match v {
+            "1.2" => Ok(TlsVersion::Tls12),
+            "1.3" => Ok(TlsVersion::Tls13),
+            _ => Err(format_err!(
+                "the minimum TLS version should be one of '1.2' or '1.3', got '{}'",
+                v
+            )),
+        }
+    }
+}
+
+/// `TimeUnit` time resolution of the Warp10 platform.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeUnit {
+    Millis,
+    Micros,
+    Nanos,
+}
+
+impl TryFrom<&str> for TimeUnit {
+    type Error = Error;
+
+    fn try_from(v: &str) -> Result<Self, Self::Error> {
+        match v {
+            "ms" => Ok(TimeUnit::Millis),
+            "us" => Ok(TimeUnit::Micros),
+            "ns" => Ok(TimeUnit::Nanos),
+            _ => Err(format_err!(
+                "the time unit should be one of 'ms', 'us' or 'ns', got '{}'",
+                v
+            )),
+        }
+    }
+}
+
+impl TimeUnit {
+    /// Number of units in a millisecond
+    pub fn per_milli(self) -> i64 {
+        match self {
+            TimeUnit::Millis => 1,
+            TimeUnit::Micros => 1_000,
+            TimeUnit::Nanos => 1_000_000,
+        }
+    }
+
+    /// Convert the time in microseconds into the unit
+    pub fn scale(self, micros: i64) -> i64 {
+        match self {
+            TimeUnit::Millis => micros / 1_000,
+            TimeUnit::Micros => micros,
+            TimeUnit::Nanos => micros * 1_000,
+        }
+    }
+}
+
 /// `RawParameters` config.
 #[derive(Clone, Debug)]
 pub struct Parameters {
@@ -435,36 +2077,259 @@ pub struct Parameters {
     pub log_file: String,
     pub log_level: usize,
     pub syslog: bool,
-    pub timeout: Duration,
+    /// Timeout of the scrapes and keep-alive timeout of their connections
+    pub scrape_timeout: Duration,
+    /// Timeout of the requests of the sinks
+    pub push_timeout: Duration,
     pub router_parallel: usize,
+    pub router_max_inflight: usize,
     pub backoff: Backoff,
     pub metrics: Option<SocketAddr>,
+    /// Fail the startup if the metrics server could not bind its address
+    pub metrics_required: bool,
+    /// Expose the last error of each scraper and sink on the '/status' endpoint of the metrics server
+    pub status_endpoint: bool,
+    /// Label the time series of each scraper with the configuration file defining it
+    pub emit_config_origin_label: bool,
+    /// Number of retries of the creation of the directories on startup, e.g. while a volume is mounted
+    pub directory_retries: usize,
+    pub directory_retry_delay: Duration,
+    /// Label holding an identifier generated on startup added to the time series, `None` if disabled
+    pub run_id_label: Option<String>,
     pub filesystem_threads: usize,
+    /// Maximum number of sinks a source file is written into, `None` if unbounded
+    pub max_sink_fanout: Option<usize>,
+    pub sink_fanout_policy: FanoutPolicy,
+    /// Cache TLS sessions shared by scrapers and sinks to resume handshakes
+    pub tls_session_resumption: bool,
+    /// Write the files of each sink in a subdirectory of the sink directory named after the sink
+    pub nested_sink_dir: bool,
+    /// Maximum number of warning and error lines per second for each message, `None` if unbounded
+    pub log_rate_limit: Option<u32>,
+    /// Maximum number of dns lookups running at the same time across scrapers and sinks
+    pub max_dns_lookups: usize,
+    /// Handling of the symbolic links found in the source and sink directories
+    pub spool_symlinks: Symlinks,
+    /// Maximum number of scrapes per second across scrapers, `None` if unbounded
+    pub max_scrapes_per_second: Option<u32>,
+    /// Maximum number of scrapes of the same host running at the same time, `None` if unbounded
+    pub max_scrapes_per_host: Option<usize>,
+    /// Key hashed to route the time series to the sinks declaring a shard range, `None` if disabled
+    pub shard_by: Option<ShardBy>,
+    /// Number of buckets of the hashed time series
+    pub shard_count: u64,
+    /// Series written at a fixed interval regardless of the scrapes, `None` if disabled
+    pub heartbeat: Option<Heartbeat>,
+    /// Sort the samples of each time series of a source file by timestamp before routing them
+    pub sort_timestamps: bool,
+    /// Maximum number of continuation lines following a time series, `None` if unbounded
+    pub max_continuations: Option<usize>,
+    /// Local address of the connections of the scrapers and sinks, `None` to let the system pick it
+    pub bind_address: Option<IpAddr>,
+    /// Number of scrapers started at once on startup, `None` to start them all at once
+    pub scraper_start_wave: Option<usize>,
+    /// Delay between two waves of started scrapers
+    pub scraper_start_delay: Duration,
+    /// Forward the samples of a time series only when its value changes
+    pub router_delta: bool,
+    /// Delay after which an unchanged time series is forwarded again
+    pub router_delta_heartbeat: Duration,
+    /// Policy applied when a scraper or a sink is defined in several files
+    pub duplicate_names: DuplicatePolicy,
+    /// Save the state of the counters and of the router deltas on disk, `None` if disabled
+    pub checkpoint: Option<Checkpoint>,
+    /// Interval between two status lines summarizing the activity in the log, `None` if disabled
+    pub status_interval: Option<Duration>,
+    /// Lowest TLS protocol version negotiated by the scrapers and sinks
+    pub min_tls_version: TlsVersion,
+    /// Delay during which the writers of the spool directories are paused once the disk is full
+    pub disk_full_backoff: Duration,
+    /// Whether the spooled files are flushed or synced on disk before being renamed
+    pub durability: Durability,
+    /// Duration after which spooled files are drained and the process halts, `None` if unbounded
+    pub max_lifetime: Option<Duration>,
+    /// Time resolution of the Warp10 platform, used for the timestamps of the written time series
+    pub platform_time_unit: TimeUnit,
+    /// Log the successful scrapes of a scraper at info level once every this number of scrapes,
+    /// the other ones at debug level, `None` to log each of them at info level
+    pub fetch_success_log_every: Option<u64>,
+}
+
+impl Parameters {
+    /// Directory holding the files of the given sink
+    pub fn sink_dir_of(&self, sink: &str) -> PathBuf {
+        let dir = PathBuf::from(self.sink_dir.to_owned());
+        if self.nested_sink_dir {
+            return dir.join(sink);
+        }
+
+        dir
+    }
 }
 
 impl TryFrom<RawParameters> for Parameters {
     type Error = Error;
 
-    fn try_from(raw_parameters: RawParameters) -> Result<Self, Self::Error> {
-        let scan_period = match raw_parameters.scan_period.parse::<u64>() {
-            Ok(scan_period) => Duration::from_millis(scan_period),
-            Err(_) => parse(raw_parameters.scan_period.as_str())
-                .with_context(|err| format!("could not parse 'scan-period' setting, {}", err))?,
+    fn try_from(raw_parameters: RawParameters) -> Result<Self, Self::Error> {
+        let scan_period = match raw_parameters.scan_period.parse::<u64>() {
+            Ok(scan_period) => Duration::from_millis(scan_period),
+            Err(_) => parse(raw_parameters.scan_period.as_str())
+                .with_context(|err| format!("could not parse 'scan-period' setting, {}", err))?,
+        };
+
+        if scan_period < MIN_PERIOD {
+            return Err(format_err!(
+                "could not parse 'scan-period' setting, it should be at least {}ms, got {}ms",
+                MIN_PERIOD.as_millis(),
+                scan_period.as_millis()
+            ));
+        }
+
+        if raw_parameters.timeout.is_some() {
+            warn!("setting 'timeout' is deprecated and will be removed in further revision. Please use 'scrape-timeout' and 'push-timeout' instead.");
+        }
+
+        let timeout = Conf::parse_timeout("timeout", raw_parameters.timeout)?
+            .unwrap_or_else(|| Duration::from_secs(500));
+        let scrape_timeout = Conf::parse_timeout("scrape-timeout", raw_parameters.scrape_timeout)?
+            .unwrap_or(timeout);
+        let push_timeout =
+            Conf::parse_timeout("push-timeout", raw_parameters.push_timeout)?.unwrap_or(timeout);
+
+        let batch_size = match raw_parameters.batch_size.parse::<u64>() {
+            Ok(batch_size) => batch_size,
+            Err(_) => raw_parameters
+                .batch_size
+                .parse::<Bytes>()
+                .with_context(|err| format!("could not parse 'batch-size' setting, {}", err))?
+                .size() as u64,
+        };
+
+        if batch_size < MIN_BATCH_SIZE {
+            return Err(format_err!(
+                "could not parse 'batch-size' setting, it should be at least {} bytes, got {} bytes",
+                MIN_BATCH_SIZE,
+                batch_size
+            ));
+        }
+
+        if raw_parameters.batch_count == 0 {
+            return Err(format_err!(
+                "could not parse 'batch-count' setting, it should be greater than 0 to send batches of at most '{}' bytes",
+                batch_size
+            ));
+        }
+
+        if raw_parameters.filesystem_threads == 0 {
+            return Err(format_err!(
+                "could not parse 'filesystem-threads' setting, it should be greater than 0"
+            ));
+        }
+
+        if raw_parameters.router_max_inflight == 0 {
+            return Err(format_err!(
+                "could not parse 'router-max-inflight' setting, it should be greater than 0"
+            ));
+        }
+
+        if raw_parameters.max_sink_fanout == Some(0) {
+            return Err(format_err!(
+                "could not parse 'max-sink-fanout' setting, it should be greater than 0"
+            ));
+        }
+
+        if raw_parameters.log_rate_limit == Some(0) {
+            return Err(format_err!(
+                "could not parse 'log-rate-limit' setting, it should be greater than 0"
+            ));
+        }
+
+        if raw_parameters.fetch_success_log_every == Some(0) {
+            return Err(format_err!(
+                "could not parse 'fetch-success-log-every' setting, it should be greater than 0"
+            ));
+        }
+
+        if raw_parameters.shard_count == 0 {
+            return Err(format_err!(
+                "could not parse 'shard-count' setting, it should be greater than 0"
+            ));
+        }
+
+        let shard_by = match raw_parameters.shard_by {
+            None => None,
+            Some(ref shard_by) => Some(
+                ShardBy::try_from(shard_by.as_str())
+                    .with_context(|err| format!("could not parse 'shard-by' setting, {}", err))?,
+            ),
+        };
+
+        if raw_parameters.max_scrapes_per_second == Some(0) {
+            return Err(format_err!(
+                "could not parse 'max-scrapes-per-second' setting, it should be greater than 0"
+            ));
+        }
+
+        if raw_parameters.max_scrapes_per_host == Some(0) {
+            return Err(format_err!(
+                "could not parse 'max-scrapes-per-host' setting, it should be greater than 0"
+            ));
+        }
+
+        if raw_parameters.max_dns_lookups == 0 {
+            return Err(format_err!(
+                "could not parse 'max-dns-lookups' setting, it should be greater than 0"
+            ));
+        }
+
+        let directory_retry_delay = parse(raw_parameters.directory_retry_delay.as_str())
+            .with_context(|err| {
+                format!("could not parse 'directory-retry-delay' setting, {}", err)
+            })?;
+
+        if raw_parameters.scraper_start_wave == Some(0) {
+            return Err(format_err!(
+                "could not parse 'scraper-start-wave' setting, it should be greater than 0"
+            ));
+        }
+
+        let scraper_start_delay =
+            parse(raw_parameters.scraper_start_delay.as_str()).with_context(|err| {
+                format!("could not parse 'scraper-start-delay' setting, {}", err)
+            })?;
+
+        let router_delta_heartbeat = parse(raw_parameters.router_delta_heartbeat.as_str())
+            .with_context(|err| {
+                format!("could not parse 'router-delta-heartbeat' setting, {}", err)
+            })?;
+
+        let status_interval = match raw_parameters.status_interval {
+            None => None,
+            Some(ref interval) => {
+                let interval = parse(interval.as_str()).with_context(|err| {
+                    format!("could not parse 'status-interval' setting, {}", err)
+                })?;
+
+                if interval < MIN_PERIOD {
+                    return Err(format_err!(
+                        "could not parse 'status-interval' setting, it should be at least {}ms, got {}ms",
+                        MIN_PERIOD.as_millis(),
+                        interval.as_millis()
+                    ));
+                }
+
+                Some(interval)
+            }
         };
 
-        let timeout = match raw_parameters.timeout.parse::<u64>() {
-            Ok(timeout) => Duration::from_secs(timeout),
-            Err(_) => parse(raw_parameters.timeout.as_str())
-                .with_context(|err| format!("could not parse 'timeout' setting, {}", err))?,
-        };
+        let disk_full_backoff = parse(raw_parameters.disk_full_backoff.as_str())
+            .with_context(|err| format!("could not parse 'disk-full-backoff' setting, {}", err))?;
 
-        let batch_size = match raw_parameters.batch_size.parse::<u64>() {
-            Ok(batch_size) => batch_size,
-            Err(_) => raw_parameters
-                .batch_size
-                .parse::<Bytes>()
-                .with_context(|err| format!("could not parse 'size' setting, {}", err))?
-                .size() as u64,
+        let max_lifetime = match raw_parameters.max_lifetime {
+            None => None,
+            Some(ref lifetime) => Some(parse(lifetime.as_str()).with_context(|err| {
+                format!("could not parse 'max-lifetime' setting, {}", err)
+            })?),
         };
 
         let metrics = match raw_parameters.metrics {
@@ -485,11 +2350,67 @@ impl TryFrom<RawParameters> for Parameters {
             log_file: raw_parameters.log_file,
             log_level: raw_parameters.log_level,
             syslog: raw_parameters.syslog,
-            timeout,
+            scrape_timeout,
+            push_timeout,
             router_parallel: raw_parameters.router_parallel,
+            router_max_inflight: raw_parameters.router_max_inflight,
             backoff: Backoff::try_from(&raw_parameters.backoff)?,
             metrics,
+            metrics_required: raw_parameters.metrics_required,
+            status_endpoint: raw_parameters.status_endpoint,
+            emit_config_origin_label: raw_parameters.emit_config_origin_label,
+            directory_retries: raw_parameters.directory_retries,
+            directory_retry_delay,
+            run_id_label: raw_parameters.run_id_label,
             filesystem_threads: raw_parameters.filesystem_threads,
+            max_sink_fanout: raw_parameters.max_sink_fanout,
+            sink_fanout_policy: FanoutPolicy::try_from(raw_parameters.sink_fanout_policy.as_str())
+                .with_context(|err| {
+                    format!("could not parse 'sink-fanout-policy' setting, {}", err)
+                })?,
+            tls_session_resumption: raw_parameters.tls_session_resumption,
+            nested_sink_dir: raw_parameters.nested_sink_dir,
+            log_rate_limit: raw_parameters.log_rate_limit,
+            max_dns_lookups: raw_parameters.max_dns_lookups,
+            spool_symlinks: Symlinks::try_from(raw_parameters.spool_symlinks.as_str())
+                .with_context(|err| format!("could not parse 'spool-symlinks' setting, {}", err))?,
+            max_scrapes_per_second: raw_parameters.max_scrapes_per_second,
+            max_scrapes_per_host: raw_parameters.max_scrapes_per_host,
+            shard_by,
+            shard_count: raw_parameters.shard_count,
+            heartbeat: match raw_parameters.heartbeat {
+                None => None,
+                Some(ref heartbeat) => Some(Heartbeat::try_from(heartbeat)?),
+            },
+            sort_timestamps: raw_parameters.sort_timestamps,
+            max_continuations: raw_parameters.max_continuations,
+            bind_address: Conf::parse_bind_address(raw_parameters.bind_address)?,
+            scraper_start_wave: raw_parameters.scraper_start_wave,
+            scraper_start_delay,
+            router_delta: raw_parameters.router_delta,
+            router_delta_heartbeat,
+            duplicate_names: DuplicatePolicy::try_from(raw_parameters.duplicate_names.as_str())
+                .with_context(|err| {
+                    format!("could not parse 'duplicate-names' setting, {}", err)
+                })?,
+            status_interval,
+            checkpoint: match raw_parameters.checkpoint {
+                None => None,
+                Some(ref checkpoint) => Some(Checkpoint::try_from(checkpoint)?),
+            },
+            min_tls_version: TlsVersion::try_from(raw_parameters.min_tls_version.as_str())
+                .with_context(|err| {
+                    format!("could not parse 'min-tls-version' setting, {}", err)
+                })?,
+            disk_full_backoff,
+            durability: Durability::try_from(raw_parameters.durability.as_str())
+                .with_context(|err| format!("could not parse 'durability' setting, {}", err))?,
+            max_lifetime,
+            platform_time_unit: TimeUnit::try_from(raw_parameters.platform_time_unit.as_str())
+                .with_context(|err| {
+                    format!("could not parse 'platform-time-unit' setting, {}", err)
+                })?,
+            fetch_success_log_every: raw_parameters.fetch_success_log_every,
         })
     }
 }
@@ -556,12 +2477,85 @@ impl TryFrom<RawConf> for Conf {
             labels.insert(k, v);
         }
 
+        let parameters = Parameters::try_from(raw_config.parameters)
+            .with_context(|err| format!("'parameters' is malformed, {}", err))?;
+
+        for duplicate in &raw_config.duplicates {
+            match parameters.duplicate_names {
+                DuplicatePolicy::Warn => {
+                    warn!("{}, the settings of the last file win", duplicate);
+                }
+                DuplicatePolicy::Reject => return Err(format_err!("{}", duplicate)),
+            }
+        }
+
+        for conflict in Conf::filtered_label_conflicts(&scrapers, &sinks) {
+            warn!("{}", conflict);
+        }
+
+        if parameters.emit_config_origin_label {
+            for scraper in &mut scrapers {
+                if let Some(origin) = raw_config.origins.get(&scraper.name) {
+                    scraper
+                        .labels
+                        .insert(String::from(CONFIG_ORIGIN_LABEL), origin.to_owned());
+                }
+            }
+        }
+
+        for sink in &sinks {
+            let range = match &sink.shard_range {
+                None => continue,
+                Some(range) => range,
+            };
+
+            if parameters.shard_by.is_none() {
+                return Err(format_err!(
+                    "sink '{}' is malformed, could not parse 'shard-range' setting, it requires the 'shard-by' parameter",
+                    sink.name
+                ));
+            }
+
+            if *range.end() >= parameters.shard_count {
+                return Err(format_err!(
+                    "sink '{}' is malformed, could not parse 'shard-range' setting, buckets should be lower than {}",
+                    sink.name,
+                    parameters.shard_count
+                ));
+            }
+        }
+
+        // The lines of a bucket out of the range of every sink would be dropped by the router
+        let mut ranges: Vec<&RangeInclusive<u64>> = sinks
+            .iter()
+            .filter_map(|sink| sink.shard_range.as_ref())
+            .collect();
+
+        if !ranges.is_empty() {
+            ranges.sort_by_key(|range| *range.start());
+
+            let mut uncovered = 0;
+            for range in ranges {
+                if *range.start() > uncovered {
+                    break;
+                }
+
+                uncovered = max(uncovered, range.end() + 1);
+            }
+
+            if uncovered < parameters.shard_count {
+                return Err(format_err!(
+                    "could not parse 'shard-range' settings, bucket {} is not in the range of any sink",
+                    uncovered
+                ));
+            }
+        }
+
         Ok(Self {
             scrapers,
             sinks,
             labels,
-            parameters: Parameters::try_from(raw_config.parameters)
-                .with_context(|err| format!("'parameters' is malformed, {}", err))?,
+            parameters,
         })
     }
 }
@@ -601,6 +2595,60 @@ impl Conf {
         }
     }
 
+    /// Describe the labels filtered by a scraper while they are required elsewhere, removing them
+    /// could merge distinct time series
+    fn filtered_label_conflicts(scrapers: &[Scraper], sinks: &[Sink]) -> Vec<String> {
+        let mut conflicts = vec![];
+        for scraper in scrapers {
+            for label in &scraper.filtered_labels {
+                if scraper.labels.contains_key(label) {
+                    conflicts.push(format!(
+                        "scraper '{}' filters the label '{}' which it adds",
+                        scraper.name, label
+                    ));
+                }
+
+                for sink in sinks {
+                    let required = sink.tenant_label.as_ref() == Some(label)
+                        || sink.token_by_label.as_ref().map(|by| &by.label) == Some(label);
+
+                    if required {
+                        conflicts.push(format!(
+                            "scraper '{}' filters the label '{}' which is required by sink '{}'",
+                            scraper.name, label, sink.name
+                        ));
+                    }
+                }
+            }
+        }
+
+        conflicts
+    }
+
+    /// Parse a timeout given in seconds or as a humanized duration
+    fn parse_timeout(setting: &str, timeout: Option<String>) -> Result<Option<Duration>, Error> {
+        let timeout = match timeout {
+            None => return Ok(None),
+            Some(timeout) => timeout,
+        };
+
+        match timeout.parse::<u64>() {
+            Ok(timeout) => Ok(Some(Duration::from_secs(timeout))),
+            Err(_) => Ok(Some(parse(timeout.as_str()).with_context(|err| {
+                format!("could not parse '{}' setting, {}", setting, err)
+            })?)),
+        }
+    }
+
+    fn parse_bind_address(address: Option<String>) -> Result<Option<IpAddr>, Error> {
+        match address {
+            None => Ok(None),
+            Some(address) => Ok(Some(address.parse::<IpAddr>().with_context(|err| {
+                format!("could not parse 'bind-address' setting, {}", err)
+            })?)),
+        }
+    }
+
     fn env_labels(prefix: String) -> HashMap<String, String> {
         env::vars()
             .filter(|(k, _)| k.starts_with(&prefix))
@@ -689,3 +2737,829 @@ impl Conf {
         Ok((tx, rx, w))
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::convert::TryFrom;
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use config::File;
+    use failure::Error;
+    use uuid::Uuid;
+
+    use super::{
+        BackoffStrategy, CardinalityGuard, Conf, Durability, FanoutPolicy, Geo, GuardMode,
+        OverflowPolicy, Parameters, RawCardinalityGuard, RawConf, RawGeo, RawScraper, Scraper,
+        ShardBy, SinkFormat, Symlinks, TimeUnit, TlsVersion, Transport,
+    };
+
+    pub(crate) fn parameters(settings: &[(&str, &str)]) -> Result<Parameters, Error> {
+        let mut config = RawConf::initialize()?;
+        for (key, value) in settings {
+            config.set(&format!("parameters.{}", key), *value)?;
+        }
+
+        Parameters::try_from(config.try_into::<RawConf>()?.parameters)
+    }
+
+    #[test]
+    fn default_parameters() {
+        let params = parameters(&[]).expect("default parameters are valid");
+        assert_eq!(200_000, params.batch_size);
+        assert_eq!(250, params.batch_count);
+    }
+
+    #[test]
+    fn humanized_batch_size() {
+        let params = parameters(&[("batch-size", "2Kb")]).expect("valid parameters");
+        assert_eq!(2_000, params.batch_size);
+    }
+
+    #[test]
+    fn reject_too_small_batch_size() {
+        let err = parameters(&[("batch-size", "10")]).expect_err("batch-size is too small");
+        assert_eq!(
+            "could not parse 'batch-size' setting, it should be at least 1024 bytes, got 10 bytes",
+            err.to_string()
+        );
+
+        let err = parameters(&[("batch-size", "foo")]).expect_err("batch-size is not a size");
+        assert!(err
+            .to_string()
+            .starts_with("could not parse 'batch-size' setting"));
+    }
+
+    #[test]
+    fn reject_empty_batch_count() {
+        let err = parameters(&[("batch-count", "0")]).expect_err("batch-count is zero");
+        assert!(err
+            .to_string()
+            .starts_with("could not parse 'batch-count' setting"));
+    }
+
+    #[test]
+    fn reject_too_small_periods() {
+        let err = parameters(&[("scan-period", "0")]).expect_err("scan-period is zero");
+        assert_eq!(
+            "could not parse 'scan-period' setting, it should be at least 100ms, got 0ms",
+            err.to_string()
+        );
+
+        let raw = RawScraper {
+            url: String::from("http://127.0.0.1/metrics"),
+            period: String::from("0"),
+            ..Default::default()
+        };
+
+        let err = Scraper::try_from((String::from("test"), raw)).expect_err("period is zero");
+        assert_eq!(
+            "could not parse 'period' setting, it should be at least 100ms, got 0ms",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn reject_empty_token() {
+        let conf = |token: &str, format: &str| -> Result<Conf, Error> {
+            let mut config = RawConf::initialize()?;
+            config.set("sinks.warp.url", "https://warp.example.com/api/v0/update")?;
+            config.set("sinks.warp.token", token)?;
+            config.set("sinks.warp.format", format)?;
+
+            Conf::try_from(config.try_into::<RawConf>()?)
+        };
+
+        conf("token", "warp10").expect("valid configuration");
+        for token in &["", "  "] {
+            let err = conf(token, "warp10").expect_err("token is empty");
+            assert_eq!(
+                "sink 'warp' is malformed, could not parse 'token' setting, it should not be empty",
+                err.to_string()
+            );
+        }
+
+        // OpenTSDB does not use the token
+        let conf = conf("", "opentsdb").expect("valid configuration");
+        assert_eq!(SinkFormat::OpenTsdb, conf.sinks[0].format);
+    }
+
+    #[test]
+    fn config_origin_label() {
+        let dir = temp_dir().join(format!("beamium-{}", Uuid::new_v4()));
+        create_dir_all(&dir).expect("create directory");
+
+        let node = dir.join("node.yaml");
+        let app = dir.join("app.yaml");
+        write(
+            &node,
+            "scrapers:\n  node:\n    url: http://127.0.0.1:9100/metrics\n    period: 10s\n",
+        )
+        .expect("write node configuration");
+        write(&app, "scrapers:\n  app:\n    url: http://127.0.0.1:8080/metrics\n    period: 10s\nparameters:\n  emit-config-origin-label: true\n")
+            .expect("write app configuration");
+
+        let mut config = RawConf::initialize().expect("initialize configuration");
+        let files = vec![&node, &app]
+            .into_iter()
+            .map(|path| (path.display().to_string(), File::from(path.to_owned())))
+            .collect();
+        let (origins, _) = RawConf::merge_files(&mut config, files).expect("merge files");
+
+        let mut raw_config = config
+            .try_into::<RawConf>()
+            .expect("valid raw configuration");
+        raw_config.origins = origins;
+
+        let conf = Conf::try_from(raw_config).expect("valid configuration");
+        assert_eq!(2, conf.scrapers.len());
+        for scraper in conf.scrapers {
+            let origin = if scraper.name == "node" { &node } else { &app };
+            assert_eq!(
+                Some(&origin.display().to_string()),
+                scraper.labels.get("config_origin")
+            );
+        }
+
+        remove_dir_all(&dir).expect("remove directory");
+    }
+
+    #[test]
+    fn failed_statuses() {
+        let conf = |retryable: &[i64], droppable: &[i64]| -> Result<Conf, Error> {
+            let mut config = RawConf::initialize()?;
+            config.set("sinks.warp.url", "https://warp.example.com/api/v0/update")?;
+            config.set("sinks.warp.token", "token")?;
+            config.set("sinks.warp.retryable-statuses", retryable.to_vec())?;
+            config.set("sinks.warp.droppable-statuses", droppable.to_vec())?;
+
+            Conf::try_from(config.try_into::<RawConf>()?)
+        };
+
+        let valid = conf(&[420, 503], &[400]).expect("valid configuration");
+        assert_eq!(Some(vec![420, 503]), valid.sinks[0].retryable_statuses);
+        assert_eq!(vec![400], valid.sinks[0].droppable_statuses);
+
+        let err = conf(&[200], &[]).expect_err("status is a success");
+        assert_eq!(
+            "sink 'warp' is malformed, could not parse 'retryable-statuses' setting, status 200 is not a failed http status, it should be between 300 and 599",
+            err.to_string()
+        );
+
+        let err = conf(&[420], &[420]).expect_err("status is in both lists");
+        assert_eq!(
+            "sink 'warp' is malformed, could not parse 'droppable-statuses' setting, status 420 is also retryable",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn shard_range() {
+        // The first range is the one of the sink 'warp', the next ones are the ranges of others
+        let conf = |ranges: &[&str], shard_by: bool| -> Result<Conf, Error> {
+            let mut config = RawConf::initialize()?;
+            for (idx, range) in ranges.iter().enumerate() {
+                let name = match idx {
+                    0 => String::from("warp"),
+                    _ => format!("other-{}", idx),
+                };
+
+                config.set(
+                    &format!("sinks.{}.url", name),
+                    "https://warp.example.com/api/v0/update",
+                )?;
+                config.set(&format!("sinks.{}.token", name), "token")?;
+                config.set(&format!("sinks.{}.shard-range", name), *range)?;
+            }
+
+            if shard_by {
+                config.set("parameters.shard-by", "series")?;
+            }
+
+            Conf::try_from(config.try_into::<RawConf>()?)
+        };
+
+        let range = |conf: &Conf| {
+            conf.sinks
+                .iter()
+                .find(|sink| sink.name == "warp")
+                .and_then(|sink| sink.shard_range.to_owned())
+        };
+
+        let valid = conf(&["0-7", "8-15"], true).expect("valid configuration");
+        assert_eq!(Some(0..=7), range(&valid));
+        assert_eq!(Some(ShardBy::Series), valid.parameters.shard_by);
+        assert_eq!(16, valid.parameters.shard_count);
+
+        let valid = conf(&["3", "0-2", "2-15"], true).expect("valid configuration");
+        assert_eq!(Some(3..=3), range(&valid));
+
+        let err = conf(&["0-7"], false).expect_err("shard-by is missing");
+        assert_eq!(
+            "sink 'warp' is malformed, could not parse 'shard-range' setting, it requires the 'shard-by' parameter",
+            err.to_string()
+        );
+
+        let err = conf(&["8-16"], true).expect_err("bucket is out of range");
+        assert_eq!(
+            "sink 'warp' is malformed, could not parse 'shard-range' setting, buckets should be lower than 16",
+            err.to_string()
+        );
+
+        // Every bucket should be routed to a sink
+        let err = conf(&["0-7", "9-15"], true).expect_err("bucket is not routed");
+        assert_eq!(
+            "could not parse 'shard-range' settings, bucket 8 is not in the range of any sink",
+            err.to_string()
+        );
+
+        let err = conf(&["0-7"], true).expect_err("buckets are not routed");
+        assert_eq!(
+            "could not parse 'shard-range' settings, bucket 8 is not in the range of any sink",
+            err.to_string()
+        );
+
+        for range in &["7-0", "a-b", ""] {
+            let err = conf(&[range, "0-15"], true).expect_err("invalid range");
+            assert!(err
+                .to_string()
+                .starts_with("sink 'warp' is malformed, could not parse 'shard-range' setting"));
+        }
+    }
+
+    #[test]
+    fn sink_fanout_policy() {
+        let params = parameters(&[]).expect("default parameters are valid");
+        assert_eq!(None, params.max_sink_fanout);
+        assert_eq!(FanoutPolicy::Skip, params.sink_fanout_policy);
+
+        let params = parameters(&[("max-sink-fanout", "2"), ("sink-fanout-policy", "abort")])
+            .expect("valid parameters");
+        assert_eq!(Some(2), params.max_sink_fanout);
+        assert_eq!(FanoutPolicy::Abort, params.sink_fanout_policy);
+
+        let err = parameters(&[("sink-fanout-policy", "foo")]).expect_err("unknown policy");
+        assert!(err
+            .to_string()
+            .starts_with("could not parse 'sink-fanout-policy' setting"));
+    }
+
+    #[test]
+    fn min_tls_version() {
+        let params = parameters(&[]).expect("default parameters are valid");
+        assert_eq!(TlsVersion::Tls12, params.min_tls_version);
+
+        let params = parameters(&[("min-tls-version", "1.3")]).expect("valid parameters");
+        assert_eq!(TlsVersion::Tls13, params.min_tls_version);
+
+        let err = parameters(&[("min-tls-version", "1.1")]).expect_err("unsupported version");
+        assert!(err
+            .to_string()
+            .starts_with("could not parse 'min-tls-version' setting"));
+    }
+
+    #[test]
+    fn platform_time_unit() {
+        let params = parameters(&[]).expect("default parameters are valid");
+        assert_eq!(TimeUnit::Micros, params.platform_time_unit);
+
+        let params = parameters(&[("platform-time-unit", "ms")]).expect("valid parameters");
+        assert_eq!(TimeUnit::Millis, params.platform_time_unit);
+        assert_eq!(1_500, params.platform_time_unit.scale(1_500_000));
+
+        let params = parameters(&[("platform-time-unit", "ns")]).expect("valid parameters");
+        assert_eq!(TimeUnit::Nanos, params.platform_time_unit);
+        assert_eq!(1_500_000_000, params.platform_time_unit.scale(1_500_000));
+
+        let err = parameters(&[("platform-time-unit", "s")]).expect_err("unsupported unit");
+        assert!(err
+            .to_string()
+            .starts_with("could not parse 'platform-time-unit' setting"));
+    }
+
+    #[test]
+    fn durability() {
+        let params = parameters(&[]).expect("default parameters are valid");
+        assert_eq!(Durability::Flush, params.durability);
+
+        let params = parameters(&[("durability", "fsync")]).expect("valid parameters");
+        assert_eq!(Durability::Fsync, params.durability);
+
+        let err = parameters(&[("durability", "foo")]).expect_err("unknown durability");
+        assert!(err
+            .to_string()
+            .starts_with("could not parse 'durability' setting"));
+    }
+
+    #[test]
+    fn spool_symlinks() {
+        let params = parameters(&[]).expect("default parameters are valid");
+        assert_eq!(Symlinks::Follow, params.spool_symlinks);
+
+        let params = parameters(&[("spool-symlinks", "ignore")]).expect("valid parameters");
+        assert_eq!(Symlinks::Ignore, params.spool_symlinks);
+
+        let err = parameters(&[("spool-symlinks", "foo")]).expect_err("unknown handling");
+        assert!(err
+            .to_string()
+            .starts_with("could not parse 'spool-symlinks' setting"));
+    }
+
+    #[test]
+    fn heartbeat() {
+        let params = parameters(&[]).expect("default parameters are valid");
+        assert!(params.heartbeat.is_none());
+
+        let params = parameters(&[("heartbeat.interval", "1m"), ("heartbeat.labels.dc", "rbx")])
+            .expect("valid parameters");
+        let heartbeat = params.heartbeat.expect("heartbeat is set");
+        assert_eq!(Duration::from_secs(60), heartbeat.interval);
+        assert_eq!("beamium_heartbeat", heartbeat.class);
+        assert_eq!(Some(&String::from("rbx")), heartbeat.labels.get("dc"));
+
+        let err = parameters(&[("heartbeat.interval", "10ms")]).expect_err("interval is too small");
+        assert_eq!(
+            "could not parse 'heartbeat.interval' setting, it should be at least 100ms, got 10ms",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn checkpoint() {
+        let params = parameters(&[]).expect("default parameters are valid");
+        assert!(params.checkpoint.is_none());
+
+        let params =
+            parameters(&[("checkpoint.dir", "/var/lib/beamium/state")]).expect("valid parameters");
+        let checkpoint = params.checkpoint.expect("checkpoint is set");
+        assert_eq!(PathBuf::from("/var/lib/beamium/state"), checkpoint.dir);
+        assert_eq!(Duration::from_secs(60), checkpoint.interval);
+        assert_eq!(Duration::from_secs(3600), checkpoint.max_age);
+        assert_eq!(100_000, checkpoint.max_series);
+
+        let err = parameters(&[
+            ("checkpoint.dir", "/var/lib/beamium/state"),
+            ("checkpoint.max-series", "0"),
+        ])
+        .expect_err("max-series is zero");
+        assert_eq!(
+            "could not parse 'checkpoint.max-series' setting, it should be greater than 0",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn max_scrapes_per_second() {
+        let params = parameters(&[]).expect("default parameters are valid");
+        assert_eq!(None, params.max_scrapes_per_second);
+
+        let params = parameters(&[("max-scrapes-per-second", "50")]).expect("valid parameters");
+        assert_eq!(Some(50), params.max_scrapes_per_second);
+
+        let err = parameters(&[("max-scrapes-per-second", "0")]).expect_err("rate is zero");
+        assert_eq!(
+            "could not parse 'max-scrapes-per-second' setting, it should be greater than 0",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn max_scrapes_per_host() {
+        let params = parameters(&[]).expect("default parameters are valid");
+        assert_eq!(None, params.max_scrapes_per_host);
+
+        let params = parameters(&[("max-scrapes-per-host", "2")]).expect("valid parameters");
+        assert_eq!(Some(2), params.max_scrapes_per_host);
+
+        let err = parameters(&[("max-scrapes-per-host", "0")]).expect_err("limit is zero");
+        assert_eq!(
+            "could not parse 'max-scrapes-per-host' setting, it should be greater than 0",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn max_dns_lookups() {
+        let params = parameters(&[]).expect("default parameters are valid");
+        assert_eq!(32, params.max_dns_lookups);
+
+        let err = parameters(&[("max-dns-lookups", "0")]).expect_err("max-dns-lookups is zero");
+        assert_eq!(
+            "could not parse 'max-dns-lookups' setting, it should be greater than 0",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn redact_sensitive_headers() {
+        let mut config = RawConf::initialize().expect("initialize configuration");
+        config
+            .set("scrapers.node.url", "http://127.0.0.1:9100/metrics")
+            .expect("set url");
+        config
+            .set("scrapers.node.period", "10s")
+            .expect("set period");
+        config
+            .set("scrapers.node.headers.authorization", "Basic c2VjcmV0")
+            .expect("set header");
+        config
+            .set("scrapers.node.headers.x-api-key", "secret")
+            .expect("set header");
+        config
+            .set("scrapers.node.headers.accept", "text/plain")
+            .expect("set header");
+
+        let raw_conf = config
+            .try_into::<RawConf>()
+            .expect("valid raw configuration");
+        let conf = Conf::try_from(raw_conf.to_owned()).expect("valid configuration");
+
+        for debug in &[format!("{:#?}", raw_conf), format!("{:#?}", conf)] {
+            assert!(!debug.contains("secret"));
+            assert!(debug.contains("\"accept\": \"text/plain\""));
+            assert!(debug.contains("\"x-api-key\": \"***\""));
+        }
+
+        // Values are kept for the scrapes
+        assert_eq!("secret", conf.scrapers[0].headers["x-api-key"]);
+    }
+
+    #[test]
+    fn timeouts() {
+        let conf = |settings: &[(&str, &str)]| -> Result<Conf, Error> {
+            let mut config = RawConf::initialize()?;
+            config.set("scrapers.node.url", "http://127.0.0.1:9100/metrics")?;
+            config.set("scrapers.node.period", "10s")?;
+            config.set("sinks.warp.url", "https://warp.example.com/api/v0/update")?;
+            config.set("sinks.warp.token", "token")?;
+            for (key, value) in settings {
+                config.set(key, *value)?;
+            }
+
+            Conf::try_from(config.try_into::<RawConf>()?)
+        };
+
+        let timeouts = |conf: Result<Conf, Error>| {
+            let conf = conf.expect("valid configuration");
+            (
+                conf.scrapers[0].timeout_of(&conf.parameters),
+                conf.sinks[0].timeout_of(&conf.parameters),
+            )
+        };
+
+        assert_eq!(
+            (Duration::from_secs(500), Duration::from_secs(500)),
+            timeouts(conf(&[]))
+        );
+
+        // The deprecated 'timeout' is the fallback of both timeouts
+        assert_eq!(
+            (Duration::from_secs(30), Duration::from_secs(30)),
+            timeouts(conf(&[("parameters.timeout", "30")]))
+        );
+
+        assert_eq!(
+            (Duration::from_secs(5), Duration::from_secs(60)),
+            timeouts(conf(&[
+                ("parameters.timeout", "30"),
+                ("parameters.scrape-timeout", "5s"),
+                ("parameters.push-timeout", "1m"),
+            ]))
+        );
+
+        assert_eq!(
+            (Duration::from_secs(2), Duration::from_secs(10)),
+            timeouts(conf(&[
+                ("parameters.scrape-timeout", "5s"),
+                ("scrapers.node.timeout", "2s"),
+                ("sinks.warp.timeout", "10s"),
+            ]))
+        );
+
+        let err = conf(&[("sinks.warp.timeout", "soon")]).expect_err("timeout is malformed");
+        assert!(err
+            .to_string()
+            .starts_with("sink 'warp' is malformed, could not parse 'timeout' setting"));
+    }
+
+    #[test]
+    fn geo() {
+        let geo = |latitude: &str, longitude: &str, elevation: Option<&str>| {
+            Geo::try_from(&RawGeo {
+                latitude: String::from(latitude),
+                longitude: String::from(longitude),
+                elevation: elevation.map(String::from),
+            })
+        };
+
+        geo("lat", "lon", None).expect("valid geo");
+        geo("lat", "lon", Some("elev")).expect("valid geo");
+
+        let err = geo("lat", " ", None).expect_err("longitude is empty");
+        assert_eq!(
+            "could not parse 'geo.longitude' setting, it should not be empty",
+            err.to_string()
+        );
+
+        let err = geo("lat", "lon", Some("lat")).expect_err("labels are not distinct");
+        assert_eq!(
+            "could not parse 'geo' setting, labels should be distinct",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn cardinality_guard() {
+        let guard = |mode: Option<&str>, factor: Option<f64>, window: Option<usize>| {
+            CardinalityGuard::try_from(&RawCardinalityGuard {
+                mode: mode.map(String::from),
+                factor,
+                window,
+            })
+        };
+
+        assert_eq!(
+            CardinalityGuard {
+                mode: GuardMode::Warn,
+                factor: 2.0,
+                window: 10,
+            },
+            guard(None, None, None).expect("valid guard")
+        );
+        assert_eq!(
+            GuardMode::Block,
+            guard(Some("block"), Some(1.5), Some(3))
+                .expect("valid guard")
+                .mode
+        );
+
+        let err = guard(Some("drop"), None, None).expect_err("unknown mode");
+        assert!(err
+            .to_string()
+            .starts_with("could not parse 'cardinality-guard.mode' setting"));
+
+        let err = guard(None, Some(1.0), None).expect_err("factor is not a jump");
+        assert!(err
+            .to_string()
+            .starts_with("could not parse 'cardinality-guard.factor' setting"));
+
+        let err = guard(None, None, Some(0)).expect_err("window is empty");
+        assert!(err
+            .to_string()
+            .starts_with("could not parse 'cardinality-guard.window' setting"));
+    }
+
+    #[test]
+    fn targets_file() {
+        let raw = RawScraper {
+            period: String::from("10s"),
+            targets_file: Some(String::from("/etc/beamium/targets.yaml")),
+            ..Default::default()
+        };
+
+        let scraper =
+            Scraper::try_from((String::from("node"), raw.to_owned())).expect("valid scraper");
+        assert_eq!(
+            Some(PathBuf::from("/etc/beamium/targets.yaml")),
+            scraper.targets_file
+        );
+
+        let raw = RawScraper {
+            url: String::from("http://127.0.0.1:9100/metrics"),
+            ..raw
+        };
+
+        let err = Scraper::try_from((String::from("node"), raw)).expect_err("url is set");
+        assert_eq!(
+            "could not parse 'targets-file' setting, it could not be used with 'url'",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn duplicate_names() {
+        let dir = temp_dir().join(format!("beamium-{}", Uuid::new_v4()));
+        create_dir_all(&dir).expect("create directory");
+
+        let node = dir.join("node.yaml");
+        let other = dir.join("other.yaml");
+        write(
+            &node,
+            "scrapers:\n  node:\n    url: http://127.0.0.1:9100/metrics\n    period: 10s\nsinks:\n  warp:\n    url: https://warp.example.com/api/v0/update\n    token: token\n",
+        )
+        .expect("write node configuration");
+
+        let conf = |policy: &str| -> Result<Conf, Error> {
+            write(&other, format!("scrapers:\n  node:\n    url: http://127.0.0.1:9101/metrics\n    period: 10s\nparameters:\n  duplicate-names: {}\n", policy))?;
+
+            let mut config = RawConf::initialize()?;
+            let files = vec![&node, &other]
+                .into_iter()
+                .map(|path| (path.display().to_string(), File::from(path.to_owned())))
+                .collect();
+            let (origins, duplicates) = RawConf::merge_files(&mut config, files)?;
+
+            let mut raw_config = config.try_into::<RawConf>()?;
+            raw_config.origins = origins;
+            raw_config.duplicates = duplicates;
+
+            Conf::try_from(raw_config)
+        };
+
+        // The settings of the last file win
+        let merged = conf("warn").expect("valid configuration");
+        assert_eq!(1, merged.scrapers.len());
+        assert_eq!(
+            "http://127.0.0.1:9101/metrics",
+            merged.scrapers[0].url.to_string()
+        );
+
+        let err = conf("error").expect_err("scraper is defined twice");
+        assert_eq!(
+            format!(
+                "scraper 'node' is defined in several files, '{}', '{}'",
+                node.display(),
+                other.display()
+            ),
+            err.to_string()
+        );
+
+        remove_dir_all(&dir).expect("remove directory");
+    }
+
+    #[test]
+    fn filtered_label_conflicts() {
+        let mut config = RawConf::initialize().expect("initialize configuration");
+        let settings = vec![
+            ("scrapers.node.url", "http://127.0.0.1:9100/metrics"),
+            ("scrapers.node.period", "10s"),
+            ("scrapers.node.labels.dc", "gra"),
+            (
+                "sinks.warp.url",
+                "https://warp.example.com/api/v0/update/{tenant}",
+            ),
+            ("sinks.warp.token", "token"),
+            ("sinks.warp.tenant-label", "team"),
+        ];
+
+        for (key, value) in settings {
+            config.set(key, value).expect("set setting");
+        }
+
+        let filtered = vec![String::from("dc"), String::from("team"), String::from("id")];
+        config
+            .set("scrapers.node.filtered-labels", filtered)
+            .expect("set filtered labels");
+
+        let raw_conf = config
+            .try_into::<RawConf>()
+            .expect("valid raw configuration");
+        let conf = Conf::try_from(raw_conf).expect("valid configuration");
+
+        assert_eq!(
+            vec![
+                "scraper 'node' filters the label 'dc' which it adds",
+                "scraper 'node' filters the label 'team' which is required by sink 'warp'",
+            ],
+            Conf::filtered_label_conflicts(&conf.scrapers, &conf.sinks)
+        );
+    }
+
+    #[test]
+    fn sink_transport() {
+        let conf = |url: &str, settings: &[(&str, &str)]| -> Result<Conf, Error> {
+            let mut config = RawConf::initialize()?;
+            config.set("sinks.relay.url", url)?;
+            config.set("sinks.relay.token", "")?;
+            config.set("sinks.relay.transport", "tcp")?;
+            for (key, value) in settings {
+                config.set(key, *value)?;
+            }
+
+            Conf::try_from(config.try_into::<RawConf>()?)
+        };
+
+        let relay = conf("tls://relay.example.com:4378", &[]).expect("valid configuration");
+        assert_eq!(Transport::Tcp, relay.sinks[0].transport);
+
+        let cases = vec![
+            ("https://relay.example.com/api/v0/update", vec![]),
+            ("tcp://relay.example.com", vec![]),
+            (
+                "tcp://relay.example.com:4378",
+                vec![("sinks.relay.format", "opentsdb")],
+            ),
+            (
+                "tcp://relay.example.com:4378",
+                vec![
+                    ("sinks.relay.token-by-label.label", "app"),
+                    ("sinks.relay.token-by-label.tokens.web", "token"),
+                ],
+            ),
+        ];
+
+        for (url, settings) in cases {
+            let err = conf(url, &settings).expect_err("invalid relay");
+            assert!(err
+                .to_string()
+                .contains("could not parse 'transport' setting"));
+        }
+    }
+
+    #[test]
+    fn sink_overflow_policy() {
+        let conf = |settings: &[(&str, &str)]| -> Result<Conf, Error> {
+            let mut config = RawConf::initialize()?;
+            config.set("sinks.warp.url", "https://warp.example.com/api/v0/update")?;
+            config.set("sinks.warp.token", "token")?;
+            for (key, value) in settings {
+                config.set(key, *value)?;
+            }
+
+            Conf::try_from(config.try_into::<RawConf>()?)
+        };
+
+        let sink = &conf(&[]).expect("valid configuration").sinks[0];
+        assert_eq!(OverflowPolicy::Drop, sink.overflow_policy);
+        assert_eq!(None, sink.archive_dir);
+
+        let sink = &conf(&[
+            ("sinks.warp.overflow-policy", "archive"),
+            ("sinks.warp.archive-dir", "/var/lib/beamium/archive"),
+        ])
+        .expect("valid configuration")
+        .sinks[0];
+        assert_eq!(OverflowPolicy::Archive, sink.overflow_policy);
+        assert_eq!(
+            Some(PathBuf::from("/var/lib/beamium/archive")),
+            sink.archive_dir
+        );
+        assert_eq!(10_000_000_000, sink.archive_size);
+
+        let sink = &conf(&[
+            ("sinks.warp.overflow-policy", "archive"),
+            ("sinks.warp.archive-dir", "/var/lib/beamium/archive"),
+            ("sinks.warp.archive-size", "100Mb"),
+        ])
+        .expect("valid configuration")
+        .sinks[0];
+        assert_eq!(100_000_000, sink.archive_size);
+
+        let err = conf(&[("sinks.warp.overflow-policy", "archive")]).expect_err("no directory");
+        assert!(err
+            .to_string()
+            .contains("could not parse 'archive-dir' setting"));
+
+        let err = conf(&[("sinks.warp.archive-dir", "/var/lib/beamium/archive")])
+            .expect_err("files are dropped");
+        assert!(err
+            .to_string()
+            .contains("could not parse 'archive-dir' setting"));
+
+        let err = conf(&[("sinks.warp.archive-size", "100Mb")]).expect_err("files are dropped");
+        assert!(err
+            .to_string()
+            .contains("could not parse 'archive-size' setting"));
+    }
+
+    #[test]
+    fn sink_backoff() {
+        let conf = |settings: &[(&str, &str)]| -> Result<Conf, Error> {
+            let mut config = RawConf::initialize()?;
+            config.set("sinks.canary.url", "https://warp.example.com/api/v0/update")?;
+            config.set("sinks.canary.token", "token")?;
+            config.set("parameters.backoff.initial", "1s")?;
+            config.set("parameters.backoff.max", "5m")?;
+            for (key, value) in settings {
+                config.set(key, *value)?;
+            }
+
+            Conf::try_from(config.try_into::<RawConf>()?)
+        };
+
+        let global = conf(&[]).expect("valid configuration");
+        let backoff = global.sinks[0].backoff_of(&global.parameters);
+        assert_eq!(Duration::from_secs(1), backoff.initial);
+        assert_eq!(Duration::from_secs(300), backoff.max);
+        assert_eq!(BackoffStrategy::Exponential, backoff.strategy);
+
+        // Unset values of the sink fall back on the parameters
+        let canary = conf(&[
+            ("sinks.canary.backoff.max", "2s"),
+            ("sinks.canary.backoff.strategy", "full-jitter"),
+        ])
+        .expect("valid configuration");
+        let backoff = canary.sinks[0].backoff_of(&canary.parameters);
+        assert_eq!(Duration::from_secs(1), backoff.initial);
+        assert_eq!(Duration::from_secs(2), backoff.max);
+        assert_eq!(1.5, backoff.multiplier);
+        assert_eq!(BackoffStrategy::FullJitter, backoff.strategy);
+
+        let err = conf(&[("sinks.canary.backoff.initial", "soon")]).expect_err("malformed backoff");
+        assert!(err
+            .to_string()
+            .starts_with("sink 'canary' is malformed, could not parse 'backoff.initial' setting"));
+    }
+}
@@ -6,15 +6,42 @@ use std::time::Duration;
 /// Keep only files that have the following extension
 pub(crate) const EXTENSION: &str = "metrics";
 
+/// Sink files holding urgent time series are suffixed by this tag before the extension
+pub(crate) const URGENT: &str = "urgent";
+
+/// Files of sources without continuation lines are suffixed by this tag before the extension
+pub(crate) const RAW: &str = "raw";
+
 /// Time to sleep for thread in waiting to achieve an action
 pub(crate) const THREAD_SLEEP: Duration = Duration::from_millis(100);
 
 /// Size of a chunk to send
 pub(crate) const CHUNK_SIZE: u64 = 1024 * 1024;
 
+/// Minimum size of a batch to send
+pub(crate) const MIN_BATCH_SIZE: u64 = 1024;
+
+/// Minimum period between two scrapes or two scans
+pub(crate) const MIN_PERIOD: Duration = Duration::from_millis(100);
+
 /// Number of threads used by hyper to resolve dns request
 pub(crate) const NUMBER_DNS_WORKER_THREADS: usize = 4;
 
+/// Number of concurrent dns lookups until the `max-dns-lookups` setting is applied
+pub(crate) const MAX_DNS_LOOKUPS: usize = 32;
+
+/// Label added to the samples of a counter following its reset
+pub(crate) const COUNTER_RESET_LABEL: &str = "reset=true";
+
+/// Label holding the configuration file defining the scraper
+pub(crate) const CONFIG_ORIGIN_LABEL: &str = "config_origin";
+
+/// Placeholder of the sink url replaced by the tenant of the time series
+pub(crate) const TENANT_PLACEHOLDER: &str = "{tenant}";
+
+/// Number of TLS sessions kept to resume handshakes with servers
+pub(crate) const TLS_SESSION_CACHE_SIZE: usize = 256;
+
 /// Number of handlers per tokio reactor
 pub(crate) const MAX_HANDLERS_PER_REACTOR: usize = 20;
 
@@ -23,3 +50,6 @@ pub(crate) const BACKOFF_WARN: Duration = Duration::from_millis(1_000);
 
 /// Keep alive duration of threads in tokio runtime
 pub(crate) const KEEP_ALIVE_TOKIO_RUNTIME: Duration = Duration::from_millis(5_000);
+
+/// Maximum number of files of a batch displayed in logs
+pub(crate) const MAX_LOGGED_FILES: usize = 10;
@@ -0,0 +1,434 @@
+//! # Discovery module.
+//!
+//! The Discovery module expands a scraper into a scrape of each target listed in its targets file,
+//! which is watched to start and stop the scrapes of the added and removed targets.
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, Sender};
+
+use failure::{format_err, Error, ResultExt};
+use hyper::Uri;
+use notify::{DebouncedEvent, RecommendedWatcher};
+use tokio::runtime::{Builder, Runtime};
+use yaml_rust::{Yaml, YamlLoader};
+
+use crate::conf::{self, Conf};
+use crate::constants::KEEP_ALIVE_TOKIO_RUNTIME;
+use crate::lib::{Named, Runner};
+use crate::scraper::Scraper;
+
+/// Watch of the targets file, the sender and the watcher are kept to receive its events
+type Watch = (
+    Sender<DebouncedEvent>,
+    Receiver<DebouncedEvent>,
+    RecommendedWatcher,
+);
+
+/// `Target` is an entry of a targets file
+#[derive(Clone, Debug, PartialEq)]
+pub struct Target {
+    url: Uri,
+    labels: HashMap<String, String>,
+}
+
+impl TryFrom<&Yaml> for Target {
+    type Error = Error;
+
+    fn try_from(entry: &Yaml) -> Result<Self, Self::Error> {
+        let url = match entry["url"].as_str() {
+            Some(url) => conf::Scraper::parse_url(url)?,
+            None => {
+                return Err(format_err!(
+                    "could not parse 'url' setting, it should be a string"
+                ));
+            }
+        };
+
+        let mut labels = HashMap::new();
+        match &entry["labels"] {
+            Yaml::BadValue | Yaml::Null => {}
+            Yaml::Hash(hash) => {
+                for (key, value) in hash {
+                    let key = key.as_str().ok_or_else(|| {
+                        format_err!("could not parse 'labels' setting, keys should be strings")
+                    })?;
+
+                    let value = match value {
+                        Yaml::String(value) | Yaml::Real(value) => value.to_owned(),
+                        Yaml::Integer(value) => value.to_string(),
+                        Yaml::Boolean(value) => value.to_string(),
+                        _ => {
+                            return Err(format_err!(
+                                "could not parse 'labels' setting, values should be scalars"
+                            ));
+                        }
+                    };
+
+                    labels.insert(key.to_owned(), value);
+                }
+            }
+            _ => {
+                return Err(format_err!(
+                    "could not parse 'labels' setting, it should be a map"
+                ));
+            }
+        }
+
+        Ok(Self { url, labels })
+    }
+}
+
+impl Target {
+    /// Address of the target, made of its host and port
+    fn address(&self) -> String {
+        let mut address = self.url.host().unwrap_or_default().to_owned();
+        if let Some(port) = self.url.port_u16() {
+            address += &format!("-{}", port);
+        }
+
+        address
+    }
+
+    /// Name of the scrapes of the target, made of the name of the scraper and the target address
+    fn name(&self, scraper: &str) -> String {
+        format!("{}-{}", scraper, self.address())
+    }
+
+    /// Settings of the scrapes of the target, the labels of the target override the scraper ones
+    fn scraper(&self, template: &conf::Scraper) -> conf::Scraper {
+        let mut conf = template.to_owned();
+        conf.name = self.name(&template.name);
+        conf.url = self.url.to_owned();
+        conf.targets_file = None;
+        conf.labels.extend(self.labels.to_owned());
+        conf
+    }
+}
+
+/// Read the targets file, a YAML or JSON list of objects holding a `url` and optional `labels`
+pub fn read(path: &Path) -> Result<Vec<Target>, Error> {
+    let content = read_to_string(path)
+        .with_context(|err| format!("could not read targets file '{}', {}", path.display(), err))?;
+
+    let documents = YamlLoader::load_from_str(&content).with_context(|err| {
+        format!("could not parse targets file '{}', {}", path.display(), err)
+    })?;
+
+    let entries = match documents.first() {
+        None => return Ok(vec![]),
+        Some(Yaml::Array(entries)) => entries,
+        Some(_) => {
+            return Err(format_err!(
+                "could not parse targets file '{}', it should be a list",
+                path.display()
+            ));
+        }
+    };
+
+    let mut targets: Vec<Target> = vec![];
+    for (index, entry) in entries.iter().enumerate() {
+        let target = Target::try_from(entry).with_context(|err| {
+            format!(
+                "could not parse target {} of '{}', {}",
+                index,
+                path.display(),
+                err
+            )
+        })?;
+
+        // Scrapes are named after the target address, so each address should be listed once
+        if targets.iter().any(|t| t.address() == target.address()) {
+            return Err(format_err!(
+                "could not parse target {} of '{}', address of '{}' is already listed",
+                index,
+                path.display(),
+                target.url
+            ));
+        }
+
+        targets.push(target);
+    }
+
+    Ok(targets)
+}
+
+/// `Discovery` runs a scrape of each target of the targets file of a scraper
+pub struct Discovery {
+    conf: conf::Scraper,
+    params: conf::Parameters,
+    path: PathBuf,
+    watch: Watch,
+    scrapers: HashMap<String, (Target, Scraper, Runtime)>,
+}
+
+impl TryFrom<(conf::Scraper, conf::Parameters)> for Discovery {
+    type Error = Error;
+
+    fn try_from(tuple: (conf::Scraper, conf::Parameters)) -> Result<Self, Self::Error> {
+        let (conf, params) = tuple;
+        let path = match conf.targets_file {
+            Some(ref path) => path.to_owned(),
+            None => return Err(format_err!("targets file is not set")),
+        };
+
+        let watch = Conf::watch(Some(path.to_owned()))?;
+
+        Ok(Self {
+            conf,
+            params,
+            path,
+            watch,
+            scrapers: HashMap::new(),
+        })
+    }
+}
+
+impl Named for Discovery {
+    fn name(&self) -> String {
+        self.conf.name.to_owned()
+    }
+}
+
+impl Discovery {
+    /// Synchronize the scrapes with the targets file if it changed since the last call
+    pub fn refresh(&mut self) {
+        if self.watch.1.try_iter().count() == 0 {
+            return;
+        }
+
+        info!("reload targets file"; "scraper" => self.name(), "path" => self.path.to_str());
+
+        // Editors replace the file, so the watch is put again on the new one
+        match Conf::watch(Some(self.path.to_owned())) {
+            Ok(watch) => self.watch = watch,
+            Err(err) => {
+                error!("could not watch targets file"; "scraper" => self.name(), "error" => err.to_string())
+            }
+        }
+
+        self.sync();
+    }
+
+    /// Start the scrapes of the added targets and stop the ones of the removed targets, the
+    /// scrapes are kept as is if the targets file could not be read
+    pub fn sync(&mut self) {
+        let targets = match read(&self.path) {
+            Ok(targets) => targets,
+            Err(err) => {
+                error!("could not read targets file"; "scraper" => self.name(), "error" => err.to_string());
+                return;
+            }
+        };
+
+        let mut targets: HashMap<String, Target> = targets
+            .into_iter()
+            .map(|target| (target.name(&self.conf.name), target))
+            .collect();
+
+        // Stop the scrapes of the removed or modified targets
+        let stale: Vec<String> = self
+            .scrapers
+            .iter()
+            .filter(|(name, (target, _, _))| targets.get(*name) != Some(target))
+            .map(|(name, _)| name.to_owned())
+            .collect();
+
+        for name in stale {
+            if let Some((_, scraper, rt)) = self.scrapers.remove(&name) {
+                info!("stop scrape of target"; "scraper" => name.as_str());
+                scraper.stop(rt);
+            }
+        }
+
+        for (name, target) in targets.drain() {
+            if self.scrapers.contains_key(&name) {
+                continue;
+            }
+
+            match self.start(&target) {
+                Ok((scraper, rt)) => {
+                    info!("start scrape of target"; "scraper" => name.as_str(), "uri" => target.url.to_string());
+                    self.scrapers.insert(name, (target, scraper, rt));
+                }
+                Err(err) => {
+                    error!("could not start scrape of target"; "scraper" => name.as_str(), "error" => err.to_string())
+                }
+            }
+        }
+    }
+
+    /// Stop the scrapes of every target
+    pub fn stop(self) {
+        for (_, (_, scraper, rt)) in self.scrapers {
+            scraper.stop(rt);
+        }
+    }
+
+    /// Start the scrape of the target on its own runtime
+    fn start(&self, target: &Target) -> Result<(Scraper, Runtime), Error> {
+        let conf = target.scraper(&self.conf);
+        let mut rt = Builder::new()
+            .keep_alive(Some(KEEP_ALIVE_TOKIO_RUNTIME))
+            .core_threads(conf.pool + 1)
+            .blocking_threads(self.params.filesystem_threads)
+            .name_prefix(format!("{}-", conf.name.as_str()))
+            .build()
+            .with_context(|err| format!("could not build the runtime, {}", err))?;
+
+        let scraper = Scraper::from((conf, self.params.to_owned()));
+        scraper.start(&mut rt)?;
+
+        Ok((scraper, rt))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{channel, Receiver};
+    use std::thread;
+    use std::time::Duration;
+
+    use uuid::Uuid;
+
+    use crate::conf::tests::parameters;
+    use crate::conf::{RawScraper, Scraper};
+
+    use super::{read, Discovery};
+
+    /// Listen on a local port, a message is received on each accepted connection
+    fn target() -> (u16, Receiver<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind listener");
+        let port = listener.local_addr().expect("listener address").port();
+
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stream.is_err() || tx.send(()).is_err() {
+                    break;
+                }
+            }
+        });
+
+        (port, rx)
+    }
+
+    #[test]
+    fn scrape_added_targets() {
+        let dir = temp_dir().join(format!("beamium-{}", Uuid::new_v4()));
+        let source_dir = dir.join("sources");
+        create_dir_all(&source_dir).expect("create source directory");
+
+        let path = dir.join("targets.yaml");
+        let (first, first_rx) = target();
+        let (second, second_rx) = target();
+        write(
+            &path,
+            format!("- url: http://127.0.0.1:{}/metrics\n", first),
+        )
+        .expect("write targets file");
+
+        let raw = RawScraper {
+            period: String::from("10s"),
+            targets_file: path.to_str().map(String::from),
+            ..Default::default()
+        };
+
+        let conf = Scraper::try_from((String::from("node"), raw)).expect("valid scraper");
+        let params = parameters(&[("source-dir", source_dir.to_str().expect("utf-8 path"))])
+            .expect("valid parameters");
+
+        let mut discovery = Discovery::try_from((conf, params)).expect("watch targets file");
+        discovery.sync();
+
+        let names = |discovery: &Discovery| {
+            let mut names: Vec<String> = discovery.scrapers.keys().cloned().collect();
+            names.sort();
+            names
+        };
+
+        assert_eq!(vec![format!("node-127.0.0.1-{}", first)], names(&discovery));
+        first_rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("first target is scraped");
+
+        // Targets could be listed in JSON, adding one spawns its scrape
+        write(
+            &path,
+            format!(
+                r#"[{{"url": "http://127.0.0.1:{}/metrics"}}, {{"url": "http://127.0.0.1:{}/metrics", "labels": {{"zone": "a"}}}}]"#,
+                first, second
+            ),
+        )
+        .expect("write targets file");
+        discovery.sync();
+
+        let mut expected = vec![
+            format!("node-127.0.0.1-{}", first),
+            format!("node-127.0.0.1-{}", second),
+        ];
+        expected.sort();
+        assert_eq!(expected, names(&discovery));
+        second_rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("second target is scraped");
+
+        // The scrape of a removed target is stopped, an invalid file keeps the scrapes as is
+        write(
+            &path,
+            format!("- url: http://127.0.0.1:{}/metrics\n", second),
+        )
+        .expect("write targets file");
+        discovery.sync();
+        assert_eq!(
+            vec![format!("node-127.0.0.1-{}", second)],
+            names(&discovery)
+        );
+
+        write(&path, "- url: ftp://127.0.0.1/metrics\n").expect("write targets file");
+        discovery.sync();
+        assert_eq!(
+            vec![format!("node-127.0.0.1-{}", second)],
+            names(&discovery)
+        );
+
+        discovery.stop();
+        remove_dir_all(&dir).expect("remove directory");
+    }
+
+    #[test]
+    fn reject_duplicate_addresses() {
+        let dir = temp_dir().join(format!("beamium-{}", Uuid::new_v4()));
+        create_dir_all(&dir).expect("create directory");
+
+        let path = dir.join("targets.yaml");
+        write(
+            &path,
+            "- url: http://127.0.0.1:9100/metrics\n- url: http://127.0.0.1:9101/metrics\n",
+        )
+        .expect("write targets file");
+        assert_eq!(2, read(&path).expect("valid targets file").len());
+
+        // Paths of a same address would be scraped under the same name
+        write(
+            &path,
+            "- url: http://127.0.0.1:9100/metrics\n- url: http://127.0.0.1:9100/federate\n",
+        )
+        .expect("write targets file");
+        let err = read(&path).expect_err("duplicate address");
+        assert_eq!(
+            format!(
+                "could not parse target 1 of '{}', address of 'http://127.0.0.1:9100/federate' is already listed",
+                path.display()
+            ),
+            err.to_string()
+        );
+
+        remove_dir_all(&dir).expect("remove directory");
+    }
+}
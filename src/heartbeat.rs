@@ -0,0 +1,198 @@
+//! # Heartbeat module.
+//!
+//! The Heartbeat module write a series at a fixed interval, regardless of the scrapes.
+use std::convert::From;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
+
+use failure::{format_err, Error};
+use futures::future::{ExecuteErrorKind, Executor};
+use time::now_utc;
+use tokio::prelude::*;
+use tokio::runtime::Runtime;
+use tokio::timer::Interval;
+
+use crate::conf;
+use crate::lib::disk;
+use crate::lib::{add_labels, Named, Runner};
+use crate::scraper::Scraper;
+
+#[derive(Clone, Debug)]
+pub struct Heartbeat {
+    conf: Arc<conf::Heartbeat>,
+    params: Arc<conf::Parameters>,
+}
+
+impl From<(conf::Heartbeat, conf::Parameters)> for Heartbeat {
+    fn from(tuple: (conf::Heartbeat, conf::Parameters)) -> Self {
+        let (conf, params) = tuple;
+
+        Self {
+            conf: arc!(conf),
+            params: arc!(params),
+        }
+    }
+}
+
+impl Named for Heartbeat {
+    fn name(&self) -> String {
+        String::from("heartbeat")
+    }
+}
+
+impl Runner for Heartbeat {
+    type Error = Error;
+
+    fn start(&self, rt: &mut Runtime) -> Result<(), Self::Error> {
+        // Owned variables by creating a new reference using Arc.
+        let name = self.name();
+        let conf = self.conf.to_owned();
+        let params = self.params.to_owned();
+
+        let executor = rt.executor();
+
+        let ticker = Interval::new(Instant::now(), conf.interval.to_owned())
+            .map_err(|err| format_err!("{}", err))
+            .for_each(move |_| {
+                if disk::is_paused(&name) {
+                    debug!("skip heartbeat, the disk is full");
+                    return future::ok(());
+                }
+
+                let name = name.to_owned();
+                let beat = Self::beat(&conf, &params, name.to_owned()).map_err(move |err| {
+                    error!("could not write heartbeat"; "error" => err.to_string(), "heartbeat" => name);
+                });
+
+                if let Err(err) = executor.execute(beat) {
+                    match err.kind() {
+                        ExecuteErrorKind::Shutdown => {
+                            warn!("could not execute the future, runtime is closed");
+                        }
+                        _ => {
+                            return future::err(format_err!(
+                                "could not execute future, got runtime error"
+                            ));
+                        }
+                    }
+                }
+
+                future::ok(())
+            })
+            .map_err(|err| {
+                error!("could not handle heartbeat ticker"; "error" => err.to_string());
+            });
+
+        rt.spawn(ticker);
+
+        Ok(())
+    }
+}
+
+impl Heartbeat {
+    /// Write the heartbeat series into the source directory, it is routed as a scraped one
+    fn beat(
+        conf: &conf::Heartbeat,
+        params: &conf::Parameters,
+        name: String,
+    ) -> impl Future<Item = (), Error = Error> {
+        let labels: Vec<String> = conf
+            .labels
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect();
+
+        let start = now_utc();
+        let now =
+            start.to_timespec().sec * 1_000_000 + (i64::from(start.to_timespec().nsec) / 1000);
+
+        let line = format!(
+            "{}// {}{{}} 1",
+            params.platform_time_unit.scale(now),
+            conf.class
+        );
+        let line = match add_labels(&line, &labels.join(",")) {
+            Ok(line) => line,
+            Err(err) => return future::Either::A(future::err(err)),
+        };
+
+        let dir = PathBuf::from(params.source_dir.to_owned());
+        future::Either::B(Scraper::write_chunk(
+            vec![line],
+            dir,
+            name.to_owned(),
+            name,
+            true,
+            now,
+            0,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, read_dir, read_to_string, remove_dir_all};
+    use std::path::PathBuf;
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+
+    use futures::Future;
+    use tokio::runtime::Runtime;
+    use uuid::Uuid;
+
+    use super::Heartbeat;
+    use crate::conf::{self, tests::parameters};
+    use crate::lib::Runner;
+
+    #[test]
+    fn write_heartbeat_on_schedule() {
+        let dir = temp_dir().join(format!("beamium-{}", Uuid::new_v4()));
+        create_dir_all(&dir).expect("create source directory");
+
+        let params = parameters(&[("source-dir", dir.to_str().expect("utf-8 path"))])
+            .expect("valid parameters");
+
+        let mut labels = HashMap::new();
+        labels.insert(String::from("instance"), String::from("a"));
+
+        let heartbeat = Heartbeat::from((
+            conf::Heartbeat {
+                interval: Duration::from_millis(100),
+                class: String::from("beamium_heartbeat"),
+                labels,
+            },
+            params,
+        ));
+
+        let beats = || -> Vec<PathBuf> {
+            read_dir(&dir)
+                .expect("read source directory")
+                .map(|entry| entry.expect("directory entry").path())
+                .filter(|path| path.extension().and_then(|ext| ext.to_str()) == Some("metrics"))
+                .collect()
+        };
+
+        let mut rt = Runtime::new().expect("runtime");
+        heartbeat.start(&mut rt).expect("start heartbeat");
+
+        // Wait for a few beats instead of a fixed delay, the runtime could be slow to tick
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while beats().len() < 3 && Instant::now() < deadline {
+            sleep(Duration::from_millis(10));
+        }
+        rt.shutdown_now().wait().expect("shutdown runtime");
+
+        let files = beats();
+        assert!(files.len() >= 3, "got {} heartbeats", files.len());
+        for file in files {
+            let content = read_to_string(&file).expect("read heartbeat");
+            let (_, series) = content.split_once("// ").expect("heartbeat series");
+            assert_eq!("beamium_heartbeat{instance=a} 1\n", series);
+        }
+
+        remove_dir_all(&dir).expect("remove source directory");
+    }
+}
@@ -0,0 +1,137 @@
+//! # Archive module
+//!
+//! The `archive` module compresses the files overflowing a sink into a cold storage directory
+//! using gzip, so they could be replayed later.
+use std::fs::{read, read_dir, remove_file, write};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures::future::poll_fn;
+use tokio::prelude::*;
+use tokio_threadpool::blocking;
+
+/// Extension appended to the name of the archived files
+pub const GZIP_EXTENSION: &str = "gz";
+
+/// Compression level of the archived files, from 0 to 9
+const LEVEL: u32 = 6;
+
+/// Compress the file into the directory and remove it once the compressed file is written, the
+/// oldest archived files are then removed while the directory is larger than the maximum size.
+/// The compression runs on the blocking threads of the runtime.
+pub fn archive(
+    path: PathBuf,
+    dir: PathBuf,
+    max_size: u64,
+) -> impl Future<Item = PathBuf, Error = io::Error> {
+    poll_fn(move || {
+        blocking(|| compress(&path, &dir, max_size))
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))
+    })
+    .and_then(|result| result)
+}
+
+/// Compress the file into the directory, then bound the size of the directory
+fn compress(path: &Path, dir: &Path, max_size: u64) -> Result<PathBuf, io::Error> {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(".");
+    name.push(GZIP_EXTENSION);
+    let archive = dir.join(name);
+
+    write(&archive, gzip(&read(path)?)?)?;
+    remove_file(path)?;
+
+    for old in overflowing(dir, max_size)? {
+        debug!("remove archived file"; "path" => old.to_str(), "reason" => "archive directory is too large");
+        remove_file(old)?;
+    }
+
+    Ok(archive)
+}
+
+/// Compress the data into a gzip member
+pub fn gzip(data: &[u8]) -> Result<Vec<u8>, io::Error> {
+    let mut encoder = GzEncoder::new(vec![], Compression::new(LEVEL));
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// Archived files of the directory to remove, the oldest first, so it is not larger than the
+/// maximum size
+fn overflowing(dir: &Path, max_size: u64) -> Result<Vec<PathBuf>, io::Error> {
+    let mut files: Vec<(SystemTime, PathBuf, u64)> = vec![];
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some(GZIP_EXTENSION) {
+            continue;
+        }
+
+        let meta = path.metadata()?;
+        files.push((meta.modified()?, path, meta.len()));
+    }
+
+    files.sort();
+
+    let mut size: u64 = files.iter().map(|(_, _, len)| len).sum();
+    let mut overflowing = vec![];
+    for (_, path, len) in files {
+        if size <= max_size {
+            break;
+        }
+
+        size -= len;
+        overflowing.push(path);
+    }
+
+    Ok(overflowing)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+    use uuid::Uuid;
+
+    use super::{gzip, overflowing};
+
+    #[test]
+    fn gzip_member() {
+        let data = b"1// f{host=a} 1\n2// f{host=a} 2\n";
+        let member = gzip(data).expect("compress data");
+        assert_eq!([0x1f, 0x8b, 8], member[..3]);
+
+        let mut decoded = vec![];
+        GzDecoder::new(&member[..])
+            .read_to_end(&mut decoded)
+            .expect("valid gzip");
+        assert_eq!(data.to_vec(), decoded);
+    }
+
+    #[test]
+    fn remove_oldest_archives() {
+        let dir = temp_dir().join(format!("beamium-{}", Uuid::new_v4()));
+        create_dir_all(&dir).expect("create archive directory");
+
+        // Files are sorted by modification time, then by name as it holds the timestamp
+        for idx in 1..=3 {
+            let path = dir.join(format!("warp-{}-0.metrics.gz", idx));
+            write(&path, vec![0; 100]).expect("write archive");
+        }
+        write(dir.join("warp-4-0.metrics"), vec![0; 100]).expect("write sink file");
+
+        assert!(overflowing(&dir, 300).expect("list archives").is_empty());
+        assert_eq!(
+            vec![dir.join("warp-1-0.metrics.gz")],
+            overflowing(&dir, 250).expect("list archives")
+        );
+        assert_eq!(3, overflowing(&dir, 0).expect("list archives").len());
+
+        remove_dir_all(&dir).expect("remove archive directory");
+    }
+}
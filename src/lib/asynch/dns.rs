@@ -0,0 +1,156 @@
+//! # Dns module
+//!
+//! The `dns` module provide a resolver bounding the number of concurrent lookups.
+use std::io;
+use std::sync::Mutex;
+
+use futures::Future;
+use hyper::client::connect::dns::{GaiResolver, Name, Resolve};
+
+use crate::constants::{MAX_DNS_LOOKUPS, NUMBER_DNS_WORKER_THREADS};
+use crate::lib::asynch::sync::Semaphore;
+
+lazy_static! {
+    /// Semaphore shared by every resolver, so lookups are bounded across scrapers and sinks
+    static ref LOOKUPS: Mutex<Semaphore> = Mutex::new(Semaphore::from(MAX_DNS_LOOKUPS));
+}
+
+/// Bound the number of concurrent lookups of the resolvers created afterwards
+pub(crate) fn limit(max: usize) {
+    match LOOKUPS.lock() {
+        Ok(mut lookups) => *lookups = Semaphore::from(max),
+        Err(err) => error!("could not limit dns lookups"; "error" => err.to_string()),
+    }
+}
+
+/// `Resolver` wait for a permit of the shared semaphore before resolving a name, lookups beyond
+/// the bound are queued
+#[derive(Clone, Debug)]
+pub struct Resolver<R = GaiResolver> {
+    inner: R,
+    semaphore: Semaphore,
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        let semaphore = match LOOKUPS.lock() {
+            Ok(lookups) => lookups.to_owned(),
+            Err(err) => {
+                error!("could not get dns lookups semaphore"; "error" => err.to_string());
+                Semaphore::from(MAX_DNS_LOOKUPS)
+            }
+        };
+
+        Self::from((GaiResolver::new(NUMBER_DNS_WORKER_THREADS), semaphore))
+    }
+}
+
+impl<R> From<(R, Semaphore)> for Resolver<R> {
+    fn from(tuple: (R, Semaphore)) -> Self {
+        let (inner, semaphore) = tuple;
+
+        Self { inner, semaphore }
+    }
+}
+
+impl<R> Resolve for Resolver<R>
+where
+    R: Resolve + Clone + Send + 'static,
+    R::Addrs: Send,
+    R::Future: Send,
+{
+    type Addrs = R::Addrs;
+    type Future = Box<dyn Future<Item = Self::Addrs, Error = io::Error> + Send>;
+
+    fn resolve(&self, name: Name) -> Self::Future {
+        let inner = self.inner.to_owned();
+
+        Box::new(
+            self.semaphore
+                .acquire()
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))
+                .and_then(move |guard| {
+                    inner.resolve(name).then(move |result| {
+                        drop(guard);
+                        result
+                    })
+                }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use std::vec::IntoIter;
+
+    use futures::future::join_all;
+    use futures::Future;
+    use hyper::client::connect::dns::{Name, Resolve};
+    use tokio::runtime::Runtime;
+    use tokio::timer::Delay;
+
+    use super::Resolver;
+    use crate::lib::asynch::sync::Semaphore;
+
+    /// `Slow` resolver records the number of lookups in flight
+    #[derive(Clone)]
+    struct Slow {
+        in_flight: Arc<AtomicUsize>,
+        max: Arc<AtomicUsize>,
+    }
+
+    impl Resolve for Slow {
+        type Addrs = IntoIter<IpAddr>;
+        type Future = Box<dyn Future<Item = Self::Addrs, Error = io::Error> + Send>;
+
+        fn resolve(&self, _: Name) -> Self::Future {
+            let current = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max.fetch_max(current, Ordering::SeqCst);
+
+            let in_flight = self.in_flight.to_owned();
+            Box::new(
+                Delay::new(Instant::now() + Duration::from_millis(2))
+                    .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))
+                    .map(move |_| {
+                        in_flight.fetch_sub(1, Ordering::SeqCst);
+                        vec![IpAddr::V4(Ipv4Addr::LOCALHOST)].into_iter()
+                    }),
+            )
+        }
+    }
+
+    #[test]
+    fn lookups_never_exceed_bound() {
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max = Arc::new(AtomicUsize::new(0));
+        let slow = Slow {
+            in_flight: in_flight.to_owned(),
+            max: max.to_owned(),
+        };
+
+        // Resolvers of different scrapers and sinks share the semaphore
+        let semaphore = Semaphore::from(3);
+        let resolvers: Vec<_> = (0..5)
+            .map(|_| Resolver::from((slow.to_owned(), semaphore.to_owned())))
+            .collect();
+
+        let mut bulk = vec![];
+        for idx in 0..50 {
+            let name = Name::from_str("localhost").expect("valid name");
+            bulk.push(resolvers[idx % resolvers.len()].resolve(name));
+        }
+
+        let mut rt = Runtime::new().expect("runtime");
+        let addrs = rt.block_on(join_all(bulk)).expect("resolve names");
+
+        assert_eq!(50, addrs.len());
+        assert_eq!(3, max.load(Ordering::SeqCst));
+        assert_eq!(0, in_flight.load(Ordering::SeqCst));
+    }
+}
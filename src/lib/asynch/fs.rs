@@ -6,12 +6,13 @@ use std::path::PathBuf;
 use std::time::{Duration, Instant};
 
 use failure::{format_err, Error};
-use futures::future::{err, join_all, ok};
+use futures::future::{err, join_all, ok, Either};
 use futures::{try_ready, Async, Future, Poll, Stream};
 use prometheus::GaugeVec;
-use tokio::fs::{metadata, read_dir, remove_file};
+use tokio::fs::{metadata, read_dir, remove_file, symlink_metadata};
 use tokio::timer::Interval;
 
+use crate::conf::Symlinks;
 use crate::constants::EXTENSION;
 
 lazy_static! {
@@ -29,15 +30,17 @@ lazy_static! {
 pub struct Scanner {
     interval: Interval,
     dir: PathBuf,
+    symlinks: Symlinks,
 }
 
-impl From<(PathBuf, Duration)> for Scanner {
-    fn from(tuple: (PathBuf, Duration)) -> Self {
-        let (dir, period) = tuple;
+impl From<(PathBuf, Duration, Symlinks)> for Scanner {
+    fn from(tuple: (PathBuf, Duration, Symlinks)) -> Self {
+        let (dir, period, symlinks) = tuple;
 
         Self {
             interval: Interval::new(Instant::now(), period),
             dir,
+            symlinks,
         }
     }
 }
@@ -49,7 +52,7 @@ impl Stream for Scanner {
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
         try_ready!(self.interval.poll().map_err(|err| format_err!("{}", err)));
 
-        let mut scan = Self::scan(self.dir.to_owned());
+        let mut scan = Self::scan(self.dir.to_owned(), &self.symlinks);
 
         loop {
             return match scan.poll()? {
@@ -72,7 +75,12 @@ impl Stream for Scanner {
 }
 
 impl Scanner {
-    fn scan(path: PathBuf) -> impl Future<Item = HashMap<PathBuf, Metadata>, Error = Error> {
+    fn scan(
+        path: PathBuf,
+        symlinks: &Symlinks,
+    ) -> impl Future<Item = HashMap<PathBuf, Metadata>, Error = Error> {
+        let symlinks = symlinks.to_owned();
+
         read_dir(path)
             .map_err(|err| format_err!("{}", err))
             .and_then(move |entries| {
@@ -93,17 +101,29 @@ impl Scanner {
                         })
                     })
             })
-            .and_then(|entries| {
+            .and_then(move |entries| {
                 let mut bulk = vec![];
                 for entry in entries {
                     let entry = entry.to_owned();
 
+                    // The metadata of the link itself tell whether it has to be ignored
+                    let meta = match symlinks {
+                        Symlinks::Follow => Either::A(metadata(entry.to_owned())),
+                        Symlinks::Ignore => Either::B(symlink_metadata(entry.to_owned())),
+                    };
+
                     bulk.push(
                         // In some cases, metadata failed to retrieve the meta of the file.
                         // This occurred when a file is deleted by a sink.
-                        metadata(entry.to_owned())
-                            .and_then(move |meta| ok(Some((entry, meta))))
-                            .or_else(|_| ok(None)),
+                        meta.and_then(move |meta| {
+                            if meta.file_type().is_symlink() {
+                                trace!("ignore symbolic link"; "path" => entry.to_str());
+                                return ok(None);
+                            }
+
+                            ok(Some((entry, meta)))
+                        })
+                        .or_else(|_| ok(None)),
                     );
                 }
 
@@ -143,3 +163,51 @@ impl Scanner {
             })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, remove_dir_all, File};
+    use std::io::Write;
+    use std::os::unix::fs::symlink;
+
+    use tokio::runtime::Runtime;
+    use uuid::Uuid;
+
+    use super::Scanner;
+    use crate::conf::Symlinks;
+
+    #[test]
+    fn symlinked_files() {
+        let root = temp_dir().join(format!("beamium-{}", Uuid::new_v4()));
+        let dir = root.join("sources");
+        let volume = root.join("volume");
+        create_dir_all(&dir).expect("create source directory");
+        create_dir_all(&volume).expect("create volume directory");
+
+        let target = volume.join("scraper-0-0.metrics");
+        let mut file = File::create(&target).expect("create file");
+        file.write_all(b"1// f{} 1\n").expect("write file");
+
+        let regular = dir.join("scraper-0-1.metrics");
+        let mut file = File::create(&regular).expect("create file");
+        file.write_all(b"1// f{} 1\n").expect("write file");
+
+        let link = dir.join("scraper-0-0.metrics");
+        symlink(&target, &link).expect("create symbolic link");
+
+        let mut rt = Runtime::new().expect("runtime");
+        let entries = rt
+            .block_on(Scanner::scan(dir.to_owned(), &Symlinks::Follow))
+            .expect("scan directory");
+        assert_eq!(2, entries.len());
+        assert_eq!(10, entries[&link].len());
+
+        let entries = rt
+            .block_on(Scanner::scan(dir.to_owned(), &Symlinks::Ignore))
+            .expect("scan directory");
+        assert_eq!(vec![&regular], entries.keys().collect::<Vec<_>>());
+
+        remove_dir_all(&root).expect("remove directories");
+    }
+}
@@ -1,32 +1,50 @@
-use std::collections::VecDeque;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashSet};
 use std::convert::From;
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead, BufReader};
+use std::net::IpAddr;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use backoff::backoff::Backoff;
 use backoff::ExponentialBackoff;
 use crossbeam::queue::SegQueue;
 use failure::{format_err, Error, ResultExt};
-use futures::future::{err, join_all, ok};
-use futures::{try_ready, Poll, Stream};
+use futures::future::{err, join_all, ok, Executor};
+use futures::sync::oneshot;
+use futures::{stream, try_ready, Poll, Stream};
 use hyper::body::{Chunk, Payload};
-use hyper::client::connect::dns::GaiResolver;
 use hyper::client::HttpConnector;
-use hyper::{Client, Method, Request};
+use hyper::header::CONTENT_TYPE;
+use hyper::{Client, Method, Request, Uri};
 use hyper_rustls::HttpsConnector;
-use prometheus::CounterVec;
-use tokio::fs::remove_file;
+use prometheus::{CounterVec, GaugeVec};
+use rand::{thread_rng, Rng};
+use rustls::{ClientConfig, ClientSessionMemoryCache, NoClientSessionStorage, ProtocolVersion};
+use serde_json::to_string;
+use time::now_utc;
+use tokio::executor::DefaultExecutor;
+use tokio::fs::{metadata, remove_file};
 use tokio::prelude::*;
 use tokio::timer::{Delay, Interval};
+use tokio_threadpool::blocking;
 
-use crate::conf;
-use crate::constants::{BACKOFF_WARN, CHUNK_SIZE, NUMBER_DNS_WORKER_THREADS, THREAD_SLEEP};
+use crate::conf::{self, TlsVersion};
+use crate::constants::{
+    BACKOFF_WARN, CHUNK_SIZE, MAX_LOGGED_FILES, THREAD_SLEEP, TLS_SESSION_CACHE_SIZE,
+};
+use crate::lib::asynch::dns::Resolver;
+use crate::lib::asynch::tcp::Relay;
+use crate::lib::errors::{self, Component};
+use crate::lib::has_continuations;
+use crate::lib::opentsdb;
+use crate::lib::queue::Queue;
 
 /// Alias for the hyper's https client
-type HttpsClient = Client<HttpsConnector<HttpConnector<GaiResolver>>, Body>;
+type HttpsClient = Client<HttpsConnector<HttpConnector<Resolver>>, Body>;
 
 lazy_static! {
     static ref BEAMIUM_PUSH_DP: CounterVec = register_counter_vec!(
@@ -34,6 +52,16 @@ lazy_static! {
         &["sink"]
     )
     .expect("create metric: 'beamium_fetch_datapoints'");
+    static ref BEAMIUM_PUSH_FILES: CounterVec = register_counter_vec!(
+        opts!("beamium_push_files_total", "Number of files pushed"),
+        &["sink"]
+    )
+    .expect("create metric: 'beamium_push_files_total'");
+    static ref BEAMIUM_PUSH_BYTES: CounterVec = register_counter_vec!(
+        opts!("beamium_push_bytes_total", "Number of bytes pushed"),
+        &["sink"]
+    )
+    .expect("create metric: 'beamium_push_bytes_total'");
     static ref BEAMIUM_PUSH_ERRORS: CounterVec = register_counter_vec!(
         opts!("beamium_push_errors", "Number of push error"),
         &["sink"]
@@ -44,6 +72,68 @@ lazy_static! {
         &["sink", "status"]
     )
     .expect("create metric: 'beamium_push_http_status'");
+    static ref BEAMIUM_PUSH_CIRCUIT: GaugeVec = register_gauge_vec!(
+        opts!(
+            "beamium_push_circuit_state",
+            "Circuit breaker state (0: closed, 1: open, 2: half-open)"
+        ),
+        &["sink"]
+    )
+    .expect("create metric: 'beamium_push_circuit_state'");
+    static ref BEAMIUM_SKIP_PARTIAL_LINES: CounterVec = register_counter_vec!(
+        opts!(
+            "beamium_skip_partial_lines",
+            "Number of partial lines skipped at the end of files"
+        ),
+        &["sink"]
+    )
+    .expect("create metric: 'beamium_skip_partial_lines'");
+    /// TLS configuration shared by every client, so sessions are resumed across scrapers, sinks
+    /// and recycled connections
+    static ref TLS_CONFIG: Arc<ClientConfig> = {
+        let mut config = ClientConfig::new();
+        config
+            .root_store
+            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+        config.ct_logs = Some(&ct_logs::LOGS);
+        config.set_persistence(ClientSessionMemoryCache::new(TLS_SESSION_CACHE_SIZE));
+        Arc::new(config)
+    };
+}
+
+/// Create an https connector, TLS sessions are cached and resumed if `resumption` is set and
+/// dns lookups are bounded by the shared resolver semaphore. Connections are bound to the local
+/// address, if any, and never negotiate a TLS version below `min_version`.
+pub(crate) fn connector(
+    resumption: bool,
+    local: Option<IpAddr>,
+    min_version: TlsVersion,
+) -> HttpsConnector<HttpConnector<Resolver>> {
+    let mut http = HttpConnector::new_with_resolver(Resolver::default());
+    http.enforce_http(false);
+    http.set_local_address(local);
+
+    HttpsConnector::from((http, tls_config(resumption, min_version)))
+}
+
+/// TLS configuration of the clients, the shared one if `resumption` is set. The session cache
+/// is still shared when only the protocol versions differ.
+fn tls_config(resumption: bool, min_version: TlsVersion) -> Arc<ClientConfig> {
+    if resumption && min_version == TlsVersion::Tls12 {
+        return TLS_CONFIG.to_owned();
+    }
+
+    let mut config = (**TLS_CONFIG).to_owned();
+    if min_version == TlsVersion::Tls13 {
+        config.versions = vec![ProtocolVersion::TLSv1_3];
+    }
+
+    if !resumption {
+        config.set_persistence(Arc::new(NoClientSessionStorage {}));
+        config.enable_tickets = false;
+    }
+
+    Arc::new(config)
 }
 
 pub enum State {
@@ -51,46 +141,290 @@ pub enum State {
     Sending(Box<dyn Future<Item = (), Error = Error> + Send>),
     Waiting,
     Backoff(Box<dyn Future<Item = (), Error = Error> + Send>),
+    Open,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Circuit {
+    Closed,
+    Open(Instant),
+    HalfOpen,
+}
+
+/// `Breaker` stops sending data of a sink after too many consecutive failures
+/// and probes it periodically with a single batch
+#[derive(Debug)]
+pub struct Breaker {
+    name: String,
+    threshold: Option<u64>,
+    probe: Duration,
+    failures: u64,
+    circuit: Circuit,
+}
+
+impl From<&conf::Sink> for Breaker {
+    fn from(conf: &conf::Sink) -> Self {
+        BEAMIUM_PUSH_CIRCUIT
+            .with_label_values(&[conf.name.as_str()])
+            .set(0.0);
+
+        Self {
+            name: conf.name.to_owned(),
+            threshold: conf.circuit_breaker_threshold,
+            probe: conf.circuit_breaker_probe,
+            failures: 0,
+            circuit: Circuit::Closed,
+        }
+    }
+}
+
+impl Breaker {
+    /// Check if a batch could be sent, the first caller once the probe delay is
+    /// elapsed is elected to send the probe batch
+    pub fn allow(&mut self, now: Instant) -> bool {
+        match self.circuit {
+            Circuit::Closed => true,
+            Circuit::HalfOpen => false,
+            Circuit::Open(until) => {
+                if now < until {
+                    return false;
+                }
+
+                info!("probe sink"; "sink" => self.name.as_str());
+                self.transition(Circuit::HalfOpen);
+                true
+            }
+        }
+    }
+
+    pub fn success(&mut self) {
+        self.failures = 0;
+        if self.circuit != Circuit::Closed {
+            info!("close circuit"; "sink" => self.name.as_str());
+            self.transition(Circuit::Closed);
+        }
+    }
+
+    pub fn failure(&mut self, now: Instant) {
+        self.failures += 1;
+
+        let threshold = match self.threshold {
+            Some(threshold) => threshold,
+            None => return,
+        };
+
+        let open = match self.circuit {
+            Circuit::Closed => self.failures >= threshold,
+            Circuit::HalfOpen => true,
+            Circuit::Open(_) => false,
+        };
+
+        if open {
+            warn!("open circuit"; "sink" => self.name.as_str(), "failures" => self.failures, "probe" => format!("{:?}", self.probe));
+            self.transition(Circuit::Open(now + self.probe));
+        }
+    }
+
+    fn transition(&mut self, circuit: Circuit) {
+        let value = match circuit {
+            Circuit::Closed => 0.0,
+            Circuit::Open(_) => 1.0,
+            Circuit::HalfOpen => 2.0,
+        };
+
+        BEAMIUM_PUSH_CIRCUIT
+            .with_label_values(&[self.name.as_str()])
+            .set(value);
+
+        self.circuit = circuit;
+    }
+}
+
+/// `Ramp` bounds the number of batches of a sink sent at the same time, starting with a single
+/// one and reaching the sink parallelism once the ramp-up period is elapsed
+#[derive(Debug)]
+pub struct Ramp {
+    name: String,
+    parallel: usize,
+    period: Option<Duration>,
+    since: Instant,
+    running: usize,
+}
+
+impl From<(&conf::Sink, Instant)> for Ramp {
+    fn from(tuple: (&conf::Sink, Instant)) -> Self {
+        let (conf, now) = tuple;
+
+        Self {
+            name: conf.name.to_owned(),
+            parallel: conf.parallel,
+            period: conf.ramp_up,
+            since: now,
+            running: 0,
+        }
+    }
+}
+
+impl Ramp {
+    /// Number of batches allowed to be sent at the same time
+    pub fn senders(&self, now: Instant) -> usize {
+        let period = match self.period {
+            Some(period) if self.parallel > 1 => period,
+            _ => return self.parallel,
+        };
+
+        let elapsed = now.saturating_duration_since(self.since);
+        if elapsed >= period {
+            return self.parallel;
+        }
+
+        1 + ((self.parallel - 1) as u128 * elapsed.as_nanos() / period.as_nanos()) as usize
+    }
+
+    /// Check if a batch could be sent, the caller should then `release` it or report a `failure`
+    pub fn acquire(&mut self, now: Instant) -> bool {
+        if self.running >= self.senders(now) {
+            return false;
+        }
+
+        self.running += 1;
+        true
+    }
+
+    pub fn release(&mut self) {
+        self.running = self.running.saturating_sub(1);
+    }
+
+    /// Release the batch and step the ramp back to half of the allowed batches
+    pub fn failure(&mut self, now: Instant) {
+        self.release();
+
+        let period = match self.period {
+            Some(period) if self.parallel > 1 => period,
+            _ => return,
+        };
+
+        let senders = self.senders(now);
+        let target = (senders / 2).max(1);
+        if target == senders {
+            return;
+        }
+
+        // Round up, so the target is reached despite the integer division of `senders`
+        let steps = self.parallel as u128 - 1;
+        let progress = (period.as_nanos() * (target as u128 - 1) + steps - 1) / steps;
+
+        debug!("step back ramp-up"; "sink" => self.name.as_str(), "senders" => target);
+        self.since = now
+            .checked_sub(Duration::from_nanos(progress as u64))
+            .unwrap_or(now);
+    }
+}
+
+/// `JitteredBackoff` applies the backoff strategy on an exponential backoff
+pub struct JitteredBackoff {
+    backoff: ExponentialBackoff,
+    strategy: conf::BackoffStrategy,
+}
+
+impl From<&conf::Backoff> for JitteredBackoff {
+    fn from(conf: &conf::Backoff) -> Self {
+        let mut backoff = ExponentialBackoff {
+            initial_interval: conf.initial,
+            max_interval: conf.max,
+            multiplier: conf.multiplier,
+            max_elapsed_time: None,
+            randomization_factor: match conf.strategy {
+                conf::BackoffStrategy::Exponential => conf.randomization,
+                // The jitter replaces the randomization
+                _ => 0.0,
+            },
+            ..Default::default()
+        };
+
+        // Start from the configured initial interval instead of the default one
+        backoff.reset();
+
+        Self {
+            backoff,
+            strategy: conf.strategy.to_owned(),
+        }
+    }
+}
+
+impl Backoff for JitteredBackoff {
+    fn reset(&mut self) {
+        self.backoff.reset();
+    }
+
+    fn next_backoff(&mut self) -> Option<Duration> {
+        let delay = self.backoff.next_backoff()?;
+
+        Some(Self::jitter(&self.strategy, delay, thread_rng().gen()))
+    }
+}
+
+impl JitteredBackoff {
+    /// Apply the strategy on the delay, `random` is in [0, 1)
+    fn jitter(strategy: &conf::BackoffStrategy, delay: Duration, random: f64) -> Duration {
+        match strategy {
+            conf::BackoffStrategy::Exponential => delay,
+            conf::BackoffStrategy::FullJitter => delay.mul_f64(random),
+            conf::BackoffStrategy::EqualJitter => delay / 2 + (delay / 2).mul_f64(random),
+        }
+    }
 }
 
 pub struct Sender {
     interval: Interval,
-    queue: Arc<Mutex<VecDeque<PathBuf>>>,
+    queue: Arc<Mutex<Queue>>,
     files: Arc<SegQueue<PathBuf>>,
     conf: Arc<conf::Sink>,
     params: Arc<conf::Parameters>,
     client: Arc<HttpsClient>,
+    client_created_at: Instant,
+    /// Connection to the relay of a sink using the tcp transport
+    relay: Option<Relay>,
     state: State,
-    backoff: ExponentialBackoff,
+    backoff: JitteredBackoff,
+    breaker: Arc<Mutex<Breaker>>,
+    ramp: Arc<Mutex<Ramp>>,
+    delivered: Arc<Mutex<Delivered>>,
+}
+
+/// `Delivered` holds the groups of the last grouped batch which were posted, so a batch sent
+/// again only posts the groups which failed
+#[derive(Debug, Default)]
+struct Delivered {
+    /// Hash of the batch
+    batch: u64,
+    /// Url and token of the posted groups
+    groups: HashSet<String>,
 }
 
 impl
     From<(
-        Arc<Mutex<VecDeque<PathBuf>>>,
+        Arc<Mutex<Queue>>,
+        Arc<Mutex<Breaker>>,
+        Arc<Mutex<Ramp>>,
         Arc<conf::Sink>,
         Arc<conf::Parameters>,
     )> for Sender
 {
     fn from(
         tuple: (
-            Arc<Mutex<VecDeque<PathBuf>>>,
+            Arc<Mutex<Queue>>,
+            Arc<Mutex<Breaker>>,
+            Arc<Mutex<Ramp>>,
             Arc<conf::Sink>,
             Arc<conf::Parameters>,
         ),
     ) -> Self {
-        let (queue, conf, params) = tuple;
-        let client = Client::builder()
-            .keep_alive(conf.keep_alive)
-            .keep_alive_timeout(conf.keep_alive_timeout)
-            .build(HttpsConnector::new(NUMBER_DNS_WORKER_THREADS));
+        let (queue, breaker, ramp, conf, params) = tuple;
+        let client = Self::client(&conf, &params);
+        let relay = Self::relay(&conf, &params);
 
-        let mut backoff = ExponentialBackoff::default();
-
-        backoff.initial_interval = params.backoff.initial;
-        backoff.max_interval = params.backoff.max;
-        backoff.multiplier = params.backoff.multiplier;
-        backoff.randomization_factor = params.backoff.randomization;
-        backoff.max_elapsed_time = None;
+        let backoff = JitteredBackoff::from(&conf.backoff_of(&params));
 
         Self {
             interval: Interval::new(Instant::now(), THREAD_SLEEP),
@@ -99,8 +433,13 @@ impl
             conf,
             params,
             client: Arc::new(client),
+            client_created_at: Instant::now(),
+            relay,
             state: State::Idle,
             backoff,
+            breaker,
+            ramp,
+            delivered: Arc::new(Mutex::new(Delivered::default())),
         }
     }
 }
@@ -128,52 +467,96 @@ impl Stream for Sender {
                     return Ok(Async::Ready(Some(())));
                 }
 
-                let body = Body::from((
-                    self.queue.to_owned(),
-                    self.conf.to_owned(),
-                    self.params.to_owned(),
-                ));
+                let acquired = {
+                    let mut ramp = self.ramp.lock().map_err(|err| format_err!("{}", err))?;
+                    ramp.acquire(Instant::now())
+                };
 
-                self.files = body.get_files();
-                let request = Request::builder()
-                    .method(Method::POST)
-                    .uri(self.conf.url.to_owned())
-                    .header(self.conf.token_header.as_str(), self.conf.token.as_str())
-                    .body(body)
-                    .with_context(|err| format!("could not create the http request, {}", err))?;
+                if !acquired {
+                    // Enough batches are sent at the same time during the ramp-up
+                    self.state = State::Waiting;
+                    return Ok(Async::Ready(Some(())));
+                }
 
-                let name = self.conf.name.to_owned();
-                let sink = self.conf.name.to_owned();
-                let files = self.files.to_owned();
+                let allowed = {
+                    let mut breaker = self.breaker.lock().map_err(|err| format_err!("{}", err))?;
+                    breaker.allow(Instant::now())
+                };
+
+                if !allowed {
+                    self.ramp
+                        .lock()
+                        .map_err(|err| format_err!("{}", err))?
+                        .release();
+
+                    // The circuit is open, keep spooling until the next probe
+                    self.state = State::Open;
+                    return Ok(Async::Ready(Some(())));
+                }
+
+                // Recycle connections that are too old, they could have been dropped silently
+                if Self::is_expired(self.client_created_at.elapsed(), conf.max_connection_age) {
+                    debug!("recycle connections"; "sink" => conf.name.as_str());
+                    self.client = Arc::new(Self::client(&conf, &self.params));
+                    self.relay = Self::relay(&conf, &self.params);
+                    self.client_created_at = Instant::now();
+                }
 
-                let request = self
-                    .client
-                    .to_owned()
-                    .request(request)
-                    .timeout(self.params.timeout.to_owned())
-                    .map_err(|err| format_err!("{}", err))
-                    .and_then(move |res| {
-                        let status = res.status();
-
-                        BEAMIUM_PUSH_HTTP_STATUS
-                            .with_label_values(&[sink.as_str(), status.as_str()])
-                            .inc();
-                        if status.is_success() {
-                            info!("post success"; "sink" => sink.as_str());
-                            return ok(());
+                let request: Box<dyn Future<Item = (), Error = Error> + Send> =
+                    match (&conf.tenant_label, &conf.token_by_label, &conf.format) {
+                        _ if self.relay.is_some() => {
+                            self.files = Arc::new(SegQueue::new());
+                            self.send_batch()
                         }
+                        (None, None, conf::SinkFormat::Warp10) if !conf.skip_empty_batches => {
+                            let body = Body::from((
+                                self.queue.to_owned(),
+                                self.conf.to_owned(),
+                                self.params.to_owned(),
+                            ));
 
-                        err(format_err!("http request failed, got {}", status.as_u16()))
-                    })
-                    .and_then(move |_| {
-                        let mut bulk = vec![];
-                        while let Ok(file) = files.pop() {
-                            trace!("remove file"; "sink" => name.as_str(), "path" => file.to_str());
-                            bulk.push(Sender::remove(file));
+                            self.files = body.get_files();
+                            match self.post(self.conf.url.to_owned(), &self.conf.token, body) {
+                                Ok(request) => Box::new(request),
+                                Err(err) => {
+                                    // Give the permit back, else the ramp-up would stay lowered
+                                    self.ramp
+                                        .lock()
+                                        .map_err(|err| format_err!("{}", err))?
+                                        .release();
+
+                                    return Err(err);
+                                }
+                            }
+                        }
+                        // The batch is read beforehand to be converted or checked for datapoints
+                        (None, None, _) => {
+                            self.files = Arc::new(SegQueue::new());
+                            self.post_batch()
+                        }
+                        (None, Some(tokens), _) => {
+                            self.files = Arc::new(SegQueue::new());
+                            self.post_by_token(tokens)
                         }
+                        (Some(label), _, _) => {
+                            self.files = Arc::new(SegQueue::new());
+                            self.post_by_tenant(label)
+                        }
+                    };
+
+                let name = self.conf.name.to_owned();
+                let files = self.files.to_owned();
+                let concurrency = self.params.filesystem_threads;
 
-                        join_all(bulk).and_then(|_| ok(()))
-                    });
+                let request = request.and_then(move |_| {
+                    let mut bulk = vec![];
+                    while let Ok(file) = files.pop() {
+                        trace!("remove file"; "sink" => name.as_str(), "path" => file.to_str());
+                        bulk.push(file);
+                    }
+
+                    Sender::remove_all(bulk, name, concurrency)
+                });
 
                 self.state = State::Sending(Box::new(request));
                 Ok(Async::Ready(Some(())))
@@ -181,18 +564,33 @@ impl Stream for Sender {
             State::Sending(req) => match req.poll() {
                 Err(err) => {
                     error!("post failed"; "sink" => conf.name.as_str(), "error" => err.to_string());
+                    errors::record(Component::Sink, &conf.name, &err.to_string());
                     {
+                        let mut batch = vec![];
                         let mut queue = self.queue.lock().map_err(|err| format_err!("{}", err))?;
                         while let Ok(file) = self.files.pop() {
                             debug!("push back file in queue"; "sink" => conf.name.as_str(), "path" => file.to_str());
+                            batch.push(file.to_owned());
                             queue.push_front(file);
                         }
+
+                        debug!("post failed for batch"; "sink" => conf.name.as_str(), "count" => batch.len(), "files" => Sender::describe(&batch, MAX_LOGGED_FILES));
                     }
 
                     BEAMIUM_PUSH_ERRORS
                         .with_label_values(&[conf.name.as_str()])
                         .inc();
 
+                    self.breaker
+                        .lock()
+                        .map_err(|err| format_err!("{}", err))?
+                        .failure(Instant::now());
+
+                    self.ramp
+                        .lock()
+                        .map_err(|err| format_err!("{}", err))?
+                        .failure(Instant::now());
+
                     let delay = self
                         .backoff
                         .next_backoff()
@@ -209,6 +607,14 @@ impl Stream for Sender {
                 }
                 Ok(poll) => {
                     if let Async::Ready(_) = poll {
+                        self.breaker
+                            .lock()
+                            .map_err(|err| format_err!("{}", err))?
+                            .success();
+                        self.ramp
+                            .lock()
+                            .map_err(|err| format_err!("{}", err))?
+                            .release();
                         self.backoff.reset();
                         self.state = State::Idle;
                         return Ok(Async::Ready(Some(())));
@@ -218,7 +624,7 @@ impl Stream for Sender {
                     Ok(Async::NotReady)
                 }
             },
-            State::Waiting => {
+            State::Waiting | State::Open => {
                 try_ready!(self.interval.poll().map_err(|err| format_err!("{}", err)));
 
                 self.state = State::Idle;
@@ -235,133 +641,1943 @@ impl Stream for Sender {
 }
 
 impl Sender {
-    fn remove(path: PathBuf) -> impl Future<Item = (), Error = Error> {
-        remove_file(path)
+    /// Post the body to the given url
+    fn post(
+        &self,
+        uri: Uri,
+        token: &str,
+        body: Body,
+    ) -> Result<impl Future<Item = (), Error = Error>, Error> {
+        let request = self.request(uri, token, body)?;
+        let conf = self.conf.to_owned();
+
+        Ok(self
+            .client
+            .to_owned()
+            .request(request)
+            .timeout(self.conf.timeout_of(&self.params))
             .map_err(|err| format_err!("{}", err))
-            .and_then(|_| ok(()))
+            .and_then(move |res| {
+                let status = res.status();
+
+                BEAMIUM_PUSH_HTTP_STATUS
+                    .with_label_values(&[conf.name.as_str(), status.as_str()])
+                    .inc();
+                if status.is_success() {
+                    info!("post success"; "sink" => conf.name.as_str());
+                    return ok(());
+                }
+
+                if Self::is_droppable(status.as_u16(), &conf) {
+                    warn!("drop batch"; "sink" => conf.name.as_str(), "status" => status.as_u16());
+                    return ok(());
+                }
+
+                err(format_err!("http request failed, got {}", status.as_u16()))
+            }))
     }
-}
 
-pub struct Body {
-    queue: Arc<Mutex<VecDeque<PathBuf>>>,
-    conf: Arc<conf::Sink>,
-    params: Arc<conf::Parameters>,
-    files: Arc<SegQueue<PathBuf>>,
-    current_batch_size: u64,
-    current_batch_count: u64,
-    reader: Option<BufReader<File>>,
-}
+    /// Create the request posting the body with the given token, no token header is sent if it
+    /// is empty
+    fn request(&self, uri: Uri, token: &str, body: Body) -> Result<Request<Body>, Error> {
+        let mut request = Request::builder();
+        request.method(Method::POST).uri(uri);
 
-impl
-    From<(
-        Arc<Mutex<VecDeque<PathBuf>>>,
-        Arc<conf::Sink>,
-        Arc<conf::Parameters>,
-    )> for Body
-{
-    fn from(
-        tuple: (
-            Arc<Mutex<VecDeque<PathBuf>>>,
-            Arc<conf::Sink>,
-            Arc<conf::Parameters>,
-        ),
-    ) -> Self {
-        let (queue, conf, params) = tuple;
+        if !token.is_empty() {
+            request.header(self.conf.token_header.as_str(), token);
+        }
 
-        Self {
-            queue,
-            conf,
-            params,
-            files: Arc::new(SegQueue::new()),
-            current_batch_size: 0,
-            current_batch_count: 0,
-            reader: None,
+        if self.conf.format == conf::SinkFormat::OpenTsdb {
+            request.header(CONTENT_TYPE, "application/json");
         }
+
+        Ok(request
+            .body(body)
+            .with_context(|err| format!("could not create the http request, {}", err))?)
     }
-}
 
-impl Payload for Body {
-    type Data = Chunk;
-    type Error = Error;
+    /// Check if the batch should be dropped instead of being sent again on the failed status
+    fn is_droppable(status: u16, conf: &conf::Sink) -> bool {
+        if conf.droppable_statuses.contains(&status) {
+            return true;
+        }
 
-    fn poll_data(&mut self) -> Poll<Option<Self::Data>, Self::Error> {
-        match &mut self.reader {
-            None => {
-                if self.current_batch_count >= self.params.batch_count
-                    || self.current_batch_size >= self.params.batch_size
-                {
-                    // We reach the maximum of the batch
-                    return Ok(Async::Ready(None));
+        match &conf.retryable_statuses {
+            None => false,
+            Some(statuses) => !statuses.contains(&status),
+        }
+    }
+
+    /// Post the time series of the next batch converted into the format of the sink
+    fn post_batch(&self) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        // On failure, the files of the batch are pushed back in the queue
+        match Self::batch(&self.queue, &self.files, &self.conf, &self.params) {
+            Ok(batch) => {
+                let (data, continuations) = Self::concat(&batch);
+                self.post_data(
+                    self.conf.url.to_owned(),
+                    &self.conf.token,
+                    data,
+                    continuations,
+                )
+            }
+            Err(e) => Box::new(err(e)),
+        }
+    }
+
+    /// Post the time series converted into the format of the sink, nothing is posted if none
+    /// of them could be converted
+    fn post_data(
+        &self,
+        uri: Uri,
+        token: &str,
+        data: String,
+        continuations: bool,
+    ) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        let (data, datapoints) = match self.conf.format {
+            conf::SinkFormat::Warp10 => {
+                if self.conf.skip_empty_batches && !Self::has_datapoints(&data, continuations) {
+                    debug!("skip empty batch"; "sink" => self.conf.name.as_str());
+                    return Box::new(ok(()));
                 }
 
-                let path = {
-                    match self.queue.try_lock() {
-                        Ok(mut queue) => queue.pop_front(),
-                        Err(_) => {
-                            task::current().notify();
-                            return Ok(Async::NotReady);
-                        }
-                    }
-                };
+                let datapoints = Self::datapoints(&data);
+                (data, datapoints)
+            }
+            conf::SinkFormat::OpenTsdb => {
+                let start = now_utc();
+                let now = start.to_timespec().sec * 1_000_000
+                    + (i64::from(start.to_timespec().nsec) / 1000);
 
-                let path = match path {
-                    Some(path) => path,
-                    None => {
-                        // We don't have more files to send
-                        return Ok(Async::Ready(None));
-                    }
-                };
+                let unit = self.params.platform_time_unit;
+                let (points, dropped) = opentsdb::convert(&data, unit.scale(now), unit);
+                if dropped > 0 {
+                    warn!("drop time series not supported by opentsdb"; "sink" => self.conf.name.as_str(), "count" => dropped);
+                }
 
-                trace!("open file"; "sink" => self.conf.name.as_str(), "path" => path.to_str());
-                self.reader = Some(BufReader::new(File::open(path.to_owned())?));
-                self.files.push(path);
-                self.current_batch_count += 1;
+                if points.is_empty() {
+                    return Box::new(ok(()));
+                }
 
-                task::current().notify();
-                Ok(Async::NotReady)
+                match to_string(&points) {
+                    Ok(data) => (data, points.len()),
+                    Err(e) => {
+                        return Box::new(err(format_err!("could not serialize data points, {}", e)))
+                    }
+                }
             }
-            Some(reader) => {
-                let mut acc = String::new();
-                let mut len = 0;
+        };
 
-                while len < CHUNK_SIZE {
-                    let mut line = String::new();
-                    let mut line_len = reader.read_line(&mut line)? as u64;
+        // Only the lines which are sent are counted, once converted or grouped
+        BEAMIUM_PUSH_DP
+            .with_label_values(&[self.conf.name.as_str()])
+            .inc_by(datapoints as f64);
 
-                    if line_len == 0 {
-                        // We have read all the file
-                        trace!("we have read all the file");
-                        self.reader = None;
-                        break;
-                    }
+        let body = Body::from((data, self.conf.to_owned(), self.params.to_owned()));
+        match self.post(uri, token, body) {
+            Ok(post) => Box::new(post),
+            Err(e) => Box::new(err(e)),
+        }
+    }
 
-                    if &line == "\n" {
-                        continue;
-                    }
+    /// Stream the time series of the next batch to the relay
+    fn send_batch(&self) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        let relay = match &self.relay {
+            Some(relay) => relay,
+            None => return Box::new(err(format_err!("the sink has no relay"))),
+        };
+
+        // On failure, the files of the batch are pushed back in the queue
+        let (data, continuations) =
+            match Self::batch(&self.queue, &self.files, &self.conf, &self.params) {
+                Ok(batch) => Self::concat(&batch),
+                Err(e) => return Box::new(err(e)),
+            };
+
+        if self.conf.skip_empty_batches && !Self::has_datapoints(&data, continuations) {
+            debug!("skip empty batch"; "sink" => self.conf.name.as_str());
+            return Box::new(ok(()));
+        }
+
+        BEAMIUM_PUSH_DP
+            .with_label_values(&[self.conf.name.as_str()])
+            .inc_by(Self::datapoints(&data) as f64);
+
+        let name = self.conf.name.to_owned();
+        Box::new(
+            relay
+                .send(data)
+                .timeout(self.conf.timeout_of(&self.params))
+                .map_err(|err| format_err!("{}", err))
+                .map(move |_| info!("send success"; "sink" => name.as_str())),
+        )
+    }
+
+    /// Number of lines of the data, as counted while reading the files of the batch
+    fn datapoints(data: &str) -> usize {
+        data.lines().filter(|line| !line.is_empty()).count()
+    }
+
+    /// Check if the data holds a time series, comments and continuation lines alone are not sent
+    fn has_datapoints(data: &str, continuations: bool) -> bool {
+        data.lines().any(|line| {
+            let line = line.trim();
+            !line.is_empty() && !line.starts_with('#') && (!continuations || !line.starts_with('='))
+        })
+    }
+
+    /// Post the time series of the next batch grouped by tenant, each group is sent to the url
+    /// expanded with its tenant. Time series without tenant are sent to the fallback tenant, or
+    /// their batch fails so its files are kept.
+    fn post_by_tenant(&self, label: &str) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        // On failure, the files of the batch are pushed back in the queue
+        let batch = match Self::batch(&self.queue, &self.files, &self.conf, &self.params) {
+            Ok(batch) => batch,
+            Err(e) => return Box::new(err(e)),
+        };
+
+        let fallback = self.conf.tenant_fallback.as_deref();
+        let (groups, dropped) = Self::group(&batch, label, fallback);
+        if dropped > 0 {
+            return Box::new(err(format_err!(
+                "could not post {} time series without the '{}' label, files are kept",
+                dropped,
+                label
+            )));
+        }
 
-                    if !line.ends_with('\n') {
-                        line += "\n";
-                        line_len += "\n".len() as u64;
+        let mut posts = vec![];
+        for (tenant, group) in groups {
+            let uri = match (self.conf.url_of(&tenant), fallback) {
+                (Ok(uri), _) => uri,
+                (Err(e), Some(fallback)) => {
+                    warn!("post time series of tenant to the fallback one"; "sink" => self.conf.name.as_str(), "tenant" => tenant.as_str(), "error" => e.to_string());
+                    match self.conf.url_of(fallback) {
+                        Ok(uri) => uri,
+                        Err(e) => return Box::new(err(e)),
                     }
+                }
+                (Err(e), None) => return Box::new(err(e)),
+            };
 
-                    acc += &line;
-                    len += line_len;
+            posts.push((uri, self.conf.token.to_owned(), group));
+        }
 
-                    self.current_batch_size += line_len;
+        let (data, continuations) = Self::concat(&batch);
+        self.post_groups(&data, continuations, posts)
+    }
 
-                    BEAMIUM_PUSH_DP
-                        .with_label_values(&[self.conf.name.as_str()])
-                        .inc();
+    /// Post the time series of the next batch grouped by token, each group is sent with its
+    /// token.
+    fn post_by_token(
+        &self,
+        tokens: &conf::TokenByLabel,
+    ) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        // On failure, the files of the batch are pushed back in the queue
+        let batch = match Self::batch(&self.queue, &self.files, &self.conf, &self.params) {
+            Ok(batch) => batch,
+            Err(e) => return Box::new(err(e)),
+        };
+
+        let posts = Self::group_by_token(&batch, tokens, &self.conf.token)
+            .into_iter()
+            .map(|(token, group)| (self.conf.url.to_owned(), token, group))
+            .collect();
+
+        let (data, continuations) = Self::concat(&batch);
+        self.post_groups(&data, continuations, posts)
+    }
+
+    /// Post the groups of the batch with their url and token. The groups posted when the same
+    /// batch was sent before are skipped, so only the failed ones are sent again.
+    fn post_groups(
+        &self,
+        batch: &str,
+        continuations: bool,
+        groups: Vec<(Uri, String, String)>,
+    ) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        let mut hasher = DefaultHasher::new();
+        batch.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let posted = match self.delivered.lock() {
+            Ok(mut delivered) => {
+                if delivered.batch != hash {
+                    *delivered = Delivered {
+                        batch: hash,
+                        groups: HashSet::new(),
+                    };
                 }
 
-                Ok(Async::Ready(Some(Chunk::from(acc))))
+                delivered.groups.to_owned()
+            }
+            Err(e) => return Box::new(err(format_err!("{}", e))),
+        };
+
+        let mut posts = vec![];
+        for (uri, token, data) in groups {
+            let key = format!("{} {}", uri, token);
+            if posted.contains(&key) {
+                debug!("skip group already posted"; "sink" => self.conf.name.as_str(), "uri" => uri.to_string());
+                continue;
+            }
+
+            let delivered = self.delivered.to_owned();
+            posts.push(
+                self.post_data(uri, &token, data, continuations)
+                    .and_then(move |_| {
+                        delivered
+                            .lock()
+                            .map_err(|e| format_err!("{}", e))?
+                            .groups
+                            .insert(key);
+                        Ok(())
+                    }),
+            );
+        }
+
+        Box::new(join_all(posts).and_then(|_| ok(())))
+    }
+
+    /// Read the files of the next batch, read files are kept in `files`. Return the content of
+    /// each file and whether its lines starting with '=' are continuation lines.
+    fn batch(
+        queue: &Mutex<Queue>,
+        files: &SegQueue<PathBuf>,
+        conf: &conf::Sink,
+        params: &conf::Parameters,
+    ) -> Result<Vec<(String, bool)>, Error> {
+        let mut batch = vec![];
+        let mut size = 0;
+        let mut count = 0;
+        while count < params.batch_count && size < params.batch_size {
+            let path = match queue
+                .lock()
+                .map_err(|err| format_err!("{}", err))?
+                .pop_front()
+            {
+                Some(path) => path,
+                None => break,
+            };
+
+            files.push(path.to_owned());
+            count += 1;
+
+            trace!("open file"; "sink" => conf.name.as_str(), "path" => path.to_str());
+            let mut reader = BufReader::new(File::open(&path)?);
+            let mut data = String::new();
+            loop {
+                let read = Body::read(&mut reader, conf.skip_partial_lines)?;
+                data += &read.data;
+                if read.eof {
+                    break;
+                }
             }
+
+            size += data.len() as u64;
+            batch.push((data, has_continuations(&path)));
         }
+
+        Ok(batch)
     }
-}
 
-impl Body {
-    pub fn get_files(&self) -> Arc<SegQueue<PathBuf>> {
-        self.files.to_owned()
+    /// Content of the files of the batch and whether all their lines starting with '=' are
+    /// continuation lines
+    fn concat(batch: &[(String, bool)]) -> (String, bool) {
+        (
+            batch.iter().map(|(data, _)| data.as_str()).collect(),
+            batch.iter().all(|(_, continuations)| *continuations),
+        )
+    }
+
+    /// Group the time series of the batch by the value of the tenant label, continuation lines
+    /// follow their time series. Lines without tenant follow the fallback one if any, otherwise
+    /// they are counted as dropped. Return the groups and the number of dropped lines.
+    fn group(
+        batch: &[(String, bool)],
+        label: &str,
+        fallback: Option<&str>,
+    ) -> (BTreeMap<String, String>, u64) {
+        let mut groups: BTreeMap<String, String> = BTreeMap::new();
+        let mut dropped = 0;
+        let mut tenant = None;
+        let lines = batch
+            .iter()
+            .flat_map(|(data, continuations)| data.lines().map(move |line| (line, *continuations)));
+
+        for (line, continuations) in lines {
+            if line.is_empty() {
+                continue;
+            }
+
+            if !continuations || !line.starts_with('=') {
+                tenant = Self::label_value(line, label).or(fallback);
+            }
+
+            match tenant {
+                None => dropped += 1,
+                Some(tenant) => {
+                    let group = groups.entry(tenant.to_owned()).or_default();
+                    group.push_str(line);
+                    group.push('\n');
+                }
+            }
+        }
+
+        (groups, dropped)
+    }
+
+    /// Group the time series of the batch by the token selected by the value of their label,
+    /// continuation lines follow their time series. Time series without the label or with a
+    /// value without token use the default token.
+    fn group_by_token(
+        batch: &[(String, bool)],
+        tokens: &conf::TokenByLabel,
+        default: &str,
+    ) -> BTreeMap<String, String> {
+        let mut groups: BTreeMap<String, String> = BTreeMap::new();
+        let mut token = default;
+        let lines = batch
+            .iter()
+            .flat_map(|(data, continuations)| data.lines().map(move |line| (line, *continuations)));
+
+        for (line, continuations) in lines {
+            if line.is_empty() {
+                continue;
+            }
+
+            if !continuations || !line.starts_with('=') {
+                token = Self::label_value(line, &tokens.label)
+                    .and_then(|value| tokens.tokens.get(value))
+                    .map_or(default, String::as_str);
+            }
+
+            let group = groups.entry(token.to_owned()).or_default();
+            group.push_str(line);
+            group.push('\n');
+        }
+
+        groups
+    }
+
+    /// Value of the label of the time series, `None` if it is missing or empty
+    fn label_value<'a>(line: &'a str, label: &str) -> Option<&'a str> {
+        let gts = line.split_whitespace().nth(1)?;
+        let (_, labels) = gts.split_once('{')?;
+        let labels = labels.split('}').next()?;
+
+        labels.split(',').find_map(|pair| {
+            let mut pair = pair.splitn(2, '=');
+            if pair.next()? == label {
+                return pair.next().filter(|value| !value.is_empty());
+            }
+
+            None
+        })
+    }
+
+    fn client(conf: &conf::Sink, params: &conf::Parameters) -> HttpsClient {
+        Client::builder()
+            .keep_alive(conf.keep_alive)
+            .keep_alive_timeout(conf.keep_alive_timeout)
+            .build(connector(
+                params.tls_session_resumption,
+                conf.bind_address.or(params.bind_address),
+                params.min_tls_version,
+            ))
+    }
+
+    /// Relay of the sink, `None` unless the sink uses the tcp transport
+    fn relay(conf: &conf::Sink, params: &conf::Parameters) -> Option<Relay> {
+        match conf.transport {
+            conf::Transport::Http => None,
+            conf::Transport::Tcp => Some(Relay::from((conf, params))),
+        }
+    }
+
+    /// Check if the client has to be recreated regarding its age
+    fn is_expired(age: Duration, max_age: Option<Duration>) -> bool {
+        match max_age {
+            None => false,
+            Some(max_age) => age >= max_age,
+        }
+    }
+
+    /// Describe the files of a batch, display at most `max` file names
+    fn describe(files: &[PathBuf], max: usize) -> String {
+        let mut names: Vec<String> = files
+            .iter()
+            .take(max)
+            .map(|file| file.display().to_string())
+            .collect();
+
+        if files.len() > max {
+            names.push(format!("... ({} more)", files.len() - max));
+        }
+
+        names.join(", ")
+    }
+
+    /// Remove the files which have been sent, at most `concurrency` at the same time
+    fn remove_all(
+        files: Vec<PathBuf>,
+        sink: String,
+        concurrency: usize,
+    ) -> impl Future<Item = (), Error = Error> {
+        stream::iter_ok(files)
+            .map(move |file| Sender::remove(file, sink.to_owned()))
+            .buffer_unordered(concurrency)
+            .for_each(|_| ok(()))
+    }
+
+    /// Remove a file which has been sent and account it in the sink's metrics
+    fn remove(path: PathBuf, sink: String) -> impl Future<Item = (), Error = Error> {
+        metadata(path.to_owned())
+            .map(|meta| meta.len())
+            .and_then(move |len| remove_file(path).map(move |_| len))
+            .map_err(|err| format_err!("{}", err))
+            .and_then(move |len| {
+                BEAMIUM_PUSH_FILES.with_label_values(&[sink.as_str()]).inc();
+                BEAMIUM_PUSH_BYTES
+                    .with_label_values(&[sink.as_str()])
+                    .inc_by(len as f64);
+
+                ok(())
+            })
+    }
+}
+
+pub struct Body {
+    queue: Arc<Mutex<Queue>>,
+    conf: Arc<conf::Sink>,
+    params: Arc<conf::Parameters>,
+    files: Arc<SegQueue<PathBuf>>,
+    current_batch_size: u64,
+    current_batch_count: u64,
+    reader: Option<BufReader<File>>,
+    prefetch: Option<Prefetch>,
+    data: Option<String>,
+}
+
+/// `Read` is a chunk read from a file
+struct Read {
+    data: String,
+    len: u64,
+    datapoints: u64,
+    partial: u64,
+    eof: bool,
+}
+
+/// `Prefetch` is the next file of the batch which is opened and read in background
+struct Prefetch {
+    path: PathBuf,
+    rx: oneshot::Receiver<Result<(BufReader<File>, Read), Error>>,
+}
+
+impl From<(Arc<Mutex<Queue>>, Arc<conf::Sink>, Arc<conf::Parameters>)> for Body {
+    fn from(tuple: (Arc<Mutex<Queue>>, Arc<conf::Sink>, Arc<conf::Parameters>)) -> Self {
+        let (queue, conf, params) = tuple;
+
+        Self {
+            queue,
+            conf,
+            params,
+            files: Arc::new(SegQueue::new()),
+            current_batch_size: 0,
+            current_batch_count: 0,
+            reader: None,
+            prefetch: None,
+            data: None,
+        }
+    }
+}
+
+impl From<(String, Arc<conf::Sink>, Arc<conf::Parameters>)> for Body {
+    /// Body sending the given data instead of the files of the queue
+    fn from(tuple: (String, Arc<conf::Sink>, Arc<conf::Parameters>)) -> Self {
+        let (data, conf, params) = tuple;
+        let mut body = Self::from((Arc::new(Mutex::new(Queue::default())), conf, params));
+
+        body.data = Some(data);
+        body
+    }
+}
+
+impl Payload for Body {
+    type Data = Chunk;
+    type Error = Error;
+
+    fn poll_data(&mut self) -> Poll<Option<Self::Data>, Self::Error> {
+        if let Some(data) = self.data.take() {
+            return Ok(Async::Ready(Some(Chunk::from(data))));
+        }
+
+        match &mut self.reader {
+            None => {
+                if self.current_batch_count >= self.params.batch_count
+                    || self.current_batch_size >= self.params.batch_size
+                {
+                    // We reach the maximum of the batch
+                    self.requeue_prefetch();
+                    return Ok(Async::Ready(None));
+                }
+
+                let (path, reader, read) = match self.prefetch.take() {
+                    Some(mut prefetch) => match prefetch.rx.poll() {
+                        Ok(Async::NotReady) => {
+                            self.prefetch = Some(prefetch);
+                            return Ok(Async::NotReady);
+                        }
+                        Ok(Async::Ready(Ok((reader, read)))) => (prefetch.path, reader, Some(read)),
+                        Ok(Async::Ready(Err(err))) => {
+                            // Keep the file in the batch, so it will be pushed back in the queue
+                            self.files.push(prefetch.path);
+                            return Err(format_err!("could not prefetch file, {}", err));
+                        }
+                        Err(err) => {
+                            self.files.push(prefetch.path);
+                            return Err(format_err!("could not prefetch file, {}", err));
+                        }
+                    },
+                    None => {
+                        let path = {
+                            match self.queue.try_lock() {
+                                Ok(mut queue) => queue.pop_front(),
+                                Err(_) => {
+                                    task::current().notify();
+                                    return Ok(Async::NotReady);
+                                }
+                            }
+                        };
+
+                        let path = match path {
+                            Some(path) => path,
+                            None => {
+                                // We don't have more files to send
+                                return Ok(Async::Ready(None));
+                            }
+                        };
+
+                        trace!("open file"; "sink" => self.conf.name.as_str(), "path" => path.to_str());
+                        let reader = BufReader::new(File::open(&path)?);
+
+                        (path, reader, None)
+                    }
+                };
+
+                self.reader = Some(reader);
+                self.files.push(path);
+                self.current_batch_count += 1;
+
+                if self.conf.prefetch {
+                    self.start_prefetch();
+                }
+
+                if let Some(read) = read {
+                    return Ok(Async::Ready(Some(self.consume(read))));
+                }
+
+                task::current().notify();
+                Ok(Async::NotReady)
+            }
+            Some(reader) => {
+                let read = Self::read(reader, self.conf.skip_partial_lines)?;
+
+                Ok(Async::Ready(Some(self.consume(read))))
+            }
+        }
+    }
+}
+
+impl Drop for Body {
+    fn drop(&mut self) {
+        self.requeue_prefetch();
+    }
+}
+
+impl Body {
+    pub fn get_files(&self) -> Arc<SegQueue<PathBuf>> {
+        self.files.to_owned()
+    }
+
+    /// Read a chunk of the file, a line without terminating newline could only be the last one
+    /// of the file, it is skipped if `skip_partial` is set
+    fn read(reader: &mut BufReader<File>, skip_partial: bool) -> io::Result<Read> {
+        let mut read = Read {
+            data: String::new(),
+            len: 0,
+            datapoints: 0,
+            partial: 0,
+            eof: false,
+        };
+
+        while read.len < CHUNK_SIZE {
+            let mut line = String::new();
+            let mut line_len = reader.read_line(&mut line)? as u64;
+
+            if line_len == 0 {
+                // We have read all the file
+                trace!("we have read all the file");
+                read.eof = true;
+                break;
+            }
+
+            if &line == "\n" {
+                continue;
+            }
+
+            if !line.ends_with('\n') {
+                if skip_partial {
+                    read.partial += 1;
+                    continue;
+                }
+
+                line += "\n";
+                line_len += "\n".len() as u64;
+            }
+
+            read.data += &line;
+            read.len += line_len;
+            read.datapoints += 1;
+        }
+
+        Ok(read)
+    }
+
+    /// Account the chunk in the batch and release the reader once the file is read
+    fn consume(&mut self, read: Read) -> Chunk {
+        if read.eof {
+            self.reader = None;
+        }
+
+        self.current_batch_size += read.len;
+
+        if read.partial > 0 {
+            warn!("skip partial line at the end of file"; "sink" => self.conf.name.as_str());
+            BEAMIUM_SKIP_PARTIAL_LINES
+                .with_label_values(&[self.conf.name.as_str()])
+                .inc_by(read.partial as f64);
+        }
+
+        BEAMIUM_PUSH_DP
+            .with_label_values(&[self.conf.name.as_str()])
+            .inc_by(read.datapoints as f64);
+
+        Chunk::from(read.data)
+    }
+
+    /// Open and read the first chunk of the next file in background
+    fn start_prefetch(&mut self) {
+        if self.prefetch.is_some()
+            || self.current_batch_count >= self.params.batch_count
+            || self.current_batch_size >= self.params.batch_size
+        {
+            return;
+        }
+
+        let path = match self.queue.try_lock() {
+            Ok(mut queue) => queue.pop_front(),
+            Err(_) => None,
+        };
+
+        let path = match path {
+            Some(path) => path,
+            None => return,
+        };
+
+        trace!("prefetch file"; "sink" => self.conf.name.as_str(), "path" => path.to_str());
+        let (tx, rx) = oneshot::channel();
+        let mut file = Some(path.to_owned());
+        let skip_partial = self.conf.skip_partial_lines;
+
+        // The file is read on the blocking threads of the runtime, bounded by 'filesystem-threads'
+        let prefetch = future::poll_fn(move || {
+            blocking(|| {
+                let file = match file.take() {
+                    Some(file) => file,
+                    None => return Err(format_err!("file is already prefetched")),
+                };
+
+                let mut reader = BufReader::new(File::open(file)?);
+                let read = Self::read(&mut reader, skip_partial)?;
+
+                Ok((reader, read))
+            })
+        })
+        .then(move |result| {
+            let result = match result {
+                Ok(result) => result,
+                Err(err) => Err(format_err!("{}", err)),
+            };
+
+            // The receiver is dropped if the batch is over
+            let _ = tx.send(result);
+            Ok(())
+        });
+
+        match DefaultExecutor::current().execute(prefetch) {
+            Ok(_) => self.prefetch = Some(Prefetch { path, rx }),
+            Err(err) => {
+                warn!("could not prefetch file"; "sink" => self.conf.name.as_str(), "path" => path.to_str(), "error" => format!("{:?}", err.kind()));
+                if let Ok(mut queue) = self.queue.lock() {
+                    queue.push_front(path);
+                }
+            }
+        }
+    }
+
+    /// Push back the prefetched file in the queue as it is not part of the batch
+    fn requeue_prefetch(&mut self) {
+        if let Some(prefetch) = self.prefetch.take() {
+            trace!("push back prefetched file in queue"; "sink" => self.conf.name.as_str(), "path" => prefetch.path.to_str());
+            match self.queue.lock() {
+                Ok(mut queue) => queue.push_front(prefetch.path),
+                Err(err) => {
+                    error!("could not push back prefetched file in queue"; "sink" => self.conf.name.as_str(), "error" => err.to_string());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::convert::TryFrom;
+    use std::env::temp_dir;
+    use std::fmt;
+    use std::fs::{create_dir_all, read_dir, remove_dir_all, File};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::path::{Path, PathBuf};
+    use std::sync::mpsc::{channel, Receiver};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use failure::Error;
+    use futures::future::{self, join_all};
+    use futures::{stream, try_ready, Async, Future, Poll, Stream};
+    use hyper::body::Payload;
+    use rustls::ProtocolVersion;
+    use slog::{Drain, Key, Level, Logger, Never, OwnedKVList, Record, Serializer, KV};
+    use tokio::runtime::Runtime;
+    use uuid::Uuid;
+
+    use crate::conf::{
+        self, tests::parameters, BackoffStrategy, RawSink, RawTokenByLabel, TlsVersion,
+    };
+    use crate::constants::MAX_LOGGED_FILES;
+    use crate::lib::queue::Queue;
+
+    use backoff::backoff::Backoff;
+    use crossbeam::queue::SegQueue;
+
+    use super::{
+        tls_config, Body, Breaker, Circuit, JitteredBackoff, Ramp, Sender, BEAMIUM_PUSH_BYTES,
+        BEAMIUM_PUSH_CIRCUIT, BEAMIUM_PUSH_DP, BEAMIUM_PUSH_FILES,
+    };
+
+    fn spool(dir: &Path, count: usize) -> Queue {
+        let mut queue = Queue::default();
+        for idx in 0..count {
+            let path = dir.join(format!("sink-{}-0.metrics", idx));
+            let mut file = File::create(&path).expect("create sink file");
+            for ts in 0..100 {
+                writeln!(file, "{}// f{{file={}}} {}", ts, idx, ts).expect("write sink file");
+            }
+
+            queue.push_front(path);
+        }
+
+        queue
+    }
+
+    fn breaker(name: &str, threshold: Option<u64>) -> Breaker {
+        let sink = RawSink {
+            url: String::from("http://127.0.0.1"),
+            token: String::from("token"),
+            circuit_breaker_threshold: threshold,
+            circuit_breaker_probe: Some(String::from("30s")),
+            ..Default::default()
+        };
+
+        let sink = conf::Sink::try_from((String::from(name), sink)).expect("valid sink");
+        Breaker::from(&sink)
+    }
+
+    fn drain(
+        dir: &Path,
+        count: usize,
+        prefetch: bool,
+        settings: &[(&str, &str)],
+    ) -> (Vec<u8>, usize, Arc<Mutex<Queue>>) {
+        let sink = RawSink {
+            url: String::from("http://127.0.0.1"),
+            token: String::from("token"),
+            prefetch: Some(prefetch),
+            ..Default::default()
+        };
+
+        let sink = conf::Sink::try_from((String::from("sink"), sink)).expect("valid sink");
+        let params = parameters(settings).expect("valid parameters");
+        let queue = Arc::new(Mutex::new(spool(dir, count)));
+
+        let mut body = Body::from((queue.to_owned(), Arc::new(sink), Arc::new(params)));
+        let files = body.get_files();
+        let bytes = stream::poll_fn(|| body.poll_data())
+            .concat2()
+            .wait()
+            .expect("read body");
+
+        drop(body);
+        (bytes.to_vec(), files.len(), queue)
+    }
+
+    #[test]
+    fn prefetch_does_not_change_body() {
+        let dir = temp_dir().join(format!("beamium-{}", Uuid::new_v4()));
+        create_dir_all(&dir).expect("create sink directory");
+
+        let (expected, expected_files, _) = drain(&dir, 5, false, &[]);
+        let (bytes, files, queue) = drain(&dir, 5, true, &[]);
+
+        assert_eq!(5, expected_files);
+        assert_eq!(expected_files, files);
+        assert_eq!(String::from_utf8(expected), String::from_utf8(bytes));
+        assert!(queue.lock().expect("queue").is_empty());
+
+        remove_dir_all(&dir).expect("remove sink directory");
+    }
+
+    #[test]
+    fn prefetch_keeps_batch_boundaries() {
+        let dir = temp_dir().join(format!("beamium-{}", Uuid::new_v4()));
+        create_dir_all(&dir).expect("create sink directory");
+
+        let settings = [("batch-count", "2")];
+        let (expected, _, _) = drain(&dir, 5, false, &settings);
+        let (bytes, files, queue) = drain(&dir, 5, true, &settings);
+
+        assert_eq!(2, files);
+        assert_eq!(expected, bytes);
+
+        let mut queue = queue.lock().expect("queue");
+        let mut remaining = 0;
+        while queue.pop_front().is_some() {
+            remaining += 1;
+        }
+
+        assert_eq!(3, remaining);
+
+        remove_dir_all(&dir).expect("remove sink directory");
+    }
+
+    #[test]
+    fn skip_partial_line_at_end_of_file() {
+        let dir = temp_dir().join(format!("beamium-{}", Uuid::new_v4()));
+        create_dir_all(&dir).expect("create sink directory");
+
+        let path = dir.join("sink-0-0.metrics");
+        let mut file = File::create(&path).expect("create sink file");
+        write!(file, "1// f{{}} 1\n2// f{{}} 2\n3// f{{").expect("write sink file");
+
+        let mut reader = BufReader::new(File::open(&path).expect("open sink file"));
+        let read = Body::read(&mut reader, false).expect("read sink file");
+        assert_eq!("1// f{} 1\n2// f{} 2\n3// f{\n", read.data);
+        assert_eq!(3, read.datapoints);
+        assert_eq!(0, read.partial);
+
+        let mut reader = BufReader::new(File::open(&path).expect("open sink file"));
+        let read = Body::read(&mut reader, true).expect("read sink file");
+        assert_eq!("1// f{} 1\n2// f{} 2\n", read.data);
+        assert_eq!(2, read.datapoints);
+        assert_eq!(1, read.partial);
+        assert!(read.eof);
+
+        remove_dir_all(&dir).expect("remove sink directory");
+    }
+
+    fn sample(strategy: &str) -> Vec<f64> {
+        let params = parameters(&[
+            ("backoff.initial", "1s"),
+            ("backoff.max", "1s"),
+            ("backoff.strategy", strategy),
+        ])
+        .expect("valid parameters");
+
+        let mut backoff = JitteredBackoff::from(&params.backoff);
+        (0..1_000)
+            .map(|_| {
+                backoff
+                    .next_backoff()
+                    .expect("no maximum elapsed time")
+                    .as_secs_f64()
+            })
+            .collect()
+    }
+
+    fn mean(delays: &[f64]) -> f64 {
+        delays.iter().sum::<f64>() / delays.len() as f64
+    }
+
+    #[test]
+    fn backoff_strategies() {
+        let delay = Duration::from_secs(1);
+        assert_eq!(
+            delay,
+            JitteredBackoff::jitter(&BackoffStrategy::Exponential, delay, 0.0)
+        );
+        assert_eq!(
+            Duration::from_millis(250),
+            JitteredBackoff::jitter(&BackoffStrategy::FullJitter, delay, 0.25)
+        );
+        assert_eq!(
+            Duration::from_millis(625),
+            JitteredBackoff::jitter(&BackoffStrategy::EqualJitter, delay, 0.25)
+        );
+
+        // The randomization factor spreads delays around the exponential delay
+        let delays = sample("exponential");
+        assert!(delays.iter().all(|delay| *delay >= 0.7 && *delay <= 1.3));
+        assert!((mean(&delays) - 1.0).abs() < 0.1);
+
+        // Full jitter spreads delays between zero and the exponential delay
+        let delays = sample("full-jitter");
+        assert!(delays.iter().all(|delay| *delay <= 1.0));
+        assert!(delays.iter().any(|delay| *delay < 0.1));
+        assert!((mean(&delays) - 0.5).abs() < 0.1);
+
+        // Equal jitter keeps at least the half of the exponential delay
+        let delays = sample("equal-jitter");
+        assert!(delays.iter().all(|delay| *delay >= 0.5 && *delay <= 1.0));
+        assert!((mean(&delays) - 0.75).abs() < 0.1);
+    }
+
+    #[test]
+    fn group_time_series_by_tenant() {
+        let data = "1// f{tenant=a,host=h} 1\n\
+                    =2// 2\n\
+                    1// f{host=h,tenant=b} 3\n\
+                    1// f{host=h} 4\n\
+                    =2// 5\n\
+                    1/48.0:-4.5/10 g{tenant=a}{owner=c} 6\n\
+                    1// g{tenant=} 7\n";
+
+        let batch = [(String::from(data), true)];
+        let (groups, dropped) = Sender::group(&batch, "tenant", None);
+        assert_eq!(2, groups.len());
+        assert_eq!(
+            Some(&String::from(
+                "1// f{tenant=a,host=h} 1\n=2// 2\n1/48.0:-4.5/10 g{tenant=a}{owner=c} 6\n"
+            )),
+            groups.get("a")
+        );
+        assert_eq!(
+            Some(&String::from("1// f{host=h,tenant=b} 3\n")),
+            groups.get("b")
+        );
+        assert_eq!(3, dropped);
+
+        // Time series without tenant follow the fallback one
+        let (groups, dropped) = Sender::group(&batch, "tenant", Some("b"));
+        assert_eq!(
+            Some(&String::from(
+                "1// f{host=h,tenant=b} 3\n1// f{host=h} 4\n=2// 5\n1// g{tenant=} 7\n"
+            )),
+            groups.get("b")
+        );
+        assert_eq!(0, dropped);
+
+        // Lines starting with '=' of sources without continuation lines hold their own tenant
+        let batch = [(
+            String::from("1// f{tenant=a} 1\n=1// f{tenant=b} 2\n"),
+            false,
+        )];
+        let (groups, dropped) = Sender::group(&batch, "tenant", None);
+        assert_eq!(Some(&String::from("=1// f{tenant=b} 2\n")), groups.get("b"));
+        assert_eq!(0, dropped);
+    }
+
+    /// Answer the requests, failing the ones whose path ends with `/fail`, and report their path
+    fn serve(listener: TcpListener) -> Receiver<String> {
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.expect("accept connection");
+                let mut reader = BufReader::new(stream.try_clone().expect("clone stream"));
+
+                let mut line = String::new();
+                reader.read_line(&mut line).expect("read request line");
+                let path = line
+                    .split_whitespace()
+                    .nth(1)
+                    .unwrap_or_default()
+                    .to_owned();
+                while line != "\r\n" {
+                    line.clear();
+                    reader.read_line(&mut line).expect("read header");
+                }
+
+                // The body is chunked
+                loop {
+                    line.clear();
+                    reader.read_line(&mut line).expect("read chunk size");
+                    let size = usize::from_str_radix(line.trim(), 16).expect("chunk size");
+                    let mut chunk = vec![0; size + 2];
+                    reader.read_exact(&mut chunk).expect("read chunk");
+                    if size == 0 {
+                        break;
+                    }
+                }
+
+                let status = if path.ends_with("/fail") {
+                    "500 Internal Server Error"
+                } else {
+                    "200 OK"
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                )
+                .expect("write response");
+                tx.send(path).expect("send path");
+            }
+        });
+
+        rx
+    }
+
+    fn tenant_sender(dir: &Path, addr: &str, fallback: Option<&str>) -> Sender {
+        let sink = RawSink {
+            url: format!("http://{}/{{tenant}}", addr),
+            token: String::from("token"),
+            tenant_label: Some(String::from("tenant")),
+            tenant_fallback: fallback.map(String::from),
+            ..Default::default()
+        };
+
+        let sink = conf::Sink::try_from((String::from("sink"), sink)).expect("valid sink");
+        let params = parameters(&[("push-timeout", "1s")]).expect("valid parameters");
+
+        let path = dir.join("sink-0-0.metrics");
+        let mut file = File::create(&path).expect("create sink file");
+        write!(
+            file,
+            "1// f{{tenant=ok}} 1\n1// f{{tenant=fail}} 2\n1// f{{}} 3\n"
+        )
+        .expect("write sink file");
+        let mut queue = Queue::default();
+        queue.push_front(path);
+
+        Sender::from((
+            Arc::new(Mutex::new(queue)),
+            Arc::new(Mutex::new(Breaker::from(&sink))),
+            Arc::new(Mutex::new(Ramp::from((&sink, Instant::now())))),
+            Arc::new(sink.to_owned()),
+            Arc::new(params),
+        ))
+    }
+
+    #[test]
+    fn post_time_series_without_tenant() {
+        let dir = temp_dir().join(format!("beamium-{}", Uuid::new_v4()));
+        create_dir_all(&dir).expect("create sink directory");
+
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind listener");
+        let addr = listener.local_addr().expect("listener address").to_string();
+        let paths = serve(listener);
+        let mut rt = Runtime::new().expect("runtime");
+
+        // The batch fails without posting anything, so its files are kept
+        let sender = tenant_sender(&dir, &addr, None);
+        let err = rt
+            .block_on(sender.post_by_tenant("tenant"))
+            .expect_err("time series without tenant");
+        assert!(err
+            .to_string()
+            .starts_with("could not post 1 time series without the 'tenant' label"));
+        assert!(paths.try_recv().is_err());
+
+        // Using a fallback, they are posted along with its tenant
+        let sender = tenant_sender(&dir, &addr, Some("ok"));
+        rt.block_on(sender.post_by_tenant("tenant"))
+            .expect_err("tenant fails");
+
+        let mut posted: Vec<String> = (0..2).map(|_| paths.recv().expect("path")).collect();
+        posted.sort();
+        assert_eq!(vec!["/fail", "/ok"], posted);
+
+        remove_dir_all(&dir).expect("remove sink directory");
+    }
+
+    #[test]
+    fn post_only_failed_groups() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind listener");
+        let addr = listener.local_addr().expect("listener address").to_string();
+        let paths = serve(listener);
+
+        let dir = temp_dir().join(format!("beamium-{}", Uuid::new_v4()));
+        create_dir_all(&dir).expect("create sink directory");
+        let sender = tenant_sender(&dir, &addr, None);
+
+        let group = |tenant: &str| {
+            let uri = format!("http://{}/{}", addr, tenant).parse().expect("uri");
+            (
+                uri,
+                String::from("token"),
+                format!("1// f{{tenant={}}} 1\n", tenant),
+            )
+        };
+
+        let mut rt = Runtime::new().expect("runtime");
+        let batch = "1// f{tenant=ok} 1\n1// f{tenant=fail} 1\n";
+        for _ in 0..2 {
+            rt.block_on(sender.post_groups(batch, true, vec![group("ok"), group("fail")]))
+                .expect_err("group fails");
+        }
+
+        // The group of the tenant which succeeded is posted once
+        let mut posted: Vec<String> = (0..3).map(|_| paths.recv().expect("path")).collect();
+        posted.sort();
+        assert_eq!(vec!["/fail", "/fail", "/ok"], posted);
+        assert!(paths.recv_timeout(Duration::from_millis(100)).is_err());
+
+        // Another batch posts all its groups
+        rt.block_on(sender.post_groups("other", true, vec![group("ok")]))
+            .expect("post group");
+        assert_eq!("/ok", paths.recv().expect("path"));
+
+        remove_dir_all(&dir).expect("remove sink directory");
+    }
+
+    fn token_sink() -> conf::Sink {
+        let sink = RawSink {
+            url: String::from("http://127.0.0.1/api/v0/update"),
+            token: String::from("default"),
+            token_by_label: Some(RawTokenByLabel {
+                label: String::from("team"),
+                tokens: vec![
+                    (String::from("ops"), String::from("ops-token")),
+                    (String::from("dev"), String::from("dev-token")),
+                ]
+                .into_iter()
+                .collect(),
+            }),
+            ..Default::default()
+        };
+
+        conf::Sink::try_from((String::from("sink"), sink)).expect("valid sink")
+    }
+
+    #[test]
+    fn group_time_series_by_token() {
+        let data = "1// f{team=ops,host=h} 1\n\
+                    =2// 2\n\
+                    1// f{host=h,team=dev} 3\n\
+                    1// f{host=h} 4\n\
+                    =2// 5\n\
+                    1// g{team=qa} 6\n\
+                    1// g{team=ops} 7\n";
+
+        let sink = token_sink();
+        let tokens = sink.token_by_label.as_ref().expect("token by label");
+        let groups = Sender::group_by_token(&[(String::from(data), true)], tokens, &sink.token);
+        assert_eq!(
+            vec!["default", "dev-token", "ops-token"],
+            groups.keys().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            "1// f{team=ops,host=h} 1\n=2// 2\n1// g{team=ops} 7\n",
+            groups["ops-token"]
+        );
+        assert_eq!("1// f{host=h,team=dev} 3\n", groups["dev-token"]);
+        assert_eq!(
+            "1// f{host=h} 4\n=2// 5\n1// g{team=qa} 6\n",
+            groups["default"]
+        );
+    }
+
+    #[test]
+    fn token_header_of_group() {
+        let sink = token_sink();
+        let params = parameters(&[]).expect("valid parameters");
+        let sender = Sender::from((
+            Arc::new(Mutex::new(Queue::default())),
+            Arc::new(Mutex::new(Breaker::from(&sink))),
+            Arc::new(Mutex::new(Ramp::from((&sink, Instant::now())))),
+            Arc::new(sink.to_owned()),
+            Arc::new(params.to_owned()),
+        ));
+
+        for token in &["ops-token", "default"] {
+            let body = Body::from((
+                String::new(),
+                Arc::new(sink.to_owned()),
+                Arc::new(params.to_owned()),
+            ));
+            let request = sender
+                .request(sink.url.to_owned(), token, body)
+                .expect("create request");
+
+            assert_eq!(
+                Some(*token),
+                request
+                    .headers()
+                    .get("X-Warp10-Token")
+                    .and_then(|value| value.to_str().ok())
+            );
+        }
+    }
+
+    #[test]
+    fn expand_url_with_tenant() {
+        let sink = RawSink {
+            url: String::from("https://127.0.0.1/api/v0/update/{tenant}"),
+            token: String::from("token"),
+            tenant_label: Some(String::from("tenant")),
+            ..Default::default()
+        };
+
+        let sink = conf::Sink::try_from((String::from("sink"), sink)).expect("valid sink");
+        assert_eq!(
+            "https://127.0.0.1/api/v0/update/a",
+            sink.url_of("a").expect("valid url").to_string()
+        );
+
+        let sink = RawSink {
+            url: String::from("https://127.0.0.1/api/v0/update"),
+            token: String::from("token"),
+            ..Default::default()
+        };
+
+        let sink = conf::Sink::try_from((String::from("sink"), sink)).expect("valid sink");
+        assert_eq!(
+            "https://127.0.0.1/api/v0/update",
+            sink.url_of("a").expect("valid url").to_string()
+        );
+
+        // The placeholder and the label go together
+        let sink = RawSink {
+            url: String::from("https://127.0.0.1/api/v0/update/{tenant}"),
+            token: String::from("token"),
+            ..Default::default()
+        };
+
+        assert!(conf::Sink::try_from((String::from("sink"), sink)).is_err());
+
+        let sink = RawSink {
+            url: String::from("https://127.0.0.1/api/v0/update"),
+            token: String::from("token"),
+            tenant_label: Some(String::from("tenant")),
+            ..Default::default()
+        };
+
+        assert!(conf::Sink::try_from((String::from("sink"), sink)).is_err());
+        // The fallback tenant requires the label
+        let sink = RawSink {
+            url: String::from("https://127.0.0.1/api/v0/update"),
+            token: String::from("token"),
+            tenant_fallback: Some(String::from("default")),
+            ..Default::default()
+        };
+
+        let err = conf::Sink::try_from((String::from("sink"), sink)).expect_err("no label");
+        assert!(err
+            .to_string()
+            .starts_with("could not parse 'tenant-fallback' setting"));
+    }
+
+    #[test]
+    fn read_batch_of_tenant_sink() {
+        let dir = temp_dir().join(format!("beamium-{}", Uuid::new_v4()));
+        create_dir_all(&dir).expect("create sink directory");
+
+        let sink = RawSink {
+            url: String::from("http://127.0.0.1/{tenant}"),
+            token: String::from("token"),
+            tenant_label: Some(String::from("file")),
+            ..Default::default()
+        };
+
+        let sink = conf::Sink::try_from((String::from("sink"), sink)).expect("valid sink");
+        let params = parameters(&[("batch-count", "2")]).expect("valid parameters");
+        let queue = Mutex::new(spool(&dir, 3));
+        let files = SegQueue::new();
+
+        let batch = Sender::batch(&queue, &files, &sink, &params).expect("read batch");
+        assert_eq!(2, files.len());
+
+        let mut queue = queue.lock().expect("queue");
+        assert!(queue.pop_front().is_some());
+        assert!(queue.is_empty());
+
+        let (groups, dropped) = Sender::group(&batch, "file", None);
+        assert_eq!(vec!["1", "2"], groups.keys().collect::<Vec<_>>());
+        assert_eq!(100, groups["1"].lines().count());
+        assert_eq!(0, dropped);
+
+        remove_dir_all(&dir).expect("remove sink directory");
+    }
+
+    #[test]
+    fn skip_empty_batch() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind listener");
+        listener
+            .set_nonblocking(true)
+            .expect("non-blocking listener");
+        let addr = listener.local_addr().expect("listener address");
+
+        let sink = RawSink {
+            url: format!("http://{}/api/v0/update", addr),
+            token: String::from("token"),
+            skip_empty_batches: Some(true),
+            ..Default::default()
+        };
+
+        let sink = conf::Sink::try_from((String::from("sink"), sink)).expect("valid sink");
+        let params = parameters(&[("push-timeout", "100ms")]).expect("valid parameters");
+        let sender = Sender::from((
+            Arc::new(Mutex::new(Queue::default())),
+            Arc::new(Mutex::new(Breaker::from(&sink))),
+            Arc::new(Mutex::new(Ramp::from((&sink, Instant::now())))),
+            Arc::new(sink.to_owned()),
+            Arc::new(params),
+        ));
+
+        let mut rt = Runtime::new().expect("runtime");
+        let empty = String::from("# comment\n\n=2// 2\n");
+        rt.block_on(sender.post_data(sink.url.to_owned(), &sink.token, empty, true))
+            .expect("empty batch is not posted");
+        assert!(listener.accept().is_err());
+
+        let data = String::from("1// f{} 1\n=2// 2\n");
+        assert!(rt
+            .block_on(sender.post_data(sink.url.to_owned(), &sink.token, data, true))
+            .is_err());
+        assert!(listener.accept().is_ok());
+    }
+
+    #[test]
+    fn count_posted_datapoints() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind listener");
+        listener
+            .set_nonblocking(true)
+            .expect("non-blocking listener");
+        let addr = listener.local_addr().expect("listener address");
+
+        let sink = RawSink {
+            url: format!("http://{}/api/put", addr),
+            token: String::from("token"),
+            format: Some(String::from("opentsdb")),
+            ..Default::default()
+        };
+
+        let sink = conf::Sink::try_from((String::from("datapoints"), sink)).expect("valid sink");
+        let params = parameters(&[("push-timeout", "100ms")]).expect("valid parameters");
+        let sender = Sender::from((
+            Arc::new(Mutex::new(Queue::default())),
+            Arc::new(Mutex::new(Breaker::from(&sink))),
+            Arc::new(Mutex::new(Ramp::from((&sink, Instant::now())))),
+            Arc::new(sink.to_owned()),
+            Arc::new(params),
+        ));
+
+        // The time series without labels is dropped by the conversion, so it is not counted
+        let mut rt = Runtime::new().expect("runtime");
+        let data = String::from("1// f{host=a} 1\n2// f{} 2\n");
+        assert!(rt
+            .block_on(sender.post_data(sink.url.to_owned(), &sink.token, data, true))
+            .is_err());
+        assert_eq!(
+            1.0,
+            BEAMIUM_PUSH_DP.with_label_values(&["datapoints"]).get()
+        );
+    }
+
+    #[test]
+    fn account_removed_files() {
+        let dir = temp_dir().join(format!("beamium-{}", Uuid::new_v4()));
+        create_dir_all(&dir).expect("create sink directory");
+
+        let sink = format!("sink-{}", Uuid::new_v4());
+        let mut bulk = vec![];
+        for idx in 0..3 {
+            let path = dir.join(format!("{}-0-{}.metrics", sink, idx));
+            let mut file = File::create(&path).expect("create sink file");
+            file.write_all(b"1// f{} 1\n").expect("write sink file");
+
+            bulk.push(Sender::remove(path, sink.to_owned()));
+        }
+
+        let mut rt = Runtime::new().expect("runtime");
+        rt.block_on(join_all(bulk)).expect("remove files");
+
+        assert_eq!(
+            3.0,
+            BEAMIUM_PUSH_FILES.with_label_values(&[sink.as_str()]).get()
+        );
+        assert_eq!(
+            30.0,
+            BEAMIUM_PUSH_BYTES.with_label_values(&[sink.as_str()]).get()
+        );
+
+        let missing = dir.join(format!("{}-0-missing.metrics", sink));
+        assert!(rt
+            .block_on(Sender::remove(missing, sink.to_owned()))
+            .is_err());
+        assert_eq!(
+            3.0,
+            BEAMIUM_PUSH_FILES.with_label_values(&[sink.as_str()]).get()
+        );
+
+        remove_dir_all(&dir).expect("remove sink directory");
+    }
+
+    #[test]
+    fn remove_files_with_low_concurrency() {
+        let dir = temp_dir().join(format!("beamium-{}", Uuid::new_v4()));
+        create_dir_all(&dir).expect("create sink directory");
+
+        let sink = format!("sink-{}", Uuid::new_v4());
+        let mut files = vec![];
+        for idx in 0..50 {
+            let path = dir.join(format!("{}-0-{}.metrics", sink, idx));
+            let mut file = File::create(&path).expect("create sink file");
+            file.write_all(b"1// f{} 1\n").expect("write sink file");
+
+            files.push(path);
+        }
+
+        let mut rt = Runtime::new().expect("runtime");
+        rt.block_on(Sender::remove_all(files, sink.to_owned(), 2))
+            .expect("remove files");
+
+        assert_eq!(0, read_dir(&dir).expect("read sink directory").count());
+        assert_eq!(
+            50.0,
+            BEAMIUM_PUSH_FILES.with_label_values(&[sink.as_str()]).get()
+        );
+
+        remove_dir_all(&dir).expect("remove sink directory");
+    }
+
+    #[test]
+    fn open_circuit_after_consecutive_failures() {
+        let name = format!("sink-{}", Uuid::new_v4());
+        let mut breaker = breaker(&name, Some(3));
+        let now = Instant::now();
+
+        breaker.failure(now);
+        breaker.failure(now);
+        breaker.success();
+        breaker.failure(now);
+        breaker.failure(now);
+        assert_eq!(Circuit::Closed, breaker.circuit);
+        assert!(breaker.allow(now));
+
+        breaker.failure(now);
+        assert_eq!(
+            Circuit::Open(now + Duration::from_secs(30)),
+            breaker.circuit
+        );
+        assert!(!breaker.allow(now + Duration::from_secs(29)));
+        assert_eq!(
+            1.0,
+            BEAMIUM_PUSH_CIRCUIT
+                .with_label_values(&[name.as_str()])
+                .get()
+        );
+    }
+
+    #[test]
+    fn probe_half_open_circuit() {
+        let name = format!("sink-{}", Uuid::new_v4());
+        let mut breaker = breaker(&name, Some(1));
+        let now = Instant::now();
+
+        breaker.failure(now);
+        let probe = now + Duration::from_secs(30);
+
+        // A single probe batch is allowed
+        assert!(breaker.allow(probe));
+        assert_eq!(Circuit::HalfOpen, breaker.circuit);
+        assert!(!breaker.allow(probe));
+        assert_eq!(
+            2.0,
+            BEAMIUM_PUSH_CIRCUIT
+                .with_label_values(&[name.as_str()])
+                .get()
+        );
+
+        // A failed probe re-opens the circuit
+        breaker.failure(probe);
+        assert_eq!(
+            Circuit::Open(probe + Duration::from_secs(30)),
+            breaker.circuit
+        );
+
+        // A successful probe closes the circuit
+        assert!(breaker.allow(probe + Duration::from_secs(30)));
+        breaker.success();
+        assert_eq!(Circuit::Closed, breaker.circuit);
+        assert!(breaker.allow(probe));
+        assert_eq!(
+            0.0,
+            BEAMIUM_PUSH_CIRCUIT
+                .with_label_values(&[name.as_str()])
+                .get()
+        );
+    }
+
+    #[test]
+    fn ramp_up_senders() {
+        let sink = RawSink {
+            url: String::from("http://127.0.0.1"),
+            token: String::from("token"),
+            parallel: Some(5),
+            ramp_up: Some(String::from("40s")),
+            ..Default::default()
+        };
+
+        let sink = conf::Sink::try_from((String::from("sink"), sink)).expect("valid sink");
+        let start = Instant::now();
+        let mut ramp = Ramp::from((&sink, start));
+
+        let schedule: Vec<usize> = [0, 9, 10, 25, 40, 60]
+            .iter()
+            .map(|secs| ramp.senders(start + Duration::from_secs(*secs)))
+            .collect();
+        assert_eq!(vec![1, 1, 2, 3, 5, 5], schedule);
+
+        let now = start + Duration::from_secs(30);
+        assert!(ramp.acquire(now));
+        assert!(ramp.acquire(now));
+        assert!(ramp.acquire(now));
+        assert!(ramp.acquire(now));
+        assert!(!ramp.acquire(now));
+
+        // A failure steps the ramp back to half of the senders
+        ramp.failure(now);
+        assert_eq!(2, ramp.senders(now));
+        assert!(!ramp.acquire(now));
+        assert_eq!(5, ramp.senders(now + Duration::from_secs(30)));
+
+        let sink = conf::Sink {
+            ramp_up: None,
+            ..sink
+        };
+        assert_eq!(5, Ramp::from((&sink, start)).senders(start));
+    }
+
+    #[test]
+    fn release_ramp_on_invalid_request() {
+        let dir = temp_dir().join(format!("beamium-{}", Uuid::new_v4()));
+        create_dir_all(&dir).expect("create sink directory");
+
+        // The token is not a valid header value, so the request could not be built
+        let sink = RawSink {
+            url: String::from("http://127.0.0.1"),
+            token: String::from("tok\nen"),
+            ramp_up: Some(String::from("1h")),
+            ..Default::default()
+        };
+
+        let sink = conf::Sink::try_from((String::from("sink"), sink)).expect("valid sink");
+        let params = parameters(&[]).expect("valid parameters");
+
+        let path = dir.join("sink-0-0.metrics");
+        File::create(&path).expect("create sink file");
+        let mut queue = Queue::default();
+        queue.push_front(path);
+
+        let ramp = Arc::new(Mutex::new(Ramp::from((&sink, Instant::now()))));
+        let sender = Sender::from((
+            Arc::new(Mutex::new(queue)),
+            Arc::new(Mutex::new(Breaker::from(&sink))),
+            ramp.to_owned(),
+            Arc::new(sink),
+            Arc::new(params),
+        ));
+
+        let mut rt = Runtime::new().expect("runtime");
+        let err = rt.block_on(sender.into_future().map(|_| ()).map_err(|(err, _)| err));
+        assert!(err.is_err());
+
+        // The only permit of the ramp-up is given back
+        assert!(ramp.lock().expect("ramp").acquire(Instant::now()));
+
+        remove_dir_all(&dir).expect("remove sink directory");
+    }
+
+    #[test]
+    fn classify_failed_statuses() {
+        let sink = |retryable: Option<Vec<u16>>, droppable: Option<Vec<u16>>| {
+            let sink = RawSink {
+                url: String::from("http://127.0.0.1"),
+                token: String::from("token"),
+                retryable_statuses: retryable,
+                droppable_statuses: droppable,
+                ..Default::default()
+            };
+
+            conf::Sink::try_from((String::from("sink"), sink)).expect("valid sink")
+        };
+
+        // Every failed batch is sent again by default
+        let conf = sink(None, None);
+        assert!(!Sender::is_droppable(400, &conf));
+        assert!(!Sender::is_droppable(503, &conf));
+
+        let conf = sink(None, Some(vec![400, 413]));
+        assert!(Sender::is_droppable(413, &conf));
+        assert!(!Sender::is_droppable(420, &conf));
+
+        let conf = sink(Some(vec![420, 503]), None);
+        assert!(!Sender::is_droppable(420, &conf));
+        assert!(Sender::is_droppable(500, &conf));
+    }
+
+    #[test]
+    fn circuit_breaker_is_disabled_by_default() {
+        let mut breaker = breaker(&format!("sink-{}", Uuid::new_v4()), None);
+        let now = Instant::now();
+
+        for _ in 0..100 {
+            breaker.failure(now);
+        }
+
+        assert_eq!(Circuit::Closed, breaker.circuit);
+        assert!(breaker.allow(now));
+    }
+
+    #[test]
+    fn recycle_client_on_age() {
+        assert!(!Sender::is_expired(Duration::from_secs(3600), None));
+        assert!(!Sender::is_expired(
+            Duration::from_secs(59),
+            Some(Duration::from_secs(60))
+        ));
+        assert!(Sender::is_expired(
+            Duration::from_secs(60),
+            Some(Duration::from_secs(60))
+        ));
+    }
+
+    #[test]
+    fn describe_batch_files() {
+        let files = vec![
+            PathBuf::from("sinks/a.metrics"),
+            PathBuf::from("sinks/b.metrics"),
+        ];
+
+        assert_eq!(
+            "sinks/a.metrics, sinks/b.metrics",
+            Sender::describe(&files, 10)
+        );
+    }
+
+    #[test]
+    fn describe_batch_files_is_bounded() {
+        let files: Vec<PathBuf> = (0..5)
+            .map(|idx| PathBuf::from(format!("sinks/{}.metrics", idx)))
+            .collect();
+
+        assert_eq!(
+            "sinks/0.metrics, sinks/1.metrics, ... (3 more)",
+            Sender::describe(&files, 2)
+        );
+    }
+
+    /// Level, message and key/values of a logged record
+    type Entry = (Level, String, HashMap<String, String>);
+
+    /// `Collect` drain keeps the logged records
+    #[derive(Clone, Default)]
+    struct Collect {
+        records: Arc<Mutex<Vec<Entry>>>,
+    }
+
+    /// `Values` of a logged record keyed by their name
+    #[derive(Default)]
+    struct Values(HashMap<String, String>);
+
+    impl Serializer for Values {
+        fn emit_arguments(&mut self, key: Key, val: &fmt::Arguments) -> slog::Result {
+            self.0.insert(key.to_string(), val.to_string());
+            Ok(())
+        }
+    }
+
+    impl Drain for Collect {
+        type Ok = ();
+        type Err = Never;
+
+        fn log(&self, record: &Record, _: &OwnedKVList) -> Result<(), Never> {
+            let mut values = Values::default();
+            let _ = record.kv().serialize(record, &mut values);
+            self.records.lock().expect("records").push((
+                record.level(),
+                record.msg().to_string(),
+                values.0,
+            ));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn log_batch_files_at_debug() {
+        let dir = temp_dir().join(format!("beamium-{}", Uuid::new_v4()));
+        create_dir_all(&dir).expect("create sink directory");
+
+        // The batch is read by the server which then fails
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind listener");
+        let addr = listener.local_addr().expect("listener address").to_string();
+        let _paths = serve(listener);
+
+        let sink = RawSink {
+            url: format!("http://{}/fail", addr),
+            token: String::from("token"),
+            ..Default::default()
+        };
+
+        let sink = conf::Sink::try_from((String::from("sink"), sink)).expect("valid sink");
+        let params = parameters(&[]).expect("valid parameters");
+        let mut sender = Sender::from((
+            Arc::new(Mutex::new(spool(&dir, MAX_LOGGED_FILES + 2))),
+            Arc::new(Mutex::new(Breaker::from(&sink))),
+            Arc::new(Mutex::new(Ramp::from((&sink, Instant::now())))),
+            Arc::new(sink),
+            Arc::new(params),
+        ));
+
+        // The batch is sent, then its post fails
+        let collect = Collect::default();
+        let logger = Logger::root(collect.to_owned().fuse(), o!());
+        let mut polls = 0;
+        let failed = future::poll_fn(move || -> Poll<(), Error> {
+            while polls < 2 {
+                try_ready!(slog_scope::scope(&logger, || sender.poll()));
+                polls += 1;
+            }
+
+            Ok(Async::Ready(()))
+        });
+
+        let mut rt = Runtime::new().expect("runtime");
+        rt.block_on(failed).expect("post fails");
+
+        let records = collect.records.lock().expect("records");
+        let (level, _, values) = records
+            .iter()
+            .find(|(_, msg, _)| msg == "post failed for batch")
+            .expect("batch record");
+
+        // The file list is bounded and only logged at debug level
+        assert_eq!(Level::Debug, *level);
+        assert_eq!(
+            Some(&(MAX_LOGGED_FILES + 2).to_string()),
+            values.get("count")
+        );
+        assert!(values
+            .get("files")
+            .expect("files of the batch")
+            .ends_with("... (2 more)"));
+        assert!(records
+            .iter()
+            .filter(|(_, _, values)| values.contains_key("files"))
+            .all(|(level, _, _)| *level == Level::Debug));
+
+        remove_dir_all(&dir).expect("remove sink directory");
+    }
+
+    #[test]
+    fn bind_connections_to_local_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind listener");
+        listener
+            .set_nonblocking(true)
+            .expect("non-blocking listener");
+        let addr = listener.local_addr().expect("listener address");
+        let mut rt = Runtime::new().expect("runtime");
+
+        // The address of the sink takes precedence over the one of the parameters
+        let cases = vec![
+            (Some("127.0.0.2"), Some("127.0.0.3"), "127.0.0.2"),
+            (None, Some("127.0.0.3"), "127.0.0.3"),
+        ];
+
+        for (sink_address, param_address, expected) in cases {
+            let sink = RawSink {
+                url: format!("http://{}/api/v0/update", addr),
+                token: String::from("token"),
+                bind_address: sink_address.map(String::from),
+                ..Default::default()
+            };
+
+            let sink = conf::Sink::try_from((String::from("sink"), sink)).expect("valid sink");
+            let params = match param_address {
+                None => parameters(&[]),
+                Some(address) => parameters(&[("bind-address", address)]),
+            }
+            .expect("valid parameters");
+
+            let body = Body::from((
+                String::new(),
+                Arc::new(sink.to_owned()),
+                Arc::new(params.to_owned()),
+            ));
+            let request = hyper::Request::post(sink.url.to_owned())
+                .body(body)
+                .expect("create request");
+
+            let client = Sender::client(&sink, &params);
+            rt.spawn(client.request(request).then(|_| Ok(())));
+
+            let deadline = Instant::now() + Duration::from_secs(5);
+            let peer = loop {
+                match listener.accept() {
+                    Ok((_, peer)) => break peer,
+                    Err(_) if Instant::now() < deadline => thread::sleep(Duration::from_millis(10)),
+                    Err(err) => panic!("no connection, {}", err),
+                }
+            };
+
+            assert_eq!(expected, peer.ip().to_string());
+        }
+    }
+
+    #[test]
+    fn tls_sessions_are_shared_between_clients() {
+        // A session stored by a first connection is found by the next ones
+        let first = tls_config(true, TlsVersion::Tls12);
+        assert!(first
+            .session_persistence
+            .put(b"test-session".to_vec(), b"ticket".to_vec()));
+
+        let second = tls_config(true, TlsVersion::Tls12);
+        assert_eq!(
+            Some(b"ticket".to_vec()),
+            second.session_persistence.get(b"test-session")
+        );
+
+        let disabled = tls_config(false, TlsVersion::Tls12);
+        assert!(!disabled.enable_tickets);
+        assert_eq!(None, disabled.session_persistence.get(b"test-session"));
+    }
+
+    #[test]
+    fn tls_versions_are_bounded() {
+        let config = tls_config(true, TlsVersion::Tls12);
+        assert_eq!(
+            vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2],
+            config.versions
+        );
+
+        for resumption in &[true, false] {
+            let config = tls_config(*resumption, TlsVersion::Tls13);
+            assert_eq!(vec![ProtocolVersion::TLSv1_3], config.versions);
+            assert!(!config.supports_version(ProtocolVersion::TLSv1_2));
+        }
     }
 }
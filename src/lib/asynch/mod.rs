@@ -1,8 +1,11 @@
 //! # async module
 //!
 //! The `async` module provide asynchronous helpers.
+pub mod dns;
 pub mod fs;
 pub mod http;
+pub mod sync;
+pub mod tcp;
 
 /// The `try_future` macro provide an elegant way to manage errors in future.
 #[macro_export]
@@ -0,0 +1,141 @@
+use std::sync::Arc;
+
+use failure::{format_err, Error};
+use futures::{Async, Future, Poll};
+use tokio_sync::semaphore;
+
+/// `Semaphore` bound the number of futures running concurrently across tasks
+#[derive(Clone, Debug)]
+pub struct Semaphore {
+    inner: Arc<semaphore::Semaphore>,
+}
+
+impl From<usize> for Semaphore {
+    fn from(permits: usize) -> Self {
+        Self {
+            inner: Arc::new(semaphore::Semaphore::new(permits)),
+        }
+    }
+}
+
+impl Semaphore {
+    /// Wait for a permit, the permit is released once the returned [`Guard`] is dropped
+    pub fn acquire(&self) -> Acquire {
+        Acquire {
+            semaphore: self.inner.to_owned(),
+            permit: Some(semaphore::Permit::new()),
+        }
+    }
+
+    /// Run the future once a permit is acquired and release it on completion
+    pub fn run<F>(&self, fut: F) -> impl Future<Item = F::Item, Error = F::Error>
+    where
+        F: Future,
+        F::Error: From<Error>,
+    {
+        self.acquire()
+            .map_err(F::Error::from)
+            .and_then(move |guard| {
+                fut.then(move |result| {
+                    drop(guard);
+                    result
+                })
+            })
+    }
+}
+
+pub struct Acquire {
+    semaphore: Arc<semaphore::Semaphore>,
+    permit: Option<semaphore::Permit>,
+}
+
+impl Future for Acquire {
+    type Item = Guard;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let permit = self
+            .permit
+            .as_mut()
+            .ok_or_else(|| format_err!("permit is already acquired"))?;
+
+        match permit.poll_acquire(&self.semaphore) {
+            Err(err) => Err(format_err!("could not acquire permit, {}", err)),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Ok(Async::Ready(_)) => Ok(Async::Ready(Guard {
+                semaphore: self.semaphore.to_owned(),
+                permit: self.permit.take().expect("permit is checked above"),
+            })),
+        }
+    }
+}
+
+impl Drop for Acquire {
+    fn drop(&mut self) {
+        // Cancel the interest if the permit is still waiting
+        if let Some(permit) = self.permit.as_mut() {
+            permit.release(&self.semaphore);
+        }
+    }
+}
+
+pub struct Guard {
+    semaphore: Arc<semaphore::Semaphore>,
+    permit: semaphore::Permit,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        self.permit.release(&self.semaphore);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use failure::Error;
+    use futures::future::{join_all, lazy};
+    use futures::Future;
+    use tokio::runtime::Runtime;
+    use tokio::timer::Delay;
+
+    use super::Semaphore;
+
+    #[test]
+    fn in_flight_never_exceed_permits() {
+        let semaphore = Semaphore::from(3);
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max = Arc::new(AtomicUsize::new(0));
+
+        let mut bulk = vec![];
+        for _ in 0..50 {
+            let in_flight = in_flight.to_owned();
+            let max = max.to_owned();
+            let in_flight2 = in_flight.to_owned();
+
+            bulk.push(
+                semaphore.run(
+                    lazy(move || {
+                        let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                        max.fetch_max(current, Ordering::SeqCst);
+
+                        Delay::new(Instant::now() + Duration::from_millis(2))
+                    })
+                    .map_err(Error::from)
+                    .map(move |_| {
+                        in_flight2.fetch_sub(1, Ordering::SeqCst);
+                    }),
+                ),
+            );
+        }
+
+        let mut rt = Runtime::new().expect("runtime");
+        rt.block_on(join_all(bulk)).expect("run futures");
+
+        assert_eq!(3, max.load(Ordering::SeqCst));
+        assert_eq!(0, in_flight.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,217 @@
+//! # Tcp module
+//!
+//! The `tcp` module streams time series to the Warp10 relays accepting raw lines over a
+//! persistent connection, which is secured using TLS for 'tls://' urls.
+use std::convert::From;
+use std::sync::{Arc, Mutex};
+
+use failure::{format_err, Error};
+use futures::future::{self, Either};
+use hyper::client::connect::{Connect, Destination};
+use hyper::client::HttpConnector;
+use hyper::Uri;
+use hyper_rustls::{HttpsConnector, MaybeHttpsStream};
+use tokio::io::{flush, write_all};
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+
+use crate::conf;
+use crate::lib::asynch::dns::Resolver;
+use crate::lib::asynch::http::connector;
+
+/// Connection to a relay, plain or secured using TLS
+type Connection = MaybeHttpsStream<TcpStream>;
+
+/// `Relay` keeps the connection of a sender across batches
+#[derive(Clone)]
+pub struct Relay {
+    uri: Uri,
+    connector: HttpsConnector<HttpConnector<Resolver>>,
+    connection: Arc<Mutex<Option<Connection>>>,
+}
+
+impl From<(&conf::Sink, &conf::Parameters)> for Relay {
+    fn from(tuple: (&conf::Sink, &conf::Parameters)) -> Self {
+        let (conf, params) = tuple;
+
+        // The https connector secures the connections to https destinations only
+        let scheme = match conf.url.scheme_str() {
+            Some("tls") => "https",
+            _ => "http",
+        };
+
+        let authority = conf
+            .url
+            .authority_part()
+            .map_or_else(String::new, |authority| authority.to_string());
+
+        Self {
+            uri: format!("{}://{}", scheme, authority)
+                .parse()
+                .unwrap_or_else(|_| conf.url.to_owned()),
+            connector: connector(
+                params.tls_session_resumption,
+                conf.bind_address.or(params.bind_address),
+                params.min_tls_version,
+            ),
+            connection: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+impl Relay {
+    /// Send the time series on the connection, which is opened if needed. The connection is
+    /// dropped on failure, so the next batch opens a new one. A connection closed by the relay is
+    /// never reused, as writes into it would succeed while the time series are lost.
+    pub fn send(&self, data: String) -> impl Future<Item = (), Error = Error> {
+        let slot = self.connection.to_owned();
+        let connection = match slot.lock() {
+            Ok(mut connection) => connection.take(),
+            Err(err) => return Either::A(future::err(format_err!("{}", err))),
+        };
+
+        let connection = match connection {
+            Some(ref connection) if is_closed(connection) => {
+                debug!("drop connection closed by relay"; "uri" => self.uri.to_string());
+                Either::B(self.connect())
+            }
+            Some(connection) => Either::A(future::ok(connection)),
+            None => Either::B(self.connect()),
+        };
+
+        Either::B(
+            connection
+                .and_then(|connection| {
+                    write_all(connection, data)
+                        .and_then(|(connection, _)| flush(connection))
+                        .map_err(|err| format_err!("could not write on connection, {}", err))
+                })
+                .and_then(move |connection| {
+                    *slot.lock().map_err(|err| format_err!("{}", err))? = Some(connection);
+                    Ok(())
+                }),
+        )
+    }
+
+    /// Open a connection to the relay
+    fn connect(&self) -> impl Future<Item = Connection, Error = Error> {
+        let destination = match Destination::try_from_uri(self.uri.to_owned()) {
+            Ok(destination) => destination,
+            Err(err) => return Either::A(future::err(format_err!("{}", err))),
+        };
+
+        debug!("open connection to relay"; "uri" => self.uri.to_string());
+        Either::B(
+            self.connector
+                .connect(destination)
+                .map(|(connection, _)| connection)
+                .map_err(|err| format_err!("could not connect to relay, {}", err)),
+        )
+    }
+}
+
+/// Whether the connection is closed by the relay. Relays never write, so a connection holding
+/// data, at its end or failing is not reused.
+#[cfg(unix)]
+fn is_closed(connection: &Connection) -> bool {
+    use std::io;
+    use std::mem::ManuallyDrop;
+    use std::net;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    let stream = match connection {
+        MaybeHttpsStream::Http(stream) => stream,
+        MaybeHttpsStream::Https(stream) => stream.get_ref().0,
+    };
+
+    // The socket is only borrowed to peek at it without blocking, it must not be closed on drop
+    let socket = ManuallyDrop::new(unsafe { net::TcpStream::from_raw_fd(stream.as_raw_fd()) });
+    let mut buf = [0; 1];
+    !matches!(socket.peek(&mut buf), Err(ref err) if err.kind() == io::ErrorKind::WouldBlock)
+}
+
+/// Whether the connection is closed by the relay, only write failures are detected elsewhere
+#[cfg(not(unix))]
+fn is_closed(_connection: &Connection) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::Duration;
+
+    use tokio::runtime::Runtime;
+
+    use crate::conf::tests::parameters;
+    use crate::conf::{self, RawSink};
+
+    use super::Relay;
+
+    #[test]
+    fn stream_on_persistent_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind listener");
+        let addr = listener.local_addr().expect("listener address");
+
+        // The relay reads the lines of each connection, the first one is closed after a line
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            for (index, stream) in listener.incoming().enumerate() {
+                let mut reader = BufReader::new(stream.expect("accept connection"));
+                let mut line = String::new();
+                while reader.read_line(&mut line).expect("read line") > 0 {
+                    tx.send((index, line.trim().to_owned())).expect("send line");
+                    line.clear();
+                    if index == 0 {
+                        break;
+                    }
+                }
+
+                if index == 0 {
+                    drop(reader);
+                    tx.send((index, String::from("closed")))
+                        .expect("send close");
+                }
+            }
+        });
+
+        let sink = RawSink {
+            url: format!("tcp://{}", addr),
+            transport: Some(String::from("tcp")),
+            ..Default::default()
+        };
+
+        let sink = conf::Sink::try_from((String::from("relay"), sink)).expect("valid sink");
+        let params = parameters(&[]).expect("valid parameters");
+        let relay = Relay::from((&sink, &params));
+        let mut rt = Runtime::new().expect("runtime");
+
+        rt.block_on(relay.send(String::from("1// a{} 1\n")))
+            .expect("send first batch");
+        assert_eq!((0, String::from("1// a{} 1")), rx.recv().expect("line"));
+
+        assert_eq!((0, String::from("closed")), rx.recv().expect("close"));
+
+        // The first batch once the connection is closed reaches a new one, or fails so its files
+        // are kept
+        match rt.block_on(relay.send(String::from("2// b{} 2\n"))) {
+            Ok(()) => assert_eq!(
+                (1, String::from("2// b{} 2")),
+                rx.recv_timeout(Duration::from_secs(5))
+                    .expect("line on a new connection")
+            ),
+            Err(_) => rt
+                .block_on(relay.send(String::from("2// b{} 2\n")))
+                .expect("send on a new connection"),
+        }
+
+        rt.block_on(relay.send(String::from("3// c{} 3\n4// d{} 4\n")))
+            .expect("send on the same connection");
+        assert_eq!((1, String::from("3// c{} 3")), rx.recv().expect("line"));
+        assert_eq!((1, String::from("4// d{} 4")), rx.recv().expect("line"));
+    }
+}
@@ -0,0 +1,85 @@
+//! # Bucket module
+//!
+//! The `bucket` module provide a token bucket bounding the rate of an action.
+use std::time::Instant;
+
+/// Number of units of a token, so refills are computed exactly using nanoseconds
+const TOKEN: u128 = 1_000_000_000;
+
+/// `TokenBucket` holds up to `rate` tokens and is refilled by `rate` tokens per second
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: u128,
+    units: u128,
+    last: Instant,
+}
+
+impl From<(u32, Instant)> for TokenBucket {
+    fn from(tuple: (u32, Instant)) -> Self {
+        let (rate, now) = tuple;
+        let rate = u128::from(rate);
+
+        Self {
+            rate,
+            units: rate * TOKEN,
+            last: now,
+        }
+    }
+}
+
+impl TokenBucket {
+    /// Take a token, return false if the bucket is exhausted
+    pub fn take(&mut self, now: Instant) -> bool {
+        if let Some(elapsed) = now.checked_duration_since(self.last) {
+            self.units = (self.units + elapsed.as_nanos() * self.rate).min(self.rate * TOKEN);
+            self.last = now;
+        }
+
+        if self.units < TOKEN {
+            return false;
+        }
+
+        self.units -= TOKEN;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::TokenBucket;
+
+    #[test]
+    fn enforce_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::from((10, start));
+
+        // The bucket starts full
+        let taken = (0..50).filter(|_| bucket.take(start)).count();
+        assert_eq!(10, taken);
+
+        // Tokens are refilled over time, one every 100ms
+        assert!(!bucket.take(start + Duration::from_millis(50)));
+        assert!(bucket.take(start + Duration::from_millis(100)));
+        assert!(!bucket.take(start + Duration::from_millis(150)));
+
+        let now = start + Duration::from_millis(600);
+        let taken = (0..50).filter(|_| bucket.take(now)).count();
+        assert_eq!(5, taken);
+
+        // The bucket never holds more than a second of tokens
+        let now = start + Duration::from_secs(60);
+        let taken = (0..50).filter(|_| bucket.take(now)).count();
+        assert_eq!(10, taken);
+
+        // Over a long period, the rate is enforced
+        let mut taken = 0;
+        for tick in 1..=1_000 {
+            if bucket.take(now + Duration::from_millis(tick * 10)) {
+                taken += 1;
+            }
+        }
+        assert_eq!(100, taken);
+    }
+}
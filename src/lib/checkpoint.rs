@@ -0,0 +1,210 @@
+//! # Checkpoint module
+//!
+//! The `checkpoint` module periodically saves the state kept for each time series across scrapes,
+//! e.g. the last value of the counters, so it is reloaded on startup instead of being lost.
+use std::cmp::Reverse;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use failure::{format_err, Error, ResultExt};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_derive::{Deserialize, Serialize};
+use tokio::fs::{rename, write};
+use tokio::prelude::*;
+use tokio::timer::Interval;
+
+use crate::conf;
+
+/// `Entry` is the saved state of a time series along with the unix time it was last updated
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Entry<T> {
+    pub series: String,
+    pub state: T,
+    pub updated: u64,
+}
+
+/// Seconds elapsed since the unix epoch
+pub fn unix(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+/// File holding the state of the given owner, e.g. a scraper or the router
+pub fn path(conf: &conf::Checkpoint, owner: &str) -> PathBuf {
+    conf.dir.join(format!("{}.json", owner))
+}
+
+/// Load the entries of the file, the ones not updated for longer than the max age are evicted. A
+/// missing file holds no entries.
+pub fn load<T>(
+    path: &Path,
+    conf: &conf::Checkpoint,
+    now: SystemTime,
+) -> Result<Vec<Entry<T>>, Error>
+where
+    T: DeserializeOwned,
+{
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => {
+            return Err(format_err!(
+                "could not read checkpoint '{}', {}",
+                path.display(),
+                err
+            ))
+        }
+    };
+
+    let entries: Vec<Entry<T>> = serde_json::from_slice(&data)
+        .with_context(|err| format!("could not parse checkpoint '{}', {}", path.display(), err))?;
+
+    let now = unix(now);
+    let max_age = conf.max_age.as_secs();
+
+    Ok(entries
+        .into_iter()
+        .filter(|entry| now.saturating_sub(entry.updated) <= max_age)
+        .collect())
+}
+
+/// Save the entries in the file, only the most recently updated ones are kept beyond the maximum
+/// number of series. The file is written aside and then renamed, so it is never read half written.
+pub fn save<T>(
+    path: PathBuf,
+    mut entries: Vec<Entry<T>>,
+    conf: &conf::Checkpoint,
+) -> impl Future<Item = (), Error = Error>
+where
+    T: Serialize,
+{
+    if entries.len() > conf.max_series {
+        entries.sort_by_key(|entry| Reverse(entry.updated));
+        entries.truncate(conf.max_series);
+    }
+
+    let data = match serde_json::to_vec(&entries) {
+        Ok(data) => data,
+        Err(err) => {
+            return future::Either::A(future::err(format_err!(
+                "could not serialize checkpoint, {}",
+                err
+            )))
+        }
+    };
+
+    let temp = path.with_extension("tmp");
+    future::Either::B(
+        write(temp.to_owned(), data)
+            .and_then(move |_| rename(temp, path))
+            .map_err(|err| format_err!("could not write checkpoint, {}", err)),
+    )
+}
+
+/// Save the entries returned by the given closure at each interval
+pub fn every<T, F>(
+    path: PathBuf,
+    conf: conf::Checkpoint,
+    mut entries: F,
+) -> impl Future<Item = (), Error = ()>
+where
+    T: Serialize,
+    F: FnMut() -> Result<Vec<Entry<T>>, Error>,
+{
+    let epath = path.to_owned();
+
+    Interval::new(Instant::now() + conf.interval, conf.interval)
+        .map_err(|err| format_err!("{}", err))
+        .for_each(move |_| {
+            let entries = match entries() {
+                Ok(entries) => entries,
+                Err(err) => return future::Either::A(future::err(err)),
+            };
+
+            let path = path.to_owned();
+            future::Either::B(save(path.to_owned(), entries, &conf).or_else(move |err| {
+                error!("could not save checkpoint"; "path" => path.to_str(), "error" => err.to_string());
+                Ok(())
+            }))
+        })
+        .map_err(move |err| {
+            error!("could not handle checkpoint ticker"; "path" => epath.to_str(), "error" => err.to_string());
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, remove_dir_all};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use tokio::runtime::Runtime;
+    use uuid::Uuid;
+
+    use crate::conf;
+
+    use super::{load, path, save, Entry};
+
+    #[test]
+    fn round_trip() {
+        let dir = temp_dir().join(format!("beamium-{}", Uuid::new_v4()));
+        create_dir_all(&dir).expect("create directory");
+
+        let conf = conf::Checkpoint {
+            dir: dir.to_owned(),
+            interval: Duration::from_secs(60),
+            max_age: Duration::from_secs(3600),
+            max_series: 2,
+        };
+
+        let entry = |series: &str, state: f64, updated: u64| Entry {
+            series: String::from(series),
+            state,
+            updated,
+        };
+
+        let path = path(&conf, "scraper-node");
+        assert_eq!(dir.join("scraper-node.json"), path);
+
+        // A missing file holds no entries
+        let now = UNIX_EPOCH + Duration::from_secs(10_000);
+        assert!(load::<f64>(&path, &conf, now)
+            .expect("load checkpoint")
+            .is_empty());
+
+        // Only the most recently updated series are kept beyond the bound
+        let entries = vec![
+            entry("cpu{host=a}", 1.0, 9_000),
+            entry("cpu{host=b}", 2.0, 5_000),
+            entry("cpu{host=c}", 3.0, 9_900),
+        ];
+        Runtime::new()
+            .expect("runtime")
+            .block_on(save(path.to_owned(), entries, &conf))
+            .expect("save checkpoint");
+        assert!(!path.with_extension("tmp").exists());
+
+        let mut entries = load::<f64>(&path, &conf, now).expect("load checkpoint");
+        entries.sort_by(|a, b| a.series.cmp(&b.series));
+        assert_eq!(
+            vec![
+                entry("cpu{host=a}", 1.0, 9_000),
+                entry("cpu{host=c}", 3.0, 9_900)
+            ],
+            entries
+        );
+
+        // Series not updated for longer than the max age are evicted on load
+        let later = UNIX_EPOCH + Duration::from_secs(13_000);
+        assert_eq!(
+            vec![entry("cpu{host=c}", 3.0, 9_900)],
+            load::<f64>(&path, &conf, later).expect("load checkpoint")
+        );
+
+        remove_dir_all(&dir).expect("remove directory");
+    }
+}
@@ -0,0 +1,228 @@
+//! # Counter module
+//!
+//! The `counter` module detect the resets of cumulative counters across scrapes.
+use std::collections::{HashMap, HashSet};
+use std::mem;
+
+use failure::Error;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::conf::CounterReset;
+use crate::constants::COUNTER_RESET_LABEL;
+use crate::lib::add_labels;
+use crate::lib::checkpoint::Entry;
+
+/// `Series` state of a counter
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Series {
+    /// Last value fetched
+    last: f64,
+    /// Sum of the values reached before each reset
+    offset: f64,
+}
+
+/// `Counters` keep the last value of the counters of a scraper across scrapes
+#[derive(Debug, Default)]
+pub struct Counters {
+    series: HashMap<String, Series>,
+    seen: HashSet<String>,
+}
+
+impl Counters {
+    /// Compare the formatted time series of a counter, as declared by the `# TYPE` comments, with
+    /// the last value of its series and apply the policy if the counter has been reset
+    pub fn check(&mut self, line: &str, policy: &CounterReset) -> Result<String, Error> {
+        let (gts, value) = match line.rsplit_once(' ') {
+            Some((gts, value)) => (gts, value),
+            None => return Ok(line.to_owned()),
+        };
+
+        let value = match value.parse::<f64>() {
+            Ok(value) => value,
+            Err(_) => return Ok(line.to_owned()),
+        };
+
+        // Series are identified by their class, labels and attributes, not by their timestamp
+        let key = match line.split_whitespace().nth(1) {
+            Some(key) => key.to_owned(),
+            None => return Ok(line.to_owned()),
+        };
+
+        self.seen.insert(key.to_owned());
+        let series = self.series.entry(key).or_insert(Series {
+            last: value,
+            offset: 0.0,
+        });
+
+        let reset = value < series.last;
+        if reset {
+            series.offset += series.last;
+        }
+        series.last = value;
+
+        match policy {
+            CounterReset::Adjust if series.offset > 0.0 => {
+                Ok(format!("{} {}", gts, value + series.offset))
+            }
+            CounterReset::Marker if reset => add_labels(line, COUNTER_RESET_LABEL),
+            _ => Ok(line.to_owned()),
+        }
+    }
+
+    /// State of the counters to save, stamped with the given unix time
+    pub fn checkpoint(&self, updated: u64) -> Vec<Entry<Series>> {
+        self.series
+            .iter()
+            .map(|(series, state)| Entry {
+                series: series.to_owned(),
+                state: state.to_owned(),
+                updated,
+            })
+            .collect()
+    }
+
+    /// Restore the saved state of the counters, so a reset happening while stopped is detected
+    pub fn restore(&mut self, entries: Vec<Entry<Series>>) {
+        for entry in entries {
+            self.series.insert(entry.series, entry.state);
+        }
+    }
+
+    /// Forget the series missing from the scrape, called once it is processed
+    pub fn sweep(&mut self) {
+        let seen = mem::take(&mut self.seen);
+        self.series.retain(|key, _| seen.contains(key));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Counters;
+    use crate::conf::CounterReset;
+    use crate::lib::class;
+
+    /// Check the lines of the 'requests' counter, as the scraper does for declared counters
+    fn scrape(counters: &mut Counters, lines: &[&str], policy: &CounterReset) -> Vec<String> {
+        let lines = lines
+            .iter()
+            .map(|line| match class(line) {
+                Some("requests") => counters.check(line, policy).expect("check line"),
+                _ => String::from(*line),
+            })
+            .collect();
+
+        counters.sweep();
+        lines
+    }
+
+    #[test]
+    fn increase() {
+        for policy in &[CounterReset::Adjust, CounterReset::Marker] {
+            let mut counters = Counters::default();
+            for value in &[1, 5, 5, 12] {
+                let line = format!("1// requests_total{{host=a}} {}", value);
+                assert_eq!(
+                    vec![line.to_owned()],
+                    scrape(&mut counters, &[&line], policy)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn reset_adjust() {
+        let mut counters = Counters::default();
+        let policy = CounterReset::Adjust;
+
+        assert_eq!(
+            vec![
+                "1// requests{host=a} 10",
+                "1// requests{host=b} 3",
+                "1// gauge{} 8"
+            ],
+            scrape(
+                &mut counters,
+                &[
+                    "1// requests{host=a} 10",
+                    "1// requests{host=b} 3",
+                    "1// gauge{} 8"
+                ],
+                &policy
+            )
+        );
+
+        // The target restarted, only the counters are adjusted
+        assert_eq!(
+            vec![
+                "2// requests{host=a} 12",
+                "2// requests{host=b} 4",
+                "2// gauge{} 1"
+            ],
+            scrape(
+                &mut counters,
+                &[
+                    "2// requests{host=a} 2",
+                    "2// requests{host=b} 4",
+                    "2// gauge{} 1"
+                ],
+                &policy
+            )
+        );
+
+        assert_eq!(
+            vec!["3// requests{host=a} 16", "3// requests{host=a}{dc=rbx} 1"],
+            scrape(
+                &mut counters,
+                &["3// requests{host=a} 6", "3// requests{host=a}{dc=rbx} 1"],
+                &policy
+            )
+        );
+
+        // A reset of a series missing from the previous scrape is not detected
+        assert_eq!(
+            vec!["4// requests{host=b} 1"],
+            scrape(&mut counters, &["4// requests{host=b} 1"], &policy)
+        );
+    }
+
+    #[test]
+    fn restore_checkpoint() {
+        let mut counters = Counters::default();
+        let policy = CounterReset::Adjust;
+
+        scrape(&mut counters, &["1// requests{host=a} 10"], &policy);
+        scrape(&mut counters, &["2// requests{host=a} 2"], &policy);
+
+        // The adjusted counter keeps increasing once restored, the reset is still detected
+        let mut restored = Counters::default();
+        restored.restore(counters.checkpoint(2));
+        assert_eq!(
+            vec!["3// requests{host=a} 14", "3// requests{host=b} 1"],
+            scrape(
+                &mut restored,
+                &["3// requests{host=a} 4", "3// requests{host=b} 1"],
+                &policy
+            )
+        );
+        assert_eq!(
+            vec!["4// requests{host=a} 15"],
+            scrape(&mut restored, &["4// requests{host=a} 1"], &policy)
+        );
+    }
+
+    #[test]
+    fn reset_marker() {
+        let mut counters = Counters::default();
+        let policy = CounterReset::Marker;
+
+        scrape(&mut counters, &["1// requests{host=a} 10"], &policy);
+        assert_eq!(
+            vec!["2// requests{reset=true,host=a} 2"],
+            scrape(&mut counters, &["2// requests{host=a} 2"], &policy)
+        );
+        assert_eq!(
+            vec!["3// requests{host=a} 6"],
+            scrape(&mut counters, &["3// requests{host=a} 6"], &policy)
+        );
+    }
+}
@@ -0,0 +1,283 @@
+//! # Disk module
+//!
+//! The `disk` module pauses the writers of the spool directories while the disk is full, so they
+//! neither flood the logs nor spin on failing writes. It also writes the spooled files with the
+//! configured durability.
+use std::collections::HashMap;
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use futures::future::{self, Either};
+use futures::{Future, Poll};
+use prometheus::Gauge;
+use tokio::fs::File;
+use tokio::io::{flush, write_all, AsyncWrite};
+
+use crate::conf::Durability;
+
+lazy_static! {
+    static ref BEAMIUM_DISK_FULL: Gauge = register_gauge!(opts!(
+        "beamium_disk_full",
+        "Whether a writer is paused as the disk is full"
+    ))
+    .expect("create metric: 'beamium_disk_full'");
+    /// Writers paused as the disk is full, shared by the scrapers, the heartbeat and the router
+    /// which are kept apart by the namespace of their name, e.g. `scraper/<name>`
+    static ref PAUSES: Mutex<Pauses> = Mutex::new(Pauses::from(Duration::from_secs(10)));
+    /// Durability of the files written by the scrapers, the heartbeat and the router
+    static ref DURABILITY: Mutex<Durability> = Mutex::new(Durability::Flush);
+}
+
+/// Set the durability of the spooled files
+pub(crate) fn durability(durability: Durability) {
+    match DURABILITY.lock() {
+        Ok(mut current) => *current = durability,
+        Err(err) => error!("could not set durability"; "error" => err.to_string()),
+    }
+}
+
+/// Write the data into the spooled file with the configured durability
+pub(crate) fn spool(file: File, data: Vec<u8>) -> impl Future<Item = (), Error = io::Error> {
+    let durability = match DURABILITY.lock() {
+        Ok(durability) => *durability,
+        Err(err) => {
+            error!("could not get lock on durability"; "error" => err.to_string());
+            Durability::Flush
+        }
+    };
+
+    persist(file, data, durability)
+}
+
+/// `Durable` is a file whose data could be synced on disk
+pub trait Durable: AsyncWrite {
+    /// Sync the data and the metadata of the file on disk
+    fn poll_sync_all(&mut self) -> Poll<(), io::Error>;
+}
+
+impl Durable for File {
+    fn poll_sync_all(&mut self) -> Poll<(), io::Error> {
+        File::poll_sync_all(self)
+    }
+}
+
+/// Write the data into the file, then flush or sync it according to the durability
+pub fn persist<F>(
+    file: F,
+    data: Vec<u8>,
+    durability: Durability,
+) -> impl Future<Item = (), Error = io::Error>
+where
+    F: Durable,
+{
+    write_all(file, data).and_then(move |(file, _)| match durability {
+        Durability::None => Either::A(future::ok(())),
+        Durability::Flush => Either::B(Either::A(flush(file).map(|_| ()))),
+        Durability::Fsync => {
+            Either::B(Either::B(flush(file).and_then(|mut file| {
+                future::poll_fn(move || file.poll_sync_all())
+            })))
+        }
+    })
+}
+
+/// Name of the scraper in the pauses of the writers, scrapers are namespaced so that a scraper
+/// never shares the pause of the heartbeat or the router
+pub(crate) fn scraper(name: &str) -> String {
+    format!("scraper/{}", name)
+}
+
+/// Set the delay after which a paused writer tries to write again
+pub(crate) fn backoff(delay: Duration) {
+    match PAUSES.lock() {
+        Ok(mut pauses) => pauses.backoff = delay,
+        Err(err) => error!("could not set disk full backoff"; "error" => err.to_string()),
+    }
+}
+
+/// Whether the writer is paused as the disk is full
+pub(crate) fn is_paused(writer: &str) -> bool {
+    match PAUSES.lock() {
+        Ok(pauses) => pauses.is_paused(writer, Instant::now()),
+        Err(err) => {
+            error!("could not get lock on disk pauses"; "error" => err.to_string());
+            false
+        }
+    }
+}
+
+/// Pause the writer if the error is raised by a full disk
+pub(crate) fn fail(writer: &str, err: &io::Error) {
+    match PAUSES.lock() {
+        Ok(mut pauses) => {
+            if pauses.fail(writer, err, Instant::now()) {
+                crit!("disk is full, pause writing"; "writer" => writer, "backoff" => format!("{:?}", pauses.backoff));
+            }
+
+            BEAMIUM_DISK_FULL.set(pauses.gauge());
+        }
+        Err(err) => error!("could not get lock on disk pauses"; "error" => err.to_string()),
+    }
+}
+
+/// Resume the writer once a write succeeded
+pub(crate) fn resume(writer: &str) {
+    match PAUSES.lock() {
+        Ok(mut pauses) => {
+            if pauses.resume(writer) {
+                info!("disk space is available, resume writing"; "writer" => writer);
+            }
+
+            BEAMIUM_DISK_FULL.set(pauses.gauge());
+        }
+        Err(err) => error!("could not get lock on disk pauses"; "error" => err.to_string()),
+    }
+}
+
+/// `Pauses` holds the instant until which each writer stopped by a full disk is paused
+#[derive(Debug)]
+pub struct Pauses {
+    backoff: Duration,
+    until: HashMap<String, Instant>,
+}
+
+impl From<Duration> for Pauses {
+    fn from(backoff: Duration) -> Self {
+        Self {
+            backoff,
+            until: HashMap::new(),
+        }
+    }
+}
+
+impl Pauses {
+    /// Whether the writer is paused, it tries to write again once the backoff is elapsed
+    pub fn is_paused(&self, writer: &str, now: Instant) -> bool {
+        self.until.get(writer).map_or(false, |until| now < *until)
+    }
+
+    /// Pause the writer if the error is raised by a full disk, return true if it was not paused
+    pub fn fail(&mut self, writer: &str, err: &io::Error, now: Instant) -> bool {
+        if err.raw_os_error() != Some(libc::ENOSPC) {
+            return false;
+        }
+
+        self.until
+            .insert(writer.to_owned(), now + self.backoff)
+            .is_none()
+    }
+
+    /// Resume the writer, return true if it was paused
+    pub fn resume(&mut self, writer: &str) -> bool {
+        self.until.remove(writer).is_some()
+    }
+
+    /// Value of the disk full gauge, 1 if a writer is paused
+    fn gauge(&self) -> f64 {
+        if self.until.is_empty() {
+            0.0
+        } else {
+            1.0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use futures::{Async, Future, Poll};
+    use tokio::io::AsyncWrite;
+
+    use crate::conf::Durability;
+
+    use super::{persist, Durable, Pauses};
+
+    /// `Recorder` is a file recording the calls made to write it
+    #[derive(Clone, Default)]
+    struct Recorder {
+        calls: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    impl Recorder {
+        fn record(&self, call: &'static str) {
+            self.calls.lock().expect("lock calls").push(call);
+        }
+
+        fn calls(&self) -> Vec<&'static str> {
+            self.calls.lock().expect("lock calls").to_owned()
+        }
+    }
+
+    impl io::Write for Recorder {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.record("write");
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.record("flush");
+            Ok(())
+        }
+    }
+
+    impl AsyncWrite for Recorder {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    impl Durable for Recorder {
+        fn poll_sync_all(&mut self) -> Poll<(), io::Error> {
+            self.record("sync");
+            Ok(Async::Ready(()))
+        }
+    }
+
+    #[test]
+    fn persist_with_durability() {
+        let expected: &[(Durability, &[&str])] = &[
+            (Durability::None, &["write"]),
+            (Durability::Flush, &["write", "flush"]),
+            (Durability::Fsync, &["write", "flush", "sync"]),
+        ];
+
+        for (durability, calls) in expected {
+            let file = Recorder::default();
+            persist(file.to_owned(), b"1// f{} 1\n".to_vec(), *durability)
+                .wait()
+                .expect("persist file");
+            assert_eq!(calls.to_vec(), file.calls(), "{:?}", durability);
+        }
+    }
+
+    #[test]
+    fn pause_on_full_disk() {
+        let start = Instant::now();
+        let mut pauses = Pauses::from(Duration::from_secs(10));
+
+        // Other errors do not pause the writer
+        let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+        assert!(!pauses.fail("router", &denied, start));
+        assert!(!pauses.is_paused("router", start));
+
+        let full = io::Error::from_raw_os_error(libc::ENOSPC);
+        assert!(pauses.fail("router", &full, start));
+        assert!(pauses.is_paused("router", start + Duration::from_secs(5)));
+        assert!(!pauses.is_paused(&super::scraper("router"), start + Duration::from_secs(5)));
+
+        // The writer tries again once the backoff is elapsed, a new failure is not reported
+        let retry = start + Duration::from_secs(10);
+        assert!(!pauses.is_paused("router", retry));
+        assert!(!pauses.fail("router", &full, retry));
+        assert!(pauses.is_paused("router", retry + Duration::from_secs(5)));
+
+        // A successful write resumes the writer
+        assert!(pauses.resume("router"));
+        assert!(!pauses.is_paused("router", retry + Duration::from_secs(5)));
+        assert!(!pauses.resume("router"));
+    }
+}
@@ -1,11 +1,25 @@
 //! # Library module.
 //!
 //! This module provide traits and standard stuffs.
+use std::ffi::OsStr;
+use std::path::Path;
+
 use failure::{format_err, Error, ResultExt};
 use tokio::runtime::Runtime;
 
+use crate::constants::RAW;
+
+pub mod archive;
 #[macro_use]
 pub mod asynch;
+pub mod bucket;
+pub mod checkpoint;
+pub mod counter;
+pub mod disk;
+pub mod errors;
+pub mod opentsdb;
+pub mod queue;
+pub mod secret;
 pub mod transcompiler;
 
 /// `Runner` trait provide a method to start a job on the given runtime
@@ -22,6 +36,51 @@ pub trait Named {
     fn name(&self) -> String;
 }
 
+/// `has_continuations` checks whether the lines of the file starting with '=' are continuation
+/// lines, files of sources without continuation lines look like '<name>.raw.metrics'
+pub fn has_continuations(path: &Path) -> bool {
+    let stem = path.file_stem().and_then(OsStr::to_str).unwrap_or_default();
+    let tags = stem.rsplit('-').next().unwrap_or_default();
+
+    !tags.split('.').skip(1).any(|tag| tag == RAW)
+}
+
+/// `class` of the time series, `None` for continuation lines
+pub fn class(line: &str) -> Option<&str> {
+    if line.starts_with('=') {
+        return None;
+    }
+
+    line.split_whitespace()
+        .nth(1)
+        .and_then(|gts| gts.split('{').next())
+}
+
+/// `class_and_labels` of the time series, `None` for continuation lines
+pub fn class_and_labels(line: &str) -> Option<&str> {
+    if line.starts_with('=') {
+        return None;
+    }
+
+    line.split_whitespace()
+        .nth(1)
+        .and_then(|gts| gts.split_inclusive('}').next())
+}
+
+/// `split_labels` of the time series into keys and values, none for continuation lines
+pub fn split_labels(line: &str) -> Vec<(&str, &str)> {
+    class_and_labels(line)
+        .and_then(|gts| gts.split_once('{'))
+        .map(|(_, labels)| {
+            labels
+                .trim_end_matches('}')
+                .split(',')
+                .filter_map(|label| label.split_once('='))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
 /// `add_labels` to the time series
 pub fn add_labels(line: &str, labels: &str) -> Result<String, Error> {
     if labels.is_empty() {
@@ -63,27 +122,32 @@ pub fn remove_labels(line: &str, labels_to_drop: &[String]) -> Result<String, Er
         .next()
         .ok_or_else(|| format_err!("no_class"))
         .with_context(|err| format!("could not parse '{}', {}", line, err))?;
-    let class = String::from(class);
 
     let plabels = parts
         .next()
         .ok_or_else(|| format_err!("no_labels"))
         .with_context(|err| format!("could not parse '{}', {}", line, err))?;
-    let plabels = String::from(plabels);
 
-    let mut end_parts = plabels.splitn(2, "} ");
+    // The remaining part holds the attributes, if any, and the value
+    let mut end_parts = plabels.splitn(2, '}');
 
     let plabels = end_parts
         .next()
         .ok_or_else(|| format_err!("no_end"))
         .with_context(|err| format!("could not parse '{}', {}", line, err))?;
-    let plabels = String::from(plabels);
 
     let value = end_parts
         .next()
         .ok_or_else(|| format_err!("no_value"))
         .with_context(|err| format!("could not parse '{}', {}", line, err))?;
-    let value = String::from(value);
+
+    // Fast path, none of the labels to drop could be in the time series
+    if !labels_to_drop
+        .iter()
+        .any(|label| plabels.contains(label.as_str()))
+    {
+        return Ok(String::from(line));
+    }
 
     let labels: Vec<String> = plabels
         .split(',')
@@ -93,17 +157,92 @@ pub fn remove_labels(line: &str, labels_to_drop: &[String]) -> Result<String, Er
             let key = label_splits.pop()?;
             Some((key, value))
         })
-        .filter(|(key, _)| !labels_to_drop.contains(&key.to_owned()))
+        .filter(|(key, _)| !labels_to_drop.contains(key))
         .map(|(key, value)| format!("{}={}", key, value))
         .collect();
 
-    Ok(format!("{}{{{}}} {}", class, labels.join(","), value))
+    Ok(format!("{}{{{}}}{}", class, labels.join(","), value))
+}
+
+/// `normalize_labels` of the time series, keys are lowercased and characters other than
+/// alphanumerics are replaced by '_', values are lowercased if `values` is set. The
+/// first label wins when several keys are normalized to the same one.
+pub fn normalize_labels(line: &str, values: bool) -> Result<String, Error> {
+    let mut parts = line.splitn(2, '{');
+
+    let class = parts
+        .next()
+        .ok_or_else(|| format_err!("no_class"))
+        .with_context(|err| format!("could not parse '{}', {}", line, err))?;
+
+    let plabels = parts
+        .next()
+        .ok_or_else(|| format_err!("no_labels"))
+        .with_context(|err| format!("could not parse '{}', {}", line, err))?;
+
+    let mut end_parts = plabels.splitn(2, '}');
+
+    let plabels = end_parts
+        .next()
+        .ok_or_else(|| format_err!("no_end"))
+        .with_context(|err| format!("could not parse '{}', {}", line, err))?;
+
+    let value = end_parts
+        .next()
+        .ok_or_else(|| format_err!("no_value"))
+        .with_context(|err| format!("could not parse '{}', {}", line, err))?;
+
+    let mut keys = vec![];
+    let mut labels = vec![];
+    for label in plabels.split(',') {
+        let mut label_splits = label.splitn(2, '=');
+        let (key, label_value) = match (label_splits.next(), label_splits.next()) {
+            (Some(key), Some(label_value)) => (key, label_value),
+            _ => continue,
+        };
+
+        let key: String = key
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() {
+                    c.to_ascii_lowercase()
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+
+        if keys.contains(&key) {
+            continue;
+        }
+
+        let label_value = if values {
+            label_value.to_lowercase()
+        } else {
+            String::from(label_value)
+        };
+
+        labels.push(format!("{}={}", key, label_value));
+        keys.push(key);
+    }
+
+    Ok(format!("{}{{{}}}{}", class, labels.join(","), value))
 }
 
 #[cfg(test)]
 mod tests {
+    use std::path::Path;
+
     use failure::Error;
 
+    #[test]
+    fn continuation_lines_of_file() {
+        assert!(super::has_continuations(Path::new("node.raw-1-0.metrics")));
+        assert!(super::has_continuations(Path::new("warp-0-1#2#a.urgent.metrics")));
+        assert!(!super::has_continuations(Path::new("node-1-0.raw.metrics")));
+        assert!(!super::has_continuations(Path::new("warp-0-1#2#a.raw.urgent.metrics")));
+    }
+
     #[test]
     fn no_labels_at_all() {
         let line = "1484828198557102// f{} 10";
@@ -147,6 +286,73 @@ mod tests {
         assert_eq!(expected.unwrap(), result.unwrap());
     }
 
+    #[test]
+    fn remove_absent_labels() {
+        let line = "1484828198557102// f{job_id=123,job_name=job1,another_id=456} 10";
+        let labels = vec![String::from("host"), String::from("dc")];
+        let result = super::remove_labels(line, &labels).expect("labels are removed");
+        assert_eq!(line, result);
+
+        let labels = vec![String::from("job"), String::from("456")];
+        let result = super::remove_labels(line, &labels).expect("labels are removed");
+        assert_eq!(line, result);
+    }
+
+    #[test]
+    fn remove_labels_of_malformed_line() {
+        let labels = vec![String::from("host")];
+        assert!(super::remove_labels("1484828198557102// f 10", &labels).is_err());
+        assert!(super::remove_labels("1484828198557102// f{host=a 10", &labels).is_err());
+    }
+
+    #[test]
+    fn keep_attributes() {
+        let line = "1484828198557102// f{Host=a,job_id=123}{owner=b} 10";
+        let labels = vec![String::from("job_id")];
+        let result = super::remove_labels(line, &labels).expect("labels are removed");
+        assert_eq!("1484828198557102// f{Host=a}{owner=b} 10", result);
+
+        let result = super::normalize_labels(&result, false).expect("labels are normalized");
+        assert_eq!("1484828198557102// f{host=a}{owner=b} 10", result);
+    }
+
+    #[test]
+    fn normalize_label_keys() {
+        let line = "1484828198557102// f{Host=Web-1,JOB.ID=123} 10";
+        let result = super::normalize_labels(line, false).expect("labels are normalized");
+        assert_eq!("1484828198557102// f{host=Web-1,job_id=123} 10", result);
+
+        let line = "1484828198557102// f{} 10";
+        let result = super::normalize_labels(line, false).expect("labels are normalized");
+        assert_eq!(line, result);
+    }
+
+    #[test]
+    fn normalize_label_values() {
+        let line = "1484828198557102// f{Host=Web-1,dc=GRA} 10";
+        let result = super::normalize_labels(line, true).expect("labels are normalized");
+        assert_eq!("1484828198557102// f{host=web-1,dc=gra} 10", result);
+    }
+
+    #[test]
+    fn normalize_colliding_label_keys() {
+        let line = "1484828198557102// f{Host=a,HOST=b,host=c,job=d} 10";
+        let result = super::normalize_labels(line, false).expect("labels are normalized");
+        assert_eq!("1484828198557102// f{host=a,job=d} 10", result);
+    }
+
+    #[test]
+    fn class_of_time_series() {
+        let line = "1562656816000000// node_cpu{type=count} 1486";
+        assert_eq!(Some("node_cpu"), super::class(line));
+
+        let line = "1562656816000000/48.0:-4.5/ node_cpu{} 1486";
+        assert_eq!(Some("node_cpu"), super::class(line));
+
+        let line = "=1562656816000001// 1487";
+        assert_eq!(None, super::class(line));
+    }
+
     #[test]
     fn add_one_label() {
         let line = "1562656816000000// f{type=count} 1486";
@@ -0,0 +1,191 @@
+//! # OpenTSDB module
+//!
+//! The `opentsdb` module convert Warp10 time series into data points of the OpenTSDB http api.
+use std::collections::BTreeMap;
+
+use serde_derive::Serialize;
+use serde_json::Number;
+use urlencoding::decode;
+
+use crate::conf::TimeUnit;
+
+/// `DataPoint` of the OpenTSDB `/api/put` endpoint
+#[derive(Serialize, Debug, PartialEq)]
+pub struct DataPoint {
+    pub metric: String,
+    /// Milliseconds since epoch
+    pub timestamp: i64,
+    pub value: Number,
+    pub tags: BTreeMap<String, String>,
+}
+
+/// Convert the Warp10 time series into OpenTSDB data points, continuation lines use the class and
+/// labels of their time series. Timestamps are in the given unit, time series without timestamp
+/// are given `now`.
+/// Return the data points and the number of dropped lines, which have no labels or a value which
+/// is neither a number nor a boolean.
+pub fn convert(data: &str, now: i64, unit: TimeUnit) -> (Vec<DataPoint>, u64) {
+    let mut points = vec![];
+    let mut dropped = 0;
+    let mut series = None;
+    for line in data.lines() {
+        if line.is_empty() {
+            continue;
+        }
+
+        let (location, value) = match line.strip_prefix('=') {
+            Some(line) => match line.split_once(' ') {
+                Some(tuple) => tuple,
+                None => {
+                    dropped += 1;
+                    continue;
+                }
+            },
+            None => {
+                let mut tokens = line.splitn(3, ' ');
+                match (tokens.next(), tokens.next(), tokens.next()) {
+                    (Some(location), Some(gts), Some(value)) => {
+                        series = parse_series(gts);
+                        (location, value)
+                    }
+                    _ => {
+                        series = None;
+                        dropped += 1;
+                        continue;
+                    }
+                }
+            }
+        };
+
+        let timestamp = match location.split('/').next() {
+            None | Some("") => Some(now),
+            Some(timestamp) => timestamp.parse::<i64>().ok(),
+        };
+
+        match (&series, timestamp, parse_value(value.trim())) {
+            (Some((metric, tags)), Some(timestamp), Some(value)) => points.push(DataPoint {
+                metric: metric.to_owned(),
+                timestamp: timestamp / unit.per_milli(),
+                value,
+                tags: tags.to_owned(),
+            }),
+            _ => dropped += 1,
+        }
+    }
+
+    (points, dropped)
+}
+
+/// Metric and tags of the time series, `None` if it has no labels as OpenTSDB requires a tag
+fn parse_series(gts: &str) -> Option<(String, BTreeMap<String, String>)> {
+    let (class, labels) = gts.split_once('{')?;
+    let labels = labels.split('}').next()?;
+
+    let mut tags = BTreeMap::new();
+    for pair in labels.split(',').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=')?;
+        tags.insert(sanitize(key), sanitize(value));
+    }
+
+    if tags.is_empty() {
+        return None;
+    }
+
+    Some((sanitize(class), tags))
+}
+
+/// Decode the Warp10 value, booleans are converted to 0 or 1
+fn parse_value(value: &str) -> Option<Number> {
+    match value {
+        "T" | "true" => Some(Number::from(1)),
+        "F" | "false" => Some(Number::from(0)),
+        _ => match value.parse::<i64>() {
+            Ok(value) => Some(Number::from(value)),
+            Err(_) => Number::from_f64(value.parse::<f64>().ok()?),
+        },
+    }
+}
+
+/// Decode the Warp10 string and replace the characters forbidden by OpenTSDB by '_'
+fn sanitize(encoded: &str) -> String {
+    let decoded = decode(encoded).unwrap_or_else(|_| encoded.to_owned());
+
+    decoded
+        .chars()
+        .map(|c| match c {
+            '-' | '_' | '.' | '/' => c,
+            _ if c.is_alphanumeric() => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use serde_json::{to_string, Number};
+
+    use crate::conf::TimeUnit;
+
+    use super::{convert, DataPoint};
+
+    #[test]
+    fn convert_sample_line() {
+        let (points, dropped) = convert(
+            "1562656816000123// os.cpu{host=a,mode=user}{dc=rbx} 42",
+            0,
+            TimeUnit::Micros,
+        );
+
+        let mut tags = BTreeMap::new();
+        tags.insert(String::from("host"), String::from("a"));
+        tags.insert(String::from("mode"), String::from("user"));
+
+        assert_eq!(
+            vec![DataPoint {
+                metric: String::from("os.cpu"),
+                timestamp: 1_562_656_816_000,
+                value: Number::from(42),
+                tags,
+            }],
+            points
+        );
+        assert_eq!(0, dropped);
+        assert_eq!(
+            r#"[{"metric":"os.cpu","timestamp":1562656816000,"value":42,"tags":{"host":"a","mode":"user"}}]"#,
+            to_string(&points).expect("serialize data points")
+        );
+    }
+
+    #[test]
+    fn convert_lines() {
+        let data = "1000000/48.0:-4.5/10 f{job=a%20b} 1.5\n\
+                    =2000000// T\n\
+                    // g{host=a} 3\n\
+                    1000000// h{} 4\n\
+                    =2000000// 5\n\
+                    1000000// i{host=a} 'string'\n";
+
+        let (points, dropped) = convert(data, 9_000_000, TimeUnit::Micros);
+        let points: Vec<_> = points
+            .iter()
+            .map(|point| {
+                format!(
+                    "{} {} {} {:?}",
+                    point.metric, point.timestamp, point.value, point.tags
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            vec![
+                "f 1000 1.5 {\"job\": \"a_b\"}",
+                "f 2000 1 {\"job\": \"a_b\"}",
+                "g 9000 3 {\"host\": \"a\"}",
+            ],
+            points
+        );
+        assert_eq!(3, dropped);
+    }
+}
@@ -0,0 +1,116 @@
+//! # Queue module.
+//!
+//! This module provide the queue of files to send for a sink.
+use std::collections::VecDeque;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+use crate::constants::URGENT;
+
+/// `Priority` tier of a file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Priority {
+    Urgent,
+    Bulk,
+}
+
+impl From<&Path> for Priority {
+    /// Retrieve the priority from the file name, urgent files look like '<name>.urgent.metrics'
+    fn from(path: &Path) -> Self {
+        let tag = path.file_stem().map(Path::new).and_then(Path::extension);
+        if tag == Some(OsStr::new(URGENT)) {
+            return Priority::Urgent;
+        }
+
+        Priority::Bulk
+    }
+}
+
+/// `Queue` of files split into priority tiers, urgent files are always dequeued first
+#[derive(Debug, Default)]
+pub struct Queue {
+    urgent: VecDeque<PathBuf>,
+    bulk: VecDeque<PathBuf>,
+}
+
+impl Queue {
+    fn tier(&mut self, path: &Path) -> &mut VecDeque<PathBuf> {
+        match Priority::from(path) {
+            Priority::Urgent => &mut self.urgent,
+            Priority::Bulk => &mut self.bulk,
+        }
+    }
+
+    /// Push the file at the front of its tier
+    pub fn push_front(&mut self, path: PathBuf) {
+        self.tier(&path).push_front(path);
+    }
+
+    /// Pop the next file to send, urgent files are preferred
+    pub fn pop_front(&mut self) -> Option<PathBuf> {
+        self.urgent.pop_front().or_else(|| self.bulk.pop_front())
+    }
+
+    /// Pop the last file to send, bulk files are evicted first
+    pub fn pop_back(&mut self) -> Option<PathBuf> {
+        self.bulk.pop_back().or_else(|| self.urgent.pop_back())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.urgent.is_empty() && self.bulk.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::{Priority, Queue};
+
+    #[test]
+    fn priority_from_file_name() {
+        assert_eq!(
+            Priority::Urgent,
+            Priority::from(Path::new("sinks/warp-0-1.urgent.metrics"))
+        );
+        assert_eq!(
+            Priority::Bulk,
+            Priority::from(Path::new("sinks/warp-0-1.metrics"))
+        );
+    }
+
+    #[test]
+    fn urgent_files_are_dequeued_first() {
+        let mut queue = Queue::default();
+        queue.push_front(PathBuf::from("warp-0-1.metrics"));
+        queue.push_front(PathBuf::from("warp-0-2.urgent.metrics"));
+        queue.push_front(PathBuf::from("warp-0-3.metrics"));
+        queue.push_front(PathBuf::from("warp-0-4.urgent.metrics"));
+
+        assert_eq!(
+            Some(PathBuf::from("warp-0-4.urgent.metrics")),
+            queue.pop_front()
+        );
+        assert_eq!(
+            Some(PathBuf::from("warp-0-2.urgent.metrics")),
+            queue.pop_front()
+        );
+        assert_eq!(Some(PathBuf::from("warp-0-3.metrics")), queue.pop_front());
+        assert_eq!(Some(PathBuf::from("warp-0-1.metrics")), queue.pop_front());
+        assert_eq!(None, queue.pop_front());
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn bulk_files_are_evicted_first() {
+        let mut queue = Queue::default();
+        queue.push_front(PathBuf::from("warp-0-1.urgent.metrics"));
+        queue.push_front(PathBuf::from("warp-0-2.metrics"));
+
+        assert_eq!(Some(PathBuf::from("warp-0-2.metrics")), queue.pop_back());
+        assert_eq!(
+            Some(PathBuf::from("warp-0-1.urgent.metrics")),
+            queue.pop_back()
+        );
+    }
+}
@@ -1,41 +1,488 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::error::Error;
+use std::time::Instant;
 
 use time::now_utc;
 
 use urlencoding::encode;
 
-use crate::conf::ScraperFormat;
+use crate::conf::{Geo, LabelEscapes, ScraperFormat, SummaryHandling, TimeUnit};
+
+/// Encoded labels blocks keyed by the raw labels block
+type LabelsCache = HashMap<String, String>;
+
+/// Types declared by the Prometheus `# TYPE` comments keyed by the encoded family
+type Types = HashMap<String, MetricType>;
+
+/// `MetricType` of a Prometheus family
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MetricType {
+    Counter,
+    Gauge,
+    Histogram,
+    Summary,
+    Untyped,
+}
+
+impl From<&str> for MetricType {
+    fn from(kind: &str) -> Self {
+        match kind {
+            "counter" => MetricType::Counter,
+            "gauge" => MetricType::Gauge,
+            "histogram" => MetricType::Histogram,
+            "summary" => MetricType::Summary,
+            _ => MetricType::Untyped,
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct Transcompiler {
     format: ScraperFormat,
     now: i64,
+    unit: TimeUnit,
+    labels: RefCell<LabelsCache>,
+    types: RefCell<Types>,
+    attributes: String,
+    geo: Option<Geo>,
+    summary: SummaryHandling,
+    escapes: LabelEscapes,
 }
 
 impl Transcompiler {
     pub fn new(format: ScraperFormat) -> Self {
-        let start = now_utc();
-        let now = start.to_timespec().sec * 1_000_000
-            + (i64::from(start.to_timespec().nsec) as i64 / 1_000);
+        Self {
+            format,
+            now: wall_now(),
+            unit: TimeUnit::Micros,
+            labels: RefCell::new(LabelsCache::new()),
+            types: RefCell::new(Types::new()),
+            attributes: String::new(),
+            geo: None,
+            summary: SummaryHandling::Passthrough,
+            escapes: LabelEscapes::Keep,
+        }
+    }
+
+    /// Use the given format, the time of the transcompiler is kept
+    pub fn with_format(self, format: ScraperFormat) -> Self {
+        Self { format, ..self }
+    }
+
+    /// Use the given time, in microseconds, for samples lacking a timestamp
+    pub fn at(self, now: i64) -> Self {
+        Self { now, ..self }
+    }
 
-        Self { format, now }
+    /// Write the timestamps of the Prometheus samples in the given unit
+    pub fn with_time_unit(self, unit: TimeUnit) -> Self {
+        Self { unit, ..self }
+    }
+
+    /// Render the given key/values in the Warp10 attributes block of the time series
+    pub fn with_attributes(self, attributes: &HashMap<String, String>) -> Self {
+        let mut attributes: Vec<String> = attributes
+            .iter()
+            .map(|(k, v)| format!("{}={}", encode(k), encode(v)))
+            .collect();
+
+        attributes.sort();
+        Self {
+            attributes: attributes.join(","),
+            ..self
+        }
+    }
+
+    /// Render the given labels as the Warp10 location of the time series
+    pub fn with_geo(self, geo: Option<&Geo>) -> Self {
+        Self {
+            geo: geo.cloned(),
+            ..self
+        }
+    }
+
+    /// Handle the quantile series of the Prometheus summaries
+    pub fn with_summary(self, summary: SummaryHandling) -> Self {
+        Self { summary, ..self }
+    }
+
+    /// Handle the escape sequences of the Prometheus label values
+    pub fn with_label_escapes(self, escapes: LabelEscapes) -> Self {
+        Self { escapes, ..self }
+    }
+
+    /// Type of the family of the class declared by the formatted lines, `None` if undeclared
+    pub fn type_of(&self, class: &str) -> Option<MetricType> {
+        let types = self.types.borrow();
+        if let Some(kind) = types.get(class) {
+            return Some(*kind);
+        }
+
+        // Samples of a family could have a suffix depending on its type
+        let suffixes: &[(&str, &[MetricType])] = &[
+            ("_total", &[MetricType::Counter]),
+            (
+                "_created",
+                &[
+                    MetricType::Counter,
+                    MetricType::Histogram,
+                    MetricType::Summary,
+                ],
+            ),
+            ("_bucket", &[MetricType::Histogram]),
+            ("_sum", &[MetricType::Histogram, MetricType::Summary]),
+            ("_count", &[MetricType::Histogram, MetricType::Summary]),
+        ];
+
+        suffixes.iter().find_map(|(suffix, kinds)| {
+            let kind = types.get(class.strip_suffix(suffix)?)?;
+            if kinds.contains(kind) {
+                Some(*kind)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Record the types declared by the whole body, so its parts could be formatted apart
+    pub fn declare(&self, body: &str) {
+        if let ScraperFormat::Prometheus | ScraperFormat::Auto = self.format {
+            let mut types = self.types.borrow_mut();
+            for line in body.lines() {
+                declare(line, &mut types);
+            }
+        }
     }
 
     pub fn format(&self, line: &str) -> Result<String, Box<dyn Error>> {
-        match self.format {
-            ScraperFormat::Sensision => format_warp10(line),
-            ScraperFormat::Prometheus => format_prometheus(line, self.now),
+        let line = match self.format {
+            ScraperFormat::Sensision => format_warp10(line, &self.attributes)?,
+            // The format should have been detected, fallback on Prometheus
+            ScraperFormat::Prometheus | ScraperFormat::Auto => {
+                declare(line, &mut self.types.borrow_mut());
+                let line = format_prometheus(
+                    line,
+                    self.unit.scale(self.now),
+                    self.unit,
+                    &mut self.labels.borrow_mut(),
+                    &self.attributes,
+                    self.escapes,
+                )?;
+
+                match self.summary {
+                    SummaryHandling::Passthrough => line,
+                    SummaryHandling::Split => {
+                        split_quantile(&line, &self.types.borrow()).unwrap_or(line)
+                    }
+                }
+            }
+        };
+
+        match self.geo {
+            None => Ok(line),
+            Some(ref geo) => Ok(locate(line, geo)),
         }
     }
 }
 
-/// Format Warp10 metrics from Prometheus one.
-fn format_warp10(line: &str) -> Result<String, Box<dyn Error>> {
-    Ok(String::from(line.trim()))
+/// Current wall-clock time in microseconds
+fn wall_now() -> i64 {
+    let start = now_utc();
+    start.to_timespec().sec * 1_000_000 + (i64::from(start.to_timespec().nsec) as i64 / 1_000)
+}
+
+/// `MonotonicClock` anchors the wall-clock once and then advances using monotonic time, so
+/// timestamps never go backward when the wall-clock is stepped.
+#[derive(Debug, Default)]
+pub struct MonotonicClock {
+    anchor: Option<(i64, Instant)>,
+}
+
+impl MonotonicClock {
+    /// Current time in microseconds
+    pub fn now(&mut self) -> i64 {
+        self.at(wall_now(), Instant::now())
+    }
+
+    /// Time in microseconds at `instant`, `wall` is only used to anchor the clock
+    fn at(&mut self, wall: i64, instant: Instant) -> i64 {
+        let (anchor_wall, anchor_instant) = *self.anchor.get_or_insert((wall, instant));
+        let elapsed = instant.saturating_duration_since(anchor_instant);
+
+        anchor_wall + elapsed.as_micros() as i64
+    }
+}
+
+/// Detect the format of the body using its first time series.
+/// Return `None` if there is no time series in the body, if the time series is ambiguous
+/// Prometheus is assumed.
+pub fn detect(body: &str) -> Option<ScraperFormat> {
+    let line = body
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'))?;
+
+    // Sensision time series start with 'TS/LAT:LON/ELEV ', while Prometheus ones start with the
+    // class which could not contain a '/'.
+    let head = line.split_whitespace().next()?;
+    if !head.contains('{') && head.matches('/').count() == 2 {
+        return Some(ScraperFormat::Sensision);
+    }
+
+    Some(ScraperFormat::Prometheus)
+}
+
+/// Record the type of the family declared by a Prometheus `# TYPE` comment.
+fn declare(line: &str, types: &mut Types) {
+    let mut tokens = line.split_whitespace();
+    if tokens.next() != Some("#") || tokens.next() != Some("TYPE") {
+        return;
+    }
+
+    if let (Some(family), Some(kind)) = (tokens.next(), tokens.next()) {
+        types.insert(encode(family), MetricType::from(kind));
+    }
 }
 
 /// Format Warp10 metrics from Prometheus one.
-fn format_prometheus(line: &str, now: i64) -> Result<String, Box<dyn Error>> {
+/// Attributes are merged with the ones of the time series, if any.
+fn format_warp10(line: &str, attributes: &str) -> Result<String, Box<dyn Error>> {
+    let line = line.trim();
+    if attributes.is_empty() || line.is_empty() || line.starts_with('#') || line.starts_with('=') {
+        return Ok(String::from(line));
+    }
+
+    let index = line.find('}').ok_or("bad class")?;
+    let (gts, rest) = line.split_at(index + 1);
+
+    if let Some(rest) = rest.strip_prefix("{}") {
+        return Ok(format!("{}{{{}}}{}", gts, attributes, rest));
+    }
+
+    if let Some(rest) = rest.strip_prefix('{') {
+        return Ok(format!("{}{{{},{}", gts, attributes, rest));
+    }
+
+    Ok(format!("{}{{{}}}{}", gts, attributes, rest))
+}
+
+/// Move the labels holding the coordinates of a Warp10 time series into its location.
+/// Time series already located, or whose coordinates are missing or malformed, keep their labels.
+fn locate(line: String, geo: &Geo) -> String {
+    if line.is_empty() || line.starts_with('#') || line.starts_with('=') {
+        return line;
+    }
+
+    let (head, rest) = match line.split_once(' ') {
+        Some(parts) => parts,
+        None => return line,
+    };
+
+    let (timestamp, location) = match head.split_once('/') {
+        Some(parts) => parts,
+        None => return line,
+    };
+
+    let (start, end) = match (rest.find('{'), rest.find('}')) {
+        (Some(start), Some(end)) if start < end => (start, end),
+        _ => return line,
+    };
+
+    if location != "/" {
+        return line;
+    }
+
+    let mut labels: Vec<(&str, &str)> = rest[start + 1..end]
+        .split(',')
+        .filter_map(|label| label.split_once('='))
+        .collect();
+
+    let value = |labels: &[(&str, &str)], name: &str| -> Option<String> {
+        let name = encode(name);
+        labels
+            .iter()
+            .find(|(k, _)| *k == name)
+            .map(|(_, v)| v.to_string())
+    };
+
+    let coordinates = match (
+        value(&labels, &geo.latitude).and_then(|v| v.parse::<f64>().ok()),
+        value(&labels, &geo.longitude).and_then(|v| v.parse::<f64>().ok()),
+    ) {
+        (Some(lat), Some(lon))
+            if (-90.0..=90.0).contains(&lat) && (-180.0..=180.0).contains(&lon) =>
+        {
+            let names = [encode(&geo.latitude), encode(&geo.longitude)];
+            labels.retain(|(k, _)| !names.iter().any(|name| name == k));
+            format!("{}:{}", lat, lon)
+        }
+        _ => String::new(),
+    };
+
+    let elevation = match geo.elevation {
+        Some(ref name) => match value(&labels, name).and_then(|v| v.parse::<i64>().ok()) {
+            Some(elevation) => {
+                let name = encode(name);
+                labels.retain(|(k, _)| *k != name);
+                elevation.to_string()
+            }
+            None => String::new(),
+        },
+        None => String::new(),
+    };
+
+    if coordinates.is_empty() && elevation.is_empty() {
+        return line;
+    }
+
+    let labels: Vec<String> = labels.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+    format!(
+        "{}/{}/{} {}{{{}}}{}",
+        timestamp,
+        coordinates,
+        elevation,
+        &rest[..start],
+        labels.join(","),
+        &rest[end + 1..]
+    )
+}
+
+/// Move the `quantile` label of a summary series in its class, e.g. `f{quantile=0.99}` is written
+/// as `f_p99{}`, `None` for the series of families which are not declared as summaries or holding
+/// a quantile which could not be named.
+fn split_quantile(line: &str, types: &Types) -> Option<String> {
+    let (head, rest) = line.split_at(line.find("// ")? + 3);
+    let (class, rest) = rest.split_at(rest.find('{')?);
+    let (labels, rest) = rest[1..].split_at(rest[1..].find('}')?);
+
+    if !matches!(types.get(class), Some(MetricType::Summary)) {
+        return None;
+    }
+
+    let mut quantile = None;
+    let labels: Vec<&str> = labels
+        .split(',')
+        .filter(|label| match label.strip_prefix("quantile=") {
+            Some(value) => {
+                quantile = Some(value);
+                false
+            }
+            None => true,
+        })
+        .collect();
+
+    let suffix = quantile_suffix(quantile?)?;
+    Some(format!(
+        "{}{}_{}{{{}{}",
+        head,
+        class,
+        suffix,
+        labels.join(","),
+        rest
+    ))
+}
+
+/// Name of the quantile used as a class suffix, e.g. `p99` for `0.99` or `p50` for `0.5`
+fn quantile_suffix(quantile: &str) -> Option<String> {
+    match quantile {
+        "0" => return Some(String::from("p0")),
+        "1" | "1.0" => return Some(String::from("p100")),
+        _ => {}
+    }
+
+    let digits = quantile.strip_prefix("0.")?;
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    // A single digit is a tenth, e.g. `0.9` is the 90th percentile
+    if digits.len() == 1 {
+        Some(format!("p{}0", digits))
+    } else {
+        Some(format!("p{}", digits))
+    }
+}
+
+/// Format Warp10 labels from the keys and values, they are encoded as the labels of the scraped
+/// time series
+pub fn encode_pairs(pairs: &[(&str, &str)]) -> String {
+    let plabels: Vec<String> = pairs
+        .iter()
+        .map(|(key, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+
+            format!("{}=\"{}\"", key, value)
+        })
+        .collect();
+
+    encode_labels(&format!("{}}}", plabels.join(",")), LabelEscapes::Decode)
+}
+
+/// Format Warp10 labels from Prometheus one. Using `LabelEscapes::Decode`, the escape sequences of
+/// the values are decoded before encoding, so an escaped newline is written as `%0A` instead of
+/// `%5Cn`.
+fn encode_labels(plabels: &str, escapes: LabelEscapes) -> String {
+    let mut labels = String::new();
+    let mut in_label = false;
+    let mut escaped = false;
+    let mut buffer = String::new();
+    for c in plabels.chars() {
+        if escaped {
+            escaped = false;
+            match c {
+                'n' => buffer.push('\n'),
+                '\\' | '"' => buffer.push(c),
+                _ => {
+                    buffer.push('\\');
+                    buffer.push(c);
+                }
+            }
+            continue;
+        }
+
+        if in_label && c == '\\' && escapes == LabelEscapes::Decode {
+            escaped = true;
+            continue;
+        }
+
+        if c == '"' {
+            in_label = !in_label;
+            continue;
+        }
+
+        if !in_label && (c == '=' || c == ',' || c == '}') {
+            labels.push_str(&encode(&buffer));
+            buffer = String::new();
+
+            if c == ',' {
+                labels.push(',');
+            }
+            if c == '=' {
+                labels.push('=');
+            }
+            continue;
+        }
+
+        buffer.push(c);
+    }
+    labels
+}
+
+/// Format Warp10 metrics from Prometheus one, `now` and the timestamps are written in the given
+/// unit. Encoded labels blocks are kept in the given cache as the same labels block is often shared
+/// by several time series.
+fn format_prometheus(
+    line: &str,
+    now: i64,
+    unit: TimeUnit,
+    labels: &mut LabelsCache,
+    attributes: &str,
+    escapes: LabelEscapes,
+) -> Result<String, Box<dyn Error>> {
     let line = line.trim();
 
     // Skip comments or empty line
@@ -61,7 +508,7 @@ fn format_prometheus(line: &str, now: i64) -> Result<String, Box<dyn Error>> {
 
     let timestamp = tokens.next().map_or(now, |v| {
         i64::from_str_radix(v, 10)
-            .map(|v| v * 1000)
+            .map(|v| v * unit.per_milli())
             .unwrap_or_else(|_| now)
     });
 
@@ -72,57 +519,427 @@ fn format_prometheus(line: &str, now: i64) -> Result<String, Box<dyn Error>> {
     let plabels = parts.next();
     let slabels = match plabels {
         None => String::new(),
-        Some(plabels) => {
-            let mut labels = String::new();
-            let mut in_label = false;
-            let mut buffer = String::new();
-            for c in plabels.chars() {
-                if c == '"' {
-                    in_label = !in_label;
-                    continue;
-                }
-
-                if !in_label {
-                    if c == '=' || c == ',' || c == '}' {
-                        labels.push_str(& encode(&buffer));
-                        buffer = String::new();
-
-                        if c == ',' {
-                            labels.push(',');
-                        }
-                        if c == '=' {
-                            labels.push('=');
-                        }
-                        continue;
-                    }
-                }
-
-                buffer.push(c);                
+        Some(plabels) => match labels.get(plabels) {
+            Some(slabels) => slabels.to_owned(),
+            None => {
+                let slabels = encode_labels(plabels, escapes);
+                labels.insert(plabels.to_owned(), slabels.to_owned());
+                slabels
             }
-            labels
-        }
+        },
     };
 
-    let class = format!("{}{{{}}}", class, slabels);
+    let class = if attributes.is_empty() {
+        format!("{}{{{}}}", class, slabels)
+    } else {
+        format!("{}{{{}}}{{{}}}", class, slabels, attributes)
+    };
 
     Ok(format!("{}// {} {}", timestamp, class, value))
 }
 
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
     use super::*;
 
+    #[test]
+    fn detect_sensision() {
+        let body = "1562656816000000// f{type=count} 1486\n=1562656816000001// 1487";
+        assert_eq!(Some(ScraperFormat::Sensision), super::detect(body));
+
+        let body = "// f{} 1486";
+        assert_eq!(Some(ScraperFormat::Sensision), super::detect(body));
+
+        let body = "1562656816000000/48.0:-4.5/10 f{} 1486";
+        assert_eq!(Some(ScraperFormat::Sensision), super::detect(body));
+    }
+
+    #[test]
+    fn detect_prometheus() {
+        let body = "# HELP f ...\n# TYPE f counter\nf{path=\"/a b/\"} 1486 1562656816000";
+        assert_eq!(Some(ScraperFormat::Prometheus), super::detect(body));
+
+        let body = "\nf 1486";
+        assert_eq!(Some(ScraperFormat::Prometheus), super::detect(body));
+    }
+
+    #[test]
+    fn detect_nothing() {
+        assert_eq!(None, super::detect("# HELP f ...\n\n"));
+    }
+
+    #[test]
+    fn summary_handling() {
+        let lines = vec![
+            "# TYPE rpc_duration_seconds summary",
+            "rpc_duration_seconds{service=\"a\",quantile=\"0.99\"} 7 1000",
+            "rpc_duration_seconds{quantile=\"0.5\"} 3 1000",
+            "rpc_duration_seconds{quantile=\"0.9\"} 5 1000",
+            "rpc_duration_seconds_sum{service=\"a\"} 17 1000",
+            "rpc_duration_seconds_count{service=\"a\"} 4 1000",
+            "# TYPE http_requests gauge",
+            "http_requests{quantile=\"0.99\"} 2 1000",
+            "untyped{quantile=\"0.99\"} 1 1000",
+            "# TYPE rpc_latency_seconds summary",
+            "rpc_latency_seconds{quantile=\"1e-3\"} 1 1000",
+        ];
+
+        let format = |summary| {
+            let transcompiler = Transcompiler::new(ScraperFormat::Prometheus).with_summary(summary);
+            lines
+                .iter()
+                .map(|line| transcompiler.format(line).unwrap())
+                .filter(|line| !line.is_empty())
+                .collect::<Vec<String>>()
+        };
+
+        // Quantile series are kept as is by default
+        assert_eq!(
+            vec![
+                "1000000// rpc_duration_seconds{service=a,quantile=0.99} 7",
+                "1000000// rpc_duration_seconds{quantile=0.5} 3",
+                "1000000// rpc_duration_seconds{quantile=0.9} 5",
+                "1000000// rpc_duration_seconds_sum{service=a} 17",
+                "1000000// rpc_duration_seconds_count{service=a} 4",
+                "1000000// http_requests{quantile=0.99} 2",
+                "1000000// untyped{quantile=0.99} 1",
+                "1000000// rpc_latency_seconds{quantile=1e-3} 1",
+            ],
+            format(SummaryHandling::Passthrough)
+        );
+
+        // Quantiles are moved in the class of the series declared as summaries only, a quantile
+        // which could not be named is kept as a label
+        assert_eq!(
+            vec![
+                "1000000// rpc_duration_seconds_p99{service=a} 7",
+                "1000000// rpc_duration_seconds_p50{} 3",
+                "1000000// rpc_duration_seconds_p90{} 5",
+                "1000000// rpc_duration_seconds_sum{service=a} 17",
+                "1000000// rpc_duration_seconds_count{service=a} 4",
+                "1000000// http_requests{quantile=0.99} 2",
+                "1000000// untyped{quantile=0.99} 1",
+                "1000000// rpc_latency_seconds{quantile=1e-3} 1",
+            ],
+            format(SummaryHandling::Split)
+        );
+    }
+
+    #[test]
+    fn prometheus_escaped_labels() {
+        let line = super::format_prometheus(
+            "f{msg=\"a\\nb\",path=\"C:\\\\tmp\",quote=\"say \\\"hi\\\"\",tab=\"a\tb\"} 1 1000",
+            1,
+            TimeUnit::Micros,
+            &mut LabelsCache::new(),
+            "",
+            LabelEscapes::Decode,
+        )
+        .unwrap();
+
+        // Newlines and tabs are encoded, so a sample is always written on a single line
+        assert_eq!(
+            "1000000// f{msg=a%0Ab,path=C%3A%5Ctmp,quote=say%20%22hi%22,tab=a%09b} 1",
+            line
+        );
+        assert!(!line.contains('\n'));
+
+        // Escape sequences are encoded as is by default, so the series are left unchanged
+        let line = super::format_prometheus(
+            "f{msg=\"a\\nb\",path=\"C:\\\\tmp\"} 1 1000",
+            1,
+            TimeUnit::Micros,
+            &mut LabelsCache::new(),
+            "",
+            LabelEscapes::Keep,
+        )
+        .unwrap();
+        assert_eq!("1000000// f{msg=a%5Cnb,path=C%3A%5C%5Ctmp} 1", line);
+
+        let transcompiler =
+            Transcompiler::new(ScraperFormat::Prometheus).with_label_escapes(LabelEscapes::Decode);
+        assert_eq!(
+            "1000000// f{path=C%3A%5Ctmp} 1",
+            transcompiler
+                .format("f{path=\"C:\\\\tmp\"} 1 1000")
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn prometheus_labels_cache() {
+        let lines = vec![
+            "f{job_id=\"1 3\",host=\"a\"} 1",
+            "g{job_id=\"1 3\",host=\"a\"} 2",
+            "f{job_id=\"1 3\",host=\"b\"} 3",
+            "g{job_id=\"1 3\",host=\"b\"} 4 1000",
+            "h 5",
+        ];
+
+        let mut cache = LabelsCache::new();
+        for line in lines {
+            let expected = super::format_prometheus(
+                line,
+                1,
+                TimeUnit::Micros,
+                &mut LabelsCache::new(),
+                "",
+                LabelEscapes::Keep,
+            );
+            let result = super::format_prometheus(
+                line,
+                1,
+                TimeUnit::Micros,
+                &mut cache,
+                "",
+                LabelEscapes::Keep,
+            );
+            assert_eq!(expected.unwrap(), result.unwrap());
+        }
+
+        // Labels are encoded once per distinct labels block
+        assert_eq!(2, cache.len());
+
+        let transcompiler = Transcompiler::new(ScraperFormat::Prometheus);
+        assert_eq!(
+            "1000000// g{job_id=1%203,host=b} 4",
+            transcompiler
+                .format("g{job_id=\"1 3\",host=\"b\"} 4 1000")
+                .unwrap()
+        );
+        assert_eq!(
+            "1000000// f{job_id=1%203,host=b} 4",
+            transcompiler
+                .format("f{job_id=\"1 3\",host=\"b\"} 4 1000")
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn monotonic_clock_ignores_wall_clock_steps() {
+        let mut clock = MonotonicClock::default();
+        let start = Instant::now();
+
+        assert_eq!(1_000_000_000, clock.at(1_000_000_000, start));
+
+        // The wall-clock is stepped backward by 10 minutes
+        let now = clock.at(400_000_000, start + Duration::from_secs(1));
+        assert_eq!(1_001_000_000, now);
+
+        let mut last = now;
+        for idx in 2..10 {
+            let now = clock.at(400_000_000, start + Duration::from_secs(idx));
+            assert!(now >= last);
+            last = now;
+        }
+
+        let transcompiler = Transcompiler::new(ScraperFormat::Prometheus).at(last);
+        assert_eq!(
+            format!("{}// f{{}} 4", last),
+            transcompiler.format("f 4").unwrap()
+        );
+    }
+
+    #[test]
+    fn platform_time_units() {
+        let units = vec![
+            (TimeUnit::Millis, "1000", "1500"),
+            (TimeUnit::Micros, "1000000", "1500000"),
+            (TimeUnit::Nanos, "1000000000", "1500000000"),
+        ];
+
+        for (unit, timestamp, now) in units {
+            let transcompiler = Transcompiler::new(ScraperFormat::Prometheus)
+                .with_time_unit(unit)
+                .at(1_500_000);
+
+            assert_eq!(
+                format!("{}// f{{host=a}} 4", timestamp),
+                transcompiler.format("f{host=\"a\"} 4 1000").unwrap()
+            );
+            assert_eq!(
+                format!("{}// f{{host=a}} 4", now),
+                transcompiler.format("f{host=\"a\"} 4").unwrap()
+            );
+        }
+
+        // Warp10 time series are already written in the unit of the platform
+        let transcompiler = Transcompiler::new(ScraperFormat::Sensision)
+            .with_time_unit(TimeUnit::Millis)
+            .at(1_500_000);
+        assert_eq!(
+            "1000000// f{host=a} 4",
+            transcompiler.format("1000000// f{host=a} 4").unwrap()
+        );
+    }
+
+    #[test]
+    fn attributes() {
+        let mut attributes = HashMap::new();
+        attributes.insert(String::from("owner"), String::from("team a"));
+        attributes.insert(String::from("dc"), String::from("rbx"));
+
+        let labels_only = Transcompiler::new(ScraperFormat::Prometheus);
+        let with_attributes =
+            Transcompiler::new(ScraperFormat::Prometheus).with_attributes(&attributes);
+
+        let line = "f{host=\"a\"} 4 1000";
+        assert_eq!("1000000// f{host=a} 4", labels_only.format(line).unwrap());
+        assert_eq!(
+            "1000000// f{host=a}{dc=rbx,owner=team%20a} 4",
+            with_attributes.format(line).unwrap()
+        );
+        assert_eq!(
+            "1000000// f{}{dc=rbx,owner=team%20a} 4",
+            with_attributes.format("f 4 1000").unwrap()
+        );
+
+        let labels_only = Transcompiler::new(ScraperFormat::Sensision);
+        let with_attributes =
+            Transcompiler::new(ScraperFormat::Sensision).with_attributes(&attributes);
+
+        let line = "1000// f{host=a} 4";
+        assert_eq!(line, labels_only.format(line).unwrap());
+        assert_eq!(
+            "1000// f{host=a}{dc=rbx,owner=team%20a} 4",
+            with_attributes.format(line).unwrap()
+        );
+        assert_eq!(
+            "1000// f{host=a}{dc=rbx,owner=team%20a} 4",
+            with_attributes.format("1000// f{host=a}{} 4").unwrap()
+        );
+        assert_eq!(
+            "1000// f{host=a}{dc=rbx,owner=team%20a,rack=1} 4",
+            with_attributes
+                .format("1000// f{host=a}{rack=1} 4")
+                .unwrap()
+        );
+        assert_eq!("=1001// 5", with_attributes.format("=1001// 5").unwrap());
+    }
+
+    #[test]
+    fn geo() {
+        let geo = Geo {
+            latitude: String::from("lat"),
+            longitude: String::from("lon"),
+            elevation: Some(String::from("elev")),
+        };
+
+        let plain = Transcompiler::new(ScraperFormat::Prometheus);
+        let located = Transcompiler::new(ScraperFormat::Prometheus).with_geo(Some(&geo));
+
+        let line = "temp{lat=\"48.85\",lon=\"2.35\",elev=\"35000\",room=\"a\"} 21 1000";
+        assert_eq!(
+            "1000000// temp{lat=48.85,lon=2.35,elev=35000,room=a} 21",
+            plain.format(line).unwrap()
+        );
+        assert_eq!(
+            "1000000/48.85:2.35/35000 temp{room=a} 21",
+            located.format(line).unwrap()
+        );
+        assert_eq!(
+            "1000000/48.85:-2.35/ temp{} 21",
+            located
+                .format("temp{lat=\"48.85\",lon=\"-2.35\"} 21 1000")
+                .unwrap()
+        );
+
+        // Missing or malformed coordinates are kept as labels
+        assert_eq!(
+            "1000000//35000 temp{lat=48.85,room=a} 21",
+            located
+                .format("temp{lat=\"48.85\",elev=\"35000\",room=\"a\"} 21 1000")
+                .unwrap()
+        );
+        assert_eq!(
+            "1000000// temp{lat=north,lon=2.35} 21",
+            located
+                .format("temp{lat=\"north\",lon=\"2.35\"} 21 1000")
+                .unwrap()
+        );
+        assert_eq!(
+            "1000000// temp{room=a} 21",
+            located.format("temp{room=\"a\"} 21 1000").unwrap()
+        );
+        assert_eq!(
+            "1000000// temp{} 21",
+            located.format("temp 21 1000").unwrap()
+        );
+
+        // Located Warp10 time series are kept as is
+        let located = Transcompiler::new(ScraperFormat::Sensision).with_geo(Some(&geo));
+        assert_eq!(
+            "1000/1.0:2.0/ temp{lat=3.0,lon=4.0} 21",
+            located
+                .format("1000/1.0:2.0/ temp{lat=3.0,lon=4.0} 21")
+                .unwrap()
+        );
+        assert_eq!(
+            "1000/3:4/ temp{} 21",
+            located.format("1000// temp{lat=3,lon=4} 21").unwrap()
+        );
+        assert_eq!("=1001// 22", located.format("=1001// 22").unwrap());
+    }
+
+    #[test]
+    fn prometheus_types() {
+        let transcompiler = Transcompiler::new(ScraperFormat::Prometheus);
+        let body = "# HELP requests Requests\n# TYPE requests counter\nrequests_total{} 1\n# TYPE temp gauge\ntemp 21\n# TYPE latency histogram\nlatency_bucket{le=\"1\"} 2\nlatency_sum 3\n# TYPE ns:cpu gauge\nuntyped 4";
+        for line in body.lines() {
+            transcompiler.format(line).unwrap();
+        }
+
+        assert_eq!(Some(MetricType::Counter), transcompiler.type_of("requests"));
+        assert_eq!(
+            Some(MetricType::Counter),
+            transcompiler.type_of("requests_total")
+        );
+        assert_eq!(Some(MetricType::Gauge), transcompiler.type_of("temp"));
+        assert_eq!(None, transcompiler.type_of("temp_total"));
+        assert_eq!(
+            Some(MetricType::Histogram),
+            transcompiler.type_of("latency_bucket")
+        );
+        assert_eq!(
+            Some(MetricType::Histogram),
+            transcompiler.type_of("latency_sum")
+        );
+        assert_eq!(Some(MetricType::Gauge), transcompiler.type_of("ns%3Acpu"));
+        assert_eq!(None, transcompiler.type_of("untyped"));
+
+        // Types are not declared by Warp10 time series
+        let transcompiler = Transcompiler::new(ScraperFormat::Sensision);
+        transcompiler.format("# TYPE requests counter").unwrap();
+        assert_eq!(None, transcompiler.type_of("requests"));
+    }
+
     #[test]
     fn prometheus_skip_infinity() {
         let line = "f{job_id=\"123\"} +Inf";
         let expected: Result<String, Box<dyn Error>> = Ok(String::new());
-        let result = super::format_prometheus(line, 1);
+        let result = super::format_prometheus(
+            line,
+            1,
+            TimeUnit::Micros,
+            &mut LabelsCache::new(),
+            "",
+            LabelEscapes::Keep,
+        );
         assert_eq!(expected.is_ok(), result.is_ok());
         assert_eq!(expected.unwrap(), result.unwrap());
 
         let line = "f{job_id=\"123\"} -Inf";
         let expected: Result<String, Box<dyn Error>> = Ok(String::new());
-        let result = super::format_prometheus(line, 1);
+        let result = super::format_prometheus(
+            line,
+            1,
+            TimeUnit::Micros,
+            &mut LabelsCache::new(),
+            "",
+            LabelEscapes::Keep,
+        );
         assert_eq!(expected.is_ok(), result.is_ok());
         assert_eq!(expected.unwrap(), result.unwrap());
     }
@@ -131,7 +948,14 @@ mod tests {
     fn prometheus_skip_empty() {
         let line = "";
         let expected: Result<String, Box<dyn Error>> = Ok(String::new());
-        let result = super::format_prometheus(line, 1);
+        let result = super::format_prometheus(
+            line,
+            1,
+            TimeUnit::Micros,
+            &mut LabelsCache::new(),
+            "",
+            LabelEscapes::Keep,
+        );
         assert_eq!(expected.is_ok(), result.is_ok());
         assert_eq!(expected.unwrap(), result.unwrap());
     }
@@ -140,7 +964,14 @@ mod tests {
     fn prometheus_skip_comment() {
         let line = "# HELP ...";
         let expected: Result<String, Box<dyn Error>> = Ok(String::new());
-        let result = super::format_prometheus(line, 1);
+        let result = super::format_prometheus(
+            line,
+            1,
+            TimeUnit::Micros,
+            &mut LabelsCache::new(),
+            "",
+            LabelEscapes::Keep,
+        );
         assert_eq!(expected.is_ok(), result.is_ok());
         assert_eq!(expected.unwrap(), result.unwrap());
     }
@@ -149,13 +980,27 @@ mod tests {
     fn prometheus_skip_nan() {
         let line = "f{job_id=\"123\"} nan";
         let expected: Result<String, Box<dyn Error>> = Ok(String::new());
-        let result = super::format_prometheus(line, 1);
+        let result = super::format_prometheus(
+            line,
+            1,
+            TimeUnit::Micros,
+            &mut LabelsCache::new(),
+            "",
+            LabelEscapes::Keep,
+        );
         assert_eq!(expected.is_ok(), result.is_ok());
         assert_eq!(expected.unwrap(), result.unwrap());
 
         let line = "f{job_id=\"123\"} NaN";
         let expected: Result<String, Box<dyn Error>> = Ok(String::new());
-        let result = super::format_prometheus(line, 1);
+        let result = super::format_prometheus(
+            line,
+            1,
+            TimeUnit::Micros,
+            &mut LabelsCache::new(),
+            "",
+            LabelEscapes::Keep,
+        );
         assert_eq!(expected.is_ok(), result.is_ok());
         assert_eq!(expected.unwrap(), result.unwrap());
     }
@@ -164,31 +1009,66 @@ mod tests {
     fn prometheus_urlencoding() {
         let line = "f{job_id=\"1%3\"} 1";
         let expected: Result<String, Box<dyn Error>> = Ok(String::from("1// f{job_id=1%253} 1"));
-        let result = super::format_prometheus(line, 1);
+        let result = super::format_prometheus(
+            line,
+            1,
+            TimeUnit::Micros,
+            &mut LabelsCache::new(),
+            "",
+            LabelEscapes::Keep,
+        );
         assert_eq!(expected.is_ok(), result.is_ok());
         assert_eq!(expected.unwrap(), result.unwrap());
 
         let line = "f{job_id=\"1%3\"} 1";
         let expected: Result<String, Box<dyn Error>> = Ok(String::from("1// f{job_id=1%253} 1"));
-        let result = super::format_prometheus(line, 1);
+        let result = super::format_prometheus(
+            line,
+            1,
+            TimeUnit::Micros,
+            &mut LabelsCache::new(),
+            "",
+            LabelEscapes::Keep,
+        );
         assert_eq!(expected.is_ok(), result.is_ok());
         assert_eq!(expected.unwrap(), result.unwrap());
 
         let line = "f{job_id=\"1%3\"} 1";
         let expected: Result<String, Box<dyn Error>> = Ok(String::from("1// f{job_id=1%253} 1"));
-        let result = super::format_prometheus(line, 1);
+        let result = super::format_prometheus(
+            line,
+            1,
+            TimeUnit::Micros,
+            &mut LabelsCache::new(),
+            "",
+            LabelEscapes::Keep,
+        );
         assert_eq!(expected.is_ok(), result.is_ok());
         assert_eq!(expected.unwrap(), result.unwrap());
 
         let line = "f{job_id=\"1 3\"} 1";
         let expected: Result<String, Box<dyn Error>> = Ok(String::from("1// f{job_id=1%203} 1"));
-        let result = super::format_prometheus(line, 1);
+        let result = super::format_prometheus(
+            line,
+            1,
+            TimeUnit::Micros,
+            &mut LabelsCache::new(),
+            "",
+            LabelEscapes::Keep,
+        );
         assert_eq!(expected.is_ok(), result.is_ok());
         assert_eq!(expected.unwrap(), result.unwrap());
 
         let line = "f{job_id=\"1+3\"} 1";
         let expected: Result<String, Box<dyn Error>> = Ok(String::from("1// f{job_id=1%2B3} 1"));
-        let result = super::format_prometheus(line, 1);
+        let result = super::format_prometheus(
+            line,
+            1,
+            TimeUnit::Micros,
+            &mut LabelsCache::new(),
+            "",
+            LabelEscapes::Keep,
+        );
         assert_eq!(expected.is_ok(), result.is_ok());
         assert_eq!(expected.unwrap(), result.unwrap());
     }
@@ -2,13 +2,16 @@
 //!
 //! This module provides the log facility.
 use std::cmp::min;
+use std::collections::HashMap;
 use std::fs::create_dir_all;
 use std::fs::OpenOptions;
 use std::os::unix::fs::OpenOptionsExt;
 use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 use failure::{Error, ResultExt};
-use slog::{Drain, Duplicate, Level, LevelFilter, Logger};
+use slog::{Drain, Duplicate, Level, LevelFilter, Logger, OwnedKVList, Record};
 use slog_async::Async;
 use slog_scope::{set_global_logger, GlobalLoggerGuard as Guard};
 use slog_syslog::{unix_3164, Facility};
@@ -80,6 +83,7 @@ pub fn initialize(verbose: usize, parameters: &Parameters) -> Result<Guard, Erro
         )
         .fuse();
 
+        let drain = RateLimit::new(drain, parameters.log_rate_limit);
         return Ok(set_global_logger(Logger::root(drain, o!())));
     }
 
@@ -92,5 +96,287 @@ pub fn initialize(verbose: usize, parameters: &Parameters) -> Result<Guard, Erro
     )
     .fuse();
 
+    let drain = RateLimit::new(drain, parameters.log_rate_limit);
     Ok(set_global_logger(Logger::root(drain, o!())))
 }
+
+/// `Decision` of the limiter for a log line
+#[derive(Debug, PartialEq)]
+enum Decision {
+    Allow,
+    Suppress,
+}
+
+/// `Summary` of the lines of a message suppressed during an expired window
+#[derive(Debug, PartialEq)]
+struct Summary {
+    key: String,
+    level: Level,
+    suppressed: u64,
+}
+
+/// `Window` counts the lines of a message during a second
+#[derive(Debug)]
+struct Window {
+    start: Instant,
+    level: Level,
+    count: u32,
+    suppressed: u64,
+}
+
+/// `Limiter` bounds the number of lines per second of each message
+#[derive(Debug)]
+struct Limiter {
+    max: u32,
+    swept: Instant,
+    windows: HashMap<String, Window>,
+}
+
+impl Limiter {
+    fn new(max: u32, now: Instant) -> Self {
+        Self {
+            max,
+            swept: now,
+            windows: HashMap::new(),
+        }
+    }
+
+    /// Check a line of the message, the windows expired since the last sweep are removed, so the
+    /// map only holds the messages of the last seconds, and their suppressed lines are summarized
+    fn check(&mut self, key: &str, level: Level, now: Instant) -> (Vec<Summary>, Decision) {
+        let mut summaries = vec![];
+        if now.duration_since(self.swept) >= Duration::from_secs(1) {
+            self.swept = now;
+            self.windows.retain(|key, window| {
+                if now.duration_since(window.start) < Duration::from_secs(1) {
+                    return true;
+                }
+
+                if window.suppressed > 0 {
+                    summaries.push(Summary {
+                        key: key.to_owned(),
+                        level: window.level,
+                        suppressed: window.suppressed,
+                    });
+                }
+
+                false
+            });
+        }
+
+        let window = self.windows.entry(key.to_owned()).or_insert(Window {
+            start: now,
+            level,
+            count: 0,
+            suppressed: 0,
+        });
+
+        if now.duration_since(window.start) >= Duration::from_secs(1) {
+            if window.suppressed > 0 {
+                summaries.push(Summary {
+                    key: key.to_owned(),
+                    level: window.level,
+                    suppressed: window.suppressed,
+                });
+            }
+
+            *window = Window {
+                start: now,
+                level,
+                count: 0,
+                suppressed: 0,
+            };
+        }
+
+        if window.count >= self.max {
+            window.suppressed += 1;
+            return (summaries, Decision::Suppress);
+        }
+
+        window.count += 1;
+        (summaries, Decision::Allow)
+    }
+}
+
+/// `RateLimit` drain collapses repeated warning and error lines of a message, suppressed lines
+/// are summarized by the next warning or error line once their window is over.
+struct RateLimit<D> {
+    drain: D,
+    limiter: Option<Mutex<Limiter>>,
+}
+
+impl<D: Drain<Ok = ()>> RateLimit<D> {
+    fn new(drain: D, max: Option<u32>) -> Self {
+        Self {
+            drain,
+            limiter: max.map(|max| Mutex::new(Limiter::new(max, Instant::now()))),
+        }
+    }
+
+    fn log_at(&self, record: &Record, values: &OwnedKVList, now: Instant) -> Result<(), D::Err> {
+        let limiter = match &self.limiter {
+            Some(limiter) if record.level().is_at_least(Level::Warning) => limiter,
+            _ => return self.drain.log(record, values),
+        };
+
+        let key = record.msg().to_string();
+        let (summaries, decision) = match limiter.lock() {
+            Ok(mut limiter) => limiter.check(&key, record.level(), now),
+            // Never lose a line because of the limiter
+            Err(_) => (vec![], Decision::Allow),
+        };
+
+        for summary in summaries {
+            let rstatic = record_static!(summary.level, "");
+            self.drain.log(
+                &Record::new(
+                    &rstatic,
+                    &format_args!("suppressed {} similar messages", summary.suppressed),
+                    b!("message" => summary.key.as_str()),
+                ),
+                values,
+            )?;
+        }
+
+        match decision {
+            Decision::Suppress => Ok(()),
+            Decision::Allow => self.drain.log(record, values),
+        }
+    }
+}
+
+impl<D: Drain<Ok = ()>> Drain for RateLimit<D> {
+    type Ok = ();
+    type Err = D::Err;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<Self::Ok, Self::Err> {
+        self.log_at(record, values, Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    use slog::{Drain, Level, Never, OwnedKVList, Record};
+
+    use super::{Decision, Limiter, RateLimit, Summary};
+
+    /// `Collect` drain keeps the logged messages
+    #[derive(Default)]
+    struct Collect {
+        lines: Mutex<Vec<String>>,
+    }
+
+    impl Drain for Collect {
+        type Ok = ();
+        type Err = Never;
+
+        fn log(&self, record: &Record, _: &OwnedKVList) -> Result<(), Never> {
+            self.lines
+                .lock()
+                .expect("lines")
+                .push(record.msg().to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn limit_lines_per_message() {
+        let now = Instant::now();
+        let mut limiter = Limiter::new(2, now);
+        let mut check = |key: &str, at: Instant| limiter.check(key, Level::Error, at);
+
+        assert_eq!((vec![], Decision::Allow), check("could not send data", now));
+        assert_eq!((vec![], Decision::Allow), check("could not send data", now));
+        assert_eq!(
+            (vec![], Decision::Suppress),
+            check("could not send data", now)
+        );
+        assert_eq!(
+            (vec![], Decision::Suppress),
+            check("could not send data", now + Duration::from_millis(500))
+        );
+
+        // Other messages have their own budget
+        assert_eq!((vec![], Decision::Allow), check("could not fetch", now));
+
+        // The next window summarizes the suppressed lines
+        let now = now + Duration::from_secs(1);
+        let summary = |suppressed| Summary {
+            key: String::from("could not send data"),
+            level: Level::Error,
+            suppressed,
+        };
+
+        assert_eq!(
+            (vec![summary(2)], Decision::Allow),
+            check("could not send data", now)
+        );
+        assert_eq!((vec![], Decision::Allow), check("could not send data", now));
+        assert_eq!(
+            (vec![], Decision::Suppress),
+            check("could not send data", now)
+        );
+
+        // A message which stopped is summarized by the line of any other message
+        let now = now + Duration::from_secs(1);
+        assert_eq!(
+            (vec![summary(1)], Decision::Allow),
+            check("could not fetch", now)
+        );
+    }
+
+    #[test]
+    fn sweep_expired_windows() {
+        let now = Instant::now();
+        let mut limiter = Limiter::new(1, now);
+
+        for i in 0..100 {
+            limiter.check(&format!("could not open file {}", i), Level::Error, now);
+        }
+        assert_eq!(100, limiter.windows.len());
+
+        // Windows of the messages which were not logged during the last second are removed
+        let now = now + Duration::from_secs(1);
+        limiter.check("could not open file 0", Level::Error, now);
+        assert_eq!(1, limiter.windows.len());
+    }
+
+    #[test]
+    fn summarize_suppressed_lines() {
+        let drain = RateLimit::new(Collect::default(), Some(1));
+        let values = OwnedKVList::from(o!());
+        let now = Instant::now();
+
+        for (level, msg, at) in &[
+            (Level::Error, "could not send data", 0),
+            (Level::Error, "could not send data", 100),
+            (Level::Error, "could not send data", 200),
+            (Level::Debug, "rotate file", 300),
+            (Level::Debug, "rotate file", 400),
+            (Level::Error, "could not send data", 1_000),
+        ] {
+            let record = record_static!(*level, "");
+            drain
+                .log_at(
+                    &Record::new(&record, &format_args!("{}", msg), b!()),
+                    &values,
+                    now + Duration::from_millis(*at),
+                )
+                .expect("log line");
+        }
+
+        assert_eq!(
+            vec![
+                "could not send data",
+                "rotate file",
+                "rotate file",
+                "suppressed 2 similar messages",
+                "could not send data",
+            ],
+            *drain.drain.lines.lock().expect("lines")
+        );
+    }
+}
@@ -11,10 +11,12 @@ extern crate slog;
 extern crate slog_scope;
 
 use std::convert::TryFrom;
+use std::path::PathBuf;
 use std::process::abort;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::thread;
-use std::time::Duration;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 use failure::{format_err, Error};
 use prometheus::Counter;
@@ -37,10 +39,13 @@ pub(crate) mod lib;
 pub(crate) mod cmd;
 pub(crate) mod conf;
 pub(crate) mod constants;
+pub(crate) mod discovery;
+pub(crate) mod heartbeat;
 pub(crate) mod log;
 pub(crate) mod router;
 pub(crate) mod scraper;
 pub(crate) mod sink;
+pub(crate) mod status;
 pub(crate) mod version;
 
 #[paw::main]
@@ -129,11 +134,14 @@ fn main(opts: Opts) -> Result<(), Error> {
 
     // -------------------------------------------------------------------------
     // Start beamium scraper, sinks and metrics
+    let started = Instant::now();
+    let max_lifetime = conf.parameters.max_lifetime;
     let signal = arc!(AtomicBool::new(true));
     let rx = signal.to_owned();
     let main_is_ready = cmd_main_is_ready.to_owned();
+    let mut drain = opts.drain;
     let mut handler = thread::spawn(move || {
-        if let Err(err) = cmd::main(conf, rx, main_is_ready) {
+        if let Err(err) = cmd::main(conf, rx, main_is_ready, drain) {
             crit!("{}", err);
             thread::sleep(Duration::from_millis(100)); // Sleep the time to display the message
             abort();
@@ -155,6 +163,40 @@ fn main(opts: Opts) -> Result<(), Error> {
             break;
         }
 
+        // In drain mode, cmd::main stops by itself once spooled files are sent
+        if drain && !cmd_main_is_ready.load(Ordering::SeqCst) {
+            if handler.join().is_err() {
+                crit!("could not stop main thread");
+            }
+
+            break;
+        }
+
+        // Once the max lifetime is elapsed, cmd::main is started again in drain mode, so spooled
+        // files are sent before halting
+        if !drain && cmd::is_expired(started, max_lifetime, Instant::now()) {
+            info!("max lifetime is elapsed, drain spooled files before halting");
+            signal.store(false, Ordering::SeqCst);
+            if handler.join().is_err() {
+                crit!("could not stop main thread");
+                break;
+            }
+
+            drain = true;
+            handler = spawn(
+                opts.config.to_owned(),
+                signal.to_owned(),
+                cmd_main_is_ready.to_owned(),
+                drain,
+            );
+
+            // cmd::main could already be stopped if there is no spooled file
+            while !cmd_main_is_ready.load(Ordering::SeqCst) && !handler.is_finished() {
+                thread::sleep(THREAD_SLEEP);
+            }
+            continue;
+        }
+
         // retrieve all pending events from watch
         let watch_event_count = watcher_rx.try_iter().count();
 
@@ -167,32 +209,12 @@ fn main(opts: Opts) -> Result<(), Error> {
                 break;
             }
 
-            let path = opts.config.to_owned();
-            let tx = signal.to_owned();
-            let cmd_ready = cmd_main_is_ready.to_owned();
-
-            handler = thread::spawn(move || {
-                let result = match path {
-                    Some(ref path) => Conf::try_from(path),
-                    None => Conf::default(),
-                };
-
-                let conf = match result {
-                    Ok(conf) => conf,
-                    Err(err) => {
-                        crit!("configuration is not healthy"; "error" => err.to_string());
-                        thread::sleep(Duration::from_millis(100)); // Sleep the time to display the message
-                        abort();
-                    }
-                };
-
-                tx.store(true, Ordering::SeqCst);
-                if let Err(err) = cmd::main(conf, tx, cmd_ready) {
-                    crit!("{}", err);
-                    thread::sleep(Duration::from_millis(100)); // Sleep the time to display the message
-                    abort();
-                }
-            });
+            handler = spawn(
+                opts.config.to_owned(),
+                signal.to_owned(),
+                cmd_main_is_ready.to_owned(),
+                drain,
+            );
 
             // waiting for cmd::main to be in a started state
             while !cmd_main_is_ready.load(Ordering::SeqCst) {
@@ -207,3 +229,34 @@ fn main(opts: Opts) -> Result<(), Error> {
     info!("Beamium halted!");
     Ok(())
 }
+
+/// Start cmd::main in a new thread using the configuration read again from the given path
+fn spawn(
+    path: Option<PathBuf>,
+    signal: Arc<AtomicBool>,
+    is_started_notifier: Arc<AtomicBool>,
+    drain: bool,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let result = match path {
+            Some(ref path) => Conf::try_from(path),
+            None => Conf::default(),
+        };
+
+        let conf = match result {
+            Ok(conf) => conf,
+            Err(err) => {
+                crit!("configuration is not healthy"; "error" => err.to_string());
+                thread::sleep(Duration::from_millis(100)); // Sleep the time to display the message
+                abort();
+            }
+        };
+
+        signal.store(true, Ordering::SeqCst);
+        if let Err(err) = cmd::main(conf, signal, is_started_notifier, drain) {
+            crit!("{}", err);
+            thread::sleep(Duration::from_millis(100)); // Sleep the time to display the message
+            abort();
+        }
+    })
+}
@@ -2,40 +2,91 @@
 //!
 //! The Router module forward sources to sinks.
 use std::collections::{HashMap, HashSet};
+use std::ops::RangeInclusive;
 use std::path::PathBuf;
 use std::process::abort;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 use uuid::Uuid;
 
 use failure::{format_err, Error};
 use futures::future;
 use futures::future::{ExecuteErrorKind, Executor};
+use prometheus::{Counter, CounterVec};
+use regex::Regex;
 use tokio::fs::remove_file;
 use tokio::fs::{rename, File};
 use tokio::prelude::*;
 use tokio::runtime::Runtime;
 
 use crate::conf;
+use crate::constants::{RAW, URGENT};
 use crate::lib::asynch::fs::Scanner;
-use crate::lib::{add_labels, Runner};
+use crate::lib::asynch::sync::Semaphore;
+use crate::lib::checkpoint::{self, Entry};
+use crate::lib::disk;
+use crate::lib::{add_labels, has_continuations, Runner};
+use crate::sink;
+
+lazy_static! {
+    static ref BEAMIUM_ROUTER_SINK_FULL: CounterVec = register_counter_vec!(
+        opts!(
+            "beamium_router_sink_full",
+            "Number of source files not written into a sink which queued too many files"
+        ),
+        &["sink"]
+    )
+    .expect("create metric: 'beamium_router_sink_full'");
+    static ref BEAMIUM_SINK_FANOUT_EXCEEDED: Counter = register_counter!(opts!(
+        "beamium_sink_fanout_exceeded",
+        "Number of source files fanning into more sinks than allowed"
+    ))
+    .expect("create metric: 'beamium_sink_fanout_exceeded'");
+    static ref BEAMIUM_CONTINUATIONS_TRUNCATED: Counter = register_counter!(opts!(
+        "beamium_continuations_truncated",
+        "Number of continuation lines dropped beyond the maximum"
+    ))
+    .expect("create metric: 'beamium_continuations_truncated'");
+    static ref BEAMIUM_ROUTER_DELTA_SUPPRESSED: Counter = register_counter!(opts!(
+        "beamium_router_delta_suppressed",
+        "Number of unchanged time series which are not forwarded"
+    ))
+    .expect("create metric: 'beamium_router_delta_suppressed'");
+    /// Identifier of the process, which is kept across reloads
+    static ref RUN_ID: String = Uuid::new_v4().to_string();
+}
+
+/// Name of the router in the pauses of the writers once the disk is full
+const WRITER: &str = "router";
+
+/// `Sample` of a time series: timestamp, location, class with labels and attributes, and value
+type Sample = (Option<i64>, String, String, String);
+
+/// `Deltas` holds the last forwarded values of each time series and when they were forwarded
+type Deltas = HashMap<String, (String, Instant)>;
 
 #[derive(Clone, Debug)]
 pub struct Router {
     params: Arc<conf::Parameters>,
     labels: Arc<HashMap<String, String>>,
     sinks: Arc<Vec<conf::Sink>>,
+    deltas: Arc<Mutex<Deltas>>,
 }
 
 impl From<(conf::Parameters, HashMap<String, String>, Vec<conf::Sink>)> for Router {
     fn from(tuple: (conf::Parameters, HashMap<String, String>, Vec<conf::Sink>)) -> Self {
-        let (params, labels, sinks) = tuple;
+        let (params, mut labels, sinks) = tuple;
+
+        if let Some(ref key) = params.run_id_label {
+            labels.insert(key.to_owned(), RUN_ID.to_owned());
+        }
 
         Self {
             params: arc!(params),
             labels: arc!(labels),
             sinks: arc!(sinks),
+            deltas: mutex!(HashMap::new()),
         }
     }
 }
@@ -48,12 +99,28 @@ impl Runner for Router {
         let labels = self.labels.to_owned();
         let sinks = self.sinks.to_owned();
         let params = self.params.to_owned();
+        let deltas = self.deltas.to_owned();
+
+        if let Some(checkpoint) = self.checkpoint()? {
+            rt.spawn(checkpoint);
+        }
 
         let dir = PathBuf::from(self.params.source_dir.to_owned());
         let executor = rt.executor();
+        let semaphore = Semaphore::from(self.params.router_max_inflight);
 
-        let scanner = Scanner::from((dir, self.params.scan_period.to_owned()))
+        let scanner = Scanner::from((
+            dir,
+            self.params.scan_period.to_owned(),
+            self.params.spool_symlinks.to_owned(),
+        ))
             .fold(mutex!(HashSet::new()), move |acc, entries| {
+                // Source files are kept until some disk space is freed
+                if disk::is_paused(WRITER) {
+                    trace!("skip routing, the disk is full");
+                    return future::ok(acc);
+                }
+
                 let paths: HashSet<PathBuf> =
                     entries.iter().fold(HashSet::new(), |mut acc, (path, _)| {
                         acc.insert(path.to_owned());
@@ -82,12 +149,31 @@ impl Runner for Router {
                     let params = params.to_owned();
                     let epath = path.to_owned();
                     let state = acc.to_owned();
+                    let sort_timestamps = params.sort_timestamps;
+                    let max_continuations = params.max_continuations;
+                    let continuations = has_continuations(&path);
+                    let delta = if params.router_delta {
+                        Some((deltas.to_owned(), params.router_delta_heartbeat))
+                    } else {
+                        None
+                    };
 
-                    let result = executor.execute(
+                    // Files wait for a permit in order to bound the number of files processed at
+                    // the same time
+                    let process = future::lazy(move || {
                         Self::load(path.to_owned())
                             .and_then(move |lines| Self::process(&lines, &labels))
-                            .and_then(move |lines| Self::write(&lines, &params, &sinks))
+                            .map(move |lines| if sort_timestamps { Self::sort(lines, continuations) } else { lines })
+                            .map(move |lines| match max_continuations {
+                                Some(max) => Self::truncate(lines, max, continuations),
+                                None => lines,
+                            })
+                            .and_then(move |lines| Self::forward(lines, continuations, delta, &params, &sinks))
                             .and_then(move |_| Self::remove(path))
+                    });
+
+                    let result = executor.execute(
+                        semaphore.run(process)
                             .map_err(move |err| {
                                 error!("could not process file in router"; "path" => epath.to_str(), "error" => err.to_string());
                                 let mut state = match state.lock() {
@@ -132,6 +218,58 @@ impl Runner for Router {
 }
 
 impl Router {
+    /// Restore the saved deltas and save them at each interval, `None` if there is no state to save
+    fn checkpoint(&self) -> Result<Option<impl Future<Item = (), Error = ()>>, Error> {
+        let checkpoint = match self.params.checkpoint {
+            Some(ref checkpoint) if self.params.router_delta => checkpoint.to_owned(),
+            _ => return Ok(None),
+        };
+
+        let path = checkpoint::path(&checkpoint, "router");
+        match checkpoint::load(&path, &checkpoint, SystemTime::now()) {
+            Ok(entries) => {
+                debug!("restore router deltas"; "count" => entries.len());
+                let mut deltas = self.deltas.lock().map_err(|err| format_err!("{}", err))?;
+                Self::restore(&mut deltas, entries, Instant::now(), SystemTime::now());
+            }
+            Err(err) => warn!("could not restore router deltas"; "error" => err.to_string()),
+        }
+
+        let deltas = self.deltas.to_owned();
+        Ok(Some(checkpoint::every(path, checkpoint, move || {
+            let deltas = deltas.lock().map_err(|err| format_err!("{}", err))?;
+            Ok(Self::checkpoint_deltas(
+                &deltas,
+                Instant::now(),
+                SystemTime::now(),
+            ))
+        })))
+    }
+
+    /// Deltas to save, stamped with the unix time they were forwarded
+    fn checkpoint_deltas(deltas: &Deltas, now: Instant, time: SystemTime) -> Vec<Entry<String>> {
+        let time = checkpoint::unix(time);
+        deltas
+            .iter()
+            .map(|(series, (value, forwarded))| Entry {
+                series: series.to_owned(),
+                state: value.to_owned(),
+                updated: time.saturating_sub(now.duration_since(*forwarded).as_secs()),
+            })
+            .collect()
+    }
+
+    /// Restore the saved deltas, the instant they were forwarded is computed from their age
+    fn restore(deltas: &mut Deltas, entries: Vec<Entry<String>>, now: Instant, time: SystemTime) {
+        let time = checkpoint::unix(time);
+        for entry in entries {
+            let age = Duration::from_secs(time.saturating_sub(entry.updated));
+            if let Some(forwarded) = now.checked_sub(age) {
+                deltas.insert(entry.series, (entry.state, forwarded));
+            }
+        }
+    }
+
     fn load(path: PathBuf) -> impl Future<Item = Vec<String>, Error = Error> {
         trace!("open file"; "path" => path.to_str());
         File::open(path)
@@ -168,21 +306,33 @@ impl Router {
         future::ok(body)
     }
 
+    /// Write the lines into the files of the sinks, the files are tagged if the lines starting
+    /// with '=' are not continuation lines
     fn write(
         lines: &[String],
+        continuations: bool,
         params: &conf::Parameters,
         sinks: &[conf::Sink],
     ) -> impl Future<Item = (), Error = Error> {
-        let mut bulk = vec![];
+        let mut targets = vec![];
+        let buckets = params
+            .shard_by
+            .as_ref()
+            .map(|conf::ShardBy::Series| Self::buckets(lines, continuations, params.shard_count));
 
         let mut idx = -1;
         for sink in sinks {
             idx += 1;
+            let lines = match (&buckets, &sink.shard_range) {
+                (Some(buckets), Some(range)) => Self::shard(lines, buckets, range),
+                _ => lines.to_owned(),
+            };
+
             let body = match &sink.selector {
-                None => lines.to_owned(),
+                None => lines,
                 Some(selector) => {
                     let mut body = vec![];
-                    for line in lines.to_owned() {
+                    for line in lines {
                         if line
                             .split_whitespace()
                             .nth(1)
@@ -200,36 +350,414 @@ impl Router {
                 continue;
             }
 
-            let file_uuid = Uuid::new_v4();
-            let start = time::now_utc().to_timespec();
-            let run_id = format!("{}#{}#{}", start.sec, start.nsec, file_uuid);
-            let name = sink.name.to_owned();
-            let dir = PathBuf::from(params.sink_dir.to_owned());
-            let temp_file = dir.join(format!("{}-{}-{}.tmp", sink.name, idx, run_id.to_owned()));
-
-            trace!("create tmp sink file"; "path" => temp_file.to_str());
-            bulk.push(
-                File::create(temp_file.to_owned())
-                    .map_err(|err| format_err!("could not create file, {}", err))
-                    .and_then(move |mut file| {
-                        file.poll_write((body.join("\n") + "\n").as_bytes())
-                            .and_then(|_| file.poll_flush())
-                            .map_err(|err| format_err!("could not write into file, {}", err))
-                    })
-                    .and_then(move |_| {
-                        let new = dir.join(format!("{}-{}-{}.metrics", name, idx, run_id));
-
-                        debug!("rotate file"; "old" => temp_file.to_str(), "new" => new.to_str());
-                        rename(temp_file, new)
-                            .map_err(|err| format_err!("could not rename file, {}", err))
-                    })
-                    .and_then(|_| Ok(())),
-            )
+            // The time series are dropped for a full sink, the other ones still receive them
+            if sink::is_full(sink) {
+                warn!("skip sink, it queued too many files"; "sink" => sink.name.as_str());
+                BEAMIUM_ROUTER_SINK_FULL
+                    .with_label_values(&[sink.name.as_str()])
+                    .inc();
+                continue;
+            }
+
+            targets.push((idx, sink, body));
+        }
+
+        let targets = Self::limit(targets, params).unwrap_or_default();
+
+        let mut bulk = vec![];
+        for (idx, sink, body) in targets {
+            let bodies = match &sink.priority_selector {
+                None => vec![(body, false)],
+                Some(selector) => {
+                    let (urgent, bulk) = Self::prioritize(body, selector, continuations);
+                    vec![(urgent, true), (bulk, false)]
+                }
+            };
+
+            for (body, is_urgent) in bodies {
+                if body.is_empty() {
+                    continue;
+                }
+
+                let mut suffix = String::new();
+                if !continuations {
+                    suffix += &format!(".{}", RAW);
+                }
+
+                if is_urgent {
+                    suffix += &format!(".{}", URGENT);
+                }
+
+                let file_uuid = Uuid::new_v4();
+                let start = time::now_utc().to_timespec();
+                let run_id = format!("{}#{}#{}", start.sec, start.nsec, file_uuid);
+                let name = sink.name.to_owned();
+                let queued = sink.name.to_owned();
+                let dir = params.sink_dir_of(&sink.name);
+                let temp_file =
+                    dir.join(format!("{}-{}-{}.tmp", sink.name, idx, run_id.to_owned()));
+
+                trace!("create tmp sink file"; "path" => temp_file.to_str());
+                bulk.push(
+                    File::create(temp_file.to_owned())
+                        .map_err(|err| {
+                            disk::fail(WRITER, &err);
+                            format_err!("could not create file, {}", err)
+                        })
+                        .and_then(move |file| {
+                            disk::spool(file, (body.join("\n") + "\n").into_bytes())
+                                .map_err(|err| {
+                                    disk::fail(WRITER, &err);
+                                    format_err!("could not write into file, {}", err)
+                                })
+                        })
+                        .and_then(move |_| {
+                            let new = dir.join(format!("{}-{}-{}{}.metrics", name, idx, run_id, suffix));
+
+                            debug!("rotate file"; "old" => temp_file.to_str(), "new" => new.to_str());
+                            rename(temp_file, new).map_err(|err| {
+                                disk::fail(WRITER, &err);
+                                format_err!("could not rename file, {}", err)
+                            })
+                        })
+                        .map(move |_| {
+                            sink::queue(&queued);
+                            disk::resume(WRITER)
+                        }),
+                )
+            }
         }
 
         future::join_all(bulk).and_then(|_| future::ok(()))
     }
 
+    /// Bound the number of sinks a source file is written into according to the fanout policy,
+    /// return `None` if the file should not be written at all
+    fn limit<T>(mut targets: Vec<T>, params: &conf::Parameters) -> Option<Vec<T>> {
+        let max = match params.max_sink_fanout {
+            Some(max) if targets.len() > max => max,
+            _ => return Some(targets),
+        };
+
+        BEAMIUM_SINK_FANOUT_EXCEEDED.inc();
+        match params.sink_fanout_policy {
+            conf::FanoutPolicy::Skip => {
+                error!("source file fans into too many sinks, skip the extra ones"; "sinks" => targets.len(), "max" => max);
+                targets.truncate(max);
+                Some(targets)
+            }
+            conf::FanoutPolicy::Abort => {
+                error!("source file fans into too many sinks, discard it"; "sinks" => targets.len(), "max" => max);
+                None
+            }
+        }
+    }
+
+    /// Sort the samples of each time series by timestamp, samples without timestamp come last.
+    /// Time series are grouped by class and labels and written in the order of their first sample.
+    /// Each sample keeps its attributes, a sample is written as a continuation line only if
+    /// continuation lines are enabled and it has the class, labels and attributes of the previous
+    /// one. Lines are kept as is if one of them could not be parsed.
+    fn sort(lines: Vec<String>, continuations: bool) -> Vec<String> {
+        let mut order: Vec<String> = vec![];
+        let mut groups: HashMap<String, Vec<Sample>> = HashMap::new();
+        let mut key: Option<(String, String)> = None;
+        for line in &lines {
+            if line.is_empty() {
+                continue;
+            }
+
+            let sample = match line.strip_prefix('=').filter(|_| continuations) {
+                Some(continuation) => continuation
+                    .split_once(' ')
+                    .map(|(location, value)| (location, None, value)),
+                None => line.split_once(' ').and_then(|(location, rest)| {
+                    rest.split_once(' ')
+                        .map(|(gts, value)| (location, Some(gts), value))
+                }),
+            };
+
+            let (location, gts, value) = match sample {
+                Some(sample) => sample,
+                None => {
+                    warn!("could not parse time series, keep samples unsorted"; "line" => line.as_str());
+                    return lines;
+                }
+            };
+
+            if let Some(gts) = gts {
+                let series = gts.split_inclusive('}').next().unwrap_or(gts).to_owned();
+                if !groups.contains_key(&series) {
+                    order.push(series.to_owned());
+                    groups.insert(series.to_owned(), vec![]);
+                }
+
+                key = Some((series, gts.to_owned()));
+            }
+
+            // Continuation lines get the class, labels and attributes of the previous time series
+            let (series, gts) = match &key {
+                Some(key) => key,
+                None => {
+                    warn!("continuation line without time series, keep samples unsorted"; "line" => line.as_str());
+                    return lines;
+                }
+            };
+
+            let timestamp = location
+                .split('/')
+                .next()
+                .and_then(|ts| ts.parse::<i64>().ok());
+            if let Some(group) = groups.get_mut(series) {
+                group.push((
+                    timestamp,
+                    location.to_owned(),
+                    gts.to_owned(),
+                    value.to_owned(),
+                ));
+            }
+        }
+
+        let mut sorted = Vec::with_capacity(lines.len());
+        for series in order {
+            let mut samples = match groups.remove(&series) {
+                Some(samples) => samples,
+                None => continue,
+            };
+
+            samples.sort_by_key(|(timestamp, _, _, _)| timestamp.unwrap_or(i64::MAX));
+            let mut previous: Option<String> = None;
+            for (_, location, gts, value) in samples {
+                if continuations && previous.as_ref() == Some(&gts) {
+                    sorted.push(format!("={} {}", location, value));
+                } else {
+                    sorted.push(format!("{} {} {}", location, gts, value));
+                    previous = Some(gts);
+                }
+            }
+        }
+
+        sorted
+    }
+
+    /// Drop the continuation lines following a time series beyond the maximum
+    fn truncate(lines: Vec<String>, max: usize, continuations: bool) -> Vec<String> {
+        if !continuations {
+            return lines;
+        }
+
+        let mut kept = Vec::with_capacity(lines.len());
+        let mut continuations = 0;
+        let mut dropped = 0;
+        for line in lines {
+            if !line.starts_with('=') {
+                continuations = 0;
+                kept.push(line);
+                continue;
+            }
+
+            continuations += 1;
+            if continuations > max {
+                dropped += 1;
+                continue;
+            }
+
+            kept.push(line);
+        }
+
+        if dropped > 0 {
+            warn!("truncate continuation lines"; "dropped" => dropped, "max" => max);
+            BEAMIUM_CONTINUATIONS_TRUNCATED.inc_by(dropped as f64);
+        }
+
+        kept
+    }
+
+    /// Write the lines into the sinks, the deltas of the forwarded time series are only recorded
+    /// once the write succeeded, so a retried file forwards them again
+    fn forward(
+        lines: Vec<String>,
+        continuations: bool,
+        delta: Option<(Arc<Mutex<Deltas>>, Duration)>,
+        params: &conf::Parameters,
+        sinks: &[conf::Sink],
+    ) -> impl Future<Item = (), Error = Error> {
+        let (lines, updates) = match &delta {
+            Some((deltas, heartbeat)) => {
+                let deltas = match deltas.lock() {
+                    Ok(deltas) => deltas,
+                    Err(err) => {
+                        return future::Either::A(future::err(format_err!(
+                            "could not get lock in router, {}",
+                            err
+                        )))
+                    }
+                };
+                let (lines, updates) =
+                    Self::delta(lines, continuations, &deltas, *heartbeat, Instant::now());
+                (lines, Some(updates))
+            }
+            None => (lines, None),
+        };
+
+        future::Either::B(Self::write(&lines, continuations, params, sinks).and_then(
+            move |_| match (delta, updates) {
+                (Some((deltas, heartbeat)), Some(updates)) => {
+                    let mut deltas = deltas
+                        .lock()
+                        .map_err(|err| format_err!("could not get lock in router, {}", err))?;
+                    Self::commit(&mut deltas, updates, heartbeat, Instant::now());
+                    Ok(())
+                }
+                _ => Ok(()),
+            },
+        ))
+    }
+
+    /// Drop the time series whose value, including their continuation lines, is the same as the
+    /// last forwarded one, unless it was forwarded longer than the heartbeat ago. The deltas of
+    /// the kept time series are returned to be committed once they are written
+    fn delta(
+        lines: Vec<String>,
+        continuations: bool,
+        deltas: &Deltas,
+        heartbeat: Duration,
+        now: Instant,
+    ) -> (Vec<String>, Deltas) {
+        let mut records: Vec<Vec<String>> = vec![];
+        for line in lines {
+            match records.last_mut() {
+                Some(record) if continuations && line.starts_with('=') => record.push(line),
+                _ => records.push(vec![line]),
+            }
+        }
+
+        let mut kept = vec![];
+        let mut updates = HashMap::new();
+        let mut suppressed = 0;
+        for record in records {
+            let series = record[0]
+                .split_whitespace()
+                .nth(1)
+                .and_then(|gts| gts.split_inclusive('}').next())
+                .map(String::from);
+
+            // Lines which are not a time series, e.g. comments, are always forwarded
+            let series = match series {
+                Some(series) if !continuations || !record[0].starts_with('=') => series,
+                _ => {
+                    kept.extend(record);
+                    continue;
+                }
+            };
+
+            let mut value = record[0]
+                .splitn(3, ' ')
+                .nth(2)
+                .unwrap_or_default()
+                .to_owned();
+            for line in &record[1..] {
+                value.push('\n');
+                value.push_str(line.split_once(' ').map(|(_, v)| v).unwrap_or_default());
+            }
+
+            // Time series forwarded longer than the heartbeat ago are forwarded again
+            let last = updates
+                .get(&series)
+                .or_else(|| deltas.get(&series))
+                .filter(|(_, forwarded)| now.duration_since(*forwarded) < heartbeat);
+            if let Some((last, _)) = last {
+                if *last == value {
+                    suppressed += 1;
+                    continue;
+                }
+            }
+
+            updates.insert(series, (value, now));
+            kept.extend(record);
+        }
+
+        if suppressed > 0 {
+            trace!("suppress unchanged time series"; "suppressed" => suppressed);
+            BEAMIUM_ROUTER_DELTA_SUPPRESSED.inc_by(suppressed as f64);
+        }
+
+        (kept, updates)
+    }
+
+    /// Record the deltas of the written time series, the ones forwarded longer than the
+    /// heartbeat ago are dropped as they would be forwarded on their next sample
+    fn commit(deltas: &mut Deltas, updates: Deltas, heartbeat: Duration, now: Instant) {
+        deltas.retain(|_, (_, forwarded)| now.duration_since(*forwarded) < heartbeat);
+        deltas.extend(updates);
+    }
+
+    /// Bucket of each line computed using a stable hash of the class and labels of its time
+    /// series, continuation lines follow their time series
+    fn buckets(lines: &[String], continuations: bool, count: u64) -> Vec<u64> {
+        let mut buckets = Vec::with_capacity(lines.len());
+        let mut bucket = 0;
+        for line in lines {
+            if !continuations || !line.starts_with('=') {
+                let series = line
+                    .split_whitespace()
+                    .nth(1)
+                    .and_then(|gts| gts.split_inclusive('}').next())
+                    .unwrap_or_default();
+
+                bucket = Self::hash(series) % count;
+            }
+
+            buckets.push(bucket);
+        }
+
+        buckets
+    }
+
+    /// FNV-1a hash, which is stable across runs and releases
+    fn hash(key: &str) -> u64 {
+        key.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        })
+    }
+
+    /// Keep the lines whose bucket is in the range
+    fn shard(lines: &[String], buckets: &[u64], range: &RangeInclusive<u64>) -> Vec<String> {
+        lines
+            .iter()
+            .zip(buckets)
+            .filter(|(_, bucket)| range.contains(bucket))
+            .map(|(line, _)| line.to_owned())
+            .collect()
+    }
+
+    /// Split lines into urgent and bulk ones, continuation lines follow their time series
+    fn prioritize(
+        lines: Vec<String>,
+        selector: &Regex,
+        continuations: bool,
+    ) -> (Vec<String>, Vec<String>) {
+        let mut urgent = vec![];
+        let mut bulk = vec![];
+        let mut is_urgent = false;
+        for line in lines {
+            if !continuations || !line.starts_with('=') {
+                is_urgent = match line.split_whitespace().nth(1) {
+                    Some(class) => selector.is_match(class),
+                    None => false,
+                };
+            }
+
+            if is_urgent {
+                urgent.push(line);
+            } else {
+                bulk.push(line);
+            }
+        }
+
+        (urgent, bulk)
+    }
+
     fn remove(path: PathBuf) -> impl Future<Item = (), Error = Error> {
         trace!("remove file"; "path" => path.to_str());
         remove_file(path)
@@ -237,3 +765,492 @@ impl Router {
             .and_then(|_| future::ok(()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::convert::TryFrom;
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, read_dir, read_to_string, remove_dir_all};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+
+    use futures::Future;
+    use regex::Regex;
+    use tokio::runtime::Runtime;
+    use uuid::Uuid;
+
+    use super::{Deltas, Router};
+    use crate::conf::tests::parameters;
+    use crate::conf::{self, RawSink};
+    use crate::sink;
+
+    /// Drop the unchanged time series and record the deltas of the kept ones, as if they were
+    /// written
+    fn delta(
+        lines: Vec<String>,
+        continuations: bool,
+        deltas: &mut Deltas,
+        heartbeat: Duration,
+        now: Instant,
+    ) -> Vec<String> {
+        let (kept, updates) = Router::delta(lines, continuations, deltas, heartbeat, now);
+        Router::commit(deltas, updates, heartbeat, now);
+        kept
+    }
+
+    #[test]
+    fn prioritize_urgent_time_series() {
+        let lines = vec![
+            String::from("1// alert_cpu{} 1"),
+            String::from("=2// 2"),
+            String::from("1// archive_cpu{} 1"),
+            String::from("=2// 2"),
+            String::from("1// alert_mem{} 1"),
+        ];
+
+        let selector = Regex::new("^alert.*").expect("valid regex");
+        let (urgent, bulk) = Router::prioritize(lines, &selector, true);
+
+        assert_eq!(
+            vec!["1// alert_cpu{} 1", "=2// 2", "1// alert_mem{} 1"],
+            urgent
+        );
+        assert_eq!(vec!["1// archive_cpu{} 1", "=2// 2"], bulk);
+    }
+
+    #[test]
+    fn stable_bucket_assignment() {
+        assert_eq!(0xcbf2_9ce4_8422_2325, Router::hash(""));
+        assert_eq!(0xaf63_dc4c_8601_ec8c, Router::hash("a"));
+
+        let lines = vec![
+            String::from("1// cpu{host=a} 1"),
+            String::from("2/48.0:-4.5/10 cpu{host=a}{dc=rbx} 2"),
+            String::from("1// cpu{host=b} 1"),
+            String::from("1// mem{host=a} 1"),
+        ];
+
+        let buckets = Router::buckets(&lines, true, 16);
+        let expected: Vec<u64> = ["cpu{host=a}", "cpu{host=a}", "cpu{host=b}", "mem{host=a}"]
+            .iter()
+            .map(|series| Router::hash(series) % 16)
+            .collect();
+
+        // Timestamps, locations, attributes and values do not move the series
+        assert_eq!(expected, buckets);
+        assert_eq!(buckets, Router::buckets(&lines, true, 16));
+        assert_eq!(vec![0, 0, 0, 0], Router::buckets(&lines, true, 1));
+    }
+
+    #[test]
+    fn shard_continuation_lines() {
+        let lines = vec![
+            String::from("1// cpu{host=a} 1"),
+            String::from("=2// 2"),
+            String::from("1// cpu{host=b} 1"),
+            String::from("=2// 2"),
+            String::from("=3// 3"),
+        ];
+
+        let a = Router::hash("cpu{host=a}") % 2;
+        let b = Router::hash("cpu{host=b}") % 2;
+        assert_ne!(a, b, "series should fall in distinct buckets");
+
+        let buckets = Router::buckets(&lines, true, 2);
+        assert_eq!(vec![a, a, b, b, b], buckets);
+
+        assert_eq!(
+            vec!["1// cpu{host=a} 1", "=2// 2"],
+            Router::shard(&lines, &buckets, &(a..=a))
+        );
+        assert_eq!(
+            vec!["1// cpu{host=b} 1", "=2// 2", "=3// 3"],
+            Router::shard(&lines, &buckets, &(b..=b))
+        );
+        assert_eq!(lines, Router::shard(&lines, &buckets, &(0..=1)));
+    }
+
+    #[test]
+    fn sort_out_of_order_time_series() {
+        let lines = vec![
+            String::from("3// cpu{host=a} 3"),
+            String::from("=1// 1"),
+            String::from("2/48.0:-4.5/10 mem{host=a}{dc=rbx} 'b'"),
+            String::from("=1// 'a b'"),
+            String::from("2// cpu{host=a} 2"),
+            String::from("// cpu{host=a} 4"),
+            String::from("5// cpu{host=b} 5"),
+            String::from(""),
+        ];
+
+        assert_eq!(
+            vec![
+                "1// cpu{host=a} 1",
+                "=2// 2",
+                "=3// 3",
+                "=// 4",
+                "1// mem{host=a}{dc=rbx} 'a b'",
+                "=2/48.0:-4.5/10 'b'",
+                "5// cpu{host=b} 5",
+            ],
+            Router::sort(lines, true)
+        );
+
+        // Samples keep their attributes, a continuation line follows the same attributes only
+        let lines = vec![
+            String::from("2// cpu{host=a}{dc=rbx} 2"),
+            String::from("=3// 3"),
+            String::from("1// cpu{host=a}{dc=gra} 1"),
+        ];
+        assert_eq!(
+            vec![
+                "1// cpu{host=a}{dc=gra} 1",
+                "2// cpu{host=a}{dc=rbx} 2",
+                "=3// 3",
+            ],
+            Router::sort(lines, true)
+        );
+
+        // Samples are kept as is if the time series could not be parsed
+        let lines = vec![String::from("=1// 1"), String::from("2// cpu{} 2")];
+        assert_eq!(lines, Router::sort(lines.to_owned(), true));
+    }
+
+    #[test]
+    fn sort_raw_time_series() {
+        // Lines starting with '=' are regular time series of a raw file
+        let lines = vec![
+            String::from("3// cpu{host=a} 3"),
+            String::from("=1// eq{} 1"),
+            String::from("1// cpu{host=a}{dc=rbx} 1"),
+            String::from("2// cpu{host=a} 2"),
+        ];
+
+        assert_eq!(
+            vec![
+                "1// cpu{host=a}{dc=rbx} 1",
+                "2// cpu{host=a} 2",
+                "3// cpu{host=a} 3",
+                "=1// eq{} 1",
+            ],
+            Router::sort(lines, false)
+        );
+    }
+
+    #[test]
+    fn run_id_label() {
+        let router = |params| Router::from((params, HashMap::new(), vec![]));
+
+        let params = parameters(&[]).expect("valid parameters");
+        assert!(router(params).labels.is_empty());
+
+        let params = parameters(&[("run-id-label", "run")]).expect("valid parameters");
+        let run_id = router(params.to_owned()).labels["run"].to_owned();
+        assert!(Uuid::parse_str(&run_id).is_ok());
+
+        // The run id is stable within the process
+        let labels = router(params).labels;
+        assert_eq!(run_id, labels["run"]);
+
+        let lines = Router::process(&[String::from("1// cpu{} 1")], &labels)
+            .wait()
+            .expect("process lines");
+        assert_eq!(vec![format!("1// cpu{{run={}}} 1", run_id)], lines);
+    }
+
+    #[test]
+    fn truncate_continuation_lines() {
+        let lines: Vec<String> = [
+            "1// cpu{} 1",
+            "=2// 2",
+            "=3// 3",
+            "=4// 4",
+            "=5// 5",
+            "1// mem{} 1",
+            "=2// 2",
+        ]
+        .iter()
+        .map(|line| String::from(*line))
+        .collect();
+
+        let dropped = super::BEAMIUM_CONTINUATIONS_TRUNCATED.get();
+        assert_eq!(
+            vec!["1// cpu{} 1", "=2// 2", "=3// 3", "1// mem{} 1", "=2// 2"],
+            Router::truncate(lines.to_owned(), 2, true)
+        );
+        assert!(super::BEAMIUM_CONTINUATIONS_TRUNCATED.get() - dropped >= 2.0);
+
+        assert_eq!(
+            vec!["1// cpu{} 1", "1// mem{} 1"],
+            Router::truncate(lines, 0, true)
+        );
+    }
+
+    #[test]
+    fn lines_without_continuations() {
+        let lines: Vec<String> = [
+            "1// cpu{host=a} 1",
+            "=1// eq{host=b} 2",
+            "=1// eq{host=b} 2",
+        ]
+        .iter()
+        .map(|line| String::from(*line))
+        .collect();
+
+        // Lines starting with '=' are time series of their own
+        assert_eq!(lines, Router::truncate(lines.to_owned(), 0, false));
+
+        let a = Router::hash("cpu{host=a}") % 1024;
+        let b = Router::hash("eq{host=b}") % 1024;
+        assert_eq!(vec![a, b, b], Router::buckets(&lines, false, 1024));
+
+        let selector = Regex::new("^eq").expect("valid regex");
+        let (urgent, bulk) = Router::prioritize(lines.to_owned(), &selector, false);
+        assert_eq!(lines[1..].to_vec(), urgent);
+        assert_eq!(lines[..1].to_vec(), bulk);
+
+        let mut deltas = HashMap::new();
+        assert_eq!(
+            lines[..2].to_vec(),
+            delta(
+                lines,
+                false,
+                &mut deltas,
+                Duration::from_secs(60),
+                Instant::now()
+            )
+        );
+    }
+
+    #[test]
+    fn delta_time_series() {
+        let lines = |lines: &[&str]| -> Vec<String> {
+            lines.iter().map(|line| String::from(*line)).collect()
+        };
+
+        let heartbeat = Duration::from_secs(60);
+        let now = Instant::now();
+        let mut deltas = HashMap::new();
+
+        let first = lines(&["1// cpu{host=a} 1", "1// mem{} 10", "=2// 11"]);
+        assert_eq!(
+            first,
+            delta(first.to_owned(), true, &mut deltas, heartbeat, now)
+        );
+
+        // Only changed time series are forwarded, the timestamp is not a change
+        let changes = lines(&[
+            "2// cpu{host=a} 1",
+            "2// cpu{host=b} 1",
+            "2// mem{} 10",
+            "=3// 12",
+        ]);
+        assert_eq!(
+            lines(&["2// cpu{host=b} 1", "2// mem{} 10", "=3// 12"]),
+            delta(
+                changes,
+                true,
+                &mut deltas,
+                heartbeat,
+                now + Duration::from_secs(10)
+            )
+        );
+
+        let changes = lines(&["3// cpu{host=a} 2", "3// cpu{host=b} 1"]);
+        assert_eq!(
+            lines(&["3// cpu{host=a} 2"]),
+            delta(
+                changes,
+                true,
+                &mut deltas,
+                heartbeat,
+                now + Duration::from_secs(20)
+            )
+        );
+
+        // Unchanged time series are forwarded again once the heartbeat is elapsed
+        let unchanged = lines(&["4// cpu{host=a} 2", "4// cpu{host=b} 1"]);
+        assert_eq!(
+            lines(&["4// cpu{host=b} 1"]),
+            delta(
+                unchanged.to_owned(),
+                true,
+                &mut deltas,
+                heartbeat,
+                now + Duration::from_secs(75)
+            )
+        );
+        assert_eq!(
+            Vec::<String>::new(),
+            delta(
+                unchanged,
+                true,
+                &mut deltas,
+                heartbeat,
+                now + Duration::from_secs(76)
+            )
+        );
+    }
+
+    #[test]
+    fn restore_deltas() {
+        let heartbeat = Duration::from_secs(60);
+        let now = Instant::now();
+        let time = UNIX_EPOCH + Duration::from_secs(10_000);
+        let mut deltas = HashMap::new();
+
+        let lines = vec![String::from("1// cpu{host=a} 1")];
+        delta(lines.to_owned(), true, &mut deltas, heartbeat, now);
+
+        let entries = Router::checkpoint_deltas(&deltas, now + Duration::from_secs(20), time);
+        assert_eq!(9_980, entries[0].updated);
+
+        // Restored deltas keep their age, so the heartbeat is not delayed by the restart
+        let restart = now + Duration::from_secs(3600);
+        let mut restored = HashMap::new();
+        Router::restore(&mut restored, entries, restart, time);
+        assert!(delta(
+            lines.to_owned(),
+            true,
+            &mut restored,
+            heartbeat,
+            restart + Duration::from_secs(30)
+        )
+        .is_empty());
+        assert_eq!(
+            lines.to_owned(),
+            delta(
+                lines,
+                true,
+                &mut restored,
+                heartbeat,
+                restart + Duration::from_secs(40)
+            )
+        );
+    }
+
+    #[test]
+    fn retry_failed_delta_write() {
+        let dir = temp_dir().join(format!("beamium-{}", Uuid::new_v4()));
+        let params = parameters(&[
+            ("sink-dir", dir.join("sinks").to_str().expect("utf-8 path")),
+            ("nested-sink-dir", "true"),
+        ])
+        .expect("valid parameters");
+
+        let name = format!("delta-{}", Uuid::new_v4());
+        let raw = RawSink {
+            url: String::from("https://warp.example.com/api/v0/update"),
+            token: String::from("token"),
+            ..Default::default()
+        };
+        let sinks = vec![conf::Sink::try_from((name.to_owned(), raw)).expect("valid sink")];
+
+        let deltas = Arc::new(Mutex::new(HashMap::new()));
+        let delta = Some((deltas.to_owned(), Duration::from_secs(60)));
+        let lines = vec![String::from("1// cpu{} 1"), String::from("1// mem{} 10")];
+
+        // The sink directory is missing, so the write fails and no delta is recorded
+        Runtime::new()
+            .expect("runtime")
+            .block_on(Router::forward(
+                lines.to_owned(),
+                true,
+                delta.to_owned(),
+                &params,
+                &sinks,
+            ))
+            .expect_err("sink directory is missing");
+        assert!(deltas.lock().expect("lock deltas").is_empty());
+
+        // The retried file forwards every time series
+        create_dir_all(params.sink_dir_of(&name)).expect("create sink directory");
+        Runtime::new()
+            .expect("runtime")
+            .block_on(Router::forward(lines, true, delta, &params, &sinks))
+            .expect("write sinks");
+
+        let files: Vec<_> = read_dir(params.sink_dir_of(&name))
+            .expect("read sink directory")
+            .map(|entry| entry.expect("sink file").path())
+            .collect();
+        assert_eq!(1, files.len());
+        assert_eq!(
+            "1// cpu{} 1\n1// mem{} 10\n",
+            read_to_string(&files[0]).expect("read sink file")
+        );
+        assert_eq!(2, deltas.lock().expect("lock deltas").len());
+
+        remove_dir_all(&dir).expect("remove directory");
+    }
+
+    #[test]
+    fn skip_full_sink() {
+        let dir = temp_dir().join(format!("beamium-{}", Uuid::new_v4()));
+        let sink_dir = dir.join("sinks");
+        let params = parameters(&[
+            ("sink-dir", sink_dir.to_str().expect("utf-8 path")),
+            ("nested-sink-dir", "true"),
+        ])
+        .expect("valid parameters");
+
+        let sink = |name: &str| {
+            let raw = RawSink {
+                url: String::from("https://warp.example.com/api/v0/update"),
+                token: String::from("token"),
+                max_files: Some(2),
+                ..Default::default()
+            };
+
+            create_dir_all(params.sink_dir_of(name)).expect("create sink directory");
+            conf::Sink::try_from((String::from(name), raw)).expect("valid sink")
+        };
+
+        let full = format!("full-{}", Uuid::new_v4());
+        let healthy = format!("healthy-{}", Uuid::new_v4());
+        let sinks = vec![sink(&full), sink(&healthy)];
+        sink::account(&full, 2);
+        sink::account(&healthy, 1);
+
+        let files = |name: &str| {
+            read_dir(params.sink_dir_of(name))
+                .expect("read sink directory")
+                .count()
+        };
+
+        let lines = vec![String::from("1// cpu{} 1")];
+        Runtime::new()
+            .expect("runtime")
+            .block_on(Router::write(&lines, true, &params, &sinks))
+            .expect("write sinks");
+
+        // The full sink is skipped while the healthy one receives the time series
+        assert_eq!(0, files(&full));
+        assert_eq!(1, files(&healthy));
+
+        // The routed file counts until the next scan, so the healthy sink is now full as well
+        Runtime::new()
+            .expect("runtime")
+            .block_on(Router::write(&lines, true, &params, &sinks))
+            .expect("write sinks");
+        assert_eq!(1, files(&healthy));
+
+        remove_dir_all(&dir).expect("remove directory");
+    }
+
+    #[test]
+    fn limit_sink_fanout() {
+        let params = parameters(&[]).expect("valid parameters");
+        assert_eq!(Some(vec![0, 1, 2]), Router::limit(vec![0, 1, 2], &params));
+
+        let params = parameters(&[("max-sink-fanout", "2")]).expect("valid parameters");
+        assert_eq!(Some(vec![0, 1]), Router::limit(vec![0, 1], &params));
+        assert_eq!(Some(vec![0, 1]), Router::limit(vec![0, 1, 2], &params));
+
+        let params = parameters(&[("max-sink-fanout", "2"), ("sink-fanout-policy", "abort")])
+            .expect("valid parameters");
+        assert_eq!(Some(vec![0, 1]), Router::limit(vec![0, 1], &params));
+        assert_eq!(None, Router::limit(vec![0, 1, 2], &params));
+    }
+}
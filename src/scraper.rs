@@ -1,38 +1,63 @@
 //! # Scraper module.
 //!
 //! The Scraper module fetch metrics from an HTTP endpoint.
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::From;
-use std::path::Path;
+use std::fmt;
+use std::path::PathBuf;
 use std::process::abort;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread::sleep;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
 use failure::{format_err, Error};
 use futures::future::{ExecuteErrorKind, Executor};
-use futures::{Future, Stream};
-use hyper::client::connect::dns::GaiResolver;
+use futures::sync::oneshot;
+use futures::{stream, Future, Stream};
 use hyper::client::HttpConnector;
-use hyper::{Body, Client, Method, Request};
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Client, HeaderMap, Method, Request};
 use hyper_rustls::HttpsConnector;
-use prometheus::CounterVec;
+use prometheus::core::Collector;
+use prometheus::{CounterVec, GaugeVec};
+use regex::Regex;
 use time::now_utc;
+use tokio::executor::DefaultExecutor;
 use tokio::fs::{rename, File};
-use tokio::io::AsyncWrite;
 use tokio::prelude::*;
 use tokio::runtime::Runtime;
 use tokio::timer::Interval;
 
 use crate::conf;
-use crate::constants::NUMBER_DNS_WORKER_THREADS;
-use crate::lib::transcompiler::Transcompiler;
-use crate::lib::{add_labels, remove_labels};
+use crate::conf::ScraperFormat;
+use crate::constants::RAW;
+use crate::lib::asynch::dns::Resolver;
+use crate::lib::asynch::http::connector;
+use crate::lib::asynch::sync::Semaphore;
+use crate::lib::bucket::TokenBucket;
+use crate::lib::checkpoint;
+use crate::lib::counter::Counters;
+use crate::lib::disk;
+use crate::lib::errors::{self, Component};
+use crate::lib::transcompiler::{detect, encode_pairs, MetricType, MonotonicClock, Transcompiler};
+use crate::lib::{
+    add_labels, class, class_and_labels, normalize_labels, remove_labels, split_labels,
+};
 use crate::lib::{Named, Runner};
 
 /// Alias for the hyper's https client
-type HttpsClient = Client<HttpsConnector<HttpConnector<GaiResolver>>, Body>;
+type HttpsClient = Client<HttpsConnector<HttpConnector<Resolver>>, Body>;
+
+/// Minimum number of lines handled by each worker when the body is parsed in parallel
+const MIN_CHUNK_LINES: usize = 1000;
 
 lazy_static! {
+    static ref BEAMIUM_FETCH: CounterVec = register_counter_vec!(
+        opts!("beamium_fetch_total", "Number of scrapes"),
+        &["scraper"]
+    )
+    .expect("create metric: 'beamium_fetch_total'");
     static ref BEAMIUM_FETCH_DP: CounterVec = register_counter_vec!(
         opts!("beamium_fetch_datapoints", "Number of datapoints fetched"),
         &["scraper"]
@@ -43,6 +68,88 @@ lazy_static! {
         &["scraper"]
     )
     .expect("create metric: 'beamium_fetch_errors'");
+    static ref BEAMIUM_CARDINALITY_JUMPS: CounterVec = register_counter_vec!(
+        opts!(
+            "beamium_cardinality_jumps",
+            "Number of scrapes whose number of time series jumped above the baseline"
+        ),
+        &["scraper"]
+    )
+    .expect("create metric: 'beamium_cardinality_jumps'");
+    static ref BEAMIUM_FETCH_RATE_LIMITED: CounterVec = register_counter_vec!(
+        opts!(
+            "beamium_fetch_rate_limited",
+            "Number of scrapes skipped by the global rate limit"
+        ),
+        &["scraper"]
+    )
+    .expect("create metric: 'beamium_fetch_rate_limited'");
+    static ref BEAMIUM_SERIES_MERGED: CounterVec = register_counter_vec!(
+        opts!(
+            "beamium_series_merged",
+            "Number of distinct time series merged by the removal of the filtered labels"
+        ),
+        &["scraper"]
+    )
+    .expect("create metric: 'beamium_series_merged'");
+    static ref BEAMIUM_LABEL_CARDINALITY: GaugeVec = register_gauge_vec!(
+        opts!(
+            "beamium_label_cardinality",
+            "Number of distinct values of the label key in the last scrape"
+        ),
+        &["scraper", "label"]
+    )
+    .expect("create metric: 'beamium_label_cardinality'");
+    /// Token bucket shared by every scraper, `None` if scrapes are not rate limited
+    static ref SCRAPES: Mutex<Option<TokenBucket>> = Mutex::new(None);
+    /// Semaphores of the scraped hosts shared by every scraper
+    static ref HOSTS: Mutex<Hosts> = Mutex::new(Hosts::default());
+}
+
+/// Bound the number of scrapes per second across scrapers, `None` to remove the bound
+pub(crate) fn limit(rate: Option<u32>) {
+    match SCRAPES.lock() {
+        Ok(mut scrapes) => *scrapes = rate.map(|rate| TokenBucket::from((rate, Instant::now()))),
+        Err(err) => error!("could not limit scrapes"; "error" => err.to_string()),
+    }
+}
+
+/// Bound the number of concurrent scrapes of the same host across scrapers, `None` to remove the
+/// bound
+pub(crate) fn limit_hosts(max: Option<usize>) {
+    match HOSTS.lock() {
+        Ok(mut hosts) => *hosts = Hosts::from(max),
+        Err(err) => error!("could not limit scrapes per host"; "error" => err.to_string()),
+    }
+}
+
+/// `Hosts` holds a semaphore for each scraped host, keyed on the authority of the url
+#[derive(Debug, Default)]
+struct Hosts {
+    max: Option<usize>,
+    semaphores: HashMap<String, Semaphore>,
+}
+
+impl From<Option<usize>> for Hosts {
+    fn from(max: Option<usize>) -> Self {
+        Self {
+            max,
+            semaphores: HashMap::new(),
+        }
+    }
+}
+
+impl Hosts {
+    /// Semaphore of the host, created on first use, `None` if scrapes are not bounded
+    fn semaphore(&mut self, host: &str) -> Option<Semaphore> {
+        let max = self.max?;
+        let semaphore = self
+            .semaphores
+            .entry(host.to_owned())
+            .or_insert_with(|| Semaphore::from(max));
+
+        Some(semaphore.to_owned())
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -50,6 +157,121 @@ pub struct Scraper {
     conf: Arc<conf::Scraper>,
     params: Arc<conf::Parameters>,
     client: Arc<HttpsClient>,
+    detected: Arc<Mutex<Option<ScraperFormat>>>,
+    buffer: Arc<Mutex<Buffer>>,
+    clock: Arc<Mutex<MonotonicClock>>,
+    counters: Arc<Mutex<Counters>>,
+    baseline: Arc<Mutex<Baseline>>,
+}
+
+/// `Buffer` accumulates time series across scrapes when `write-every` is set
+#[derive(Debug)]
+struct Buffer {
+    lines: Vec<String>,
+    size: u64,
+    since: Instant,
+}
+
+/// `Rejection` of a scrape whose body is not read, e.g. an unsuccessful response or a timeout,
+/// along with the `code` label of its status series
+#[derive(Debug)]
+struct Rejection {
+    code: String,
+    message: String,
+}
+
+impl Rejection {
+    fn new<C: ToString, M: ToString>(code: C, message: M) -> Self {
+        Self {
+            code: code.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for Rejection {}
+
+/// `Baseline` keeps the number of time series of the last scrapes, so a jump of cardinality is
+/// detected
+#[derive(Debug, Default)]
+struct Baseline {
+    counts: VecDeque<usize>,
+}
+
+impl Baseline {
+    /// Record the number of time series of the scrape, return the baseline if the scrape exceeds
+    /// it by more than the factor of the guard. A scrape blocked by the guard is not recorded, so
+    /// the baseline only averages the forwarded scrapes.
+    fn check(&mut self, count: usize, guard: &conf::CardinalityGuard) -> Option<f64> {
+        let baseline = match self.counts.len() {
+            0 => None,
+            len => Some(self.counts.iter().sum::<usize>() as f64 / len as f64),
+        };
+
+        // A target starting without any time series is not a jump
+        let jump =
+            baseline.filter(|baseline| *baseline > 0.0 && count as f64 > baseline * guard.factor);
+        if jump.is_some() && guard.mode == conf::GuardMode::Block {
+            return jump;
+        }
+
+        self.counts.push_back(count);
+        while self.counts.len() > guard.window {
+            self.counts.pop_front();
+        }
+
+        jump
+    }
+}
+
+/// `Selection` keeps the state of the processing of a scrape across the parts of its body
+#[derive(Debug)]
+struct Selection {
+    /// Whether the last time series is selected, its continuation lines follow the decision
+    selected: bool,
+    /// Whether the '# EOF' marker is reached
+    stopped: bool,
+    /// Time series of the scrape once the filtered labels are removed, with their original one
+    filtered: HashMap<String, String>,
+    /// Distinct values of each label key of the scrape, if `label-cardinality` is set
+    cardinality: HashMap<String, HashSet<String>>,
+}
+
+impl Default for Selection {
+    fn default() -> Self {
+        Self {
+            selected: true,
+            stopped: false,
+            filtered: HashMap::new(),
+            cardinality: HashMap::new(),
+        }
+    }
+}
+
+/// `Streaming` holds the time series of a streamed scrape which are not written yet
+#[derive(Debug)]
+struct Streaming {
+    compiler: Transcompiler,
+    selection: Selection,
+    pending: Vec<String>,
+    size: u64,
+    batch_count: usize,
+}
+
+impl Default for Buffer {
+    fn default() -> Self {
+        Self {
+            lines: vec![],
+            size: 0,
+            since: Instant::now(),
+        }
+    }
 }
 
 impl From<(conf::Scraper, conf::Parameters)> for Scraper {
@@ -57,13 +279,22 @@ impl From<(conf::Scraper, conf::Parameters)> for Scraper {
         let (conf, params) = tuple;
         let client = Client::builder()
             .keep_alive(true)
-            .keep_alive_timeout(params.timeout)
-            .build(HttpsConnector::new(NUMBER_DNS_WORKER_THREADS));
+            .keep_alive_timeout(conf.timeout_of(&params))
+            .build(connector(
+                params.tls_session_resumption,
+                conf.bind_address.or(params.bind_address),
+                params.min_tls_version,
+            ));
 
         Self {
             conf: arc!(conf),
             params: arc!(params),
             client: arc!(client),
+            detected: mutex!(None),
+            buffer: mutex!(Buffer::default()),
+            clock: mutex!(MonotonicClock::default()),
+            counters: mutex!(Counters::default()),
+            baseline: mutex!(Baseline::default()),
         }
     }
 }
@@ -83,55 +314,145 @@ impl Runner for Scraper {
         let conf = self.conf.to_owned();
         let params = self.params.to_owned();
         let client = self.client.to_owned();
+        let detected = self.detected.to_owned();
+        let buffer = self.buffer.to_owned();
+        let clock = self.clock.to_owned();
+        let counters = self.counters.to_owned();
+        let baseline = self.baseline.to_owned();
+
+        if let Some(checkpoint) = self.checkpoint()? {
+            rt.spawn(checkpoint);
+        }
 
         let executor = rt.executor();
+        let successes = arc!(AtomicU64::new(0));
 
         // Create a ticker for the scraper for the configured period
         let ticker = Interval::new(Instant::now(), conf.period.to_owned())
             .map_err(|err| format_err!("{}", err))
             .for_each(move |_| {
+                if !Self::is_allowed(Instant::now()) {
+                    BEAMIUM_FETCH_RATE_LIMITED.with_label_values(&[conf.name.as_str()]).inc();
+                    warn!("skip scrape, the rate limit is reached"; "scraper" => conf.name.as_str());
+                    return future::ok(());
+                }
+
+                // Scrapes could not be written until some disk space is freed
+                if disk::is_paused(&disk::scraper(&conf.name)) {
+                    debug!("skip scrape, the disk is full"; "scraper" => conf.name.as_str());
+                    return future::ok(());
+                }
+
+                BEAMIUM_FETCH.with_label_values(&[conf.name.as_str()]).inc();
+
                 // Owned variables by creating a new reference using Arc.
                 let name = conf.name.to_owned();
+                let name2 = conf.name.to_owned();
                 let conf = conf.to_owned();
                 let conf2 = conf.to_owned();
+                let conf3 = conf.to_owned();
                 let params = params.to_owned();
-                let compiler = Transcompiler::new(conf.format.to_owned());
+                let params2 = params.to_owned();
+                let detected = detected.to_owned();
+                let buffer = buffer.to_owned();
+                let counters = counters.to_owned();
+                let baseline = baseline.to_owned();
+                let successes = successes.to_owned();
+                let compiler = Transcompiler::new(conf.format.to_owned())
+                    .with_time_unit(params.platform_time_unit)
+                    .with_attributes(&conf.attributes)
+                    .with_geo(conf.geo.as_ref())
+                    .with_summary(conf.summary_handling)
+                    .with_label_escapes(conf.label_escapes);
+                let compiler = match conf.clock {
+                    conf::Clock::Wall => compiler,
+                    conf::Clock::Monotonic => {
+                        let mut clock = try_future!(clock.lock());
+                        compiler.at(clock.now())
+                    }
+                };
 
                 let mut request = Request::builder();
                 let request = request.method(Method::GET).uri(conf.url.to_owned());
 
-                for (header, value) in conf.headers.to_owned() {
+                for (header, value) in conf.headers.iter() {
                     request.header(header.as_str(), value.as_str());
                 }
 
-                info!("fetch success"; "uri" => conf.url.to_string(), "scraper" => name.as_str());
                 let request = try_future!(request.body(Body::empty()));
-                let process = Self::fetch(&client, request, params.timeout)
-                    .and_then(move |body| Self::process(&compiler, &body, &conf))
-                    .and_then(move |lines| {
+                let timeout = conf.timeout_of(&params);
+                if conf.stream_body {
+                    let stream = {
+                        let conf = conf.to_owned();
+                        let params = params.to_owned();
+                        let detected = detected.to_owned();
+                        let counters = counters.to_owned();
+                        let client = client.to_owned();
+
+                        // The body is received while it is processed, so the permit is held until its end
+                        Self::gate(&conf2, move || {
+                            Self::request(&client, request, timeout, conf.to_owned()).and_then(move |(code, body)| {
+                                let body = body.map_err(|err| format_err!("{}", err));
+                                let status = Self::status(&conf, &params, &code.to_string());
+                                Self::stream(body, compiler, conf, params, detected, counters, status)
+                            })
+                        })
+                    };
+
+                    let process = stream
+                        .map(move |_| Self::log_success(&conf, &params, &successes))
+                        .or_else(move |err| {
+                            BEAMIUM_FETCH_ERRORS.with_label_values(&[name.as_str()]).inc();
+                            error!("fetch failed"; "error" => err.to_string(), "scraper" => name.as_str());
+                            errors::record(Component::Scraper, &name, &err.to_string());
+
+                            Self::write_failure(&conf3, &params2, &Self::code_of(&err))
+                        })
+                        .map_err(move |err| {
+                            error!("could not write failure series"; "error" => err.to_string(), "scraper" => name2.as_str())
+                        });
+
+                    return Self::execute(&executor, process);
+                }
+
+                let fetch = {
+                    let client = client.to_owned();
+                    let conf = conf.to_owned();
+                    Self::gate(&conf3, move || Self::fetch(&client, request, timeout, conf))
+                };
+
+                let scrape = fetch
+                    .and_then(move |(code, body)| {
+                        let compiler = match conf.format {
+                            ScraperFormat::Auto => compiler.with_format(Self::detect(&body, &detected, &conf)),
+                            _ => compiler,
+                        };
+
+                        Self::process(&compiler, &body, &conf, &counters)
+                            .and_then(move |lines| Self::guard(lines, &conf, &baseline))
+                            .map(move |lines| (code, lines))
+                    })
+                    .and_then(move |(code, mut lines)| {
                         BEAMIUM_FETCH_DP.with_label_values(&[conf2.name.as_str()]).inc_by(lines.len() as f64);
-                        Self::write(lines, &conf2, &params)
+                        lines.extend(Self::status(&conf2, &params, &code.to_string()));
+                        let lines = Self::accumulate(lines, &buffer, &conf2, &params, Instant::now());
+                        Self::write(lines, &conf2, &params).map(move |_| Self::log_success(&conf2, &params, &successes))
+                    });
+
+                let process = scrape
+                    .or_else(move |err| {
+                        BEAMIUM_FETCH_ERRORS.with_label_values(&[name.as_str()]).inc();
+                        error!("fetch failed"; "error" => err.to_string(), "scraper" => name.as_str());
+                        errors::record(Component::Scraper, &name, &err.to_string());
+
+                        Self::write_failure(&conf3, &params2, &Self::code_of(&err))
                     })
                     .map_err(move |err| {
-                        BEAMIUM_FETCH_ERRORS.with_label_values(&[name.as_str()]).inc();
-                        error!("fetch failed"; "error" => err.to_string(), "scraper" => name.as_str())
+                        error!("could not write failure series"; "error" => err.to_string(), "scraper" => name2.as_str())
                     });
 
                 // Spawn the request on executor to send it
-                if let Err(err) = executor.execute(process) {
-                    match err.kind() {
-                        ExecuteErrorKind::Shutdown => {
-                            warn!("could not execute the future, runtime is closed");
-                        },
-                        _ => {
-                            return future::err(format_err!("could not execute future, got runtime error"));
-                        }
-                    }
-                }
-
-
-                // return that everything is good
-                future::ok(())
+                Self::execute(&executor, process)
             })
             .map_err(move |err| {
                 crit!("could not handle ticker"; "error" => err.to_string(), "scraper" => name);
@@ -147,36 +468,245 @@ impl Runner for Scraper {
 }
 
 impl Scraper {
+    /// Write the time series accumulated by `write-every` and shutdown the runtime of the scraper
+    pub fn stop(&self, mut rt: Runtime) {
+        let lines = match self.buffer.lock() {
+            Ok(mut buffer) => {
+                buffer.size = 0;
+                buffer.lines.split_off(0)
+            }
+            Err(err) => {
+                warn!("could not flush accumulated time series"; "scraper" => self.conf.name.as_str(), "error" => err.to_string());
+                vec![]
+            }
+        };
+
+        if let Err(err) = rt.block_on(Self::write(lines, &self.conf, &self.params)) {
+            error!("could not flush accumulated time series"; "scraper" => self.conf.name.as_str(), "error" => err.to_string());
+        }
+
+        debug!("shutdown scraper's runtime"; "scraper" => self.conf.name.as_str());
+        if rt.shutdown_now().wait().is_err() {
+            error!("could not shutdown the runtime"; "scraper" => self.conf.name.as_str());
+        }
+    }
+
+    /// Restore the saved state of the counters and save it at each interval, `None` if there is no
+    /// state to save
+    fn checkpoint(&self) -> Result<Option<impl Future<Item = (), Error = ()>>, Error> {
+        let checkpoint = match (&self.params.checkpoint, &self.conf.counter_reset_detection) {
+            (Some(checkpoint), Some(_)) => checkpoint.to_owned(),
+            _ => return Ok(None),
+        };
+
+        let path = checkpoint::path(&checkpoint, &format!("scraper-{}", self.conf.name));
+        match checkpoint::load(&path, &checkpoint, SystemTime::now()) {
+            Ok(entries) => {
+                debug!("restore counters"; "scraper" => self.conf.name.as_str(), "count" => entries.len());
+                self.counters
+                    .lock()
+                    .map_err(|err| format_err!("{}", err))?
+                    .restore(entries);
+            }
+            Err(err) => {
+                warn!("could not restore counters"; "scraper" => self.conf.name.as_str(), "error" => err.to_string())
+            }
+        }
+
+        let counters = self.counters.to_owned();
+        Ok(Some(checkpoint::every(path, checkpoint, move || {
+            let counters = counters.lock().map_err(|err| format_err!("{}", err))?;
+            Ok(counters.checkpoint(checkpoint::unix(SystemTime::now())))
+        })))
+    }
+
+    /// Take a token of the bucket shared by the scrapers, allowed if scrapes are not rate limited
+    fn is_allowed(now: Instant) -> bool {
+        match SCRAPES.lock() {
+            Ok(mut scrapes) => match scrapes.as_mut() {
+                None => true,
+                Some(bucket) => bucket.take(now),
+            },
+            Err(err) => {
+                error!("could not get lock on scrapes rate limit"; "error" => err.to_string());
+                true
+            }
+        }
+    }
+
+    /// Build and run the scrape once a permit of its host is acquired, so the concurrent scrapes
+    /// of a host are bounded across scrapers. The scrape is only built once permitted, so its
+    /// timeout does not elapse while waiting, and the permit is released on its completion.
+    fn gate<F, T>(conf: &conf::Scraper, scrape: F) -> impl Future<Item = T::Item, Error = Error>
+    where
+        F: FnOnce() -> T,
+        T: Future<Error = Error>,
+    {
+        let semaphore = match (HOSTS.lock(), conf.url.authority_part()) {
+            (Ok(mut hosts), Some(authority)) => hosts.semaphore(authority.as_str()),
+            (Ok(_), None) => None,
+            (Err(err), _) => {
+                error!("could not get lock on scrapes per host"; "error" => err.to_string(), "scraper" => conf.name.as_str());
+                None
+            }
+        };
+
+        match semaphore {
+            Some(semaphore) => future::Either::A(semaphore.acquire().and_then(move |guard| {
+                scrape().then(move |result| {
+                    drop(guard);
+                    result
+                })
+            })),
+            None => future::Either::B(future::lazy(scrape)),
+        }
+    }
+
+    /// Log the success of the scrape once it is processed and written
+    fn log_success(conf: &conf::Scraper, params: &conf::Parameters, successes: &AtomicU64) {
+        let scrapes = successes.fetch_add(1, Ordering::Relaxed);
+        if Self::is_success_logged(scrapes, params.fetch_success_log_every) {
+            info!("fetch success"; "uri" => conf.url.to_string(), "scraper" => conf.name.as_str());
+        } else {
+            debug!("fetch success"; "uri" => conf.url.to_string(), "scraper" => conf.name.as_str());
+        }
+    }
+
+    /// Whether the success of the scrape is logged at info level, given the number of previous
+    /// successful scrapes, so routine scrapes do not flood the log
+    fn is_success_logged(scrapes: u64, every: Option<u64>) -> bool {
+        match every {
+            None => true,
+            Some(every) => scrapes % every == 0,
+        }
+    }
+
+    /// Spawn the processing of a scrape on the executor
+    fn execute<E, F>(executor: &E, process: F) -> future::FutureResult<(), Error>
+    where
+        E: Executor<F>,
+        F: Future<Item = (), Error = ()>,
+    {
+        if let Err(err) = executor.execute(process) {
+            match err.kind() {
+                ExecuteErrorKind::Shutdown => {
+                    warn!("could not execute the future, runtime is closed");
+                }
+                _ => {
+                    return future::err(format_err!("could not execute future, got runtime error"));
+                }
+            }
+        }
+
+        // return that everything is good
+        future::ok(())
+    }
+
+    /// Detect the format of the body once and keep it for next scrapes, fallback on Prometheus
+    fn detect(
+        body: &str,
+        detected: &Mutex<Option<ScraperFormat>>,
+        conf: &conf::Scraper,
+    ) -> ScraperFormat {
+        let mut detected = match detected.lock() {
+            Ok(detected) => detected,
+            Err(err) => {
+                error!("could not get lock on detected format"; "scraper" => conf.name.as_str(), "error" => err.to_string());
+                return ScraperFormat::Prometheus;
+            }
+        };
+
+        if let Some(format) = detected.as_ref() {
+            return format.to_owned();
+        }
+
+        match detect(body) {
+            None => ScraperFormat::Prometheus,
+            Some(format) => {
+                info!("detect format"; "scraper" => conf.name.as_str(), "format" => format!("{:?}", format));
+                *detected = Some(format.to_owned());
+                format
+            }
+        }
+    }
+
     /// Fetch the source of the scraper using the http(s) [`Client`], the given [`Request`] and the
-    /// timeout [`Duration`].
+    /// timeout [`Duration`], along with the http status of the response.
     fn fetch(
         client: &HttpsClient,
         request: Request<Body>,
         timeout: Duration,
-    ) -> impl Future<Item = String, Error = Error> {
+        conf: Arc<conf::Scraper>,
+    ) -> impl Future<Item = (u16, String), Error = Error> {
+        Self::request(client, request, timeout, conf).and_then(|(code, body)| {
+            body.concat2()
+                .map_err(|err| format_err!("{}", err))
+                .map(move |body| (code, String::from_utf8_lossy(&body).to_string()))
+        })
+    }
+
+    /// Send the request and retrieve the http status and the body of a successful response, whose
+    /// content type should match the required pattern if any. Otherwise, the error is a
+    /// [`Rejection`] holding the status, or `timeout` if no response is received in time.
+    fn request(
+        client: &HttpsClient,
+        request: Request<Body>,
+        timeout: Duration,
+        conf: Arc<conf::Scraper>,
+    ) -> impl Future<Item = (u16, Body), Error = Error> {
         client
             .request(request)
             .map_err(|err| format_err!("{}", err))
             .timeout(timeout)
-            .map_err(|err| format_err!("{}", err))
-            .and_then(|response| {
+            .map_err(|err| {
+                if err.is_elapsed() {
+                    return Error::from(Rejection::new("timeout", err));
+                }
+
+                format_err!("{}", err)
+            })
+            .and_then(move |response| {
                 let status = response.status();
                 if !status.is_success() {
-                    return future::err(format_err!(
-                        "http request failed, got: {}",
-                        status.as_u16()
-                    ));
+                    let message = format!("http request failed, got: {}", status.as_u16());
+                    return Err(Error::from(Rejection::new(status.as_u16(), message)));
                 }
 
-                future::ok(
-                    response
-                        .into_body()
-                        .concat2()
-                        .map_err(|err| format_err!("{}", err)),
-                )
+                if let Some(ref pattern) = conf.require_content_type {
+                    Self::check_content_type(response.headers(), pattern)
+                        .map_err(|err| Rejection::new(status.as_u16(), err))?;
+                }
+
+                Ok((status.as_u16(), response.into_body()))
             })
-            .flatten()
-            .and_then(|body| future::ok(String::from_utf8_lossy(&body).to_string()))
+    }
+
+    /// Value of the `code` label of the status series of a failed scrape, `0` if no response is
+    /// received
+    fn code_of(err: &Error) -> String {
+        err.downcast_ref::<Rejection>()
+            .map_or_else(|| String::from("0"), |rejection| rejection.code.to_owned())
+    }
+
+    /// Check that the content type of the response matches the pattern, e.g. to reject the html
+    /// error page returned by a proxy along with a successful status
+    fn check_content_type(headers: &HeaderMap, pattern: &Regex) -> Result<(), Error> {
+        let content_type = match headers.get(CONTENT_TYPE) {
+            Some(value) => value
+                .to_str()
+                .map_err(|err| format_err!("could not read content type, {}", err))?,
+            None => return Err(format_err!("content type is missing")),
+        };
+
+        if !pattern.is_match(content_type) {
+            return Err(format_err!(
+                "content type '{}' does not match '{}'",
+                content_type,
+                pattern
+            ));
+        }
+
+        Ok(())
     }
 
     /// Process scraper's data in order to add/remove labels and format time series into sensision
@@ -185,7 +715,128 @@ impl Scraper {
         transcompiler: &Transcompiler,
         body: &str,
         conf: &conf::Scraper,
+        counters: &Mutex<Counters>,
+    ) -> impl Future<Item = Vec<String>, Error = Error> {
+        if let Some(chunks) = Self::chunks(body, conf) {
+            transcompiler.declare(body);
+            return future::Either::A(Self::process_chunks(
+                transcompiler,
+                chunks,
+                Arc::new(conf.to_owned()),
+            ));
+        }
+
+        let mut selection = Selection::default();
+        let lines = Self::process_lines(transcompiler, body, conf, counters, &mut selection)
+            .and_then(|lines| {
+                Self::sweep(conf, counters)?;
+                Self::account(conf, &selection);
+                Ok(lines)
+            });
+
+        future::Either::B(future::result(lines))
+    }
+
+    /// Split the body in chunks of complete time series, one per worker of the pool, if it is
+    /// parsed in parallel. A time series is never split from its continuation lines.
+    fn chunks(body: &str, conf: &conf::Scraper) -> Option<Vec<String>> {
+        // Counters and merged time series are tracked across the whole scrape
+        if !conf.parallel_parsing
+            || conf.counter_reset_detection.is_some()
+            || !conf.filtered_labels.is_empty()
+        {
+            return None;
+        }
+
+        let lines: Vec<&str> = body.lines().collect();
+        let workers = conf.pool.min(lines.len() / MIN_CHUNK_LINES);
+        if workers < 2 {
+            return None;
+        }
+
+        let size = (lines.len() + workers - 1) / workers;
+        let mut chunks = Vec::with_capacity(workers);
+        let mut start = 0;
+        while start < lines.len() {
+            let mut end = (start + size).min(lines.len());
+            while end < lines.len() && lines[end].starts_with('=') {
+                end += 1;
+            }
+
+            chunks.push(lines[start..end].join("\n"));
+            start = end;
+        }
+
+        Some(chunks)
+    }
+
+    /// Process the chunks of the body on the workers of the pool and join their lines in order
+    fn process_chunks(
+        transcompiler: &Transcompiler,
+        chunks: Vec<String>,
+        conf: Arc<conf::Scraper>,
     ) -> impl Future<Item = Vec<String>, Error = Error> {
+        let transcompiler = transcompiler.to_owned();
+
+        // Chunks are spawned once polled, so on the executor of the scraper
+        future::lazy(move || {
+            let executor = DefaultExecutor::current();
+            let parts: Vec<_> = chunks
+                .into_iter()
+                .map(|chunk| {
+                    let compiler = transcompiler.to_owned();
+                    let conf = conf.to_owned();
+                    oneshot::spawn_fn(
+                        move || {
+                            // Counters are never detected in parallel, so they are not shared
+                            let mut selection = Selection::default();
+                            let counters = Mutex::new(Counters::default());
+                            Self::process_lines(&compiler, &chunk, &conf, &counters, &mut selection)
+                                .map(|lines| (lines, selection))
+                        },
+                        &executor,
+                    )
+                })
+                .collect();
+
+            future::join_all(parts).map(move |parts| {
+                let mut lines = vec![];
+                let mut selection = Selection::default();
+                for (part, chunk) in parts {
+                    lines.extend(part);
+                    for (key, values) in chunk.cardinality {
+                        selection.cardinality.entry(key).or_default().extend(values);
+                    }
+
+                    // Chunks following the '# EOF' marker are dropped
+                    if chunk.stopped {
+                        break;
+                    }
+                }
+
+                Self::account(&conf, &selection);
+                lines
+            })
+        })
+    }
+
+    /// Process a part of the body holding complete lines, the selection is kept across the parts
+    /// of the body
+    fn process_lines(
+        transcompiler: &Transcompiler,
+        body: &str,
+        conf: &conf::Scraper,
+        counters: &Mutex<Counters>,
+        selection: &mut Selection,
+    ) -> Result<Vec<String>, Error> {
+        let mut counters = match conf.counter_reset_detection {
+            None => None,
+            Some(ref policy) => Some((
+                counters.lock().map_err(|err| format_err!("{}", err))?,
+                policy,
+            )),
+        };
+
         let mut lines = vec![];
         let labels: Vec<String> = conf
             .labels
@@ -196,109 +847,1663 @@ impl Scraper {
 
         let labels = labels.join(",");
         for line in body.lines() {
-            let mut line = try_future!(transcompiler.format(line));
+            if selection.stopped {
+                break;
+            }
+
+            if conf.stop_at_eof && line.trim() == "# EOF" {
+                selection.stopped = true;
+                break;
+            }
+
+            let mut line = transcompiler
+                .format(line)
+                .map_err(|err| format_err!("{}", err))?;
             if line.is_empty() || line.starts_with('#') {
                 continue;
             }
 
-            if let Some(ref regex) = &conf.metrics {
-                if !regex.is_match(&line) {
+            if let Some(ref metrics) = &conf.metrics {
+                let is_match = match conf.metrics_match {
+                    conf::MetricsMatch::Line => metrics.is_match(&line),
+                    // Continuation lines follow the decision made for their class
+                    conf::MetricsMatch::Class => match class(&line) {
+                        Some(class) => {
+                            selection.selected = metrics.is_match(class);
+                            selection.selected
+                        }
+                        None => selection.selected,
+                    },
+                };
+
+                if !is_match {
                     continue;
                 }
             }
 
-            if !line.starts_with('=') {
-                line = try_future!(add_labels(&line, &labels));
-                line = try_future!(remove_labels(&line, &conf.filtered_labels));
+            if let Some((ref mut counters, policy)) = counters {
+                let kind = class(&line).and_then(|class| transcompiler.type_of(class));
+                if kind == Some(MetricType::Counter) {
+                    line = counters.check(&line, policy)?;
+                }
+            }
+
+            if !conf.continuation_lines || !line.starts_with('=') {
+                // Normalize fetched labels first, so filters apply on normalized keys
+                if conf.normalize_label_keys {
+                    line = normalize_labels(&line, conf.normalize_label_values)?;
+                }
+
+                line = add_labels(&line, &labels)?;
+                if !conf.filtered_labels.is_empty() {
+                    let original = line.to_owned();
+                    line = remove_labels(&line, &conf.filtered_labels)?;
+                    if Self::is_merged(&original, &line, &mut selection.filtered) {
+                        BEAMIUM_SERIES_MERGED
+                            .with_label_values(&[conf.name.as_str()])
+                            .inc();
+                    }
+                }
+
+                if conf.label_cardinality {
+                    for (key, value) in split_labels(&line) {
+                        selection
+                            .cardinality
+                            .entry(key.to_owned())
+                            .or_default()
+                            .insert(value.to_owned());
+                    }
+                }
             }
 
             lines.push(line);
         }
 
-        future::ok(lines)
+        Ok(lines)
     }
 
-    /// Write time series into the disk
-    fn write(
-        lines: Vec<String>,
-        conf: &conf::Scraper,
-        params: &conf::Parameters,
-    ) -> impl Future<Item = (), Error = Error> {
-        let start = now_utc();
-        let now =
-            start.to_timespec().sec * 1_000_000 + (i64::from(start.to_timespec().nsec) / 1000);
+    /// Whether the time series, once its filtered labels are removed, is the same as another time
+    /// series of the scrape
+    fn is_merged(original: &str, filtered: &str, series: &mut HashMap<String, String>) -> bool {
+        let (original, filtered) = match (class_and_labels(original), class_and_labels(filtered)) {
+            (Some(original), Some(filtered)) => (original, filtered),
+            _ => return false,
+        };
 
-        let dir = Path::new(&params.source_dir);
+        match series.get(filtered) {
+            Some(known) => known != original,
+            None => {
+                series.insert(filtered.to_owned(), original.to_owned());
+                false
+            }
+        }
+    }
 
-        let mut batch_size = 0;
-        let mut batch_count = -1;
-        let mut chunks = vec![];
-        let mut chunk = vec![];
-        for line in lines {
-            batch_size += line.len() as u64;
-            if batch_size > params.batch_size && !line.starts_with('=') {
-                batch_size = 0;
-                batch_count += 1;
+    /// Compare the number of time series of the scrape with the baseline of the previous ones, the
+    /// time series of a blocked scrape are dropped
+    fn guard(
+        lines: Vec<String>,
+        conf: &conf::Scraper,
+        baseline: &Mutex<Baseline>,
+    ) -> Result<Vec<String>, Error> {
+        let guard = match conf.cardinality_guard {
+            None => return Ok(lines),
+            Some(ref guard) => guard,
+        };
 
-                let file_name = format!("{}-{}-{}.tmp", conf.name, now, batch_count);
-                let dir = dir.to_owned();
-                let batch_count = batch_count.to_owned();
-                let now = now.to_owned();
-                let name = conf.name.to_owned();
-                let name2 = conf.name.to_owned();
-                let temp_file = dir.join(file_name.to_owned());
-
-                debug!("create file"; "scraper" => name.to_owned(), "file" => temp_file.to_str());
-                chunks.push(
-                    File::create(temp_file.to_owned())
-                        .and_then(move |mut file| {
-                            trace!("write chunk on file"; "scraper" => name, "file" => temp_file.to_str());
-                            file.poll_write((chunk.join("\n") + "\n").as_bytes())
-                                .and_then(|_| file.poll_flush())
-                        })
-                        .and_then(move |_| {
-                            let old = dir.join(file_name);
-                            let new = dir.join(format!("{}-{}-{}.metrics", name2, now, batch_count));
+        let count = lines.iter().filter(|line| class(line).is_some()).count();
+        let baseline = match baseline
+            .lock()
+            .map_err(|err| format_err!("{}", err))?
+            .check(count, guard)
+        {
+            None => return Ok(lines),
+            Some(baseline) => baseline,
+        };
 
-                            debug!("rotate source file"; "scraper" => name2, "old" => old.to_str(), "new" => new.to_str());
-                            rename(old, new)
-                        })
-                        .and_then(|_| future::ok(()))
-                        .map_err(|err| format_err!("{}", err)),
-                );
+        BEAMIUM_CARDINALITY_JUMPS
+            .with_label_values(&[conf.name.as_str()])
+            .inc();
 
-                chunk = vec![];
+        match guard.mode {
+            conf::GuardMode::Warn => {
+                warn!("cardinality jumped"; "scraper" => conf.name.as_str(), "series" => count, "baseline" => baseline);
+                Ok(lines)
             }
+            conf::GuardMode::Block => {
+                warn!("skip scrape, cardinality jumped"; "scraper" => conf.name.as_str(), "series" => count, "baseline" => baseline);
+                Ok(vec![])
+            }
+        }
+    }
 
-            chunk.push(line);
+    /// Forget the counters missing from the scrape once it is processed
+    fn sweep(conf: &conf::Scraper, counters: &Mutex<Counters>) -> Result<(), Error> {
+        if conf.counter_reset_detection.is_some() {
+            counters
+                .lock()
+                .map_err(|err| format_err!("{}", err))?
+                .sweep();
         }
 
-        let bulk = future::join_all(chunks).and_then(|_| Ok(()));
+        Ok(())
+    }
 
-        batch_count += 1;
+    /// Expose the number of distinct values of each label key once the scrape is processed, the
+    /// label keys missing from the scrape are removed
+    fn account(conf: &conf::Scraper, selection: &Selection) {
+        if !conf.label_cardinality {
+            return;
+        }
 
-        let name = conf.name.to_owned();
-        let name2 = conf.name.to_owned();
-        let dir = dir.to_owned();
-        let file_name = format!("{}-{}-{}.tmp", conf.name, now, batch_count);
-        let temp_file = dir.join(file_name.to_owned());
+        let stale: Vec<String> = BEAMIUM_LABEL_CARDINALITY
+            .collect()
+            .iter()
+            .flat_map(|family| family.get_metric())
+            .filter_map(|metric| {
+                let labels = metric.get_label();
+                let value = |name: &str| {
+                    labels
+                        .iter()
+                        .find(|pair| pair.get_name() == name)
+                        .map(|pair| pair.get_value())
+                };
 
-        trace!("create tmp source file"; "scraper" => name, "file" => temp_file.to_str());
-        let chunk = File::create(temp_file)
-            .and_then(move |mut file| {
-                file.poll_write((chunk.join("\n") + "\n").as_bytes())
-                    .and_then(|_| file.poll_flush())
+                match (value("scraper"), value("label")) {
+                    (Some(scraper), Some(key))
+                        if scraper == conf.name && !selection.cardinality.contains_key(key) =>
+                    {
+                        Some(key.to_owned())
+                    }
+                    _ => None,
+                }
             })
-            .and_then(move |_| {
-                let old = dir.join(file_name);
-                let new = dir.join(format!("{}-{}-{}.metrics", name2, now, batch_count));
+            .collect();
 
-                debug!("rotate file"; "scraper" => name2, "old" => old.to_str(), "new" => new.to_str());
-                rename(old, new)
-            })
-            .and_then(|_| future::ok(()))
-            .map_err(|err| format_err!("{}", err));
+        for key in stale {
+            if let Err(err) =
+                BEAMIUM_LABEL_CARDINALITY.remove_label_values(&[conf.name.as_str(), key.as_str()])
+            {
+                warn!("could not remove label cardinality"; "scraper" => conf.name.as_str(), "label" => key, "error" => err.to_string());
+            }
+        }
+
+        for (key, values) in &selection.cardinality {
+            BEAMIUM_LABEL_CARDINALITY
+                .with_label_values(&[conf.name.as_str(), key.as_str()])
+                .set(values.len() as f64);
+        }
+    }
+
+    /// Process the body while it is received and write the time series as soon as a batch is
+    /// complete, so the body is never held in memory. The status series, if any, ends the last batch.
+    fn stream<S>(
+        body: S,
+        compiler: Transcompiler,
+        conf: Arc<conf::Scraper>,
+        params: Arc<conf::Parameters>,
+        detected: Arc<Mutex<Option<ScraperFormat>>>,
+        counters: Arc<Mutex<Counters>>,
+        status: Option<String>,
+    ) -> impl Future<Item = (), Error = Error>
+    where
+        S: Stream<Error = Error>,
+        S::Item: AsRef<[u8]>,
+    {
+        let start = now_utc();
+        let now =
+            start.to_timespec().sec * 1_000_000 + (i64::from(start.to_timespec().nsec) / 1000);
+
+        let conf2 = conf.to_owned();
+        let params2 = params.to_owned();
+        let counters2 = counters.to_owned();
+        let streaming = Streaming {
+            compiler,
+            selection: Selection::default(),
+            pending: vec![],
+            size: 0,
+            batch_count: 0,
+        };
+
+        // Each part holds the complete lines received so far, the trailing partial line is kept
+        // until its end is received
+        let mut partial = vec![];
+        body.map(|chunk| Some(chunk.as_ref().to_vec()))
+            .chain(stream::once(Ok(None)))
+            .map(move |chunk| match chunk {
+                Some(chunk) => {
+                    partial.extend(chunk);
+                    match partial.iter().rposition(|byte| *byte == b'\n') {
+                        None => String::new(),
+                        Some(index) => {
+                            let rest = partial.split_off(index + 1);
+                            let part = String::from_utf8_lossy(&partial).to_string();
+                            partial = rest;
+                            part
+                        }
+                    }
+                }
+                None => String::from_utf8_lossy(&partial.split_off(0)).to_string(),
+            })
+            .fold(streaming, move |mut streaming, part| {
+                if part.is_empty() {
+                    return future::Either::A(future::ok(streaming));
+                }
+
+                if conf.format == ScraperFormat::Auto {
+                    let format = Self::detect(&part, &detected, &conf);
+                    streaming.compiler = streaming.compiler.with_format(format);
+                }
+
+                let lines = match Self::process_lines(
+                    &streaming.compiler,
+                    &part,
+                    &conf,
+                    &counters,
+                    &mut streaming.selection,
+                ) {
+                    Ok(lines) => lines,
+                    Err(err) => return future::Either::A(future::err(err)),
+                };
+
+                BEAMIUM_FETCH_DP
+                    .with_label_values(&[conf.name.as_str()])
+                    .inc_by(lines.len() as f64);
+
+                streaming.size += lines.iter().map(|line| line.len() as u64).sum::<u64>();
+                streaming.pending.extend(lines);
+                if streaming.size <= params.batch_size {
+                    return future::Either::A(future::ok(streaming));
+                }
+
+                // Continuation lines are kept with their time series
+                let index = streaming
+                    .pending
+                    .iter()
+                    .rposition(|line| !conf.continuation_lines || !line.starts_with('='))
+                    .unwrap_or(0);
+
+                if index == 0 {
+                    return future::Either::A(future::ok(streaming));
+                }
+
+                let rest = streaming.pending.split_off(index);
+                let chunk = std::mem::replace(&mut streaming.pending, rest);
+                streaming.size = streaming.pending.iter().map(|line| line.len() as u64).sum();
+
+                let batch_count = streaming.batch_count;
+                streaming.batch_count += 1;
+
+                let dir = PathBuf::from(params.source_dir.to_owned());
+                future::Either::B(
+                    Self::write_chunk(
+                        chunk,
+                        dir,
+                        conf.name.to_owned(),
+                        disk::scraper(&conf.name),
+                        conf.continuation_lines,
+                        now,
+                        batch_count,
+                    )
+                    .map(move |_| streaming),
+                )
+            })
+            .and_then(move |mut streaming| {
+                if let Err(err) = Self::sweep(&conf2, &counters2) {
+                    return future::Either::A(future::err(err));
+                }
+
+                Self::account(&conf2, &streaming.selection);
+
+                // The status series is written along with the last time series of the scrape
+                streaming.pending.extend(status);
+                if streaming.pending.is_empty() {
+                    if streaming.batch_count == 0 {
+                        debug!("skip empty scrape"; "scraper" => conf2.name.as_str());
+                    }
+
+                    return future::Either::A(future::ok(()));
+                }
+
+                // The last chunk is always written
+                let dir = PathBuf::from(params2.source_dir.to_owned());
+                future::Either::B(Self::write_chunk(
+                    streaming.pending,
+                    dir,
+                    conf2.name.to_owned(),
+                    disk::scraper(&conf2.name),
+                    conf2.continuation_lines,
+                    now,
+                    streaming.batch_count,
+                ))
+            })
+    }
+
+    /// Accumulate time series until `write-every` is elapsed or the buffer reaches the batch
+    /// size, return the time series to write
+    fn accumulate(
+        lines: Vec<String>,
+        buffer: &Mutex<Buffer>,
+        conf: &conf::Scraper,
+        params: &conf::Parameters,
+        now: Instant,
+    ) -> Vec<String> {
+        let write_every = match conf.write_every {
+            None => return lines,
+            Some(write_every) => write_every,
+        };
+
+        let mut buffer = match buffer.lock() {
+            Ok(buffer) => buffer,
+            Err(err) => {
+                warn!("could not accumulate time series"; "scraper" => conf.name.as_str(), "error" => err.to_string());
+                return lines;
+            }
+        };
+
+        buffer.size += lines.iter().map(|line| line.len() as u64 + 1).sum::<u64>();
+        buffer.lines.extend(lines);
+
+        if now.duration_since(buffer.since) < write_every && buffer.size < params.batch_size {
+            trace!("accumulate time series"; "scraper" => conf.name.as_str(), "size" => buffer.size);
+            return vec![];
+        }
+
+        buffer.size = 0;
+        buffer.since = now;
+        buffer.lines.split_off(0)
+    }
+
+    /// Write a series on scrape failure, along with the status series of the scrape, if enabled
+    fn write_failure(
+        conf: &conf::Scraper,
+        params: &conf::Parameters,
+        code: &str,
+    ) -> impl Future<Item = (), Error = Error> {
+        let mut lines = vec![];
+        if let Some(class) = &conf.failure_series {
+            lines.push(Self::series(
+                conf,
+                params,
+                class,
+                &[("scraper", conf.name.as_str())],
+            ));
+        }
+
+        lines.extend(Self::status(conf, params, code));
+        if lines.is_empty() {
+            return future::Either::A(future::ok(()));
+        }
+
+        future::Either::B(Self::write(lines, conf, params))
+    }
+
+    /// Series holding the http status of the scrape in its `code` label, if enabled
+    fn status(conf: &conf::Scraper, params: &conf::Parameters, code: &str) -> Option<String> {
+        conf.status_series.as_ref().map(|class| {
+            Self::series(
+                conf,
+                params,
+                class,
+                &[("scraper", conf.name.as_str()), ("code", code)],
+            )
+        })
+    }
+
+    /// Series of the scraper valued 1 at the current time, along with the labels of the scraper.
+    /// The labels are encoded, so the name of the scraper could hold any character.
+    fn series(
+        conf: &conf::Scraper,
+        params: &conf::Parameters,
+        class: &str,
+        labels: &[(&str, &str)],
+    ) -> String {
+        let mut pairs: Vec<(&str, &str)> = conf
+            .labels
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        pairs.extend(labels);
+
+        let start = now_utc();
+        let now =
+            start.to_timespec().sec * 1_000_000 + (i64::from(start.to_timespec().nsec) / 1000);
+
+        format!(
+            "{}// {}{{{}}} 1",
+            params.platform_time_unit.scale(now),
+            class,
+            encode_pairs(&pairs)
+        )
+    }
+
+    /// Write time series into the disk
+    fn write(
+        lines: Vec<String>,
+        conf: &conf::Scraper,
+        params: &conf::Parameters,
+    ) -> impl Future<Item = (), Error = Error> {
+        if lines.is_empty() {
+            // The scrape succeeded but there is nothing to forward
+            debug!("skip empty scrape"; "scraper" => conf.name.as_str());
+            return future::Either::A(future::ok(()));
+        }
+
+        let start = now_utc();
+        let now =
+            start.to_timespec().sec * 1_000_000 + (i64::from(start.to_timespec().nsec) / 1000);
+
+        let dir = PathBuf::from(params.source_dir.to_owned());
+        let name = conf.name.to_owned();
+        let continuations = conf.continuation_lines;
+
+        let mut batch_size = 0;
+        let mut chunks = vec![];
+        let mut chunk = vec![];
+        for line in lines {
+            batch_size += line.len() as u64;
+            let is_continuation = conf.continuation_lines && line.starts_with('=');
+            if batch_size > params.batch_size && !is_continuation && !chunk.is_empty() {
+                batch_size = 0;
+                chunks.push(chunk);
+                chunk = vec![];
+            }
+
+            chunk.push(line);
+        }
+
+        // The last chunk is always written
+        chunks.push(chunk);
+
+        // Bound the number of files written at the same time
+        let write = stream::iter_ok(chunks.into_iter().enumerate())
+            .map(move |(batch_count, chunk)| {
+                Self::write_chunk(
+                    chunk,
+                    dir.to_owned(),
+                    name.to_owned(),
+                    disk::scraper(&name),
+                    continuations,
+                    now,
+                    batch_count,
+                )
+            })
+            .buffer_unordered(params.filesystem_threads)
+            .for_each(|_| future::ok(()));
+
+        future::Either::B(write)
+    }
+
+    /// Write a chunk of time series into a temporary file and then rotate it, the file is tagged
+    /// if its lines starting with '=' are not continuation lines. The writer is paused if the
+    /// disk is full.
+    pub(crate) fn write_chunk(
+        chunk: Vec<String>,
+        dir: PathBuf,
+        name: String,
+        writer: String,
+        continuations: bool,
+        now: i64,
+        batch_count: usize,
+    ) -> impl Future<Item = (), Error = Error> {
+        let tag = if continuations {
+            String::new()
+        } else {
+            format!(".{}", RAW)
+        };
+
+        let file_name = format!("{}-{}-{}.tmp", name, now, batch_count);
+        let temp_file = dir.join(file_name.to_owned());
+        let name2 = name.to_owned();
+
+        debug!("create file"; "scraper" => name.to_owned(), "file" => temp_file.to_str());
+        File::create(temp_file.to_owned())
+            .and_then(move |file| {
+                trace!("write chunk on file"; "scraper" => name, "file" => temp_file.to_str());
+                disk::spool(file, (chunk.join("\n") + "\n").into_bytes())
+            })
+            .and_then(move |_| {
+                let old = dir.join(file_name);
+                let new = dir.join(format!("{}-{}-{}{}.metrics", name2, now, batch_count, tag));
+
+                debug!("rotate source file"; "scraper" => name2, "old" => old.to_str(), "new" => new.to_str());
+                rename(old, new)
+            })
+            .then(move |result| match result {
+                Ok(_) => {
+                    disk::resume(&writer);
+                    Ok(())
+                }
+                Err(err) => {
+                    disk::fail(&writer, &err);
+                    Err(format_err!("{}", err))
+                }
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, read_dir, read_to_string, remove_dir_all};
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use failure::{format_err, Error};
+    use futures::{future, stream, Future};
+    use hyper::header::{HeaderValue, CONTENT_TYPE};
+    use hyper::HeaderMap;
+    use prometheus::core::Collector;
+    use regex::RegexSet;
+    use tokio::prelude::FutureExt;
+    use tokio::runtime::Runtime;
+    use tokio::timer::Delay;
+    use uuid::Uuid;
+
+    use crate::conf::tests::parameters;
+    use crate::conf::{self, RawCardinalityGuard, RawScraper, ScraperFormat};
+    use crate::lib::asynch::sync::Semaphore;
+    use crate::lib::counter::Counters;
+    use crate::lib::transcompiler::Transcompiler;
+
+    use super::{Baseline, Hosts, Rejection};
+
+    fn scraper(raw: RawScraper) -> conf::Scraper {
+        conf::Scraper::try_from((String::from("test"), raw)).expect("valid scraper")
+    }
+
+    fn process(body: &str, conf: &conf::Scraper) -> Vec<String> {
+        let transcompiler = Transcompiler::new(ScraperFormat::Sensision);
+        super::Scraper::process(&transcompiler, body, conf, &Mutex::new(Counters::default()))
+            .wait()
+            .expect("process body")
+    }
+
+    #[test]
+    fn metrics_match_line() {
+        let conf = scraper(RawScraper {
+            url: String::from("http://127.0.0.1/metrics"),
+            period: String::from("1s"),
+            metrics: Some(vec![String::from("cpu")]),
+            ..Default::default()
+        });
+
+        let body = "1// node_cpu{} 1\n1// node_mem{mode=cpu} 2\n1// node_disk{} 3";
+        assert_eq!(
+            vec!["1// node_cpu{} 1", "1// node_mem{mode=cpu} 2"],
+            process(body, &conf)
+        );
+    }
+
+    #[test]
+    fn metrics_literal_classes() {
+        let patterns: Vec<String> = vec!["node_cpu", "node_mem", "my_node_cpu"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+
+        let classes = scraper(RawScraper {
+            url: String::from("http://127.0.0.1/metrics"),
+            period: String::from("1s"),
+            metrics: Some(patterns.to_owned()),
+            metrics_match: Some(String::from("class")),
+            ..Default::default()
+        });
+        assert!(matches!(classes.metrics, Some(conf::Metrics::Classes(_))));
+
+        // A regex set anchored on the class selects the same time series
+        let anchored = patterns.iter().map(|p| format!("^(?:{})$", p));
+        let patterns = conf::Scraper {
+            metrics: Some(conf::Metrics::Patterns(
+                RegexSet::new(anchored).expect("valid patterns"),
+            )),
+            ..classes.to_owned()
+        };
+
+        let body = "1// node_cpu{} 1\n=2// 2\n1// node_cpu_seconds{} 2\n1// node_mem{mode=cpu} 3\n\
+                    =2// 4\n1// node_disk{} 5\n1// my_node_cpu{} 6\n1// node{} 7";
+        let selected = process(body, &classes);
+        assert_eq!(
+            vec![
+                "1// node_cpu{} 1",
+                "=2// 2",
+                "1// node_mem{mode=cpu} 3",
+                "=2// 4",
+                "1// my_node_cpu{} 6"
+            ],
+            selected
+        );
+        assert_eq!(process(body, &patterns), selected);
+
+        // A pattern which is not a literal falls back on the regex set
+        let conf = scraper(RawScraper {
+            url: String::from("http://127.0.0.1/metrics"),
+            period: String::from("1s"),
+            metrics: Some(vec![String::from("node_cpu"), String::from("node_.*")]),
+            metrics_match: Some(String::from("class")),
+            ..Default::default()
+        });
+        assert!(matches!(conf.metrics, Some(conf::Metrics::Patterns(_))));
+    }
+
+    #[test]
+    #[ignore]
+    fn metrics_selection_benchmark() {
+        let patterns: Vec<String> = (0..5000).map(|idx| format!("class_{}", idx)).collect();
+        let body: String = (0..20000)
+            .map(|idx| format!("1// class_{}{{host=a}} {}\n", idx, idx))
+            .collect();
+
+        let classes = scraper(RawScraper {
+            url: String::from("http://127.0.0.1/metrics"),
+            period: String::from("1s"),
+            metrics: Some(patterns.to_owned()),
+            metrics_match: Some(String::from("class")),
+            ..Default::default()
+        });
+
+        let anchored = patterns.iter().map(|p| format!("^(?:{})$", p));
+        let patterns = conf::Scraper {
+            metrics: Some(conf::Metrics::Patterns(
+                RegexSet::new(anchored).expect("valid patterns"),
+            )),
+            ..classes.to_owned()
+        };
+
+        for (name, conf) in [("classes", &classes), ("patterns", &patterns)].iter() {
+            let start = Instant::now();
+            let selected = process(&body, conf);
+            println!(
+                "{}: {} lines selected in {:?}",
+                name,
+                selected.len(),
+                start.elapsed()
+            );
+        }
+    }
+
+    #[test]
+    fn metrics_match_class() {
+        let conf = scraper(RawScraper {
+            url: String::from("http://127.0.0.1/metrics"),
+            period: String::from("1s"),
+            metrics: Some(vec![String::from("node_cpu.*")]),
+            metrics_match: Some(String::from("class")),
+            ..Default::default()
+        });
+
+        let body =
+            "1// node_cpu{} 1\n=2// 2\n1// node_mem{mode=node_cpu} 2\n=2// 3\n1// my_node_cpu{} 3";
+        assert_eq!(vec!["1// node_cpu{} 1", "=2// 2"], process(body, &conf));
+    }
+
+    #[test]
+    fn stop_at_eof() {
+        let body = "1// f{} 1\n# EOF\n1// garbage{} 1";
+        let mut raw = RawScraper {
+            url: String::from("http://127.0.0.1/metrics"),
+            period: String::from("1s"),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            vec!["1// f{} 1", "1// garbage{} 1"],
+            process(body, &scraper(raw.to_owned()))
+        );
+
+        raw.stop_at_eof = Some(true);
+        assert_eq!(vec!["1// f{} 1"], process(body, &scraper(raw)));
+    }
+
+    #[test]
+    fn windows_line_endings() {
+        let conf = scraper(RawScraper {
+            url: String::from("http://127.0.0.1/metrics"),
+            period: String::from("1s"),
+            labels: Some(
+                vec![(String::from("dc"), String::from("GRA"))]
+                    .into_iter()
+                    .collect(),
+            ),
+            ..Default::default()
+        });
+
+        // Lines are split on '\r\n' and trimmed by the transcompiler, even the last one
+        let body = "1// f{host=a} 1\r\n=2// 2\r\n1// g{} 2\r";
+        assert_eq!(
+            vec!["1// f{dc=GRA,host=a} 1", "=2// 2", "1// g{dc=GRA} 2"],
+            process(body, &conf)
+        );
+
+        let transcompiler = Transcompiler::new(ScraperFormat::Prometheus);
+        let body = "# TYPE f counter\r\nf{host=\"a\"} 1 1\r\n";
+        let lines = super::Scraper::process(
+            &transcompiler,
+            body,
+            &conf,
+            &Mutex::new(Counters::default()),
+        )
+        .wait()
+        .expect("process body");
+        assert_eq!(vec!["1000// f{dc=GRA,host=a} 1"], lines);
+    }
+
+    #[test]
+    fn continuation_lines() {
+        let body = "1// cpu{} 1\n=2// 2\n=eq{} 3";
+        let mut raw = RawScraper {
+            url: String::from("http://127.0.0.1/metrics"),
+            period: String::from("1s"),
+            labels: Some(
+                vec![(String::from("dc"), String::from("GRA"))]
+                    .into_iter()
+                    .collect(),
+            ),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            vec!["1// cpu{dc=GRA} 1", "=2// 2", "=eq{} 3"],
+            process(body, &scraper(raw.to_owned()))
+        );
+
+        raw.continuation_lines = Some(false);
+        assert_eq!(
+            vec!["1// cpu{dc=GRA} 1", "=eq{dc=GRA} 3"],
+            process("1// cpu{} 1\n=eq{} 3", &scraper(raw))
+        );
+    }
+
+    #[test]
+    fn counter_reset_detection() {
+        let conf = scraper(RawScraper {
+            url: String::from("http://127.0.0.1/metrics"),
+            period: String::from("1s"),
+            counter_reset_detection: Some(String::from("adjust")),
+            ..Default::default()
+        });
+
+        let transcompiler = Transcompiler::new(ScraperFormat::Prometheus);
+        let counters = Mutex::new(Counters::default());
+        let scrape = |body: &str| {
+            super::Scraper::process(&transcompiler, body, &conf, &counters)
+                .wait()
+                .expect("process body")
+        };
+
+        let body = "# TYPE requests counter\nrequests_total{code=\"200\"} 10 1\nmemory 8 1";
+        assert_eq!(
+            vec!["1000// requests_total{code=200} 10", "1000// memory{} 8"],
+            scrape(body)
+        );
+
+        let body = "# TYPE requests counter\nrequests_total{code=\"200\"} 14 2\nmemory 6 2";
+        assert_eq!(
+            vec!["2000// requests_total{code=200} 14", "2000// memory{} 6"],
+            scrape(body)
+        );
+
+        // The target restarted
+        let body = "# TYPE requests counter\nrequests_total{code=\"200\"} 3 3\nmemory 2 3";
+        assert_eq!(
+            vec!["3000// requests_total{code=200} 17", "3000// memory{} 2"],
+            scrape(body)
+        );
+    }
+
+    #[test]
+    fn write_failure_series() {
+        let dir = temp_dir().join(format!("beamium-{}", Uuid::new_v4()));
+        create_dir_all(&dir).expect("create source directory");
+
+        let params = parameters(&[("source-dir", dir.to_str().expect("utf-8 path"))])
+            .expect("valid parameters");
+
+        let mut rt = Runtime::new().expect("runtime");
+        let conf = scraper(RawScraper {
+            url: String::from("http://127.0.0.1/metrics"),
+            period: String::from("1s"),
+            ..Default::default()
+        });
+
+        rt.block_on(super::Scraper::write_failure(&conf, &params, "0"))
+            .expect("write nothing");
+        assert_eq!(0, read_dir(&dir).expect("read source directory").count());
+
+        let conf = scraper(RawScraper {
+            url: String::from("http://127.0.0.1/metrics"),
+            period: String::from("1s"),
+            emit_failure_series: Some(true),
+            failure_series_class: Some(String::from("beamium_scrape_error")),
+            ..Default::default()
+        });
+
+        rt.block_on(super::Scraper::write_failure(&conf, &params, "0"))
+            .expect("write failure series");
+
+        let files: Vec<_> = read_dir(&dir)
+            .expect("read source directory")
+            .map(|entry| entry.expect("valid entry").path())
+            .collect();
+
+        assert_eq!(1, files.len());
+        let content = read_to_string(&files[0]).expect("read source file");
+        assert!(content.ends_with("// beamium_scrape_error{scraper=test} 1\n"));
+
+        remove_dir_all(&dir).expect("remove source directory");
+    }
+
+    #[test]
+    fn encode_scraper_series() {
+        let params = parameters(&[]).expect("valid parameters");
+        let conf = scraper(RawScraper {
+            url: String::from("http://127.0.0.1/metrics"),
+            period: String::from("1s"),
+            labels: Some(
+                vec![(String::from("dc"), String::from("rbx \"1\""))]
+                    .into_iter()
+                    .collect(),
+            ),
+            ..Default::default()
+        });
+        let conf = conf::Scraper {
+            name: String::from("a,b}c=d"),
+            ..conf
+        };
+
+        // Characters of the name or the labels splitting the labels are encoded
+        let line = super::Scraper::series(&conf, &params, "up", &[("scraper", conf.name.as_str())]);
+        assert!(line.ends_with("// up{dc=rbx%20%221%22,scraper=a%2Cb%7Dc%3Dd} 1"));
+    }
+
+    #[test]
+    fn write_status_series() {
+        let dir = temp_dir().join(format!("beamium-{}", Uuid::new_v4()));
+        create_dir_all(&dir).expect("create source directory");
+
+        let params = parameters(&[("source-dir", dir.to_str().expect("utf-8 path"))])
+            .expect("valid parameters");
+
+        let conf = scraper(RawScraper {
+            url: String::from("http://127.0.0.1/metrics"),
+            period: String::from("1s"),
+            ..Default::default()
+        });
+        assert_eq!(None, super::Scraper::status(&conf, &params, "200"));
+
+        let conf = scraper(RawScraper {
+            url: String::from("http://127.0.0.1/metrics"),
+            period: String::from("1s"),
+            emit_status_series: Some(true),
+            ..Default::default()
+        });
+
+        let line = super::Scraper::status(&conf, &params, "200").expect("status series");
+        assert!(line.ends_with("// scrape_http_status{scraper=test,code=200} 1"));
+
+        // A failed scrape gets the status of the rejected response, `timeout` or `0`
+        assert_eq!(
+            "404",
+            super::Scraper::code_of(&Error::from(Rejection::new(404, "not found")))
+        );
+        assert_eq!(
+            "timeout",
+            super::Scraper::code_of(&Error::from(Rejection::new("timeout", "elapsed")))
+        );
+        assert_eq!(
+            "0",
+            super::Scraper::code_of(&format_err!("connection refused"))
+        );
+
+        let mut rt = Runtime::new().expect("runtime");
+        rt.block_on(super::Scraper::write_failure(&conf, &params, "timeout"))
+            .expect("write status series");
+
+        let files: Vec<_> = read_dir(&dir)
+            .expect("read source directory")
+            .map(|entry| entry.expect("valid entry").path())
+            .collect();
+
+        assert_eq!(1, files.len());
+        let content = read_to_string(&files[0]).expect("read source file");
+        assert!(content.ends_with("// scrape_http_status{scraper=test,code=timeout} 1\n"));
+
+        remove_dir_all(&dir).expect("remove source directory");
+    }
+
+    #[test]
+    fn detect_format_once() {
+        let conf = scraper(RawScraper {
+            url: String::from("http://127.0.0.1/metrics"),
+            period: String::from("1s"),
+            format: Some(String::from("auto")),
+            ..Default::default()
+        });
+
+        let detected = Mutex::new(None);
+        assert_eq!(
+            ScraperFormat::Prometheus,
+            super::Scraper::detect("# HELP f ...", &detected, &conf)
+        );
+        assert_eq!(
+            ScraperFormat::Sensision,
+            super::Scraper::detect("1// f{} 1", &detected, &conf)
+        );
+        assert_eq!(
+            ScraperFormat::Sensision,
+            super::Scraper::detect("f{} 1", &detected, &conf)
+        );
+    }
+
+    #[test]
+    fn normalize_label_keys() {
+        let conf = scraper(RawScraper {
+            url: String::from("http://127.0.0.1/metrics"),
+            period: String::from("1s"),
+            labels: Some(
+                vec![(String::from("dc"), String::from("GRA"))]
+                    .into_iter()
+                    .collect(),
+            ),
+            filtered_labels: Some(vec![String::from("job_id")]),
+            normalize_label_keys: Some(true),
+            ..Default::default()
+        });
+
+        let body = "1// cpu{Host=Web-1,HOST=web-2,Job.Id=1} 1\n=2// 2\n";
+        assert_eq!(
+            vec!["1// cpu{dc=GRA,host=Web-1} 1", "=2// 2"],
+            process(body, &conf)
+        );
+    }
+
+    #[test]
+    fn detect_merged_series() {
+        let raw = RawScraper {
+            url: String::from("http://127.0.0.1/metrics"),
+            period: String::from("1s"),
+            filtered_labels: Some(vec![String::from("id")]),
+            ..Default::default()
+        };
+
+        // A dedicated name keeps the counter away from the other tests
+        let conf = conf::Scraper::try_from((String::from("merged"), raw)).expect("valid scraper");
+        let merged = || {
+            super::BEAMIUM_SERIES_MERGED
+                .with_label_values(&["merged"])
+                .get()
+        };
+
+        // Samples of the same time series are not merged ones
+        let body = "1// cpu{host=a,id=1} 1\n2// cpu{host=a,id=1} 2\n1// cpu{host=b,id=1} 3\n";
+        assert_eq!(
+            vec![
+                "1// cpu{host=a} 1",
+                "2// cpu{host=a} 2",
+                "1// cpu{host=b} 3"
+            ],
+            process(body, &conf)
+        );
+        assert_eq!(0.0, merged());
+
+        let body =
+            "1// cpu{host=a,id=1} 1\n=2// 2\n1// cpu{host=a,id=2} 3\n1// mem{host=a,id=2} 4\n";
+        assert_eq!(
+            vec![
+                "1// cpu{host=a} 1",
+                "=2// 2",
+                "1// cpu{host=a} 3",
+                "1// mem{host=a} 4"
+            ],
+            process(body, &conf)
+        );
+        assert_eq!(1.0, merged());
+    }
+
+    #[test]
+    fn label_cardinality() {
+        let raw = RawScraper {
+            url: String::from("http://127.0.0.1/metrics"),
+            period: String::from("1s"),
+            label_cardinality: Some(true),
+            labels: Some(
+                vec![(String::from("dc"), String::from("rbx"))]
+                    .into_iter()
+                    .collect(),
+            ),
+            ..Default::default()
+        };
+
+        let conf =
+            conf::Scraper::try_from((String::from("cardinality"), raw)).expect("valid scraper");
+        let cardinality = |label: &str| {
+            super::BEAMIUM_LABEL_CARDINALITY
+                .with_label_values(&["cardinality", label])
+                .get()
+        };
+
+        let body =
+            "1// cpu{host=a,id=1} 1\n=2// 2\n1// cpu{host=b,id=1} 3\n1// mem{host=b,id=2} 4\n";
+        process(body, &conf);
+        assert_eq!(2.0, cardinality("host"));
+        assert_eq!(2.0, cardinality("id"));
+        assert_eq!(1.0, cardinality("dc"));
+
+        // The gauges reflect the last scrape only
+        process("1// cpu{host=a,id=1} 1\n", &conf);
+        assert_eq!(1.0, cardinality("host"));
+        assert_eq!(1.0, cardinality("id"));
+
+        // The label keys missing from the scrape are removed
+        let keys = || -> Vec<String> {
+            let mut keys: Vec<String> = super::BEAMIUM_LABEL_CARDINALITY
+                .collect()
+                .iter()
+                .flat_map(|family| family.get_metric())
+                .map(|metric| metric.get_label())
+                .filter(|labels| labels.iter().any(|pair| pair.get_value() == "cardinality"))
+                .flat_map(|labels| labels.iter().filter(|pair| pair.get_name() == "label"))
+                .map(|pair| pair.get_value().to_owned())
+                .collect();
+
+            keys.sort();
+            keys
+        };
+
+        process("1// cpu{host=a} 1\n", &conf);
+        assert_eq!(vec!["dc", "host"], keys());
+    }
+
+    #[test]
+    fn cardinality_guard() {
+        let guard = conf::CardinalityGuard {
+            mode: conf::GuardMode::Warn,
+            factor: 2.0,
+            window: 3,
+        };
+
+        // The first scrape has no baseline
+        let mut baseline = Baseline::default();
+        assert_eq!(None, baseline.check(100, &guard));
+        assert_eq!(None, baseline.check(200, &guard));
+
+        // The baseline is 150, a scrape jumps once it holds more than 300 time series
+        assert_eq!(None, baseline.check(300, &guard));
+        assert_eq!(Some(200.0), baseline.check(401, &guard));
+
+        // Only the last scrapes of the window make the baseline
+        assert_eq!(None, baseline.check(600, &guard));
+        assert_eq!(Some(1301.0 / 3.0), baseline.check(1000, &guard));
+
+        // Blocked scrapes are left out of the baseline, so it is not raised by the jumps
+        let guard = conf::CardinalityGuard {
+            mode: conf::GuardMode::Block,
+            ..guard
+        };
+        let mut baseline = Baseline::default();
+        assert_eq!(None, baseline.check(100, &guard));
+        assert_eq!(Some(100.0), baseline.check(300, &guard));
+        assert_eq!(Some(100.0), baseline.check(300, &guard));
+        assert_eq!(None, baseline.check(150, &guard));
+        assert_eq!(Some(125.0), baseline.check(300, &guard));
+
+        // Continuation lines are not counted and a blocked scrape is dropped
+        let conf = scraper(RawScraper {
+            url: String::from("http://127.0.0.1/metrics"),
+            period: String::from("1s"),
+            cardinality_guard: Some(RawCardinalityGuard {
+                mode: Some(String::from("block")),
+                window: Some(1),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+
+        let scrape = |count: usize| -> Vec<String> {
+            (0..count)
+                .flat_map(|idx| vec![format!("1// f{{idx={}}} 1", idx), String::from("=2// 2")])
+                .collect()
+        };
+
+        let baseline = Mutex::new(Baseline::default());
+        let guard = |lines| super::Scraper::guard(lines, &conf, &baseline).expect("guard");
+        assert_eq!(20, guard(scrape(10)).len());
+        assert_eq!(40, guard(scrape(20)).len());
+        assert!(guard(scrape(41)).is_empty());
+    }
+
+    #[test]
+    fn write_every_accumulates_scrapes() {
+        let dir = temp_dir().join(format!("beamium-{}", Uuid::new_v4()));
+        create_dir_all(&dir).expect("create source directory");
+
+        let conf = scraper(RawScraper {
+            url: String::from("http://127.0.0.1/metrics"),
+            period: String::from("1s"),
+            write_every: Some(String::from("10s")),
+            ..Default::default()
+        });
+
+        let params = parameters(&[("source-dir", dir.to_str().expect("utf-8 path"))])
+            .expect("valid parameters");
+
+        let buffer = Mutex::new(super::Buffer::default());
+        let start = buffer.lock().expect("buffer").since;
+        let mut rt = Runtime::new().expect("runtime");
+        for tick in 0..=10 {
+            let lines = process(&format!("{}// cpu{{}} {}\n", tick, tick), &conf);
+            let now = start + Duration::from_secs(tick);
+            let lines = super::Scraper::accumulate(lines, &buffer, &conf, &params, now);
+
+            assert_eq!(if tick < 10 { 0 } else { 11 }, lines.len());
+            rt.block_on(super::Scraper::write(lines, &conf, &params))
+                .expect("write scrape");
+        }
+
+        assert_eq!(1, read_dir(&dir).expect("read source directory").count());
+        assert!(buffer.lock().expect("buffer").lines.is_empty());
+
+        remove_dir_all(&dir).expect("remove source directory");
+    }
+
+    #[test]
+    fn flush_accumulated_scrapes_on_stop() {
+        let dir = temp_dir().join(format!("beamium-{}", Uuid::new_v4()));
+        create_dir_all(&dir).expect("create source directory");
+
+        let conf = scraper(RawScraper {
+            url: String::from("http://127.0.0.1/metrics"),
+            period: String::from("1s"),
+            write_every: Some(String::from("1h")),
+            ..Default::default()
+        });
+
+        let params = parameters(&[("source-dir", dir.to_str().expect("utf-8 path"))])
+            .expect("valid parameters");
+
+        let scraper = super::Scraper::from((conf.to_owned(), params.to_owned()));
+        let lines = process("1// cpu{} 1\n2// cpu{} 2\n", &conf);
+        let now = Instant::now();
+        assert!(super::Scraper::accumulate(lines, &scraper.buffer, &conf, &params, now).is_empty());
+        assert_eq!(0, read_dir(&dir).expect("read source directory").count());
+
+        scraper.stop(Runtime::new().expect("runtime"));
+
+        let files: Vec<_> = read_dir(&dir)
+            .expect("read source directory")
+            .map(|entry| entry.expect("directory entry").path())
+            .collect();
+        assert_eq!(1, files.len());
+        assert_eq!(
+            "1// cpu{} 1\n2// cpu{} 2\n",
+            read_to_string(&files[0]).expect("read source file")
+        );
+
+        remove_dir_all(&dir).expect("remove source directory");
+    }
+
+    #[test]
+    fn write_every_is_bounded_by_batch_size() {
+        let conf = scraper(RawScraper {
+            url: String::from("http://127.0.0.1/metrics"),
+            period: String::from("1s"),
+            write_every: Some(String::from("1h")),
+            ..Default::default()
+        });
+
+        let params = parameters(&[("batch-size", "1024")]).expect("valid parameters");
+        let buffer = Mutex::new(super::Buffer::default());
+        let now = buffer.lock().expect("buffer").since;
+
+        let line = format!("1// cpu{{}} {}\n", "1".repeat(500));
+        let lines = process(&line, &conf);
+        assert!(super::Scraper::accumulate(lines, &buffer, &conf, &params, now).is_empty());
+
+        let lines = process(&line.repeat(2), &conf);
+        let lines = super::Scraper::accumulate(lines, &buffer, &conf, &params, now);
+        assert_eq!(3, lines.len());
+    }
+
+    #[test]
+    fn skip_empty_scrape() {
+        let dir = temp_dir().join(format!("beamium-{}", Uuid::new_v4()));
+        create_dir_all(&dir).expect("create source directory");
+
+        let conf = scraper(RawScraper {
+            url: String::from("http://127.0.0.1/metrics"),
+            period: String::from("1s"),
+            ..Default::default()
+        });
+
+        let params = parameters(&[("source-dir", dir.to_str().expect("utf-8 path"))])
+            .expect("valid parameters");
+
+        let lines = process("", &conf);
+        assert!(lines.is_empty());
+
+        let mut rt = Runtime::new().expect("runtime");
+        rt.block_on(super::Scraper::write(lines, &conf, &params))
+            .expect("empty scrape is not an error");
+
+        assert_eq!(0, read_dir(&dir).expect("read source directory").count());
+
+        remove_dir_all(&dir).expect("remove source directory");
+    }
+
+    #[test]
+    fn write_many_chunks_with_low_concurrency() {
+        let dir = temp_dir().join(format!("beamium-{}", Uuid::new_v4()));
+        create_dir_all(&dir).expect("create source directory");
+
+        let conf = scraper(RawScraper {
+            url: String::from("http://127.0.0.1/metrics"),
+            period: String::from("1s"),
+            ..Default::default()
+        });
+
+        let params = parameters(&[
+            ("source-dir", dir.to_str().expect("utf-8 path")),
+            ("batch-size", "1024"),
+            ("filesystem-threads", "2"),
+        ])
+        .expect("valid parameters");
+
+        let lines: Vec<String> = (0..1000)
+            .flat_map(|idx| {
+                vec![
+                    format!("1// metric{{idx={}}} {}", idx, idx),
+                    format!("=2// {}", idx),
+                ]
+            })
+            .collect();
+
+        let mut rt = Runtime::new().expect("runtime");
+        rt.block_on(super::Scraper::write(lines.to_owned(), &conf, &params))
+            .expect("write chunks");
+
+        let mut files: Vec<_> = read_dir(&dir)
+            .expect("read source directory")
+            .map(|entry| entry.expect("valid entry").path())
+            .collect();
+
+        assert!(files.len() > 10);
+        assert!(files
+            .iter()
+            .all(|file| file.extension().and_then(|ext| ext.to_str()) == Some("metrics")));
+
+        files.sort_by_key(|file| {
+            let name = file
+                .file_stem()
+                .and_then(|name| name.to_str())
+                .unwrap_or("");
+            name.rsplit('-')
+                .next()
+                .and_then(|idx| idx.parse::<usize>().ok())
+                .unwrap_or(0)
+        });
+
+        let mut written = vec![];
+        for file in files {
+            let content = read_to_string(&file).expect("read source file");
+            assert!(
+                !content.starts_with('='),
+                "a chunk starts with a continuation"
+            );
+            written.extend(content.lines().map(String::from));
+        }
+
+        assert_eq!(lines, written);
+        remove_dir_all(&dir).expect("remove source directory");
+    }
+
+    #[test]
+    fn stream_large_body() {
+        let dir = temp_dir().join(format!("beamium-{}", Uuid::new_v4()));
+        create_dir_all(&dir).expect("create source directory");
+
+        let conf = scraper(RawScraper {
+            url: String::from("http://127.0.0.1/metrics"),
+            period: String::from("1s"),
+            format: Some(String::from("prometheus")),
+            metrics: Some(vec![String::from("node_.*")]),
+            metrics_match: Some(String::from("class")),
+            labels: Some(
+                vec![(String::from("dc"), String::from("GRA"))]
+                    .into_iter()
+                    .collect(),
+            ),
+            stream_body: Some(true),
+            ..Default::default()
+        });
+
+        let params = parameters(&[
+            ("source-dir", dir.to_str().expect("utf-8 path")),
+            ("batch-size", "2Kb"),
+        ])
+        .expect("valid parameters");
+
+        let mut body = String::new();
+        for i in 0..2000 {
+            body.push_str(&format!(
+                "# HELP node_cpu cpu time\nnode_cpu{{cpu=\"{}\"}} {} 1000\napp_requests{{}} {} 1000\n",
+                i, i, i
+            ));
+        }
+
+        let compiler = || Transcompiler::new(ScraperFormat::Prometheus).at(1);
+        let buffered =
+            super::Scraper::process(&compiler(), &body, &conf, &Mutex::new(Counters::default()))
+                .wait()
+                .expect("process body");
+        assert_eq!(2000, buffered.len());
+
+        // Chunks are cut in the middle of the lines
+        let chunks: Vec<Vec<u8>> = body
+            .as_bytes()
+            .chunks(1000)
+            .map(|chunk| chunk.to_vec())
+            .collect();
+
+        let mut rt = Runtime::new().expect("runtime");
+        rt.block_on(super::Scraper::stream(
+            stream::iter_ok(chunks),
+            compiler(),
+            Arc::new(conf),
+            Arc::new(params),
+            Arc::new(Mutex::new(None)),
+            Arc::new(Mutex::new(Counters::default())),
+            Some(String::from("1// scrape_http_status{code=200} 1")),
+        ))
+        .expect("stream body");
+
+        let mut files: Vec<(usize, PathBuf)> = read_dir(&dir)
+            .expect("read source directory")
+            .map(|entry| entry.expect("directory entry").path())
+            .map(|path| {
+                let stem = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .unwrap_or_default();
+                let batch_count = stem.rsplit('-').next().and_then(|count| count.parse().ok());
+                (batch_count.expect("batch count"), path)
+            })
+            .collect();
+        files.sort();
+        assert!(files.len() > 1);
+
+        let streamed: Vec<String> = files
+            .iter()
+            .flat_map(|(_, path)| {
+                let content = read_to_string(path).expect("read source file");
+                content.lines().map(String::from).collect::<Vec<_>>()
+            })
+            .collect();
+
+        // The status series ends the last batch
+        assert_eq!(
+            Some(&String::from("1// scrape_http_status{code=200} 1")),
+            streamed.last()
+        );
+        assert_eq!(buffered, streamed[..streamed.len() - 1]);
+
+        remove_dir_all(&dir).expect("remove source directory");
+    }
+
+    #[test]
+    fn parallel_parsing() {
+        let raw = || RawScraper {
+            url: String::from("http://127.0.0.1/metrics"),
+            period: String::from("1s"),
+            format: Some(String::from("prometheus")),
+            metrics: Some(vec![String::from("node_.*")]),
+            metrics_match: Some(String::from("class")),
+            summary_handling: Some(String::from("split")),
+            label_cardinality: Some(true),
+            pool: Some(4),
+            ..Default::default()
+        };
+
+        let serial = scraper(raw());
+        let parallel = scraper(RawScraper {
+            parallel_parsing: Some(true),
+            ..raw()
+        });
+
+        // Types are declared at the start of the body only, the gauge quantiles are never split
+        let mut body = String::from("# TYPE node_rpc gauge\n");
+        for i in 0..3000 {
+            body.push_str(&format!(
+                "node_cpu{{cpu=\"{}\"}} {} 1000\nnode_rpc{{quantile=\"0.99\",id=\"{}\"}} {} 1000\napp_requests{{}} {} 1000\n",
+                i, i, i, i, i
+            ));
+        }
+        body.push_str("# EOF\nnode_cpu{cpu=\"eof\"} 1 1000\n");
+
+        let mut rt = Runtime::new().expect("runtime");
+        let mut process = |body: &str, conf: &conf::Scraper| {
+            let compiler = Transcompiler::new(ScraperFormat::Prometheus).at(1);
+            rt.block_on(super::Scraper::process(
+                &compiler,
+                body,
+                conf,
+                &Mutex::new(Counters::default()),
+            ))
+            .expect("process body")
+        };
+
+        assert_eq!(
+            4,
+            super::Scraper::chunks(&body, &parallel)
+                .expect("chunks")
+                .len()
+        );
+        let lines = process(&body, &serial);
+        assert_eq!(6001, lines.len());
+        assert!(!lines.iter().any(|line| line.contains("node_rpc_p99{")));
+        assert_eq!(lines, process(&body, &parallel));
+
+        // Lines after the '# EOF' marker are dropped whatever the chunk holding them
+        let (serial, parallel) = (
+            conf::Scraper {
+                stop_at_eof: true,
+                ..serial
+            },
+            conf::Scraper {
+                stop_at_eof: true,
+                ..parallel
+            },
+        );
+        let lines = process(&body, &serial);
+        assert_eq!(6000, lines.len());
+        assert_eq!(lines, process(&body, &parallel));
+
+        // Continuation lines stay in the chunk of their time series
+        let body: String = (0..4000)
+            .map(|idx| format!("1// class_{}{{}} 1\n=2// 2\n=3// 3\n", idx))
+            .collect();
+        let chunks = super::Scraper::chunks(&body, &parallel).expect("chunks");
+        assert!(chunks.iter().all(|chunk| chunk.starts_with("1// ")));
+        assert_eq!(
+            body.lines().count(),
+            chunks
+                .iter()
+                .map(|chunk| chunk.lines().count())
+                .sum::<usize>()
+        );
+
+        // Small bodies and state tracked across the scrape are processed serially
+        assert!(super::Scraper::chunks("1// class{} 1", &parallel).is_none());
+        let counters = scraper(RawScraper {
+            parallel_parsing: Some(true),
+            counter_reset_detection: Some(String::from("adjust")),
+            ..raw()
+        });
+        assert!(super::Scraper::chunks(&body, &counters).is_none());
+
+        let err = conf::Scraper::try_from((
+            String::from("test"),
+            RawScraper {
+                parallel_parsing: Some(true),
+                stream_body: Some(true),
+                ..raw()
+            },
+        ))
+        .expect_err("parallel parsing of a streamed body");
+        assert!(err
+            .to_string()
+            .starts_with("could not parse 'parallel-parsing' setting"));
+    }
+
+    #[test]
+    #[ignore]
+    fn parallel_parsing_benchmark() {
+        let body: String = (0..200_000)
+            .map(|idx| format!("node_cpu{{cpu=\"{}\",mode=\"idle\"}} {} 1000\n", idx, idx))
+            .collect();
+
+        for parallel in &[false, true] {
+            let conf = scraper(RawScraper {
+                url: String::from("http://127.0.0.1/metrics"),
+                period: String::from("1s"),
+                format: Some(String::from("prometheus")),
+                parallel_parsing: Some(*parallel),
+                pool: Some(4),
+                ..Default::default()
+            });
+
+            let mut rt = Runtime::new().expect("runtime");
+            let compiler = Transcompiler::new(ScraperFormat::Prometheus);
+            let start = Instant::now();
+            let lines = rt
+                .block_on(super::Scraper::process(
+                    &compiler,
+                    &body,
+                    &conf,
+                    &Mutex::new(Counters::default()),
+                ))
+                .expect("process body");
+            println!(
+                "parallel: {}, {} lines processed in {:?}",
+                parallel,
+                lines.len(),
+                start.elapsed()
+            );
+        }
+    }
+
+    #[test]
+    fn require_content_type() {
+        let conf = scraper(RawScraper {
+            url: String::from("http://127.0.0.1/metrics"),
+            period: String::from("1s"),
+            require_content_type: Some(String::from("^text/plain")),
+            ..Default::default()
+        });
+
+        let pattern = conf.require_content_type.expect("pattern is set");
+        let headers = |content_type: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                CONTENT_TYPE,
+                HeaderValue::from_str(content_type).expect("valid header"),
+            );
+            headers
+        };
+
+        super::Scraper::check_content_type(&headers("text/plain; version=0.0.4"), &pattern)
+            .expect("metrics are accepted");
+
+        // The html error page of a proxy is rejected, as well as a response without content type
+        let err = super::Scraper::check_content_type(&headers("text/html"), &pattern)
+            .expect_err("html is rejected");
+        assert_eq!(
+            "content type 'text/html' does not match '^text/plain'",
+            err.to_string()
+        );
+
+        super::Scraper::check_content_type(&HeaderMap::new(), &pattern)
+            .expect_err("content type is missing");
+    }
+
+    #[test]
+    fn fetch_success_log() {
+        let logged: Vec<bool> = (0..5)
+            .map(|scrapes| super::Scraper::is_success_logged(scrapes, Some(2)))
+            .collect();
+        assert_eq!(vec![true, false, true, false, true], logged);
+
+        // Without setting, every successful scrape is logged at info level
+        assert!((0..5).all(|scrapes| super::Scraper::is_success_logged(scrapes, None)));
+    }
+
+    #[test]
+    fn gate_scrapes_per_host() {
+        let mut rt = Runtime::new().expect("runtime");
+        let mut is_ready = |semaphore: &Semaphore| {
+            let mut acquire = semaphore.acquire();
+            rt.block_on(future::lazy(move || {
+                acquire.poll().map(|acquire| acquire.is_ready())
+            }))
+            .expect("poll permit")
+        };
+
+        // Without limit, scrapes are not gated
+        assert!(Hosts::from(None).semaphore("127.0.0.1:9100").is_none());
+
+        let mut hosts = Hosts::from(Some(1));
+        let first = hosts.semaphore("127.0.0.1:9100").expect("host is limited");
+        let guard = first.acquire().wait().expect("acquire permit");
+
+        // The scrapers of the same host share the permit, other hosts have their own
+        let second = hosts.semaphore("127.0.0.1:9100").expect("host is limited");
+        assert!(!is_ready(&second));
+        assert!(is_ready(
+            &hosts.semaphore("127.0.0.1:9200").expect("host is limited")
+        ));
+
+        drop(guard);
+        assert!(is_ready(&second));
+    }
+    #[test]
+    fn gate_builds_scrape_once_permitted() {
+        let conf = scraper(RawScraper {
+            url: String::from("http://gate.test:9100/metrics"),
+            period: String::from("1s"),
+            ..Default::default()
+        });
+
+        super::limit_hosts(Some(1));
+        let built = Arc::new(Mutex::new(vec![]));
+        let build = |name: &'static str, delay: u64| {
+            let built = built.to_owned();
+            move || {
+                built.lock().expect("lock").push(name);
+                Delay::new(Instant::now() + Duration::from_millis(delay))
+                    .map_err(|err| format_err!("{}", err))
+                    .map(move |_| built.lock().expect("lock").push("done"))
+            }
+        };
+
+        // The second scrape waits for the first one, its timeout starts once it is permitted
+        let first = super::Scraper::gate(&conf, build("first", 200));
+        let second = super::Scraper::gate(&conf, {
+            let build = build("second", 10);
+            move || {
+                build()
+                    .timeout(Duration::from_millis(100))
+                    .map_err(|err| format_err!("{}", err))
+            }
+        });
+
+        let mut rt = Runtime::new().expect("runtime");
+        rt.block_on(first.join(second)).expect("scrapes succeed");
+        assert_eq!(
+            vec!["first", "done", "second", "done"],
+            *built.lock().expect("lock")
+        );
 
-        bulk.join(chunk).and_then(|_| future::ok(()))
+        super::limit_hosts(None);
     }
 }
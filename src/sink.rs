@@ -1,14 +1,14 @@
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{HashMap, HashSet};
 use std::convert::From;
 use std::fs::Metadata;
 use std::path::PathBuf;
 use std::process::abort;
 use std::sync::{Arc, Mutex};
 use std::thread::sleep;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 
 use failure::{format_err, Error};
-use futures::future::{ExecuteErrorKind, Executor};
+use futures::future::{Either, ExecuteErrorKind, Executor};
 use futures::Stream;
 use prometheus::CounterVec;
 use tokio::fs::remove_file;
@@ -16,8 +16,10 @@ use tokio::prelude::*;
 use tokio::runtime::Runtime;
 
 use crate::conf;
+use crate::lib::archive::archive;
 use crate::lib::asynch::fs::Scanner;
-use crate::lib::asynch::http::Sender;
+use crate::lib::asynch::http::{Breaker, Ramp, Sender};
+use crate::lib::queue::Queue;
 use crate::lib::{Named, Runner};
 
 lazy_static! {
@@ -37,13 +39,51 @@ lazy_static! {
         &["sink"]
     )
     .expect("create metric: 'beamium_skip_max_size'");
+    /// Number of files queued by each sink as of its last scan, read by the router
+    static ref QUEUED: Mutex<HashMap<String, usize>> = Mutex::new(HashMap::new());
+}
+
+/// Record the number of files queued by the sink
+pub(crate) fn account(sink: &str, files: usize) {
+    match QUEUED.lock() {
+        Ok(mut queued) => {
+            queued.insert(sink.to_owned(), files);
+        }
+        Err(err) => error!("could not get lock on queued files"; "error" => err.to_string()),
+    }
+}
+
+/// Count a file routed to the sink since its last scan
+pub(crate) fn queue(sink: &str) {
+    match QUEUED.lock() {
+        Ok(mut queued) => *queued.entry(sink.to_owned()).or_insert(0) += 1,
+        Err(err) => error!("could not get lock on queued files"; "error" => err.to_string()),
+    }
+}
+
+/// Whether the sink queued as many files as allowed, so the router skips it
+pub(crate) fn is_full(conf: &conf::Sink) -> bool {
+    let max = match conf.max_files {
+        Some(max) => max,
+        None => return false,
+    };
+
+    match QUEUED.lock() {
+        Ok(queued) => queued.get(&conf.name).map_or(false, |files| *files >= max),
+        Err(err) => {
+            error!("could not get lock on queued files"; "error" => err.to_string());
+            false
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Sink {
     conf: Arc<conf::Sink>,
     params: Arc<conf::Parameters>,
-    queue: Arc<Mutex<VecDeque<PathBuf>>>,
+    queue: Arc<Mutex<Queue>>,
+    breaker: Arc<Mutex<Breaker>>,
+    ramp: Arc<Mutex<Ramp>>,
 }
 
 impl From<(conf::Sink, conf::Parameters)> for Sink {
@@ -51,9 +91,11 @@ impl From<(conf::Sink, conf::Parameters)> for Sink {
         let (conf, params) = tuple;
 
         Self {
+            breaker: mutex!(Breaker::from(&conf)),
+            ramp: mutex!(Ramp::from((&conf, Instant::now()))),
             conf: arc!(conf),
             params: arc!(params),
-            queue: mutex!(VecDeque::new()),
+            queue: mutex!(Queue::default()),
         }
     }
 }
@@ -69,12 +111,14 @@ impl Runner for Sink {
 
     fn start(&self, rt: &mut Runtime) -> Result<(), Self::Error> {
         let name = self.name();
-        let dir = self.params.sink_dir.to_owned();
+        let dir = self.params.sink_dir_of(&name);
 
         for _ in 0..self.conf.parallel.to_owned() {
             let name = self.name();
             let task = Sender::from((
                 self.queue.to_owned(),
+                self.breaker.to_owned(),
+                self.ramp.to_owned(),
                 self.conf.to_owned(),
                 self.params.to_owned(),
             ))
@@ -92,7 +136,11 @@ impl Runner for Sink {
         let mutex = self.queue.to_owned();
         let executor = rt.executor();
 
-        let scanner = Scanner::from((PathBuf::from(dir.to_owned()), self.params.scan_period.to_owned()))
+        let scanner = Scanner::from((
+            dir.to_owned(),
+            self.params.scan_period.to_owned(),
+            self.params.spool_symlinks.to_owned(),
+        ))
             .fold(HashSet::new(), move |acc, entries| {
                 // Owned variables
                 let conf = conf.to_owned();
@@ -169,6 +217,8 @@ impl Runner for Sink {
                     acc
                 });
 
+                account(&conf.name, paths.len());
+
                 let mut current_size = entries.iter().fold(0, |acc, (_, meta)| acc + meta.len());
                 let new: Vec<PathBuf> = paths.difference(&acc).cloned().collect();
 
@@ -195,16 +245,12 @@ impl Runner for Sink {
 
                         let name = conf.name.to_owned();
 
-                        warn!("skip file"; "sink" => name.as_str(), "path" => path.to_str(), "reason" => "sink is too large");
+                        warn!("skip file"; "sink" => name.as_str(), "path" => path.to_str(), "reason" => "sink is too large", "policy" => format!("{:?}", conf.overflow_policy));
                         BEAMIUM_SKIP_MAX_SIZE
                             .with_label_values(&[name.as_str()])
                             .inc();
 
-                        let result = executor.execute(
-                            remove_file(path.to_owned())
-                                .and_then(|_| future::ok(()))
-                                .map_err(move |err| { error!("could not remove file"; "error" => err.to_string(), "sink" => name.as_str(), "path" => path.to_str()); })
-                        );
+                        let result = executor.execute(Self::overflow(&conf, path.to_owned()));
 
                         if let Err(err) = result {
                             match err.kind() {
@@ -225,7 +271,7 @@ impl Runner for Sink {
             })
             .and_then(|_| future::ok(()))
             .map_err(move |err| {
-                crit!("could not scan sink directory"; "sink" => name.as_str(), "dir" => dir.as_str(), "error" => err.to_string());
+                crit!("could not scan sink directory"; "sink" => name.as_str(), "dir" => dir.to_str(), "error" => err.to_string());
                 sleep(Duration::from_millis(100)); // Sleep the time to display the message
                 abort();
             });
@@ -235,3 +281,90 @@ impl Runner for Sink {
         Ok(())
     }
 }
+
+impl Sink {
+    /// Remove the file overflowing the sink, or compress it into the archive directory
+    fn overflow(conf: &conf::Sink, path: PathBuf) -> impl Future<Item = (), Error = ()> {
+        let name = conf.name.to_owned();
+        let path2 = path.to_owned();
+
+        match (&conf.overflow_policy, &conf.archive_dir) {
+            (conf::OverflowPolicy::Archive, Some(dir)) => Either::A(
+                archive(path.to_owned(), dir.to_owned(), conf.archive_size)
+                    .map(move |archive| {
+                        debug!("archive file"; "path" => path.to_str(), "archive" => archive.to_str());
+                    })
+                    .map_err(move |err| {
+                        error!("could not archive file"; "error" => err.to_string(), "sink" => name.as_str(), "path" => path2.to_str());
+                    }),
+            ),
+            _ => Either::B(remove_file(path).map_err(move |err| {
+                error!("could not remove file"; "error" => err.to_string(), "sink" => name.as_str(), "path" => path2.to_str());
+            })),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, read, read_dir, remove_dir_all, write};
+
+    use tokio::runtime::Runtime;
+    use uuid::Uuid;
+
+    use crate::conf::{self, RawSink};
+    use crate::lib::archive::gzip;
+
+    use super::Sink;
+
+    #[test]
+    fn archive_overflowing_files() {
+        let dir = temp_dir().join(format!("beamium-{}", Uuid::new_v4()));
+        let sink_dir = dir.join("sinks");
+        let archive_dir = dir.join("archive");
+        create_dir_all(&sink_dir).expect("create sink directory");
+        create_dir_all(&archive_dir).expect("create archive directory");
+
+        let sink = |policy: &str, archive_dir: Option<String>| {
+            let raw = RawSink {
+                url: String::from("https://warp.example.com/api/v0/update"),
+                token: String::from("token"),
+                overflow_policy: Some(String::from(policy)),
+                archive_dir,
+                ..Default::default()
+            };
+
+            conf::Sink::try_from((String::from("warp"), raw)).expect("valid sink")
+        };
+
+        let mut rt = Runtime::new().expect("runtime");
+        let data = b"1// f{host=a} 1\n";
+
+        // Under the archive policy, the file is compressed into the archive directory
+        let archived = sink("archive", archive_dir.to_str().map(String::from));
+        let path = sink_dir.join("warp-1-0.metrics");
+        write(&path, data).expect("write sink file");
+        rt.block_on(Sink::overflow(&archived, path.to_owned()))
+            .expect("archive file");
+
+        assert!(!path.exists());
+        assert_eq!(
+            gzip(data).expect("compress data"),
+            read(archive_dir.join("warp-1-0.metrics.gz")).expect("read archive")
+        );
+
+        // Under the drop policy, the file is removed
+        let dropped = sink("drop", None);
+        let path = sink_dir.join("warp-2-0.metrics");
+        write(&path, data).expect("write sink file");
+        rt.block_on(Sink::overflow(&dropped, path.to_owned()))
+            .expect("remove file");
+
+        assert!(!path.exists());
+        assert_eq!(1, read_dir(&archive_dir).expect("list archive").count());
+
+        remove_dir_all(&dir).expect("remove directory");
+    }
+}
@@ -0,0 +1,184 @@
+//! # Status module.
+//!
+//! The Status module logs a summary of the activity at a fixed interval, computed from the metrics.
+use std::time::{Duration, Instant};
+
+use failure::{format_err, Error};
+use prometheus::gather;
+use prometheus::proto::{MetricFamily, MetricType};
+use tokio::prelude::*;
+use tokio::runtime::Runtime;
+use tokio::timer::Interval;
+
+use crate::lib::{Named, Runner};
+
+/// Values of the metrics summed over their labels
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Totals {
+    scrapes: f64,
+    pushes: f64,
+    fetch_errors: f64,
+    push_errors: f64,
+    queued: f64,
+}
+
+impl From<&[MetricFamily]> for Totals {
+    fn from(families: &[MetricFamily]) -> Self {
+        let mut totals = Self::default();
+        for family in families {
+            let total = match family.get_name() {
+                "beamium_fetch_total" => &mut totals.scrapes,
+                "beamium_push_files_total" => &mut totals.pushes,
+                "beamium_fetch_errors" => &mut totals.fetch_errors,
+                "beamium_push_errors" => &mut totals.push_errors,
+                "beamium_directory_files" => &mut totals.queued,
+                _ => continue,
+            };
+
+            for metric in family.get_metric() {
+                *total += match family.get_field_type() {
+                    MetricType::GAUGE => metric.get_gauge().get_value(),
+                    _ => metric.get_counter().get_value(),
+                };
+            }
+        }
+
+        totals
+    }
+}
+
+/// Activity between two gatherings of the metrics
+#[derive(Clone, Debug, PartialEq)]
+struct Summary {
+    scrapes_per_second: f64,
+    pushes_per_second: f64,
+    fetch_errors: f64,
+    push_errors: f64,
+    queued: f64,
+}
+
+impl Summary {
+    fn between(previous: &Totals, current: &Totals, elapsed: Duration) -> Self {
+        // Guard against an empty interval, counters could not decrease
+        let secs = elapsed.as_secs_f64().max(f64::EPSILON);
+        let delta = |previous: f64, current: f64| (current - previous).max(0.0);
+
+        Self {
+            scrapes_per_second: delta(previous.scrapes, current.scrapes) / secs,
+            pushes_per_second: delta(previous.pushes, current.pushes) / secs,
+            fetch_errors: delta(previous.fetch_errors, current.fetch_errors),
+            push_errors: delta(previous.push_errors, current.push_errors),
+            queued: current.queued,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Status {
+    interval: Duration,
+}
+
+impl From<Duration> for Status {
+    fn from(interval: Duration) -> Self {
+        Self { interval }
+    }
+}
+
+impl Named for Status {
+    fn name(&self) -> String {
+        String::from("status")
+    }
+}
+
+impl Runner for Status {
+    type Error = Error;
+
+    fn start(&self, rt: &mut Runtime) -> Result<(), Self::Error> {
+        let start = Instant::now();
+        let mut previous = (Totals::from(&gather()[..]), start);
+
+        let ticker = Interval::new(start + self.interval, self.interval)
+            .map_err(|err| format_err!("{}", err))
+            .for_each(move |now| {
+                let totals = Totals::from(&gather()[..]);
+                let summary = Summary::between(&previous.0, &totals, now - previous.1);
+
+                info!("status";
+                    "scrapes_per_second" => format!("{:.2}", summary.scrapes_per_second),
+                    "pushes_per_second" => format!("{:.2}", summary.pushes_per_second),
+                    "queued_files" => summary.queued,
+                    "fetch_errors" => summary.fetch_errors,
+                    "push_errors" => summary.push_errors);
+
+                previous = (totals, now);
+                future::ok(())
+            })
+            .map_err(|err| {
+                error!("could not handle status ticker"; "error" => err.to_string());
+            });
+
+        rt.spawn(ticker);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use prometheus::{CounterVec, GaugeVec, Opts, Registry};
+
+    use super::{Summary, Totals};
+
+    #[test]
+    fn summary() {
+        let registry = Registry::new();
+        let counter = |name: &str, label: &str| {
+            let counter = CounterVec::new(Opts::new(name, name), &[label]).expect("valid counter");
+            registry
+                .register(Box::new(counter.to_owned()))
+                .expect("register counter");
+            counter
+        };
+
+        let scrapes = counter("beamium_fetch_total", "scraper");
+        let fetch_errors = counter("beamium_fetch_errors", "scraper");
+        let pushes = counter("beamium_push_files_total", "sink");
+        counter("beamium_push_errors", "sink");
+        let files = GaugeVec::new(
+            Opts::new("beamium_directory_files", "files"),
+            &["directory"],
+        )
+        .expect("valid gauge");
+        registry
+            .register(Box::new(files.to_owned()))
+            .expect("register gauge");
+
+        scrapes.with_label_values(&["node"]).inc_by(10.0);
+        pushes.with_label_values(&["warp"]).inc_by(4.0);
+        let previous = Totals::from(&registry.gather()[..]);
+        assert_eq!(10.0, previous.scrapes);
+        assert_eq!(0.0, previous.push_errors);
+
+        scrapes.with_label_values(&["node"]).inc_by(15.0);
+        scrapes.with_label_values(&["mysql"]).inc_by(5.0);
+        fetch_errors.with_label_values(&["mysql"]).inc_by(2.0);
+        pushes.with_label_values(&["warp"]).inc_by(10.0);
+        files.with_label_values(&["sources"]).set(3.0);
+        files.with_label_values(&["sinks"]).set(7.0);
+        let current = Totals::from(&registry.gather()[..]);
+
+        let summary = Summary::between(&previous, &current, Duration::from_secs(10));
+        assert_eq!(
+            Summary {
+                scrapes_per_second: 2.0,
+                pushes_per_second: 1.0,
+                fetch_errors: 2.0,
+                push_errors: 0.0,
+                queued: 10.0,
+            },
+            summary
+        );
+    }
+}